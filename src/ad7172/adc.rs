@@ -5,15 +5,49 @@ use stm32f4xx_hal::hal::{
     digital::v2::OutputPin,
 };
 use uom::si::{
-    f64::ElectricPotential,
+    f64::{ElectricPotential, ThermodynamicTemperature},
     electric_potential::volt,
+    thermodynamic_temperature::kelvin,
 };
 use super::{
     regs::{self, Register, RegisterData},
     checksum::{ChecksumMode, Checksum},
-    Mode, Input, RefSource, PostFilter, DigitalFilterOrder,
+    Mode, Input, RefSource, PostFilter, DigitalFilterOrder, Odr, Filter,
 };
 
+/// Channel and setup that are not used by the TEC channels, for
+/// occasional diagnostic conversions
+pub const DIAGNOSTIC_CHANNEL: u8 = 2;
+
+/// Output data rate of the TEC channels: 10 Hz
+const DEFAULT_ODR: u8 = 0b10011;
+
+/// Default external reference voltage
+const V_REF_EXTERNAL: f64 = 3.3;
+/// Internal reference voltage
+const V_REF_INTERNAL: f64 = 2.5;
+/// Nominal AVDD1 - AVSS
+const V_AVDD1: f64 = 5.0;
+/// Attenuation of the AVDD1 - AVSS supply monitor input
+const SUPPLY_MONITOR_ATTENUATION: f64 = 5.0;
+/// Internal temperature sensor sensitivity in V/K
+const TEMPERATURE_SENSITIVITY: f64 = 477e-6;
+/// Attempts to read a valid ID before giving up on the ADC
+const IDENTIFY_RETRIES: u32 = 100;
+
+#[derive(Debug)]
+pub enum AdcError<E> {
+    Spi(E),
+    /// No AD7172-2 ID was read, contains the last ID
+    IdentifyFailed(u16),
+}
+
+impl<E> From<E> for AdcError<E> {
+    fn from(e: E) -> Self {
+        AdcError::Spi(e)
+    }
+}
+
 /// AD7172-2 implementation
 ///
 /// [Manual](https://www.analog.com/media/en/technical-documentation/data-sheets/AD7172-2.pdf)
@@ -21,17 +55,26 @@ pub struct Adc<SPI: Transfer<u8>, NSS: OutputPin> {
     spi: SPI,
     nss: NSS,
     checksum_mode: ChecksumMode,
+    /// Register reads that were retried because of a checksum mismatch
+    read_checksum_errors: u32,
+    /// Register writes that the ADC flagged with `crc_error`
+    write_crc_errors: u32,
+    /// Magnitude of `RefSource::External`
+    v_ref_external: f64,
 }
 
 impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS> {
-    pub fn new(spi: SPI, mut nss: NSS) -> Result<Self, SPI::Error> {
+    pub fn new(spi: SPI, mut nss: NSS) -> Result<Self, AdcError<SPI::Error>> {
         let _ = nss.set_high();
         let mut adc = Adc {
             spi, nss,
             checksum_mode: ChecksumMode::Off,
+            read_checksum_errors: 0,
+            write_crc_errors: 0,
+            v_ref_external: V_REF_EXTERNAL,
         };
         adc.reset()?;
-        adc.set_checksum_mode(ChecksumMode::Crc).unwrap();
+        adc.set_checksum_mode(ChecksumMode::Crc)?;
 
         let mut retries = 0;
         let mut adc_id;
@@ -39,6 +82,8 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
             adc_id = adc.identify()?;
             if adc_id & 0xFFF0 == 0x00D0 {
                 break;
+            } else if retries >= IDENTIFY_RETRIES {
+                return Err(AdcError::IdentifyFailed(adc_id));
             } else {
                 retries += 1;
             }
@@ -59,6 +104,16 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
             .map(|id| id.id())
     }
 
+    pub fn checksum_mode(&self) -> ChecksumMode {
+        self.checksum_mode
+    }
+
+    /// Register reads retried after a checksum error, and register
+    /// writes with a CRC error since power-on
+    pub fn checksum_errors(&self) -> (u32, u32) {
+        (self.read_checksum_errors, self.write_crc_errors)
+    }
+
     pub fn set_checksum_mode(&mut self, mode: ChecksumMode) -> Result<(), SPI::Error> {
         // Cannot use update_reg() here because checksum_mode is
         // updated between read_reg() and write_reg().
@@ -90,8 +145,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
             data.set_enh_filt_en(true);
             data.set_enh_filt(PostFilter::F16SPS);
             data.set_order(DigitalFilterOrder::Sinc5Sinc1);
-            // output data rate: 10 Hz
-            data.set_odr(0b10011);
+            data.set_odr(DEFAULT_ODR);
         })?;
         self.update_reg(&regs::Channel { index }, |data| {
             data.set_setup(index);
@@ -102,11 +156,81 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         Ok(())
     }
 
+    /// Add a conversion of `in_pos` - `in_neg` against the internal
+    /// reference on `DIAGNOSTIC_CHANNEL` to the sequence
+    pub fn enable_diagnostic_channel(
+        &mut self, in_pos: Input, in_neg: Input
+    ) -> Result<(), SPI::Error> {
+        let index = DIAGNOSTIC_CHANNEL;
+        self.update_reg(&regs::SetupCon { index }, |data| {
+            data.set_bipolar(true);
+            data.set_refbuf_pos(true);
+            data.set_refbuf_neg(true);
+            data.set_ainbuf_pos(true);
+            data.set_ainbuf_neg(true);
+            data.set_ref_sel(RefSource::Internal);
+        })?;
+        self.update_reg(&regs::FiltCon { index }, |data| {
+            data.set_enh_filt_en(false);
+            data.set_order(DigitalFilterOrder::Sinc5Sinc1);
+            // output data rate: 200 Hz, a short break in the sequence
+            data.set_odr(0b01101);
+        })?;
+        self.update_reg(&regs::Channel { index }, |data| {
+            data.set_setup(index);
+            data.set_enabled(true);
+            data.set_a_in_pos(in_pos);
+            data.set_a_in_neg(in_neg);
+        })?;
+        Ok(())
+    }
+
+    /// Remove `DIAGNOSTIC_CHANNEL` from the sequence again
+    pub fn disable_diagnostic_channel(&mut self) -> Result<(), SPI::Error> {
+        self.update_reg(&regs::Channel { index: DIAGNOSTIC_CHANNEL }, |data| {
+            data.set_enabled(false);
+        })
+    }
+
+    /// Add a channel set up with `setup_channel` to the conversion
+    /// sequence, or remove it
+    pub fn set_channel_enabled(&mut self, index: u8, enabled: bool) -> Result<(), SPI::Error> {
+        self.update_reg(&regs::Channel { index }, |data| {
+            data.set_enabled(enabled);
+        })
+    }
+
     pub fn get_calibration(&mut self, index: u8) -> Result<ChannelCalibration, SPI::Error> {
         let offset = self.read_reg(&regs::Offset { index })?.offset();
         let gain = self.read_reg(&regs::Gain { index })?.gain();
-        let bipolar = self.read_reg(&regs::SetupCon { index })?.bipolar();
-        Ok(ChannelCalibration { offset, gain, bipolar })
+        let setup_con = self.read_reg(&regs::SetupCon { index })?;
+        let bipolar = setup_con.bipolar();
+        let v_ref = reference_voltage(setup_con.ref_sel(), self.v_ref_external);
+        Ok(ChannelCalibration { offset, gain, bipolar, v_ref })
+    }
+
+    /// Channel calibrations must be re-read afterwards
+    pub fn set_v_ref_external(&mut self, v_ref: f64) {
+        self.v_ref_external = v_ref;
+    }
+
+    pub fn get_ref_source(&mut self, index: u8) -> Result<RefSource, SPI::Error> {
+        self.read_reg(&regs::SetupCon { index })
+            .map(|data| data.ref_sel())
+    }
+
+    /// Channel calibrations must be re-read afterwards
+    pub fn set_bipolar(&mut self, index: u8, bipolar: bool) -> Result<(), SPI::Error> {
+        self.update_reg(&regs::SetupCon { index }, |data| {
+            data.set_bipolar(bipolar);
+        })
+    }
+
+    /// Channel calibrations must be re-read afterwards
+    pub fn set_ref_source(&mut self, index: u8, ref_source: RefSource) -> Result<(), SPI::Error> {
+        self.update_reg(&regs::SetupCon { index }, |data| {
+            data.set_ref_sel(ref_source);
+        })
     }
 
     pub fn start_continuous_conversion(&mut self) -> Result<(), SPI::Error> {
@@ -121,7 +245,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
     pub fn get_postfilter(&mut self, index: u8) -> Result<Option<PostFilter>, SPI::Error> {
         self.read_reg(&regs::FiltCon { index })
             .map(|data| {
-                if data.enh_filt_en() {
+                if data.enh_filt_en() && !data.sinc3_map() {
                     Some(data.enh_filt())
                 } else {
                     None
@@ -129,11 +253,16 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
             })
     }
 
+    /// Postfilters also select the sinc5 + sinc1 filter
     pub fn set_postfilter(&mut self, index: u8, filter: Option<PostFilter>) -> Result<(), SPI::Error> {
         self.update_reg(&regs::FiltCon { index }, |data| {
             match filter {
+                // the enh_filt_en bit is part of the decimation rate
+                None if data.sinc3_map() => {}
                 None => data.set_enh_filt_en(false),
                 Some(filter) => {
+                    leave_sinc3_map(data);
+                    data.set_order(DigitalFilterOrder::Sinc5Sinc1);
                     data.set_enh_filt_en(true);
                     data.set_enh_filt(filter);
                 }
@@ -141,10 +270,58 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         })
     }
 
+    pub fn get_filter(&mut self, index: u8) -> Result<Filter, SPI::Error> {
+        self.read_reg(&regs::FiltCon { index })
+            .map(|data| {
+                if data.sinc3_map() {
+                    Filter::Sinc3Map(data.sinc3_decimation())
+                } else {
+                    match data.order() {
+                        DigitalFilterOrder::Sinc3 => Filter::Sinc3,
+                        _ => Filter::Sinc5Sinc1,
+                    }
+                }
+            })
+    }
+
+    /// Sinc3 disables the postfilter
+    pub fn set_filter(&mut self, index: u8, filter: Filter) -> Result<(), SPI::Error> {
+        self.update_reg(&regs::FiltCon { index }, |data| {
+            leave_sinc3_map(data);
+            match filter {
+                Filter::Sinc5Sinc1 =>
+                    data.set_order(DigitalFilterOrder::Sinc5Sinc1),
+                Filter::Sinc3 => {
+                    data.set_enh_filt_en(false);
+                    data.set_order(DigitalFilterOrder::Sinc3);
+                }
+                Filter::Sinc3Map(decimation) => {
+                    data.set_sinc3_map(true);
+                    data.set_sinc3_decimation(decimation);
+                }
+            }
+        })
+    }
+
+    pub fn get_odr(&mut self, index: u8) -> Result<Odr, SPI::Error> {
+        self.read_reg(&regs::FiltCon { index })
+            .map(|data| Odr(data.odr()))
+    }
+
+    pub fn set_odr(&mut self, index: u8, odr: Odr) -> Result<(), SPI::Error> {
+        self.update_reg(&regs::FiltCon { index }, |data| {
+            leave_sinc3_map(data);
+            data.set_odr(odr.0);
+        })
+    }
+
     /// Returns the channel the data is from
     pub fn data_ready(&mut self) -> Result<Option<u8>, SPI::Error> {
         self.read_reg(&regs::Status)
             .map(|status| {
+                if status.crc_error() {
+                    self.write_crc_errors += 1;
+                }
                 if status.ready() {
                     Some(status.channel())
                 } else {
@@ -175,6 +352,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
                 break;
             }
             // Retry
+            self.read_checksum_errors += 1;
             warn!("read_reg {:02X}: checksum error: {:?}!={:?}, retrying", reg.address(), checksum_expected, checksum_in);
         }
         Ok(reg_data)
@@ -252,11 +430,50 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
     }
 }
 
+/// The decimation rate of `sinc3_map` overlays all other `FiltCon`
+/// fields, reset them to sinc3 at the default rate
+fn leave_sinc3_map(data: &mut <regs::FiltCon as Register>::Data) {
+    if data.sinc3_map() {
+        data.set_sinc3_map(false);
+        data.set_sinc3_decimation(0);
+        data.set_order(DigitalFilterOrder::Sinc3);
+        data.set_odr(DEFAULT_ODR);
+    }
+}
+
+/// Magnitude of a reference source
+fn reference_voltage(ref_source: RefSource, v_ref_external: f64) -> f64 {
+    match ref_source {
+        RefSource::Internal => V_REF_INTERNAL,
+        RefSource::Avdd1MinusAvss => V_AVDD1,
+        _ => v_ref_external,
+    }
+}
+
+/// Voltage of a bipolar conversion against the internal reference
+pub fn convert_diagnostic_data(data: u32) -> ElectricPotential {
+    let data = (data as i32 - 0x80_0000) as f64 / 0x80_0000 as f64;
+    ElectricPotential::new::<volt>(data * V_REF_INTERNAL)
+}
+
+/// Die temperature from a conversion of the internal temperature sensor
+pub fn convert_internal_temperature(data: u32) -> ThermodynamicTemperature {
+    let voltage = convert_diagnostic_data(data).get::<volt>();
+    ThermodynamicTemperature::new::<kelvin>(voltage / TEMPERATURE_SENSITIVITY)
+}
+
+/// AVDD1 - AVSS from a conversion of the supply monitor input
+pub fn convert_analog_supply(data: u32) -> ElectricPotential {
+    convert_diagnostic_data(data) * SUPPLY_MONITOR_ATTENUATION
+}
+
 #[derive(Debug, Clone)]
 pub struct ChannelCalibration {
-    offset: u32,
-    gain: u32,
-    bipolar: bool,
+    pub offset: u32,
+    pub gain: u32,
+    pub bipolar: bool,
+    /// Reference voltage
+    pub v_ref: f64,
 }
 
 impl ChannelCalibration {
@@ -270,7 +487,19 @@ impl ChannelCalibration {
         let data = data + (self.offset as i32 - 0x80_0000) as f64;
         let data = data / (2 << 23) as f64;
 
-        const V_REF: f64 = 3.3;
-        ElectricPotential::new::<volt>(data * V_REF / 0.75)
+        ElectricPotential::new::<volt>(data * self.v_ref / 0.75)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uom::si::thermodynamic_temperature::degree_celsius;
+
+    #[test]
+    fn internal_temperature() {
+        // 142.2 mV at 25 °C
+        let temperature = convert_internal_temperature(0x87_4813);
+        assert!((temperature.get::<degree_celsius>() - 25.0).abs() < 0.01);
     }
 }
@@ -1,4 +1,6 @@
-#[derive(Clone, Copy, PartialEq)]
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 #[repr(u8)]
 pub enum ChecksumMode {
     Off = 0b00,
@@ -110,6 +110,7 @@ impl fmt::Display for Input {
 }
 
 /// Reference source for ADC conversion
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum RefSource {
     /// External reference
@@ -124,9 +125,9 @@ pub enum RefSource {
 impl From<u8> for RefSource {
     fn from(x: u8) -> Self {
         match x {
-            0 => RefSource::External,
-            1 => RefSource::Internal,
-            2 => RefSource::Avdd1MinusAvss,
+            0b00 => RefSource::External,
+            0b10 => RefSource::Internal,
+            0b11 => RefSource::Avdd1MinusAvss,
             _ => RefSource::Invalid,
         }
     }
@@ -181,6 +182,17 @@ impl PostFilter {
         best.map(|(_, best)| best)
     }
 
+    /// Settling time in seconds
+    pub fn settling_time(&self) -> Option<f32> {
+        match self {
+            PostFilter::F27SPS => Some(0.0367),
+            PostFilter::F21SPS => Some(0.040),
+            PostFilter::F20SPS => Some(0.050),
+            PostFilter::F16SPS => Some(0.060),
+            PostFilter::Invalid => None,
+        }
+    }
+
     /// Samples per Second
     pub fn output_rate(&self) -> Option<f32> {
         match self {
@@ -205,6 +217,114 @@ impl From<u8> for PostFilter {
     }
 }
 
+/// Sinc5 + sinc1 output data rates in SPS, indexed by the `FiltCon`
+/// ODR bits. Ignored while a postfilter is enabled.
+const ODR_RATES: [f32; 23] = [
+    31250.0, 31250.0, 31250.0, 31250.0, 31250.0, 31250.0,
+    15625.0, 10417.0, 5208.0, 2597.0, 1007.0, 503.8,
+    381.0, 200.3, 100.2, 59.52, 49.68, 20.01,
+    16.63, 10.0, 5.0, 2.5, 1.25,
+];
+
+/// Sinc3 output data rates in SPS, indexed by the `FiltCon` ODR bits
+const SINC3_ODR_RATES: [f32; 23] = [
+    31250.0, 31250.0, 31250.0, 31250.0, 31250.0, 31250.0,
+    15625.0, 10417.0, 5000.0, 2500.0, 1000.0, 500.0,
+    400.0, 200.0, 100.0, 60.0, 50.0, 20.0,
+    16.67, 10.0, 5.0, 2.5, 1.25,
+];
+
+/// Modulator clock in Hz
+const F_MOD: f32 = 2_000_000.0;
+/// Deviation in SPS up to which a requested rate selects a postfilter
+const POSTFILTER_RATE_TOLERANCE: f32 = 0.01;
+
+/// `FiltCon` ODR bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Odr(pub u8);
+
+/// Output data rate mechanism chosen by `Filter::select_rate`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateSelection {
+    /// Enhanced 50/60 Hz rejection postfilter
+    PostFilter(PostFilter),
+    /// `FiltCon` ODR with the postfilter disabled
+    Odr(Odr),
+}
+
+/// Digital filter of a channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    /// Sinc5 + sinc1, the only order that supports postfilters
+    Sinc5Sinc1,
+    /// Sinc3 at the rate selected by `Odr`
+    Sinc3,
+    /// Sinc3 with its decimation rate programmed directly
+    Sinc3Map(u16),
+}
+
+impl Filter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filter::Sinc5Sinc1 => "sinc5+sinc1",
+            Filter::Sinc3 | Filter::Sinc3Map(_) => "sinc3",
+        }
+    }
+
+    /// Rates selectable with `Odr`. Setting an `Odr` ends `Sinc3Map`.
+    fn odr_rates(&self) -> &'static [f32] {
+        match self {
+            Filter::Sinc5Sinc1 => &ODR_RATES,
+            Filter::Sinc3 | Filter::Sinc3Map(_) => &SINC3_ODR_RATES,
+        }
+    }
+
+    pub fn closest_odr(&self, rate: f32) -> Odr {
+        let mut best = (f32::INFINITY, 0);
+        for (code, value) in self.odr_rates().iter().enumerate() {
+            let error = (rate - value).abs();
+            if error < best.0 {
+                best = (error, code);
+            }
+        }
+        Odr(best.1 as u8)
+    }
+
+    /// A postfilter if `rate` is one of their rates and the filter
+    /// supports them, otherwise the closest `Odr`
+    pub fn select_rate(&self, rate: f32) -> RateSelection {
+        if *self == Filter::Sinc5Sinc1 {
+            let postfilter = PostFilter::VALID_VALUES.iter()
+                .find(|postfilter| postfilter.output_rate()
+                      .map_or(false, |value| (rate - value).abs() < POSTFILTER_RATE_TOLERANCE));
+            if let Some(postfilter) = postfilter {
+                return RateSelection::PostFilter(*postfilter);
+            }
+        }
+        RateSelection::Odr(self.closest_odr(rate))
+    }
+
+    /// Samples per Second without postfilter
+    pub fn output_rate(&self, odr: Odr) -> Option<f32> {
+        match self {
+            Filter::Sinc3Map(0) => None,
+            Filter::Sinc3Map(decimation) => Some(F_MOD / (32.0 * f32::from(*decimation))),
+            _ => self.odr_rates().get(usize::from(odr.0)).cloned(),
+        }
+    }
+
+    /// Approximate settling time in seconds after switching
+    /// channels, without postfilter
+    pub fn settling_time(&self, odr: Odr) -> Option<f32> {
+        let periods = match self {
+            Filter::Sinc5Sinc1 => 1.0,
+            Filter::Sinc3 | Filter::Sinc3Map(_) => 3.0,
+        };
+        self.output_rate(odr)
+            .map(|rate| periods / rate)
+    }
+}
+
 #[repr(u8)]
 pub enum DigitalFilterOrder {
     Sinc5Sinc1 = 0b00,
@@ -221,3 +341,25 @@ impl From<u8> for DigitalFilterOrder {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn select_postfilter_rate() {
+        assert_eq!(Filter::Sinc5Sinc1.select_rate(21.25), RateSelection::PostFilter(PostFilter::F21SPS));
+        assert_eq!(Filter::Sinc5Sinc1.select_rate(16.67), RateSelection::PostFilter(PostFilter::F16SPS));
+    }
+
+    #[test]
+    fn select_odr_rate() {
+        let filter = Filter::Sinc5Sinc1;
+        match filter.select_rate(100.0) {
+            RateSelection::Odr(odr) => assert_eq!(filter.output_rate(odr), Some(100.2)),
+            selection => panic!("{:?}", selection),
+        }
+        // No postfilter with sinc3
+        assert!(matches!(Filter::Sinc3.select_rate(20.0), RateSelection::Odr(_)));
+    }
+}
@@ -227,11 +227,25 @@ impl setup_con::Data {
 
 def_reg!(FiltCon, u8, filt_con, 0x28, 2);
 impl filt_con::Data {
-    reg_bit!(sinc3_map, 0, 7, "If set, mapping of filter register changes to directly program the decimation rate of the sinc3 filter");
+    reg_bit!(sinc3_map, set_sinc3_map, 0, 7, "If set, mapping of filter register changes to directly program the decimation rate of the sinc3 filter");
     reg_bit!(enh_filt_en, set_enh_filt_en, 0, 3, "Enable postfilters for enhanced 50Hz and 60Hz rejection");
     reg_bits!(enh_filt, set_enh_filt, 0, 0..=2, PostFilter, "Select postfilters for enhanced 50Hz and 60Hz rejection");
     reg_bits!(order, set_order, 1, 5..=6, DigitalFilterOrder, "order of the digital filter that processes the modulator data");
     reg_bits!(odr, set_odr, 1, 0..=4, "Output data rate");
+
+    /// Decimation rate of the sinc3 filter, in place of all other
+    /// fields while `sinc3_map` is set
+    #[allow(unused)]
+    pub fn sinc3_decimation(&self) -> u16 {
+        (u16::from(self.0[0] & 0x7F) << 8) |
+        u16::from(self.0[1])
+    }
+
+    #[allow(unused)]
+    pub fn set_sinc3_decimation(&mut self, value: u16) {
+        self.0[0] = (self.0[0] & 0x80) | ((value >> 8) as u8 & 0x7F);
+        self.0[1] = value as u8;
+    }
 }
 
 def_reg!(Offset, u8, offset, 0x30, 3);
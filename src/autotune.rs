@@ -0,0 +1,260 @@
+use core::f64::consts::PI;
+use num_traits::float::Float;
+use serde::Serialize;
+use crate::pid;
+
+/// Relay hysteresis around the target in K, prevents chattering on
+/// measurement noise
+const HYSTERESIS: f64 = 0.05;
+/// Number of oscillation cycles to run, the first one is discarded
+const CYCLES: u32 = 5;
+/// Give up when not done after this many samples
+const MAX_SAMPLES: u32 = 36_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum AutotuneState {
+    Running,
+    Done,
+    Failed,
+}
+
+/// Why autotuning gave up
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum AutotuneFailure {
+    /// Not done after `MAX_SAMPLES`, e.g. the amplitude is too small
+    /// to drive the temperature across the target
+    Timeout,
+    /// The oscillation was not larger than the relay hysteresis
+    NoOscillation,
+}
+
+/// Relay feedback autotuning
+///
+/// Drives the output as a relay around the target temperature, measures
+/// the period and amplitude of the resulting oscillation, and derives PID
+/// gains from the ultimate gain and period with the Tyreus–Luyben rules.
+#[derive(Clone, Debug, Serialize)]
+pub struct Autotune {
+    pub state: AutotuneState,
+    /// Reason of `AutotuneState::Failed`
+    pub failure: Option<AutotuneFailure>,
+    /// Target temperature in °C
+    target: f64,
+    /// Relay output amplitude in A
+    amplitude: f64,
+    /// Current relay output in A
+    output: f64,
+    /// Completed oscillation cycles
+    cycles: u32,
+    /// Mean oscillation period in s
+    period: f64,
+    /// Mean peak-to-peak oscillation amplitude in K
+    peak_to_peak: f64,
+    /// Derived parameters when done
+    pub parameters: Option<pid::Parameters>,
+    #[serde(skip)]
+    samples: u32,
+    #[serde(skip)]
+    time: f64,
+    #[serde(skip)]
+    last_rise: Option<f64>,
+    #[serde(skip)]
+    max: f64,
+    #[serde(skip)]
+    min: f64,
+    #[serde(skip)]
+    period_sum: f64,
+    #[serde(skip)]
+    peak_to_peak_sum: f64,
+}
+
+impl Autotune {
+    pub fn new(target: f64, amplitude: f64) -> Self {
+        Autotune {
+            state: AutotuneState::Running,
+            failure: None,
+            target,
+            amplitude,
+            // start by heating
+            output: -amplitude,
+            cycles: 0,
+            period: 0.0,
+            peak_to_peak: 0.0,
+            parameters: None,
+            samples: 0,
+            time: 0.0,
+            last_rise: None,
+            max: target,
+            min: target,
+            period_sum: 0.0,
+            peak_to_peak_sum: 0.0,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == AutotuneState::Running
+    }
+
+    /// Account a new temperature sample in °C taken `dt` seconds after
+    /// the previous one, returns the relay output in A. `parameters` are
+    /// the basis of the derived parameters.
+    pub fn update(&mut self, temperature: f64, dt: f64, parameters: &pid::Parameters) -> f64 {
+        if !self.is_running() {
+            return 0.0;
+        }
+
+        self.samples += 1;
+        self.time += dt;
+        self.max = self.max.max(temperature);
+        self.min = self.min.min(temperature);
+        if self.output < 0.0 && temperature > self.target + HYSTERESIS {
+            // Rising through the target starts a new cycle
+            if let Some(last_rise) = self.last_rise {
+                self.cycles += 1;
+                if self.cycles > 1 {
+                    let n = f64::from(self.cycles - 1);
+                    self.period_sum += self.time - last_rise;
+                    self.peak_to_peak_sum += self.max - self.min;
+                    self.period = self.period_sum / n;
+                    self.peak_to_peak = self.peak_to_peak_sum / n;
+                }
+            }
+            self.last_rise = Some(self.time);
+            self.max = temperature;
+            self.min = temperature;
+            self.output = self.amplitude;
+
+            if self.cycles >= CYCLES {
+                self.finish(parameters);
+            }
+        } else if self.output > 0.0 && temperature < self.target - HYSTERESIS {
+            self.output = -self.amplitude;
+        }
+
+        if self.is_running() && self.samples >= MAX_SAMPLES {
+            self.fail(AutotuneFailure::Timeout);
+        }
+        self.output
+    }
+
+    fn finish(&mut self, parameters: &pid::Parameters) {
+        let a = self.peak_to_peak / 2.0;
+        if a <= HYSTERESIS {
+            self.fail(AutotuneFailure::NoOscillation);
+            return;
+        }
+        // Describing function of a relay with hysteresis
+        let ku = 4.0 * self.amplitude / (PI * (a * a - HYSTERESIS * HYSTERESIS).sqrt());
+        // Tyreus–Luyben
+        let kp = ku / 2.2;
+        let ti = 2.2 * self.period;
+        let td = self.period / 6.3;
+        self.parameters = Some(pid::Parameters {
+            kp: kp as f32,
+            ki: (kp / ti) as f32,
+            kd: (kp * td) as f32,
+            ..parameters.clone()
+        });
+        self.state = AutotuneState::Done;
+    }
+
+    fn fail(&mut self, failure: AutotuneFailure) {
+        self.state = AutotuneState::Failed;
+        self.failure = Some(failure);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Ambient temperature
+    const AMBIENT: f64 = 25.0;
+    // Target temperature
+    const TARGET: f64 = 30.0;
+    // Temperature change per sample per A
+    const GAIN: f64 = 0.1;
+    // Heat lost
+    const LOSS: f64 = 0.01;
+    // System response delay
+    const DELAY: usize = 10;
+    // Sample interval in s
+    const DT: f64 = 0.1;
+
+    /// Thermal plant with dead time, positive current cools
+    struct Plant {
+        temperature: f64,
+        outputs: [f64; DELAY],
+        t: usize,
+    }
+
+    impl Plant {
+        fn step(&mut self, output: f64) -> f64 {
+            let delayed = self.outputs[self.t];
+            self.outputs[self.t] = output;
+            self.t = (self.t + 1) % DELAY;
+            self.temperature += -GAIN * delayed + (AMBIENT - self.temperature) * LOSS;
+            self.temperature
+        }
+    }
+
+    #[test]
+    fn test_autotune() {
+        let base = pid::Parameters {
+            output_min: -2.0,
+            output_max: 2.0,
+            integral_min: -2.0,
+            integral_max: 2.0,
+            ..pid::Parameters::default()
+        };
+        let mut plant = Plant { temperature: AMBIENT, outputs: [0.0; DELAY], t: 0 };
+        let mut autotune = Autotune::new(TARGET, 1.0);
+        let mut temperature = plant.temperature;
+        while autotune.is_running() {
+            let output = autotune.update(temperature, DT, &base);
+            temperature = plant.step(output);
+        }
+        assert_eq!(autotune.state, AutotuneState::Done);
+        // Half period is at least the dead time in each direction
+        assert!(autotune.period > 2.0 * DELAY as f64 * DT);
+        let parameters = autotune.parameters.clone().unwrap();
+        assert!(parameters.kp > 0.0 && parameters.ki > 0.0 && parameters.kd > 0.0);
+        assert_eq!(parameters.output_max, base.output_max);
+
+        // The derived parameters settle the loop
+        let mut pid = pid::Controller::new(parameters);
+        pid.target = TARGET;
+        let mut settled = 0;
+        for _ in 0..5000 {
+            let output = pid.update(temperature, DT);
+            temperature = plant.step(output);
+            if (temperature - TARGET).abs() < 0.01 {
+                settled += 1;
+            } else {
+                settled = 0;
+            }
+        }
+        assert!(settled > 100);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut autotune = Autotune::new(TARGET, 1.0);
+        // Never reaches the target
+        while autotune.is_running() {
+            autotune.update(AMBIENT, DT, &pid::Parameters::default());
+        }
+        assert_eq!(autotune.state, AutotuneState::Failed);
+        assert_eq!(autotune.failure, Some(AutotuneFailure::Timeout));
+        assert_eq!(autotune.parameters, None);
+    }
+
+    #[test]
+    fn no_oscillation() {
+        let mut autotune = Autotune::new(TARGET, 1.0);
+        autotune.peak_to_peak = HYSTERESIS;
+        autotune.finish(&pid::Parameters::default());
+        assert_eq!(autotune.state, AutotuneState::Failed);
+        assert_eq!(autotune.failure, Some(AutotuneFailure::NoOscillation));
+    }
+}
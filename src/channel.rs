@@ -1,13 +1,18 @@
-use stm32f4xx_hal::hal::digital::v2::OutputPin;
+use stm32f4xx_hal::{
+    adc::config::SampleTime,
+    hal::{self, digital::v2::OutputPin},
+    pac::ADC1,
+    spi,
+};
 use uom::si::{
     f64::ElectricPotential,
-    electric_potential::volt,
+    electric_potential::{millivolt, volt},
 };
 use crate::{
     ad5680,
     ad7172,
     channel_state::ChannelState,
-    pins::{ChannelPins, ChannelPinSet},
+    pins::{ChannelPins, ChannelPinSet, PinsAdc},
 };
 
 /// Marker type for the first channel
@@ -59,3 +64,76 @@ impl<C: ChannelPins> Channel<C> {
         let _ = self.shdn.set_low();
     }
 }
+
+/// Either channel, so that `Channels` can index them in an array
+/// despite their different pin types
+pub enum ChannelVariant {
+    Channel0(Channel<Channel0>),
+    Channel1(Channel<Channel1>),
+}
+
+/// Evaluate `$body` with `$channel` bound to the concrete `Channel`
+macro_rules! dispatch {
+    ($variant: expr, $channel: ident => $body: expr) => {
+        match $variant {
+            ChannelVariant::Channel0($channel) => $body,
+            ChannelVariant::Channel1($channel) => $body,
+        }
+    };
+}
+
+impl ChannelVariant {
+    pub fn state(&mut self) -> &mut ChannelState {
+        dispatch!(self, channel => &mut channel.state)
+    }
+
+    pub fn vref_meas(&self) -> ElectricPotential {
+        dispatch!(self, channel => channel.vref_meas)
+    }
+
+    pub fn set_vref_meas(&mut self, vref_meas: ElectricPotential) {
+        dispatch!(self, channel => channel.vref_meas = vref_meas)
+    }
+
+    /// Raw `i_set` DAC value
+    pub fn set_dac(&mut self, value: u32) -> Result<u32, spi::Error> {
+        dispatch!(self, channel => channel.dac.set(value))
+    }
+
+    pub fn power_up(&mut self) {
+        dispatch!(self, channel => channel.power_up())
+    }
+
+    pub fn power_down(&mut self) {
+        dispatch!(self, channel => channel.power_down())
+    }
+
+    pub fn read_dac_feedback(&self, adc: &mut PinsAdc, samples: u16) -> ElectricPotential {
+        dispatch!(self, channel => read_pin(adc, &channel.dac_feedback_pin, samples))
+    }
+
+    pub fn read_itec(&self, adc: &mut PinsAdc, samples: u16) -> ElectricPotential {
+        dispatch!(self, channel => read_pin(adc, &channel.itec_pin, samples))
+    }
+
+    pub fn read_vref(&self, adc: &mut PinsAdc, samples: u16) -> ElectricPotential {
+        dispatch!(self, channel => read_pin(adc, &channel.vref_pin, samples))
+    }
+
+    pub fn read_tec_u_meas(&self, adc: &mut PinsAdc, samples: u16) -> ElectricPotential {
+        dispatch!(self, channel => read_pin(adc, &channel.tec_u_meas_pin, samples))
+    }
+}
+
+/// Mean voltage of `samples` conversions of an analog input of the
+/// stm32f4 integrated adc. Each conversion takes 492 ADC clock cycles
+/// or about 15 µs.
+pub fn read_pin<P: hal::adc::Channel<ADC1, ID = u8>>(adc: &mut PinsAdc, pin: &P, samples: u16) -> ElectricPotential {
+    let samples = samples.max(1);
+    let mut mv = 0;
+    for _ in 0..samples {
+        let sample = adc.convert(pin, SampleTime::Cycles_480);
+        mv += u32::from(adc.sample_to_millivolts(sample));
+    }
+    ElectricPotential::new::<millivolt>(mv as f64 / f64::from(samples))
+}
@@ -1,3 +1,5 @@
+use num_traits::float::Float;
+use serde::{Deserialize, Serialize};
 use smoltcp::time::{Duration, Instant};
 use uom::si::{
     f64::{
@@ -8,30 +10,470 @@ use uom::si::{
     },
     electric_potential::volt,
     electrical_resistance::ohm,
-    thermodynamic_temperature::degree_celsius,
-    time::millisecond,
+    thermodynamic_temperature::{degree_celsius, degree_fahrenheit, kelvin},
+    time::{millisecond, second},
 };
 use crate::{
     ad7172,
+    autotune::Autotune,
     pid,
+    profile::Profile,
+    rtd,
     steinhart_hart as sh,
-    command_parser::CenterPoint,
+    tec,
+    command_parser::{AdcReadMode, CenterPoint, SensorType, SetpointSource},
 };
 
+/// Default bias of the sensor input, see `HardwareParams`
 const R_INNER: f64 = 2.0 * 5100.0;
 const VREF_SENS: f64 = 3.3 / 2.0;
+/// Consecutive rejected sample intervals after which the nominal
+/// interval is learned anew, e.g. after the ADC rate was changed
+const MAX_REJECTED_INTERVALS: u32 = 10;
+/// Sensor resistance below which the thermistor is considered shorted
+const SHORT_RESISTANCE: f64 = 10.0;
+/// Samples over which `TemperatureStats::moving_mean` averages
+const MOVING_MEAN_SAMPLES: f64 = 64.0;
+/// Weight of a new sample in the one-pole filter of `d_temperature`
+const D_TEMPERATURE_FILTER: f64 = 0.25;
+/// Block size of a noise floor measurement
+pub const NOISE_FLOOR_SAMPLES: u32 = 256;
+
+/// Unit of the temperatures in reports and of temperature inputs
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TempUnit {
+    Celsius,
+    Kelvin,
+    Fahrenheit,
+}
+
+impl TempUnit {
+    pub fn value(self, temperature: ThermodynamicTemperature) -> f64 {
+        match self {
+            TempUnit::Celsius => temperature.get::<degree_celsius>(),
+            TempUnit::Kelvin => temperature.get::<kelvin>(),
+            TempUnit::Fahrenheit => temperature.get::<degree_fahrenheit>(),
+        }
+    }
+
+    pub fn temperature(self, value: f64) -> ThermodynamicTemperature {
+        match self {
+            TempUnit::Celsius => ThermodynamicTemperature::new::<degree_celsius>(value),
+            TempUnit::Kelvin => ThermodynamicTemperature::new::<kelvin>(value),
+            TempUnit::Fahrenheit => ThermodynamicTemperature::new::<degree_fahrenheit>(value),
+        }
+    }
+
+    /// A temperature difference in this unit, in K
+    pub fn interval_kelvin(self, value: f64) -> f64 {
+        match self {
+            TempUnit::Fahrenheit => value * 5.0 / 9.0,
+            _ => value,
+        }
+    }
+
+    /// A temperature difference in K, in this unit
+    pub fn interval_value(self, interval: f64) -> f64 {
+        match self {
+            TempUnit::Fahrenheit => interval * 9.0 / 5.0,
+            _ => interval,
+        }
+    }
+}
+
+/// Statistics of the sample interval that the control loop runs at
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct IntervalStats {
+    /// Number of accepted intervals
+    pub count: u32,
+    /// Mean of accepted intervals in ms, the nominal interval
+    pub mean: f64,
+    /// Shortest accepted interval in ms
+    pub min: f64,
+    /// Longest accepted interval in ms
+    pub max: f64,
+    /// Total number of rejected intervals
+    pub rejected: u32,
+    rejected_streak: u32,
+}
+
+impl IntervalStats {
+    /// Account a new sample interval in ms. Returns `false` if it
+    /// deviates from the nominal interval by more than the relative
+    /// `tolerance`. A `tolerance` of `0` accepts every interval.
+    pub fn update(&mut self, interval: f64, tolerance: f64) -> bool {
+        if self.count > 0 && tolerance > 0.0 && (interval - self.mean).abs() > tolerance * self.mean {
+            self.rejected += 1;
+            self.rejected_streak += 1;
+            if self.rejected_streak >= MAX_REJECTED_INTERVALS {
+                *self = IntervalStats {
+                    rejected: self.rejected,
+                    ..IntervalStats::default()
+                };
+            }
+            return false;
+        }
+
+        if self.count == 0 {
+            self.min = interval;
+            self.max = interval;
+        } else {
+            self.min = self.min.min(interval);
+            self.max = self.max.max(interval);
+        }
+        self.count += 1;
+        self.mean += (interval - self.mean) / f64::from(self.count);
+        self.rejected_streak = 0;
+        true
+    }
+}
+
+/// Running statistics of the measured temperature in °C since the
+/// last reset
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TemperatureStats {
+    pub count: u32,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Exponential moving average over `MOVING_MEAN_SAMPLES`
+    pub moving_mean: f64,
+    m2: f64,
+}
+
+impl TemperatureStats {
+    pub fn update(&mut self, temperature: f64) {
+        if self.count == 0 {
+            self.min = temperature;
+            self.max = temperature;
+            self.moving_mean = temperature;
+        } else {
+            self.min = self.min.min(temperature);
+            self.max = self.max.max(temperature);
+            self.moving_mean += (temperature - self.moving_mean) / MOVING_MEAN_SAMPLES;
+        }
+        self.count += 1;
+        let delta = temperature - self.mean;
+        self.mean += delta / f64::from(self.count);
+        self.m2 += delta * (temperature - self.mean);
+    }
+
+    /// Standard deviation in K
+    pub fn std(&self) -> f64 {
+        if self.count > 0 {
+            (self.m2 / f64::from(self.count)).sqrt()
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Reason why a channel has no valid temperature reading
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum SensorFault {
+    /// No thermistor connected
+    Open,
+    /// Shorted thermistor
+    Short,
+    /// Implausible resistance or temperature
+    OutOfRange,
+}
+
+/// Standard deviation and peak-to-peak of a block of ADC samples
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NoiseFloorResult {
+    /// In ADC codes
+    pub std_codes: f64,
+    pub p2p_codes: f64,
+    /// In µK, using the local slope of the temperature conversion
+    pub std_uk: f64,
+    pub p2p_uk: f64,
+}
+
+/// Noise floor measurement over a block of raw ADC samples
+#[derive(Clone, Debug, Serialize)]
+pub struct NoiseFloor {
+    /// Number of samples to collect
+    pub samples: u32,
+    /// Samples collected so far
+    pub count: u32,
+    pub result: Option<NoiseFloorResult>,
+    #[serde(skip)]
+    mean: f64,
+    #[serde(skip)]
+    m2: f64,
+    #[serde(skip)]
+    min: u32,
+    #[serde(skip)]
+    max: u32,
+}
+
+impl NoiseFloor {
+    pub fn new(samples: u32) -> Self {
+        NoiseFloor {
+            samples,
+            count: 0,
+            result: None,
+            mean: 0.0,
+            m2: 0.0,
+            min: u32::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.count >= self.samples
+    }
+
+    /// Mean ADC code of the samples collected so far
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    pub fn update(&mut self, adc_data: u32) {
+        if self.is_done() {
+            return;
+        }
+        self.count += 1;
+        let x = f64::from(adc_data);
+        let delta = x - self.mean;
+        self.mean += delta / f64::from(self.count);
+        self.m2 += delta * (x - self.mean);
+        self.min = self.min.min(adc_data);
+        self.max = self.max.max(adc_data);
+    }
+
+    /// Conclude with `slope`, the temperature change in K per ADC code
+    pub fn finish(&mut self, slope: f64) {
+        let std_codes = if self.count > 0 {
+            (self.m2 / f64::from(self.count)).sqrt()
+        } else {
+            0.0
+        };
+        let p2p_codes = f64::from(self.max.saturating_sub(self.min));
+        let uk_per_code = slope.abs() * 1e6;
+        self.result = Some(NoiseFloorResult {
+            std_codes,
+            p2p_codes,
+            std_uk: std_codes * uk_per_code,
+            p2p_uk: p2p_codes * uk_per_code,
+        });
+    }
+}
+
+/// External setpoint input, `target = offset + scale * voltage`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetpointInput {
+    pub source: SetpointSource,
+    /// °C/V
+    pub scale: f64,
+    /// °C
+    pub offset: f64,
+}
+
+impl Default for SetpointInput {
+    fn default() -> Self {
+        SetpointInput {
+            source: SetpointSource::Internal,
+            scale: 10.0,
+            offset: 0.0,
+        }
+    }
+}
+
+/// Gradual approach of the PID target
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SetpointRamp {
+    /// Final target in °C
+    pub target: f64,
+    /// K/s
+    pub rate: f64,
+}
+
+/// Controller that drives the output while closed-loop control is
+/// engaged. Serialized like `Option<Hysteresis>`, `null` for `Pid`, as
+/// `serde_json_core` cannot parse newtype variants.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Option<Hysteresis>", into = "Option<Hysteresis>")]
+pub enum ControlMode {
+    Pid,
+    /// On/off control between two thresholds
+    Hysteresis(Hysteresis),
+}
+
+impl Default for ControlMode {
+    fn default() -> Self {
+        ControlMode::Pid
+    }
+}
+
+impl From<Option<Hysteresis>> for ControlMode {
+    fn from(hysteresis: Option<Hysteresis>) -> Self {
+        hysteresis.map_or(ControlMode::Pid, ControlMode::Hysteresis)
+    }
+}
+
+impl From<ControlMode> for Option<Hysteresis> {
+    fn from(mode: ControlMode) -> Self {
+        match mode {
+            ControlMode::Pid => None,
+            ControlMode::Hysteresis(hysteresis) => Some(hysteresis),
+        }
+    }
+}
+
+/// Thresholds in °C of on/off control
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hysteresis {
+    pub low: f32,
+    pub high: f32,
+    /// Heat at `output_min` below `low` instead of turning off
+    pub heat: bool,
+}
+
+impl Hysteresis {
+    /// Cool at `output_max` above `high`, turn off or heat below
+    /// `low`, otherwise keep `output`
+    pub fn update(&self, temperature: f64, output: f64, parameters: &pid::Parameters) -> f64 {
+        if temperature > f64::from(self.high) {
+            parameters.output_max.into()
+        } else if temperature < f64::from(self.low) {
+            if self.heat { parameters.output_min.into() } else { 0.0 }
+        } else {
+            output
+        }
+    }
+}
+
+/// Absolute temperature window in °C outside of which the TEC is shut
+/// down, independent of the control loop
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TempLimits {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+impl TempLimits {
+    pub fn contains(&self, temperature: f64) -> bool {
+        self.min.map_or(true, |min| temperature >= f64::from(min)) &&
+            self.max.map_or(true, |max| temperature <= f64::from(max))
+    }
+}
+
+/// Latched until `clear`, the channel stays powered down
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ChannelFault {
+    /// Temperature in °C that left `TempLimits`
+    TempLimit(f64),
+    /// Output at its limit did not move the temperature as intended,
+    /// e.g. a detached TEC or reversed polarity
+    ThermalRunaway,
+}
+
+/// Thermal runaway detection, disabled with a `window` of 0
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunawayParameters {
+    /// Time in s that the output has to be at a limit before the
+    /// temperature trend is checked
+    pub window: f32,
+    /// Minimum temperature change in K/s in the intended direction
+    pub slope: f32,
+}
+
+/// Ramp of `i_set` after power up
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SoftStartRamp {
+    start: Instant,
+    /// Current in A that is reached at the end
+    target: f64,
+}
+
+/// Start of the current window with saturated output
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RunawayWindow {
+    start: Instant,
+    temperature: f64,
+    /// 1 for cooling, -1 for heating
+    direction: f64,
+}
+
+impl SetpointInput {
+    /// Target temperature in °C for the input `voltage`
+    pub fn get_target(&self, voltage: ElectricPotential) -> f64 {
+        self.offset + self.scale * voltage.get::<volt>()
+    }
+}
 
 pub struct ChannelState {
     pub adc_data: Option<u32>,
     pub adc_calibration: ad7172::ChannelCalibration,
     pub adc_time: Instant,
     pub adc_interval: Duration,
+    pub adc_interval_stats: IntervalStats,
+    /// Relative deviation from the nominal interval beyond which a
+    /// sample is not used for control, `0` to disable
+    pub adc_interval_tolerance: f64,
+    /// Was the last sample taken in a nominal interval?
+    adc_interval_valid: bool,
+    pub adc_read_mode: AdcReadMode,
+    /// Latest sample held back in `AdcReadMode::OnDemand`
+    adc_held: Option<(Instant, u32)>,
+    /// Number of held samples overwritten before they were read
+    pub adc_discarded: u32,
+    /// Consecutive open-sensor readings
+    pub sensor_fault_streak: u32,
+    /// Bias resistance in Ω and voltage in V of the sensor input
+    pub r_inner: f64,
+    pub vref_sens: f64,
     /// i_set 0A center point
     pub center: CenterPoint,
     pub dac_value: ElectricPotential,
     pub pid_engaged: bool,
     pub pid: pid::Controller,
+    /// Controller used while `pid_engaged`
+    pub control_mode: ControlMode,
+    pub setpoint: SetpointInput,
+    /// Moves `pid.target` on every sample while `Some`
+    pub ramp: Option<SetpointRamp>,
+    /// Sequence of ramps that drives `ramp` while running
+    pub profile: Profile,
+    /// Relay autotuning in place of the PID controller
+    pub autotune: Option<Autotune>,
+    /// Selects `sh` or `rtd` for the resistance to temperature
+    /// conversion. Plausibility range, correction and fault holdoff of
+    /// `sh` apply to both.
+    pub sensor: SensorType,
     pub sh: sh::Parameters,
+    pub rtd: rtd::Parameters,
+    pub tec: tec::ThermalEnergy,
+    pub temp_limits: TempLimits,
+    pub runaway: RunawayParameters,
+    runaway_window: Option<RunawayWindow>,
+    pub fault: Option<ChannelFault>,
+    /// Is the TEC driver enabled?
+    pub powered_up: bool,
+    /// Duration in ms of the `i_set` ramp after power up, 0 to apply
+    /// `i_set` at once
+    pub softstart: u32,
+    softstart_ramp: Option<SoftStartRamp>,
+    /// Check each DAC write against the feedback input
+    pub dac_verify: bool,
+    /// Feedback minus commanded DAC output in V at the last verified
+    /// write
+    pub dac_error: Option<f64>,
+    /// Is the channel converted by the ADC and available for output?
+    pub enabled: bool,
+    /// The TEC is wired backwards: the output current is negated and
+    /// the `max_i_pos`/`max_i_neg` limit pins are swapped, see
+    /// `Channels::set_inverted`
+    pub inverted: bool,
+    /// Running or last noise floor measurement
+    pub noise_floor: Option<NoiseFloor>,
+    pub temp_stats: TemperatureStats,
+    /// Last temperature in °C for `d_temperature`
+    last_temperature: Option<f64>,
+    /// Filtered rate of change of the temperature in K/s
+    d_temperature: Option<f64>,
 }
 
 impl ChannelState {
@@ -42,33 +484,330 @@ impl ChannelState {
             adc_time: Instant::from_secs(0),
             // default: 10 Hz
             adc_interval: Duration::from_millis(100),
+            adc_interval_stats: IntervalStats::default(),
+            adc_interval_tolerance: 0.0,
+            adc_interval_valid: true,
+            adc_read_mode: AdcReadMode::Continuous,
+            adc_held: None,
+            adc_discarded: 0,
+            sensor_fault_streak: 0,
+            r_inner: R_INNER,
+            vref_sens: VREF_SENS,
             center: CenterPoint::Vref,
             dac_value: ElectricPotential::new::<volt>(0.0),
             pid_engaged: false,
             pid: pid::Controller::new(pid::Parameters::default()),
+            control_mode: ControlMode::Pid,
+            setpoint: SetpointInput::default(),
+            ramp: None,
+            profile: Profile::default(),
+            autotune: None,
+            sensor: SensorType::Thermistor,
             sh: sh::Parameters::default(),
+            rtd: rtd::Parameters::default(),
+            tec: tec::ThermalEnergy::default(),
+            temp_limits: TempLimits::default(),
+            runaway: RunawayParameters::default(),
+            runaway_window: None,
+            fault: None,
+            powered_up: false,
+            softstart: 0,
+            dac_verify: false,
+            dac_error: None,
+            enabled: true,
+            inverted: false,
+            softstart_ramp: None,
+            noise_floor: None,
+            temp_stats: TemperatureStats::default(),
+            last_temperature: None,
+            d_temperature: None,
         }
     }
 
     pub fn update(&mut self, now: Instant, adc_data: u32) {
-        self.adc_data = if adc_data == ad7172::MAX_VALUE {
+        if adc_data == ad7172::MAX_VALUE {
             // this means there is no thermistor plugged into the ADC.
-            None
+            self.sensor_fault_streak += 1;
+            if self.sensor_fault_streak > self.sh.fault_holdoff {
+                self.adc_data = None;
+            }
+            // else keep the last good sample through a glitch
         } else {
-            Some(adc_data)
-        };
-        self.adc_interval = now - self.adc_time;
+            self.sensor_fault_streak = 0;
+            self.adc_data = Some(adc_data);
+            self.update_noise_floor(adc_data);
+        }
+        let first_sample = self.adc_time == Instant::from_secs(0);
+        // The time since boot is no interval, keep the nominal one
+        if !first_sample {
+            self.adc_interval = now - self.adc_time;
+        }
         self.adc_time = now;
+        self.adc_interval_valid = first_sample ||
+            self.adc_interval_stats.update(self.adc_interval.total_millis() as f64, self.adc_interval_tolerance);
+        let temperature = self.get_temperature()
+            .map(|temperature| temperature.get::<degree_celsius>());
+        if let Some(temperature) = temperature {
+            self.temp_stats.update(temperature);
+        }
+        self.update_d_temperature(temperature);
+    }
+
+    fn update_d_temperature(&mut self, temperature: Option<f64>) {
+        let dt = self.get_adc_interval().get::<second>();
+        match (temperature, self.last_temperature) {
+            (Some(temperature), Some(last)) if self.adc_interval_valid && dt > 0.0 => {
+                let d = (temperature - last) / dt;
+                self.d_temperature = Some(match self.d_temperature {
+                    Some(d_filtered) => d_filtered + D_TEMPERATURE_FILTER * (d - d_filtered),
+                    None => d,
+                });
+            }
+            (Some(_), _) => {}
+            (None, _) => self.d_temperature = None,
+        }
+        self.last_temperature = temperature;
+    }
+
+    fn update_noise_floor(&mut self, adc_data: u32) {
+        let noise_floor = match self.noise_floor.as_mut() {
+            Some(noise_floor) if !noise_floor.is_done() => noise_floor,
+            _ => return,
+        };
+        noise_floor.update(adc_data);
+        if noise_floor.is_done() {
+            let mean = noise_floor.mean() as u32;
+            let slope = self.get_temperature_slope(mean).unwrap_or(0.0);
+            if let Some(noise_floor) = self.noise_floor.as_mut() {
+                noise_floor.finish(slope);
+            }
+        }
+    }
+
+    /// Feed a new ADC sample, returns `true` if it has been consumed
+    /// for measurement and control.
+    pub fn feed(&mut self, now: Instant, adc_data: u32) -> bool {
+        match self.adc_read_mode {
+            AdcReadMode::Continuous => {
+                self.update(now, adc_data);
+                true
+            }
+            AdcReadMode::OnDemand => {
+                if self.adc_held.replace((now, adc_data)).is_some() {
+                    self.adc_discarded += 1;
+                }
+                false
+            }
+        }
+    }
+
+    /// Consume the latest sample held back in `AdcReadMode::OnDemand`
+    pub fn read_held(&mut self) -> bool {
+        match self.adc_held.take() {
+            Some((now, adc_data)) => {
+                self.update(now, adc_data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Disengage control and forget the last measurement, for a
+    /// channel whose conversions are stopped
+    pub fn disable(&mut self) {
+        self.enabled = false;
+        self.pid_engaged = false;
+        self.autotune = None;
+        self.ramp = None;
+        self.profile.stop();
+        self.adc_data = None;
+        self.adc_held = None;
+        self.last_temperature = None;
+        self.d_temperature = None;
+    }
+
+    pub fn set_adc_read_mode(&mut self, mode: AdcReadMode) {
+        self.adc_read_mode = mode;
+        self.adc_held = None;
+        self.adc_discarded = 0;
     }
 
-    /// Update PID state on ADC input, calculate new DAC output
+    /// Update PID state on ADC input, calculate new DAC output. `None`
+    /// without a valid temperature or if the output is not finite.
     pub fn update_pid(&mut self) -> Option<f64> {
+        self.pid_output()
+            .filter(|output| output.is_finite())
+    }
+
+    fn pid_output(&mut self) -> Option<f64> {
         let temperature = self.get_temperature()?
             .get::<degree_celsius>();
-        let pid_output = self.pid.update(temperature);
+        if !self.adc_interval_valid || self.sensor_fault_streak > 0 {
+            // Hold the output on anomalous timing or a sensor glitch
+            return Some(self.pid.y1);
+        }
+        let dt = self.get_adc_interval().get::<second>();
+        self.advance_ramp(dt);
+        if let Some(autotune) = self.autotune.as_mut().filter(|autotune| autotune.is_running()) {
+            let output = autotune.update(temperature, dt, &self.pid.parameters);
+            if let Some(parameters) = &autotune.parameters {
+                self.pid.parameters = parameters.clone();
+                self.pid.reset_state();
+            }
+            self.pid.track(temperature, output);
+            return Some(output);
+        }
+        if let ControlMode::Hysteresis(hysteresis) = self.control_mode {
+            let output = hysteresis.update(temperature, self.pid.y1, &self.pid.parameters);
+            self.pid.track(temperature, output);
+            return Some(output);
+        }
+        let pid_output = self.pid.update(temperature, dt);
         Some(pid_output)
     }
 
+    /// Move the PID target to `target` in °C at `rate` in K/s,
+    /// starting from the current temperature. A rate of 0 steps at once.
+    pub fn start_ramp(&mut self, target: f64, rate: f64) {
+        if rate > 0.0 {
+            if let Some(temperature) = self.get_temperature() {
+                self.pid.target = temperature.get::<degree_celsius>();
+            }
+            self.ramp = Some(SetpointRamp { target, rate });
+        } else {
+            self.pid.target = target;
+            self.ramp = None;
+        }
+    }
+
+    /// Advance the ramp by `dt` in s
+    fn advance_ramp(&mut self, dt: f64) {
+        if let Some(ramp) = &self.ramp {
+            let step = ramp.rate * dt;
+            let remaining = ramp.target - self.pid.target;
+            if remaining.abs() <= step {
+                self.pid.target = ramp.target;
+                self.ramp = None;
+            } else if remaining > 0.0 {
+                self.pid.target += step;
+            } else {
+                self.pid.target -= step;
+            }
+        }
+    }
+
+    /// Latch a fault and disengage control if the temperature has left
+    /// `temp_limits` or runs away. Returns `true` when newly tripped,
+    /// the caller then powers down.
+    pub fn check_faults(&mut self) -> bool {
+        if self.fault.is_some() {
+            return false;
+        }
+        let temperature = match self.get_temperature() {
+            Some(temperature) => temperature.get::<degree_celsius>(),
+            None => {
+                self.runaway_window = None;
+                return false;
+            }
+        };
+        let fault = if !self.temp_limits.contains(temperature) {
+            ChannelFault::TempLimit(temperature)
+        } else if self.is_running_away(temperature) {
+            ChannelFault::ThermalRunaway
+        } else {
+            return false;
+        };
+        self.fault = Some(fault);
+        self.runaway_window = None;
+        self.pid_engaged = false;
+        self.autotune = None;
+        true
+    }
+
+    /// Has the output been at a limit for `runaway.window` without the
+    /// temperature following at `runaway.slope`? Positive current cools.
+    fn is_running_away(&mut self, temperature: f64) -> bool {
+        let parameters = &self.pid.parameters;
+        let direction = if self.runaway.window <= 0.0 || !self.pid_engaged {
+            None
+        } else if self.pid.y1 >= f64::from(parameters.output_max) {
+            Some(1.0)
+        } else if self.pid.y1 <= f64::from(parameters.output_min) {
+            Some(-1.0)
+        } else {
+            None
+        };
+        let direction = match direction {
+            Some(direction) => direction,
+            None => {
+                self.runaway_window = None;
+                return false;
+            }
+        };
+
+        let now = self.adc_time;
+        let window = match self.runaway_window {
+            Some(window) if window.direction == direction => window,
+            _ => {
+                self.runaway_window = Some(RunawayWindow { start: now, temperature, direction });
+                return false;
+            }
+        };
+        let elapsed = (now - window.start).total_millis() as f64 / 1000.0;
+        if elapsed < f64::from(self.runaway.window) {
+            return false;
+        }
+        let slope = (window.temperature - temperature) * direction / elapsed;
+        if slope < f64::from(self.runaway.slope) {
+            return true;
+        }
+        self.runaway_window = Some(RunawayWindow { start: now, temperature, direction });
+        false
+    }
+
+    /// Begin ramping up to `target` in A, returns the current to apply
+    /// right away
+    pub fn start_softstart(&mut self, now: Instant, target: f64) -> f64 {
+        if self.softstart == 0 {
+            return target;
+        }
+        self.softstart_ramp = Some(SoftStartRamp { start: now, target });
+        0.0
+    }
+
+    pub fn stop_softstart(&mut self) {
+        self.softstart_ramp = None;
+    }
+
+    /// Retarget a running ramp, `None` if there is none
+    pub fn set_softstart_target(&mut self, now: Instant, target: f64) -> Option<f64> {
+        self.softstart_ramp.as_mut()?.target = target;
+        self.softstart_current(now)
+    }
+
+    /// Current in A along the ramp at `now`, `None` once it has ended
+    pub fn softstart_current(&mut self, now: Instant) -> Option<f64> {
+        let ramp = self.softstart_ramp?;
+        let elapsed = (now - ramp.start).total_millis() as f64;
+        let duration = f64::from(self.softstart);
+        if elapsed >= duration {
+            self.softstart_ramp = None;
+            return Some(ramp.target);
+        }
+        Some(ramp.target * elapsed / duration)
+    }
+
+    /// Limit the change from the `previous` output current according
+    /// to the configured slew rate and the last sample interval
+    pub fn limit_slew(&self, previous: f64, output: f64) -> f64 {
+        let slew = f64::from(self.pid.parameters.slew);
+        if slew <= 0.0 {
+            return output;
+        }
+        let max_delta = slew * self.get_adc_interval().get::<second>();
+        output.max(previous - max_delta).min(previous + max_delta)
+    }
+
     pub fn get_adc_time(&self) -> Time {
         Time::new::<millisecond>(self.adc_time.total_millis() as f64)
     }
@@ -83,16 +822,494 @@ impl ChannelState {
 
     /// Get `SENS[01]` input resistance
     pub fn get_sens(&self) -> Option<ElectricalResistance> {
-        let r_inner = ElectricalResistance::new::<ohm>(R_INNER);
-        let vref = ElectricPotential::new::<volt>(VREF_SENS);
-        let adc_input = self.get_adc()?;
-        let r = r_inner * adc_input / (vref - adc_input);
-        Some(r)
+        Some(self.convert_sens(self.adc_data?))
+    }
+
+    fn convert_sens(&self, adc_data: u32) -> ElectricalResistance {
+        let r_inner = ElectricalResistance::new::<ohm>(self.r_inner);
+        let vref = ElectricPotential::new::<volt>(self.vref_sens);
+        let adc_input = self.adc_calibration.convert_data(adc_data);
+        r_inner * adc_input / (vref - adc_input)
+    }
+
+    fn convert_temperature(&self, r: ElectricalResistance) -> ThermodynamicTemperature {
+        let temperature = match self.sensor {
+            SensorType::Thermistor => self.sh.get_temperature(r),
+            SensorType::Rtd => self.rtd.get_temperature(r),
+        };
+        self.sh.correct(temperature)
+    }
+
+    /// Temperature change in K per ADC code around `adc_data`
+    pub fn get_temperature_slope(&self, adc_data: u32) -> Option<f64> {
+        let temperature = |adc_data| {
+            let r = self.convert_sens(adc_data);
+            self.convert_temperature(r).get::<kelvin>()
+        };
+        let slope = temperature(adc_data.checked_add(1)?) - temperature(adc_data);
+        Some(slope).filter(|slope| slope.is_finite())
+    }
+
+    /// Convert the latest sample, `None` before the first one
+    fn read_temperature(&self) -> Option<Result<ThermodynamicTemperature, SensorFault>> {
+        if self.sensor_fault_streak > self.sh.fault_holdoff {
+            return Some(Err(SensorFault::Open));
+        }
+        let r = self.get_sens()?.get::<ohm>();
+        if !r.is_finite() || r < 0.0 {
+            return Some(Err(SensorFault::OutOfRange));
+        }
+        if r < SHORT_RESISTANCE {
+            return Some(Err(SensorFault::Short));
+        }
+        let temperature = self.convert_temperature(ElectricalResistance::new::<ohm>(r));
+        if !temperature.get::<kelvin>().is_finite() || !self.sh.is_valid(temperature) {
+            // Most likely bad calibration data, do not feed to control
+            return Some(Err(SensorFault::OutOfRange));
+        }
+        Some(Ok(temperature))
     }
 
     pub fn get_temperature(&self) -> Option<ThermodynamicTemperature> {
-        let r = self.get_sens()?;
-        let temperature = self.sh.get_temperature(r);
-        Some(temperature)
+        self.read_temperature()?.ok()
+    }
+
+    pub fn get_sensor_fault(&self) -> Option<SensorFault> {
+        self.read_temperature()?.err()
+    }
+
+    /// Rate of change of the temperature in K/s
+    pub fn get_d_temperature(&self) -> Option<f64> {
+        self.d_temperature
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn channel_state() -> ChannelState {
+        ChannelState::new(ad7172::ChannelCalibration {
+            offset: 0x80_0000,
+            gain: 0x40_0000,
+            bipolar: false,
+            v_ref: 3.3,
+        })
+    }
+
+    #[test]
+    fn on_demand_read_returns_latest_sample() {
+        let mut state = channel_state();
+        state.set_adc_read_mode(AdcReadMode::OnDemand);
+        assert!(!state.feed(Instant::from_millis(100), 1000));
+        assert!(!state.feed(Instant::from_millis(200), 2000));
+        assert!(!state.feed(Instant::from_millis(300), 3000));
+        assert_eq!(state.adc_data, None);
+        assert_eq!(state.adc_discarded, 2);
+
+        assert!(state.read_held());
+        assert_eq!(state.adc_data, Some(3000));
+        assert_eq!(state.adc_time, Instant::from_millis(300));
+        // nothing new since
+        assert!(!state.read_held());
+    }
+
+    #[test]
+    fn anomalous_intervals_are_rejected() {
+        let mut stats = IntervalStats::default();
+        for _ in 0..10 {
+            assert!(stats.update(100.0, 0.2));
+        }
+        assert!(!stats.update(30.0, 0.2));
+        assert!(!stats.update(250.0, 0.2));
+        assert!(stats.update(110.0, 0.2));
+        assert_eq!(stats.rejected, 2);
+        assert_eq!(stats.count, 11);
+        assert_eq!(stats.min, 100.0);
+        assert_eq!(stats.max, 110.0);
+    }
+
+    #[test]
+    fn first_interval_is_nominal() {
+        let mut state = channel_state();
+        state.pid.parameters.ki = 0.001;
+        state.pid.target = 100.0;
+        // First sample long after boot
+        state.update(Instant::from_millis(60_000), 0x40_0000);
+        assert_eq!(state.adc_interval, Duration::from_millis(100));
+        state.update_pid();
+        let integral = state.pid.terms.i;
+        // Integrated over the nominal interval only
+        state.update(Instant::from_millis(60_100), 0x40_0000);
+        state.update_pid();
+        assert!((state.pid.terms.i - 2.0 * integral).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pid_holds_on_anomalous_interval() {
+        let mut state = channel_state();
+        state.adc_interval_tolerance = 0.2;
+        state.pid.parameters.kp = 1.0;
+        state.pid.target = 100.0;
+        let data = 0x40_0000;
+        let mut t = 0;
+        for _ in 0..5 {
+            t += 100;
+            state.update(Instant::from_millis(t), data);
+            state.update_pid();
+        }
+        let held = state.pid.y1;
+        state.pid.target = 0.0;
+        // late sample
+        t += 1000;
+        state.update(Instant::from_millis(t), data);
+        assert_eq!(state.update_pid(), Some(held));
+        assert_eq!(state.adc_interval_stats.rejected, 1);
+        // back on schedule
+        t += 100;
+        state.update(Instant::from_millis(t), data);
+        assert_ne!(state.update_pid(), Some(held));
+    }
+
+    #[test]
+    fn temperature_stats() {
+        let mut stats = TemperatureStats::default();
+        for temperature in [20.0, 22.0, 21.0, 21.0].iter() {
+            stats.update(*temperature);
+        }
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.min, 20.0);
+        assert_eq!(stats.max, 22.0);
+        assert_eq!(stats.mean, 21.0);
+        assert!((stats.std() - 0.5f64.sqrt()).abs() < 1e-12);
+        assert!(stats.moving_mean > 20.0 && stats.moving_mean < 21.0);
+    }
+
+    #[test]
+    fn d_temperature() {
+        let mut state = channel_state();
+        state.update(Instant::from_millis(0), 0x40_0000);
+        assert_eq!(state.get_d_temperature(), None);
+        state.update(Instant::from_millis(100), 0x40_0000);
+        assert_eq!(state.get_d_temperature(), Some(0.0));
+        state.update(Instant::from_millis(200), 0x40_1000);
+        assert!(state.get_d_temperature().unwrap() != 0.0);
+        for t in 3..10 {
+            state.update(Instant::from_millis(t * 100), ad7172::MAX_VALUE);
+        }
+        assert_eq!(state.get_d_temperature(), None);
+    }
+
+    #[test]
+    fn non_finite_temperature() {
+        let mut state = channel_state();
+        state.sh.b = f64::NAN;
+        state.sh.t_min = ThermodynamicTemperature::new::<kelvin>(f64::NEG_INFINITY);
+        state.sh.t_max = ThermodynamicTemperature::new::<kelvin>(f64::INFINITY);
+        state.pid_engaged = true;
+        state.update(Instant::from_millis(0), 0x40_0000);
+        assert_eq!(state.get_temperature(), None);
+        assert_eq!(state.get_sensor_fault(), Some(SensorFault::OutOfRange));
+        assert_eq!(state.update_pid(), None);
+    }
+
+    #[test]
+    fn non_finite_pid_output() {
+        let mut state = channel_state();
+        state.pid.parameters.kp = f32::NAN;
+        state.update(Instant::from_millis(0), 0x40_0000);
+        state.update(Instant::from_millis(100), 0x40_0000);
+        assert!(state.get_temperature().is_some());
+        assert_eq!(state.update_pid(), None);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let parameters = pid::Parameters::default();
+        let mut hysteresis = Hysteresis { low: 24.0, high: 26.0, heat: false };
+        let output = hysteresis.update(27.0, 0.0, &parameters);
+        assert_eq!(output, 2.0);
+        let output = hysteresis.update(25.0, output, &parameters);
+        assert_eq!(output, 2.0);
+        let output = hysteresis.update(23.0, output, &parameters);
+        assert_eq!(output, 0.0);
+        assert_eq!(hysteresis.update(25.0, output, &parameters), 0.0);
+        hysteresis.heat = true;
+        assert_eq!(hysteresis.update(23.0, output, &parameters), -2.0);
+    }
+
+    #[test]
+    fn setpoint_ramp() {
+        let mut state = channel_state();
+        let data = 0x40_0000;
+        let mut t = 0;
+        state.update(Instant::from_millis(t), data);
+        let start = state.get_temperature().unwrap().get::<degree_celsius>();
+        state.start_ramp(start + 1.0, 2.0);
+        assert_eq!(state.pid.target, start);
+        for _ in 0..4 {
+            t += 100;
+            state.update(Instant::from_millis(t), data);
+            state.update_pid();
+        }
+        assert!((state.pid.target - (start + 0.8)).abs() < 1e-9);
+        assert!(state.ramp.is_some());
+        t += 100;
+        state.update(Instant::from_millis(t), data);
+        state.update_pid();
+        assert_eq!(state.pid.target, start + 1.0);
+        assert_eq!(state.ramp, None);
+
+        // rate 0 steps
+        state.start_ramp(start - 5.0, 0.0);
+        assert_eq!(state.pid.target, start - 5.0);
+        assert_eq!(state.ramp, None);
+    }
+
+    #[test]
+    fn continuous_consumes_every_sample() {
+        let mut state = channel_state();
+        assert!(state.feed(Instant::from_millis(100), 1000));
+        assert_eq!(state.adc_data, Some(1000));
+        assert!(!state.read_held());
+        assert_eq!(state.adc_discarded, 0);
+    }
+
+    #[test]
+    fn out_of_bounds_temperature_is_invalid() {
+        let mut state = channel_state();
+        state.pid.parameters.kp = 1.0;
+        state.update(Instant::from_millis(100), 0x40_0000);
+        let temperature = state.get_temperature().unwrap();
+        assert!(state.update_pid().is_some());
+
+        state.sh.t_max = ThermodynamicTemperature::new::<degree_celsius>(temperature.get::<degree_celsius>() - 1.0);
+        assert_eq!(state.get_temperature(), None);
+        // no output, the caller powers down
+        assert_eq!(state.update_pid(), None);
+
+        state.sh.t_max = sh::Parameters::default().t_max;
+        state.sh.t_min = ThermodynamicTemperature::new::<degree_celsius>(temperature.get::<degree_celsius>() + 1.0);
+        assert_eq!(state.get_temperature(), None);
+    }
+
+    #[test]
+    fn temp_units() {
+        let boiling = ThermodynamicTemperature::new::<degree_celsius>(100.0);
+        assert!((TempUnit::Celsius.value(boiling) - 100.0).abs() < 1e-9);
+        assert!((TempUnit::Kelvin.value(boiling) - 373.15).abs() < 1e-9);
+        assert!((TempUnit::Fahrenheit.value(boiling) - 212.0).abs() < 1e-9);
+        for unit in [TempUnit::Celsius, TempUnit::Kelvin, TempUnit::Fahrenheit].iter() {
+            let t = unit.temperature(unit.value(boiling));
+            assert!((t.get::<kelvin>() - boiling.get::<kelvin>()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn rtd_sensor() {
+        let mut state = channel_state();
+        state.sensor = SensorType::Rtd;
+        state.update(Instant::from_millis(100), 0x40_0000);
+        let r = state.get_sens().unwrap();
+        // 0 °C
+        state.rtd.r0 = r;
+        assert_eq!(state.get_temperature(), Some(state.rtd.get_temperature(r)));
+        assert_ne!(state.get_temperature(), Some(state.sh.get_temperature(r)));
+    }
+
+    #[test]
+    fn temperature_correction_drives_control() {
+        let mut reference = channel_state();
+        let mut corrected = channel_state();
+        corrected.sh.correction_gain = 2.0;
+        corrected.sh.correction_offset = 1.0;
+        reference.update(Instant::from_millis(100), 0x40_0000);
+        corrected.update(Instant::from_millis(100), 0x40_0000);
+        let t = reference.get_temperature().unwrap().get::<degree_celsius>();
+        let t_corrected = corrected.get_temperature().unwrap().get::<degree_celsius>();
+        assert!((t_corrected - (2.0 * t + 1.0)).abs() < 1e-9);
+
+        // Same error against the corrected temperature, same output
+        for state in [&mut reference, &mut corrected].iter_mut() {
+            state.pid.parameters.kp = 0.0;
+            state.pid.parameters.ki = 0.1;
+            state.pid.parameters.kd = 0.0;
+        }
+        reference.pid.target = t - 0.5;
+        corrected.pid.target = t_corrected - 0.5;
+        for i in 2..5 {
+            reference.update(Instant::from_millis(100 * i), 0x40_0000);
+            corrected.update(Instant::from_millis(100 * i), 0x40_0000);
+            let output = reference.update_pid().unwrap();
+            assert_ne!(output, 0.0);
+            assert!((corrected.update_pid().unwrap() - output).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn noise_floor_in_temperature() {
+        let mut noise_floor = NoiseFloor::new(4);
+        for adc_data in &[100, 102, 100, 102, 200] {
+            noise_floor.update(*adc_data);
+        }
+        assert!(noise_floor.is_done());
+        // NTC, temperature falls with rising code
+        noise_floor.finish(-0.5e-3);
+        assert_eq!(noise_floor.result, Some(NoiseFloorResult {
+            std_codes: 1.0,
+            p2p_codes: 2.0,
+            std_uk: 500.0,
+            p2p_uk: 1000.0,
+        }));
+    }
+
+    #[test]
+    fn noise_floor_uses_local_slope() {
+        let mut state = channel_state();
+        let slope = state.get_temperature_slope(0x40_0000).unwrap();
+        assert!(slope < 0.0);
+        state.noise_floor = Some(NoiseFloor::new(2));
+        state.update(Instant::from_millis(100), 0x40_0000);
+        state.update(Instant::from_millis(200), 0x40_0002);
+        let result = state.noise_floor.unwrap().result.unwrap();
+        assert_eq!(result.p2p_codes, 2.0);
+        assert!((result.p2p_uk - 2.0 * slope.abs() * 1e6).abs() < 1e-3 * result.p2p_uk);
+    }
+
+    #[test]
+    fn sensor_faults_are_distinguished() {
+        let mut state = channel_state();
+        assert_eq!(state.get_sensor_fault(), None);
+        state.update(Instant::from_millis(100), 0x40_0000);
+        assert_eq!(state.get_sensor_fault(), None);
+        assert!(state.get_temperature().is_some());
+
+        state.update(Instant::from_millis(200), ad7172::MAX_VALUE);
+        assert_eq!(state.get_sensor_fault(), Some(SensorFault::Open));
+        state.update(Instant::from_millis(300), 0);
+        assert_eq!(state.get_sensor_fault(), Some(SensorFault::Short));
+        // Above the reference voltage, negative resistance
+        state.update(Instant::from_millis(400), 0xF0_0000);
+        assert_eq!(state.get_sensor_fault(), Some(SensorFault::OutOfRange));
+        assert_eq!(state.get_temperature(), None);
+        // No output, the caller powers down
+        state.pid_engaged = true;
+        assert_eq!(state.update_pid(), None);
+    }
+
+    #[test]
+    fn temp_limits_latch() {
+        let mut state = channel_state();
+        state.update(Instant::from_millis(100), 0x40_0000);
+        let temperature = state.get_temperature().unwrap().get::<degree_celsius>();
+        state.pid_engaged = true;
+        state.temp_limits.max = Some(temperature as f32 + 1.0);
+        assert!(!state.check_faults());
+
+        state.temp_limits.max = Some(temperature as f32 - 1.0);
+        assert!(state.check_faults());
+        assert!(!state.pid_engaged);
+        assert!(matches!(state.fault, Some(ChannelFault::TempLimit(_))));
+        // stays latched, without tripping again
+        state.temp_limits.max = None;
+        assert!(!state.check_faults());
+        assert!(state.fault.is_some());
+    }
+
+    #[test]
+    fn thermal_runaway_latch() {
+        let mut state = channel_state();
+        state.runaway = RunawayParameters { window: 1.0, slope: 0.01 };
+        state.pid_engaged = true;
+        state.pid.parameters.output_max = 2.0;
+        // full cooling current
+        state.pid.y1 = 2.0;
+        // higher resistance of an NTC is colder
+        let mut adc_data = 0x40_0000;
+        for t in 1..=20 {
+            adc_data += 0x1000;
+            state.update(Instant::from_millis(t * 100), adc_data);
+            assert!(!state.check_faults());
+        }
+
+        // temperature does not follow
+        for t in 21..=40 {
+            state.update(Instant::from_millis(t * 100), adc_data);
+            if state.check_faults() {
+                assert_eq!(state.fault, Some(ChannelFault::ThermalRunaway));
+                assert!(!state.pid_engaged);
+                return;
+            }
+        }
+        panic!("runaway not detected");
+    }
+
+    #[test]
+    fn softstart_ramp() {
+        let mut state = channel_state();
+        // disabled
+        assert_eq!(state.start_softstart(Instant::from_millis(0), 1.0), 1.0);
+        assert_eq!(state.softstart_current(Instant::from_millis(0)), None);
+
+        state.softstart = 1000;
+        assert_eq!(state.start_softstart(Instant::from_millis(1000), 2.0), 0.0);
+        assert_eq!(state.softstart_current(Instant::from_millis(1250)), Some(0.5));
+        assert_eq!(state.set_softstart_target(Instant::from_millis(1500), -1.0), Some(-0.5));
+        assert_eq!(state.softstart_current(Instant::from_millis(2000)), Some(-1.0));
+        // done
+        assert_eq!(state.softstart_current(Instant::from_millis(2100)), None);
+        assert_eq!(state.set_softstart_target(Instant::from_millis(2100), 1.0), None);
+    }
+
+    #[test]
+    fn slew_rate_limits_output_change() {
+        let mut state = channel_state();
+        state.update(Instant::from_millis(100), 0x40_0000);
+        state.update(Instant::from_millis(200), 0x40_0000);
+        // disabled
+        assert_eq!(state.limit_slew(0.0, 1.5), 1.5);
+
+        // 2 A/s over 100 ms
+        state.pid.parameters.slew = 2.0;
+        assert!((state.limit_slew(0.0, 1.5) - 0.2).abs() < 1e-6);
+        assert!((state.limit_slew(0.5, -1.5) - 0.3).abs() < 1e-6);
+        assert_eq!(state.limit_slew(0.5, 0.6), 0.6);
+    }
+
+    #[test]
+    fn setpoint_input_scaling() {
+        let setpoint = SetpointInput {
+            source: SetpointSource::Analog,
+            scale: 20.0,
+            offset: -10.0,
+        };
+        assert_eq!(setpoint.get_target(ElectricPotential::new::<volt>(0.0)), -10.0);
+        assert_eq!(setpoint.get_target(ElectricPotential::new::<volt>(1.5)), 20.0);
+        assert_eq!(setpoint.get_target(ElectricPotential::new::<volt>(3.3)), 56.0);
+    }
+
+    #[test]
+    fn sensor_fault_holdoff() {
+        let mut state = channel_state();
+        state.sh.fault_holdoff = 2;
+        state.pid.parameters.kp = 1.0;
+        state.update(Instant::from_millis(100), 0x40_0000);
+        assert!(state.update_pid().is_some());
+
+        // A single glitch holds the output
+        state.update(Instant::from_millis(200), ad7172::MAX_VALUE);
+        assert_eq!(state.sensor_fault_streak, 1);
+        assert_eq!(state.update_pid(), Some(state.pid.y1));
+        state.update(Instant::from_millis(300), 0x40_0000);
+        assert_eq!(state.sensor_fault_streak, 0);
+        assert!(state.get_temperature().is_some());
+
+        // A sustained run trips
+        for i in 0..3 {
+            state.update(Instant::from_millis(400 + 100 * i), ad7172::MAX_VALUE);
+        }
+        assert_eq!(state.sensor_fault_streak, 3);
+        assert_eq!(state.adc_data, None);
+        assert_eq!(state.update_pid(), None);
     }
 }
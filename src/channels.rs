@@ -1,97 +1,420 @@
-use core::cmp::max_by;
+use core::fmt::{self, Write};
 use heapless::{consts::U2, Vec};
+use log::{error, warn};
 use serde::{Serialize, Serializer};
 use smoltcp::time::Instant;
-use stm32f4xx_hal::hal;
+use stm32f4xx_hal::{hal, spi};
 use uom::si::{
-    f64::{ElectricCurrent, ElectricPotential, ElectricalResistance, Time},
-    electric_potential::{millivolt, volt},
+    f64::{ElectricCurrent, ElectricPotential, ElectricalResistance, ThermodynamicTemperature, Time},
+    electric_potential::volt,
     electric_current::ampere,
     electrical_resistance::ohm,
     ratio::ratio,
     thermodynamic_temperature::degree_celsius,
+    time::second,
 };
 use crate::{
     ad5680,
     ad7172,
-    channel::{Channel, Channel0, Channel1},
-    channel_state::ChannelState,
-    command_parser::{CenterPoint, PwmPin},
+    autotune::Autotune,
+    channel::{self, Channel, ChannelVariant},
+    channel_state::{ChannelFault, ChannelState, ControlMode, Hysteresis, IntervalStats, NoiseFloor, RunawayParameters, SensorFault, SetpointInput, SetpointRamp, TempUnit},
+    command_parser::{AdcReadMode, CenterPoint, PwmPin, SensorType, SetpointSource},
     command_handler::JsonBuffer,
+    pid,
+    hw_rev::HardwareParams,
     pins,
+    profile,
+    rtd,
     steinhart_hart,
+    tec::ThermalEnergy,
+    timer,
 };
 
 pub const CHANNELS: usize = 2;
-pub const R_SENSE: f64 = 0.05;
+/// Give up waiting for a diagnostic ADC conversion
+const DIAGNOSTIC_TIMEOUT_MS: u32 = 1000;
 // DAC chip outputs 0-5v, which is then passed through a resistor dividor to provide 0-3v range
 const DAC_OUT_V_MAX: f64 = 3.0;
+/// Columns of a report in `ReportFormat::Csv`, in SI base units
+pub const REPORT_CSV_HEADER: &str = "channel,time,interval,adc,sens,temperature,d_temperature,\
+    sensor_fault,fault,enabled,pid_engaged,i_set,dac_value,dac_feedback,dac_error,i_tec,tec_i,tec_u_meas,pid_output,pid_cooling,uptime_ms,epoch_ms";
+/// Deviation of the DAC feedback from the commanded output above
+/// which a write is retried
+const DAC_VERIFY_TOLERANCE: f64 = 0.02;
+/// Feedback reads within which the DAC output must settle before it is
+/// verified, bounding the time spent after each write
+const DAC_SETTLE_READS: usize = 8;
+/// Gain of the MAX1968 ITEC output over the voltage across `r_sense`
+const ITEC_GAIN: f64 = 8.0;
+/// Default number of averaged conversions of the stm32f4 integrated adc
+pub const PINS_ADC_SAMPLES: u16 = 4;
+/// Limits the time spent in a single read of the integrated adc
+pub const PINS_ADC_SAMPLES_MAX: u16 = 256;
+/// Upper bound of a CSV report line, with every number as long as
+/// `-2.2250738585072014e-308`
+pub const REPORT_CSV_MAX_LEN: usize = 512;
+/// Upper bound of the JSON of one `Report`, with every number as long
+/// as `-2.2250738585072014e-308`. `reports_json()` must fit all
+/// channels into a `JsonBuffer`.
+pub const REPORT_JSON_MAX_LEN: usize = 768;
+
+pub type AdcError = ad7172::AdcError<spi::Error>;
 
 // TODO: -pub
 pub struct Channels {
-    channel0: Channel<Channel0>,
-    channel1: Channel<Channel1>,
+    channels: [ChannelVariant; CHANNELS],
     pub adc: ad7172::Adc<pins::AdcSpi, pins::AdcNss>,
     /// stm32f4 integrated adc
     pins_adc: pins::PinsAdc,
+    /// Conversions averaged per read of `pins_adc`
+    pins_adc_samples: u16,
+    setpoint_pin: pins::SetpointPin,
     pub pwm: pins::PwmPins,
+    hardware: HardwareParams,
+    /// SPI transfers to the ADC and DACs that failed since boot
+    spi_errors: u32,
+    temp_unit: TempUnit,
 }
 
 impl Channels {
-    pub fn new(pins: pins::Pins) -> Self {
-        let mut adc = ad7172::Adc::new(pins.adc_spi, pins.adc_nss).unwrap();
-        // Feature not used
-        adc.set_sync_enable(false).unwrap();
-
-        // Setup channels and start ADC
-        adc.setup_channel(0, ad7172::Input::Ain2, ad7172::Input::Ain3).unwrap();
-        let adc_calibration0 = adc.get_calibration(0)
-            .expect("adc_calibration0");
-        adc.setup_channel(1, ad7172::Input::Ain0, ad7172::Input::Ain1).unwrap();
-        let adc_calibration1 = adc.get_calibration(1)
-            .expect("adc_calibration1");
-        adc.start_continuous_conversion().unwrap();
-
-        let channel0 = Channel::new(pins.channel0, adc_calibration0);
-        let channel1 = Channel::new(pins.channel1, adc_calibration1);
+    /// Skips the DAC calibration of channels with a stored `vref_meas`
+    /// in V
+    pub fn new(pins: pins::Pins, hardware: HardwareParams, vref_meas: [Option<f64>; CHANNELS]) -> Self {
+        let mut adc = match ad7172::Adc::new(pins.adc_spi, pins.adc_nss) {
+            Ok(adc) => adc,
+            Err(e) => {
+                error!("ADC initialization failed: {:?}", e);
+                panic!("ADC initialization failed");
+            }
+        };
+        // A persistent failure keeps retrying until the watchdog resets
+        let mut spi_errors = 0;
+        let [adc_calibration0, adc_calibration1] = loop {
+            match setup_adc(&mut adc) {
+                Ok(adc_calibration) => break adc_calibration,
+                Err(e) => {
+                    error!("ADC setup failed: {:?}", e);
+                    spi_errors += 1;
+                }
+            }
+        };
+
+        let channels = [
+            ChannelVariant::Channel0(Channel::new(pins.channel0, adc_calibration0)),
+            ChannelVariant::Channel1(Channel::new(pins.channel1, adc_calibration1)),
+        ];
         let pins_adc = pins.pins_adc;
+        let setpoint_pin = pins.setpoint_pin;
         let pwm = pins.pwm;
-        let mut channels = Channels { channel0, channel1, adc, pins_adc, pwm };
+        let mut channels = Channels { channels, adc, pins_adc, pins_adc_samples: PINS_ADC_SAMPLES, setpoint_pin, pwm, hardware: hardware.clone(), spi_errors, temp_unit: TempUnit::Celsius };
+        if let Err(e) = channels.set_hardware_params(hardware) {
+            error!("ADC calibration readback failed: {:?}", e);
+        }
         for channel in 0..CHANNELS {
-            channels.calibrate_dac_value(channel);
+            match vref_meas[channel] {
+                Some(vref_meas) =>
+                    channels.channels[channel].set_vref_meas(ElectricPotential::new::<volt>(vref_meas)),
+                None =>
+                    channels.calibrate_dac_value(channel),
+            }
             channels.set_i(channel, ElectricCurrent::new::<ampere>(0.0));
         }
         channels
     }
 
+    pub fn spi_errors(&self) -> u32 {
+        self.spi_errors
+    }
+
+    pub fn temp_unit(&self) -> TempUnit {
+        self.temp_unit
+    }
+
+    pub fn set_temp_unit(&mut self, unit: TempUnit) {
+        self.temp_unit = unit;
+    }
+
     pub fn channel_state<I: Into<usize>>(&mut self, channel: I) -> &mut ChannelState {
-        match channel.into() {
-            0 => &mut self.channel0.state,
-            1 => &mut self.channel1.state,
-            _ => unreachable!(),
-        }
+        self.channels[channel.into()].state()
     }
 
     /// ADC input + PID processing
-    pub fn poll_adc(&mut self, instant: Instant) -> Option<u8> {
-        self.adc.data_ready().unwrap().map(|channel| {
-            let data = self.adc.read_data().unwrap();
-            let state = self.channel_state(channel);
-            state.update(instant, data);
-            match state.update_pid() {
-                Some(pid_output) if state.pid_engaged => {
-                    // Forward PID output to i_set DAC
-                    self.set_i(channel.into(), ElectricCurrent::new::<ampere>(pid_output));
-                    self.power_up(channel);
+    ///
+    /// A failed SPI transfer drops the sample and is counted.
+    pub fn poll_adc(&mut self, instant: Instant) -> Result<Option<u8>, AdcError> {
+        self.poll_softstart(instant);
+        let sample = self.adc.data_ready().and_then(|channel| match channel {
+            Some(channel) => self.adc.read_data().map(|data| Some((channel, data))),
+            None => Ok(None),
+        });
+        match sample {
+            Ok(Some((channel, data))) => Ok(self.process_adc_data(instant, channel, data)),
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.spi_errors += 1;
+                Err(e.into())
+            }
+        }
+    }
+
+    fn process_adc_data(&mut self, instant: Instant, channel: u8, data: u32) -> Option<u8> {
+        if usize::from(channel) >= CHANNELS {
+            // Late diagnostic conversion
+            return None;
+        }
+        if self.channel_state(channel).feed(instant, data) {
+            self.check_faults(channel.into());
+            self.update_setpoint(channel.into());
+            self.update_profile(channel.into());
+            self.update_thermal_energy(channel.into());
+            self.update_pid(channel);
+            Some(channel)
+        } else {
+            None
+        }
+    }
+
+    /// Convert `in_pos` - `in_neg` once on the diagnostic channel while
+    /// processing the TEC channels' samples as usual
+    fn read_diagnostic_adc(&mut self, in_pos: ad7172::Input, in_neg: ad7172::Input) -> Option<u32> {
+        if let Err(e) = self.adc.enable_diagnostic_channel(in_pos, in_neg) {
+            warn!("ADC: {:?}", e);
+            self.spi_errors += 1;
+            return None;
+        }
+        let start = timer::now();
+        let mut result = None;
+        while timer::now() - start < DIAGNOSTIC_TIMEOUT_MS {
+            let sample = self.adc.data_ready().and_then(|channel| match channel {
+                Some(channel) => self.adc.read_data().map(|data| Some((channel, data))),
+                None => Ok(None),
+            });
+            match sample {
+                Ok(Some((ad7172::DIAGNOSTIC_CHANNEL, data))) => {
+                    result = Some(data);
+                    break;
                 }
-                None if state.pid_engaged => {
-                    self.power_down(channel);
+                Ok(Some((channel, data))) => {
+                    let instant = Instant::from_millis(i64::from(timer::now()));
+                    self.process_adc_data(instant, channel, data);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("ADC: {:?}", e);
+                    self.spi_errors += 1;
+                    break;
                 }
-                _ => {}
             }
+        }
+        if let Err(e) = self.adc.disable_diagnostic_channel() {
+            warn!("ADC: {:?}", e);
+            self.spi_errors += 1;
+        }
+        result
+    }
 
-            channel
-        })
+    /// AD7172 die temperature
+    pub fn read_adc_temperature(&mut self) -> Option<ThermodynamicTemperature> {
+        self.read_diagnostic_adc(ad7172::Input::TemperaturePos, ad7172::Input::TemperatureNeg)
+            .map(ad7172::convert_internal_temperature)
+    }
+
+    /// AD7172 AVDD1 - AVSS
+    pub fn read_analog_supply(&mut self) -> Option<ElectricPotential> {
+        self.read_diagnostic_adc(ad7172::Input::AnalogSupplyPos, ad7172::Input::AnalogSupplyNeg)
+            .map(ad7172::convert_analog_supply)
+    }
+
+    /// Select the ADC reference and convert with its magnitude
+    pub fn set_adc_ref_source(&mut self, channel: usize, ref_source: ad7172::RefSource) -> Result<(), spi::Error> {
+        self.adc.set_ref_source(channel as u8, ref_source)
+            .map_err(|e| self.count_spi_error(e))?;
+        self.reload_adc_calibration(channel)
+    }
+
+    /// Switch between unipolar and bipolar coding of ADC data
+    pub fn set_adc_bipolar(&mut self, channel: usize, bipolar: bool) -> Result<(), spi::Error> {
+        self.adc.set_bipolar(channel as u8, bipolar)
+            .map_err(|e| self.count_spi_error(e))?;
+        self.reload_adc_calibration(channel)
+    }
+
+    /// Select the output data rate in SPS by either a postfilter or
+    /// the ODR, returns the mechanism and the rate actually set
+    pub fn set_adc_rate(&mut self, channel: usize, rate: f32) -> Result<(&'static str, Option<f32>), spi::Error> {
+        let index = channel as u8;
+        let selected = self.adc.get_filter(index).and_then(|filter| match filter.select_rate(rate) {
+            ad7172::RateSelection::PostFilter(postfilter) => {
+                self.adc.set_postfilter(index, Some(postfilter))?;
+                Ok(("postfilter", postfilter.output_rate()))
+            }
+            ad7172::RateSelection::Odr(odr) => {
+                self.adc.set_postfilter(index, None)?;
+                self.adc.set_odr(index, odr)?;
+                // Setting the ODR leaves a sinc3 map
+                let filter = self.adc.get_filter(index)?;
+                Ok(("odr", filter.output_rate(odr)))
+            }
+        });
+        self.channel_state(channel).adc_interval_stats = Default::default();
+        selected.map_err(|e| self.count_spi_error(e))
+    }
+
+    /// Number of conversions averaged per read of the stm32f4
+    /// integrated adc, clamped to `1..=PINS_ADC_SAMPLES_MAX`
+    pub fn set_pins_adc_samples(&mut self, samples: u16) -> u16 {
+        self.pins_adc_samples = samples.max(1).min(PINS_ADC_SAMPLES_MAX);
+        self.pins_adc_samples
+    }
+
+    /// SPI checksum of the ADC register accesses of all channels
+    pub fn set_adc_checksum_mode(&mut self, mode: ad7172::ChecksumMode) -> Result<(), spi::Error> {
+        self.adc.set_checksum_mode(mode)
+            .map_err(|e| self.count_spi_error(e))
+    }
+
+    pub fn hardware_params(&self) -> &HardwareParams {
+        &self.hardware
+    }
+
+    /// Override the constants of the analog front end. They are
+    /// applied even if the ADC calibration of a channel cannot be
+    /// read back, which is then returned.
+    pub fn set_hardware_params(&mut self, hardware: HardwareParams) -> Result<(), spi::Error> {
+        self.adc.set_v_ref_external(hardware.v_ref);
+        let mut result = Ok(());
+        for channel in 0..CHANNELS {
+            let state = self.channel_state(channel);
+            state.r_inner = hardware.r_inner;
+            state.vref_sens = hardware.vref_sens;
+            result = result.and(self.reload_adc_calibration(channel));
+        }
+        self.hardware = hardware;
+        result
+    }
+
+    /// Re-read the setup that the conversion of ADC data depends on
+    fn reload_adc_calibration(&mut self, channel: usize) -> Result<(), spi::Error> {
+        let adc_calibration = self.adc.get_calibration(channel as u8)
+            .map_err(|e| self.count_spi_error(e))?;
+        let state = self.channel_state(channel);
+        state.adc_calibration = adc_calibration;
+        state.adc_interval_stats = Default::default();
+        Ok(())
+    }
+
+    /// Count a failed ADC register access, which leaves the previous
+    /// setup in place
+    fn count_spi_error(&mut self, e: spi::Error) -> spi::Error {
+        warn!("ADC: {:?}", e);
+        self.spi_errors += 1;
+        e
+    }
+
+    /// Consume the ADC sample held back in `AdcReadMode::OnDemand`
+    pub fn read_held_adc(&mut self, channel: usize) -> bool {
+        let consumed = self.channel_state(channel).read_held();
+        if consumed {
+            self.check_faults(channel);
+            self.update_setpoint(channel);
+            self.update_profile(channel);
+            self.update_thermal_energy(channel);
+            self.update_pid(channel as u8);
+        }
+        consumed
+    }
+
+    fn update_pid(&mut self, channel: u8) {
+        let state = self.channel_state(channel);
+        match state.update_pid() {
+            Some(pid_output) if state.pid_engaged => {
+                let output = if state.pid.parameters.slew > 0.0 {
+                    let previous = self.get_i(channel.into()).get::<ampere>();
+                    let state = self.channel_state(channel);
+                    let output = state.limit_slew(previous, pid_output);
+                    // Continue from the applied output
+                    state.pid.y1 = output;
+                    output
+                } else {
+                    pid_output
+                };
+                // Forward PID output to i_set DAC
+                self.set_i(channel.into(), ElectricCurrent::new::<ampere>(output));
+                self.power_up(channel);
+            }
+            None if state.pid_engaged => {
+                self.power_down(channel);
+            }
+            _ => {}
+        }
+    }
+
+    /// Shut the TEC down as soon as a fault is detected
+    fn check_faults(&mut self, channel: usize) {
+        if self.channel_state(channel).check_faults() {
+            self.set_i(channel, ElectricCurrent::new::<ampere>(0.0));
+            self.power_down(channel);
+            match self.channel_state(channel).fault {
+                Some(ChannelFault::TempLimit(temperature)) =>
+                    error!("channel {}: temperature {} °C out of limits, powered down until cleared", channel, temperature),
+                Some(ChannelFault::ThermalRunaway) =>
+                    error!("channel {}: thermal runaway, powered down until cleared", channel),
+                None => {}
+            }
+        }
+    }
+
+    /// Clear a latched fault, the TEC stays off until it is set again
+    pub fn clear_fault(&mut self, channel: usize) {
+        self.channel_state(channel).fault = None;
+    }
+
+    /// Follow the external setpoint input
+    fn update_setpoint(&mut self, channel: usize) {
+        if self.channel_state(channel).setpoint.source == SetpointSource::Analog {
+            let voltage = self.read_setpoint_input();
+            let state = self.channel_state(channel);
+            state.pid.target = state.setpoint.get_target(voltage);
+            state.ramp = None;
+            state.profile.stop();
+        }
+    }
+
+    /// Start the next ramp of a running profile when the current
+    /// segment is done
+    fn update_profile(&mut self, channel: usize) {
+        let now = timer::now();
+        let state = self.channel_state(channel);
+        let ramp_done = state.ramp.is_none();
+        if let Some(segment) = state.profile.poll(now, ramp_done).cloned() {
+            state.start_ramp(segment.target, segment.rate);
+        }
+    }
+
+    /// Returns `false` if there are no segments
+    pub fn start_profile(&mut self, channel: usize) -> bool {
+        let state = self.channel_state(channel);
+        match state.profile.start().cloned() {
+            Some(segment) => {
+                state.start_ramp(segment.target, segment.rate);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn update_thermal_energy(&mut self, channel: usize) {
+        let state = self.channel_state(channel);
+        // No interval has been measured before the second sample
+        if state.adc_interval_stats.count == 0 {
+            return;
+        }
+        let dt = state.get_adc_interval();
+        if let Some(temperature) = state.get_temperature() {
+            let current = self.get_tec_i(channel);
+            self.channel_state(channel).tec.update(current, temperature, dt);
+        }
     }
 
     /// calculate the TEC i_set centerpoint
@@ -112,58 +435,161 @@ impl Channels {
 
     pub fn get_i(&mut self, channel: usize) -> ElectricCurrent {
         let center_point = self.get_center(channel);
-        let r_sense = ElectricalResistance::new::<ohm>(R_SENSE);
+        let r_sense = ElectricalResistance::new::<ohm>(self.hardware.r_sense);
         let voltage = self.get_dac(channel);
         let i_tec = (voltage - center_point) / (10.0 * r_sense);
-        i_tec
+        self.polarity(channel) * i_tec
+    }
+
+    /// `-1.0` if the TEC of the channel is wired backwards
+    fn polarity(&mut self, channel: usize) -> f64 {
+        if self.channel_state(channel).inverted { -1.0 } else { 1.0 }
+    }
+
+    /// The hardware pin that limits the current in the direction of
+    /// `pin`, which is swapped with an inverted TEC
+    fn limit_pin(&mut self, channel: usize, pin: PwmPin) -> PwmPin {
+        match (self.channel_state(channel).inverted, pin) {
+            (true, PwmPin::MaxIPos) => PwmPin::MaxINeg,
+            (true, PwmPin::MaxINeg) => PwmPin::MaxIPos,
+            (_, pin) => pin,
+        }
+    }
+
+    /// Swap the output polarity of a TEC that is wired backwards.
+    /// Currents and their limits keep their thermal direction: the
+    /// `max_i_pos`/`max_i_neg` limits are moved to the other pins and
+    /// the output is set to 0 A until the next update.
+    pub fn set_inverted(&mut self, channel: usize, inverted: bool) {
+        if self.channel_state(channel).inverted == inverted {
+            return;
+        }
+        let (max_i_pos, _) = self.get_max_i_pos(channel);
+        let (max_i_neg, _) = self.get_max_i_neg(channel);
+        self.channel_state(channel).inverted = inverted;
+        self.set_max_i_pos(channel, max_i_pos);
+        self.set_max_i_neg(channel, max_i_neg);
+        self.set_i(channel, ElectricCurrent::new::<ampere>(0.0));
+    }
+
+    /// Returns `false` if the SPI transfer failed, leaving the
+    /// previous value in place
+    fn write_dac(&mut self, channel: usize, value: u32) -> bool {
+        match self.channels[channel].set_dac(value) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("DAC {}: {:?}", channel, e);
+                self.spi_errors += 1;
+                false
+            }
+        }
     }
 
     /// i_set DAC
     fn set_dac(&mut self, channel: usize, voltage: ElectricPotential) -> ElectricPotential {
-        let value = ((voltage / ElectricPotential::new::<volt>(DAC_OUT_V_MAX)).get::<ratio>() * (ad5680::MAX_VALUE as f64)) as u32 ;
-        match channel {
-            0 => self.channel0.dac.set(value).unwrap(),
-            1 => self.channel1.dac.set(value).unwrap(),
-            _ => unreachable!(),
+        let value = match dac_code(voltage) {
+            Some(value) => value,
+            None => {
+                warn!("DAC {}: not writing {:?}", channel, voltage);
+                return self.get_dac(channel);
+            }
         };
-        self.channel_state(channel).dac_value = voltage;
+        if self.write_dac(channel, value) {
+            self.channel_state(channel).dac_value = voltage;
+            if self.channel_state(channel).dac_verify {
+                self.verify_dac(channel, value);
+            }
+        }
         voltage
     }
 
+    /// Compare the DAC output with the feedback input after writing
+    /// `value`, and write it once more if they disagree
+    fn verify_dac(&mut self, channel: usize, value: u32) {
+        let commanded = DAC_OUT_V_MAX * value as f64 / ad5680::MAX_VALUE as f64;
+        let mut error = self.read_dac_feedback_settled(channel) - commanded;
+        if error.abs() > DAC_VERIFY_TOLERANCE {
+            warn!("DAC {}: feedback off by {} V, retrying", channel, error);
+            if self.write_dac(channel, value) {
+                error = self.read_dac_feedback_settled(channel) - commanded;
+                if error.abs() > DAC_VERIFY_TOLERANCE {
+                    warn!("DAC {}: feedback still off by {} V", channel, error);
+                }
+            }
+        }
+        self.channel_state(channel).dac_error = Some(error);
+    }
+
+    /// DAC feedback in V once consecutive reads agree, so that the
+    /// output has settled after a write
+    fn read_dac_feedback_settled(&mut self, channel: usize) -> f64 {
+        let mut prev = self.read_dac_feedback(channel).get::<volt>();
+        for _ in 1..DAC_SETTLE_READS {
+            let current = self.read_dac_feedback(channel).get::<volt>();
+            if (current - prev).abs() < DAC_VERIFY_TOLERANCE / 4.0 {
+                return (current + prev) / 2.0;
+            }
+            prev = current;
+        }
+        prev
+    }
+
+    /// Verify every DAC write, see `verify_dac`
+    pub fn set_dac_verify(&mut self, channel: usize, verify: bool) {
+        let state = self.channel_state(channel);
+        state.dac_verify = verify;
+        state.dac_error = None;
+    }
+
+    /// Write a DAC code, bypassing the current model. Returns the
+    /// resulting output voltage.
+    pub fn set_dac_raw(&mut self, channel: usize, value: u32) -> ElectricPotential {
+        let value = value.min(ad5680::MAX_VALUE);
+        self.channel_state(channel).stop_softstart();
+        if self.write_dac(channel, value) {
+            self.channel_state(channel).dac_value =
+                (value as f64 / ad5680::MAX_VALUE as f64) * ElectricPotential::new::<volt>(DAC_OUT_V_MAX);
+        }
+        self.get_dac(channel)
+    }
+
+    /// Set `i_tec`, or the target of a running soft start ramp. A
+    /// current that is not finite sets 0 A.
     pub fn set_i(&mut self, channel: usize, i_tec: ElectricCurrent) -> ElectricCurrent {
-        let vref_meas = match channel.into() {
-            0 => self.channel0.vref_meas,
-            1 => self.channel1.vref_meas,
-            _ => unreachable!(),
+        let i_tec = if i_tec.get::<ampere>().is_finite() {
+            i_tec
+        } else {
+            warn!("channel {}: not setting i_tec {:?}", channel, i_tec);
+            ElectricCurrent::new::<ampere>(0.0)
         };
-        let center_point = vref_meas;
-        let r_sense = ElectricalResistance::new::<ohm>(R_SENSE);
-        let voltage = i_tec * 10.0 * r_sense + center_point;
+        let now = Instant::from_millis(i64::from(timer::now()));
+        match self.channel_state(channel).set_softstart_target(now, i_tec.get::<ampere>()) {
+            Some(ramp) => self.write_i(channel, ElectricCurrent::new::<ampere>(ramp)),
+            None => self.write_i(channel, i_tec),
+        }
+    }
+
+    /// Continue soft start ramps
+    fn poll_softstart(&mut self, now: Instant) {
+        for channel in 0..CHANNELS {
+            if let Some(ramp) = self.channel_state(channel).softstart_current(now) {
+                self.write_i(channel, ElectricCurrent::new::<ampere>(ramp));
+            }
+        }
+    }
+
+    fn write_i(&mut self, channel: usize, i_tec: ElectricCurrent) -> ElectricCurrent {
+        let polarity = self.polarity(channel);
+        let center_point = self.channels[channel].vref_meas();
+        let r_sense = ElectricalResistance::new::<ohm>(self.hardware.r_sense);
+        let voltage = polarity * i_tec * 10.0 * r_sense + center_point;
         let voltage = self.set_dac(channel, voltage);
         let i_tec = (voltage - center_point) / (10.0 * r_sense);
-        i_tec
+        polarity * i_tec
     }
 
     pub fn read_dac_feedback(&mut self, channel: usize) -> ElectricPotential {
-        match channel {
-            0 => {
-                let sample = self.pins_adc.convert(
-                    &self.channel0.dac_feedback_pin,
-                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480
-                );
-                let mv = self.pins_adc.sample_to_millivolts(sample);
-                ElectricPotential::new::<millivolt>(mv as f64)
-            }
-            1 => {
-                let sample = self.pins_adc.convert(
-                    &self.channel1.dac_feedback_pin,
-                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480
-                );
-                let mv = self.pins_adc.sample_to_millivolts(sample);
-                ElectricPotential::new::<millivolt>(mv as f64)
-            }
-            _ => unreachable!(),
-        }
+        self.channels[channel].read_dac_feedback(&mut self.pins_adc, self.pins_adc_samples)
     }
 
     pub fn read_dac_feedback_until_stable(&mut self, channel: usize, tolerance: ElectricPotential) -> ElectricPotential {
@@ -178,70 +604,21 @@ impl Channels {
     }
 
     pub fn read_itec(&mut self, channel: usize) -> ElectricPotential {
-        match channel {
-            0 => {
-                let sample = self.pins_adc.convert(
-                    &self.channel0.itec_pin,
-                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480
-                );
-                let mv = self.pins_adc.sample_to_millivolts(sample);
-                ElectricPotential::new::<millivolt>(mv as f64)
-            }
-            1 => {
-                let sample = self.pins_adc.convert(
-                    &self.channel1.itec_pin,
-                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480
-                );
-                let mv = self.pins_adc.sample_to_millivolts(sample);
-                ElectricPotential::new::<millivolt>(mv as f64)
-            }
-            _ => unreachable!(),
-        }
+        self.channels[channel].read_itec(&mut self.pins_adc, self.pins_adc_samples)
     }
 
     /// should be 1.5V
     pub fn read_vref(&mut self, channel: usize) -> ElectricPotential {
-        match channel {
-            0 => {
-                let sample = self.pins_adc.convert(
-                    &self.channel0.vref_pin,
-                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480
-                );
-                let mv = self.pins_adc.sample_to_millivolts(sample);
-                ElectricPotential::new::<millivolt>(mv as f64)
-            }
-            1 => {
-                let sample = self.pins_adc.convert(
-                    &self.channel1.vref_pin,
-                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480
-                );
-                let mv = self.pins_adc.sample_to_millivolts(sample);
-                ElectricPotential::new::<millivolt>(mv as f64)
-            }
-            _ => unreachable!(),
-        }
+        self.channels[channel].read_vref(&mut self.pins_adc, self.pins_adc_samples)
+    }
+
+    /// Voltage on the external setpoint input
+    pub fn read_setpoint_input(&mut self) -> ElectricPotential {
+        channel::read_pin(&mut self.pins_adc, &self.setpoint_pin, self.pins_adc_samples)
     }
 
     pub fn read_tec_u_meas(&mut self, channel: usize) -> ElectricPotential {
-        match channel {
-            0 => {
-                let sample = self.pins_adc.convert(
-                    &self.channel0.tec_u_meas_pin,
-                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480
-                );
-                let mv = self.pins_adc.sample_to_millivolts(sample);
-                ElectricPotential::new::<millivolt>(mv as f64)
-            }
-            1 => {
-                let sample = self.pins_adc.convert(
-                    &self.channel1.tec_u_meas_pin,
-                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480
-                );
-                let mv = self.pins_adc.sample_to_millivolts(sample);
-                ElectricPotential::new::<millivolt>(mv as f64)
-            }
-            _ => unreachable!(),
-        }
+        self.channels[channel].read_tec_u_meas(&mut self.pins_adc, self.pins_adc_samples)
     }
 
     /// Calibrates the DAC output to match vref of the MAX driver to reduce zero-current offset of the MAX driver output.
@@ -273,14 +650,8 @@ impl Channels {
         for step in (0..18).rev() {
             let mut prev_value = start_value;
             for value in (start_value..=ad5680::MAX_VALUE).step_by(1 << step) {
-                match channel {
-                    0 => {
-                        self.channel0.dac.set(value).unwrap();
-                    }
-                    1 => {
-                        self.channel1.dac.set(value).unwrap();
-                    }
-                    _ => unreachable!(),
+                if !self.write_dac(channel, value) {
+                    continue;
                 }
 
                 let dac_feedback = self.read_dac_feedback_until_stable(channel, ElectricPotential::new::<volt>(0.001));
@@ -292,11 +663,7 @@ impl Channels {
                     start_value = prev_value;
 
                     let vref = (value as f64 / ad5680::MAX_VALUE as f64) * ElectricPotential::new::<volt>(DAC_OUT_V_MAX);
-                    match channel {
-                        0 => self.channel0.vref_meas = vref,
-                        1 => self.channel1.vref_meas = vref,
-                        _ => unreachable!(),
-                    }
+                    self.channels[channel].set_vref_meas(vref);
                 }
 
                 prev_value = value;
@@ -307,48 +674,85 @@ impl Channels {
         self.set_dac(channel, ElectricPotential::new::<volt>(0.0));
     }
 
+    /// DAC output for 0 A as found by `calibrate_dac_value`
+    pub fn get_vref_meas(&self, channel: usize) -> ElectricPotential {
+        self.channels[channel].vref_meas()
+    }
+
+    /// `stored` is the previous calibration in V
+    pub fn dac_calibration_json(&self, channel: usize, stored: Option<f64>) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let summary = DacCalibrationSummary {
+            channel,
+            stored,
+            measured: self.get_vref_meas(channel).get::<volt>(),
+        };
+        serde_json_core::to_vec(&summary)
+    }
+
     // power up TEC
     pub fn power_up<I: Into<usize>>(&mut self, channel: I) {
-        match channel.into() {
-            0 => self.channel0.power_up(),
-            1 => self.channel1.power_up(),
-            _ => unreachable!(),
+        let channel = channel.into();
+        if !self.channel_state(channel).powered_up {
+            // Ramp up from 0 to what has been set
+            let target = self.get_i(channel).get::<ampere>();
+            let now = Instant::from_millis(i64::from(timer::now()));
+            let i_tec = self.channel_state(channel).start_softstart(now, target);
+            self.write_i(channel, ElectricCurrent::new::<ampere>(i_tec));
+            self.channel_state(channel).powered_up = true;
         }
+        self.channels[channel].power_up();
     }
 
     // power down TEC
     pub fn power_down<I: Into<usize>>(&mut self, channel: I) {
-        match channel.into() {
-            0 => self.channel0.power_down(),
-            1 => self.channel1.power_down(),
-            _ => unreachable!(),
+        let channel = channel.into();
+        let state = self.channel_state(channel);
+        state.powered_up = false;
+        state.stop_softstart();
+        self.channels[channel].power_down();
+    }
+
+    /// Stop the ADC conversions and the output of an unused channel,
+    /// or resume them. A channel stays disabled if its conversions
+    /// cannot be started.
+    pub fn set_enabled(&mut self, channel: usize, enabled: bool) -> Result<(), spi::Error> {
+        if !enabled {
+            self.power_down(channel);
+            // Off even if the ADC keeps converting
+            self.channel_state(channel).disable();
         }
+        self.adc.set_channel_enabled(channel as u8, enabled)
+            .map_err(|e| self.count_spi_error(e))?;
+        self.channel_state(channel).enabled = enabled;
+        Ok(())
+    }
+
+    /// Restore the PID, Steinhart-Hart, center point and PWM limit
+    /// settings of boot, leaving the channel powered down
+    pub fn reset_to_defaults(&mut self, channel: usize) {
+        self.power_down(channel);
+        let state = self.channel_state(channel);
+        state.pid_engaged = false;
+        state.autotune = None;
+        state.pid = pid::Controller::new(pid::Parameters::default());
+        state.control_mode = ControlMode::Pid;
+        state.ramp = None;
+        state.profile.stop();
+        state.sh = steinhart_hart::Parameters::default();
+        state.center = CenterPoint::Vref;
+        state.softstart = 0;
+        state.inverted = false;
+        self.set_i(channel, ElectricCurrent::new::<ampere>(0.0));
+        self.set_max_v(channel, ElectricPotential::new::<volt>(0.0));
+        self.set_max_i_pos(channel, ElectricCurrent::new::<ampere>(0.0));
+        self.set_max_i_neg(channel, ElectricCurrent::new::<ampere>(0.0));
     }
 
     fn get_pwm(&self, channel: usize, pin: PwmPin) -> f64 {
-        fn get<P: hal::PwmPin<Duty=u16>>(pin: &P) -> f64 {
-            let duty = pin.get_duty();
-            let max = pin.get_max_duty();
-            duty as f64 / (max as f64)
-        }
-        match (channel, pin) {
-            (_, PwmPin::ISet) =>
-                panic!("i_set is no pwm pin"),
-            (0, PwmPin::MaxIPos) =>
-                get(&self.pwm.max_i_pos0),
-            (0, PwmPin::MaxINeg) =>
-                get(&self.pwm.max_i_neg0),
-            (0, PwmPin::MaxV) =>
-                get(&self.pwm.max_v0),
-            (1, PwmPin::MaxIPos) =>
-                get(&self.pwm.max_i_pos1),
-            (1, PwmPin::MaxINeg) =>
-                get(&self.pwm.max_i_neg1),
-            (1, PwmPin::MaxV) =>
-                get(&self.pwm.max_v1),
-            _ =>
-                unreachable!(),
-        }
+        let pin = self.pwm.get(channel, pin);
+        let duty = pin.get_duty();
+        let max = pin.get_max_duty();
+        duty as f64 / (max as f64)
     }
 
     pub fn get_max_v(&mut self, channel: usize) -> ElectricPotential {
@@ -359,19 +763,28 @@ impl Channels {
 
     pub fn get_max_i_pos(&mut self, channel: usize) -> (ElectricCurrent, ElectricCurrent) {
         let max = ElectricCurrent::new::<ampere>(3.0);
-        let duty = self.get_pwm(channel, PwmPin::MaxIPos);
+        let pin = self.limit_pin(channel, PwmPin::MaxIPos);
+        let duty = self.get_pwm(channel, pin);
         (duty * max, max)
     }
 
     pub fn get_max_i_neg(&mut self, channel: usize) -> (ElectricCurrent, ElectricCurrent) {
         let max = ElectricCurrent::new::<ampere>(3.0);
-        let duty = self.get_pwm(channel, PwmPin::MaxINeg);
+        let pin = self.limit_pin(channel, PwmPin::MaxINeg);
+        let duty = self.get_pwm(channel, pin);
         (duty * max, max)
     }
 
+    /// Timer duty count and maximum of a limit PWM
+    pub fn get_pwm_raw(&self, channel: usize, pin: PwmPin) -> PwmSummaryField<u16> {
+        get_duty_raw(self.pwm.get(channel, pin))
+    }
+
     // Get current passing through TEC
     pub fn get_tec_i(&mut self, channel: usize) -> ElectricCurrent {
-        (self.read_itec(channel) - self.read_vref(channel)) / ElectricalResistance::new::<ohm>(0.4)
+        let r_sense = ElectricalResistance::new::<ohm>(self.hardware_params().r_sense);
+        let i_tec = (self.read_itec(channel) - self.read_vref(channel)) / (ITEC_GAIN * r_sense);
+        self.polarity(channel) * i_tec
     }
 
     // Get voltage across TEC
@@ -380,30 +793,7 @@ impl Channels {
     }
 
     fn set_pwm(&mut self, channel: usize, pin: PwmPin, duty: f64) -> f64 {
-        fn set<P: hal::PwmPin<Duty=u16>>(pin: &mut P, duty: f64) -> f64 {
-            let max = pin.get_max_duty();
-            let value = ((duty * (max as f64)) as u16).min(max);
-            pin.set_duty(value);
-            value as f64 / (max as f64)
-        }
-        match (channel, pin) {
-            (_, PwmPin::ISet) =>
-                panic!("i_set is no pwm pin"),
-            (0, PwmPin::MaxIPos) =>
-                set(&mut self.pwm.max_i_pos0, duty),
-            (0, PwmPin::MaxINeg) =>
-                set(&mut self.pwm.max_i_neg0, duty),
-            (0, PwmPin::MaxV) =>
-                set(&mut self.pwm.max_v0, duty),
-            (1, PwmPin::MaxIPos) =>
-                set(&mut self.pwm.max_i_pos1, duty),
-            (1, PwmPin::MaxINeg) =>
-                set(&mut self.pwm.max_i_neg1, duty),
-            (1, PwmPin::MaxV) =>
-                set(&mut self.pwm.max_v1, duty),
-            _ =>
-                unreachable!(),
-        }
+        set_duty(self.pwm.get_mut(channel, pin), duty)
     }
 
     pub fn set_max_v(&mut self, channel: usize, max_v: ElectricPotential) -> (ElectricPotential, ElectricPotential) {
@@ -416,14 +806,16 @@ impl Channels {
     pub fn set_max_i_pos(&mut self, channel: usize, max_i_pos: ElectricCurrent) -> (ElectricCurrent, ElectricCurrent) {
         let max = ElectricCurrent::new::<ampere>(3.0);
         let duty = (max_i_pos / max).get::<ratio>();
-        let duty = self.set_pwm(channel, PwmPin::MaxIPos, duty);
+        let pin = self.limit_pin(channel, PwmPin::MaxIPos);
+        let duty = self.set_pwm(channel, pin, duty);
         (duty * max, max)
     }
 
     pub fn set_max_i_neg(&mut self, channel: usize, max_i_neg: ElectricCurrent) -> (ElectricCurrent, ElectricCurrent) {
         let max = ElectricCurrent::new::<ampere>(3.0);
         let duty = (max_i_neg / max).get::<ratio>();
-        let duty = self.set_pwm(channel, PwmPin::MaxINeg, duty);
+        let pin = self.limit_pin(channel, PwmPin::MaxINeg);
+        let duty = self.set_pwm(channel, pin, duty);
         (duty * max, max)
     }
 
@@ -432,8 +824,10 @@ impl Channels {
         let i_tec = self.read_itec(channel);
         let tec_i = self.get_tec_i(channel);
         let dac_value = self.get_dac(channel);
+        let temp_unit = self.temp_unit;
         let state = self.channel_state(channel);
         let pid_output = ElectricCurrent::new::<ampere>(state.pid.y1);
+        let pid_cooling = state.pid.cooling;
         Report {
             channel,
             time: state.get_adc_time(),
@@ -441,18 +835,43 @@ impl Channels {
             adc: state.get_adc(),
             sens: state.get_sens(),
             temperature: state.get_temperature()
-                .map(|temperature| temperature.get::<degree_celsius>()),
+                .map(|temperature| temp_unit.value(temperature)),
+            d_temperature: state.get_d_temperature()
+                .map(|d_temperature| temp_unit.interval_value(d_temperature)),
+            temp_unit,
+            enabled: state.enabled,
+            sensor_fault: state.get_sensor_fault(),
+            fault: state.fault,
             pid_engaged: state.pid_engaged,
             i_set,
             dac_value,
             dac_feedback: self.read_dac_feedback(channel),
+            dac_error: self.channel_state(channel).dac_error
+                .map(|dac_error| ElectricPotential::new::<volt>(dac_error)),
             i_tec,
             tec_i,
             tec_u_meas: self.get_tec_v(channel),
             pid_output,
+            pid_cooling,
+            uptime_ms: timer::uptime(),
+            epoch_ms: timer::epoch(),
         }
     }
 
+    pub fn report_json(&mut self, channel: usize) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        serde_json_core::to_vec(&self.report(channel))
+    }
+
+    /// Report lines of one or all channels as CSV
+    pub fn write_reports_csv<W: Write>(&mut self, channel: Option<usize>, w: &mut W) -> fmt::Result {
+        for c in 0..CHANNELS {
+            if channel.map_or(true, |channel| channel == c) {
+                self.report(c).write_csv(w)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn reports_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
         let mut reports = Vec::<_, U2>::new();
         for channel in 0..CHANNELS {
@@ -463,12 +882,91 @@ impl Channels {
 
     pub fn pid_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
         let mut summaries = Vec::<_, U2>::new();
+        let temp_unit = self.temp_unit;
         for channel in 0..CHANNELS {
-            let _ = summaries.push(self.channel_state(channel).pid.summary(channel));
+            let state = self.channel_state(channel);
+            let target = ThermodynamicTemperature::new::<degree_celsius>(state.pid.target);
+            let ramp = state.ramp.as_ref().map(|ramp| SetpointRamp {
+                target: temp_unit.value(ThermodynamicTemperature::new::<degree_celsius>(ramp.target)),
+                rate: temp_unit.interval_value(ramp.rate),
+            });
+            let mode = match state.control_mode {
+                ControlMode::Hysteresis(hysteresis) => {
+                    let value = |t: f32| temp_unit.value(ThermodynamicTemperature::new::<degree_celsius>(t.into())) as f32;
+                    ControlMode::Hysteresis(Hysteresis {
+                        low: value(hysteresis.low),
+                        high: value(hysteresis.high),
+                        heat: hysteresis.heat,
+                    })
+                }
+                mode => mode,
+            };
+            let _ = summaries.push(PidSummary {
+                channel,
+                mode,
+                parameters: state.pid.parameters.clone(),
+                cool: state.pid.cool.clone(),
+                target: temp_unit.value(target),
+                ramp,
+                setpoint: state.setpoint.clone(),
+            });
         }
         serde_json_core::to_vec(&summaries)
     }
 
+    pub fn pid_terms_json(&mut self, channel: usize) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let state = self.channel_state(channel);
+        let summary = PidTermsSummary {
+            channel,
+            engaged: state.pid_engaged,
+            terms: state.pid.terms.clone(),
+            output: state.pid.y1,
+        };
+        serde_json_core::to_vec(&summary)
+    }
+
+    pub fn temperature_stats_json(&mut self, channel: usize) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let temp_unit = self.temp_unit;
+        let stats = &self.channel_state(channel).temp_stats;
+        let value = |t| temp_unit.value(ThermodynamicTemperature::new::<degree_celsius>(t));
+        let summary = if stats.count > 0 {
+            Some(TemperatureStatsSummary {
+                channel,
+                count: stats.count,
+                min: value(stats.min),
+                max: value(stats.max),
+                mean: value(stats.mean),
+                moving_mean: value(stats.moving_mean),
+                std: temp_unit.interval_value(stats.std()),
+            })
+        } else {
+            None
+        };
+        serde_json_core::to_vec(&summary)
+    }
+
+    pub fn profile_json(&mut self, channel: usize) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let now = timer::now();
+        let temp_unit = self.temp_unit;
+        let state = self.channel_state(channel);
+        let mut segments = profile::Segments::new();
+        for segment in &state.profile.segments {
+            let _ = segments.push(profile::Segment {
+                target: temp_unit.value(ThermodynamicTemperature::new::<degree_celsius>(segment.target)),
+                rate: temp_unit.interval_value(segment.rate),
+                hold_ms: segment.hold_ms,
+            });
+        }
+        let progress = state.profile.progress(now, state.pid.target);
+        serde_json_core::to_vec(&ProfileSummary {
+            channel,
+            mode: state.profile.mode,
+            segments,
+            segment: progress.map(|(segment, _)| segment),
+            remaining_ms: progress.map(|(_, remaining_ms)| remaining_ms),
+        })
+    }
+
     pub fn pid_engaged(&mut self) -> bool {
         for channel in 0..CHANNELS {
             if self.channel_state(channel).pid_engaged {
@@ -478,6 +976,14 @@ impl Channels {
         false
     }
 
+    /// A latched fault or a sensor fault of an enabled channel
+    pub fn any_fault(&mut self) -> bool {
+        (0..CHANNELS).any(|channel| {
+            let state = self.channel_state(channel);
+            state.fault.is_some() || (state.enabled && state.get_sensor_fault().is_some())
+        })
+    }
+
     fn pwm_summary(&mut self, channel: usize) -> PwmSummary {
         PwmSummary {
             channel,
@@ -486,6 +992,8 @@ impl Channels {
             max_v: (self.get_max_v(channel), ElectricPotential::new::<volt>(5.0)).into(),
             max_i_pos: self.get_max_i_pos(channel).into(),
             max_i_neg: self.get_max_i_neg(channel).into(),
+            softstart: self.channel_state(channel).softstart,
+            invert: self.channel_state(channel).inverted,
         }
     }
 
@@ -497,23 +1005,122 @@ impl Channels {
         serde_json_core::to_vec(&summaries)
     }
 
-    fn postfilter_summary(&mut self, channel: usize) -> PostFilterSummary {
-        let rate = self.adc.get_postfilter(channel as u8).unwrap()
-            .and_then(|filter| filter.output_rate());
-        PostFilterSummary { channel, rate }
+    pub fn pwm_raw_summary_json(&mut self, channel: usize, fan: Option<PwmSummaryField<u16>>) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let summary = PwmRawSummary {
+            channel,
+            max_v: self.get_pwm_raw(channel, PwmPin::MaxV),
+            max_i_pos: self.get_pwm_raw(channel, PwmPin::MaxIPos),
+            max_i_neg: self.get_pwm_raw(channel, PwmPin::MaxINeg),
+            fan,
+        };
+        serde_json_core::to_vec(&summary)
+    }
+
+    fn postfilter_summary(&mut self, channel: usize) -> Result<PostFilterSummary, spi::Error> {
+        let postfilter = self.adc.get_postfilter(channel as u8)?;
+        let filter = self.adc.get_filter(channel as u8)?;
+        let odr = self.adc.get_odr(channel as u8)?;
+        let settling = match postfilter {
+            Some(postfilter) => postfilter.settling_time(),
+            None => filter.settling_time(odr),
+        };
+        Ok(PostFilterSummary {
+            channel,
+            rate: postfilter.and_then(|postfilter| postfilter.output_rate()),
+            filter: filter.name(),
+            odr: filter.output_rate(odr),
+            settling,
+        })
     }
 
     pub fn postfilter_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, U2>::new();
+        // `null` for a channel whose setup cannot be read
+        for channel in 0..CHANNELS {
+            let summary = self.postfilter_summary(channel)
+                .map_err(|e| self.count_spi_error(e))
+                .ok();
+            let _ = summaries.push(summary);
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    fn adc_summary(&mut self, channel: usize) -> AdcSummary {
+        let checksum_mode = self.adc.checksum_mode();
+        let (read_checksum_errors, write_crc_errors) = self.adc.checksum_errors();
+        let state = self.channel_state(channel);
+        AdcSummary {
+            channel,
+            read_mode: state.adc_read_mode,
+            discarded: state.adc_discarded,
+            interval: state.adc_interval_stats.clone(),
+            interval_tolerance: state.adc_interval_tolerance,
+            noise_floor: state.noise_floor.clone(),
+            spi_errors: self.spi_errors,
+            checksum_mode,
+            read_checksum_errors,
+            write_crc_errors,
+            avg: self.pins_adc_samples,
+        }
+    }
+
+    pub fn adc_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, U2>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.adc_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    pub fn autotune_running(&mut self, channel: usize) -> bool {
+        self.channel_state(channel).autotune.as_ref()
+            .map_or(false, |autotune| autotune.is_running())
+    }
+
+    pub fn autotune_json(&mut self, channel: usize) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let autotune = self.channel_state(channel).autotune.clone();
+        serde_json_core::to_vec(&AutotuneSummary { channel, autotune })
+    }
+
+    fn tec_summary(&mut self, channel: usize) -> TecSummary {
+        let tec = self.channel_state(channel).tec.clone();
+        TecSummary { channel, tec }
+    }
+
+    pub fn tec_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, U2>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.tec_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    fn temp_limits_summary(&mut self, channel: usize) -> TempLimitsSummary {
+        let temp_unit = self.temp_unit;
+        let value = |t: f32| temp_unit.value(ThermodynamicTemperature::new::<degree_celsius>(t.into())) as f32;
+        let state = self.channel_state(channel);
+        TempLimitsSummary {
+            channel,
+            temp_min: state.temp_limits.min.map(value),
+            temp_max: state.temp_limits.max.map(value),
+            runaway: state.runaway.clone(),
+            fault: state.fault,
+        }
+    }
+
+    pub fn temp_limits_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
         let mut summaries = Vec::<_, U2>::new();
         for channel in 0..CHANNELS {
-            let _ = summaries.push(self.postfilter_summary(channel));
+            let _ = summaries.push(self.temp_limits_summary(channel));
         }
         serde_json_core::to_vec(&summaries)
     }
 
     fn steinhart_hart_summary(&mut self, channel: usize) -> SteinhartHartSummary {
-        let params = self.channel_state(channel).sh.clone();
-        SteinhartHartSummary { channel, params }
+        let state = self.channel_state(channel);
+        let params = state.sh.clone();
+        let fault_streak = state.sensor_fault_streak;
+        SteinhartHartSummary { channel, params, fault_streak }
     }
 
     pub fn steinhart_hart_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
@@ -524,13 +1131,69 @@ impl Channels {
         serde_json_core::to_vec(&summaries)
     }
 
-    pub fn current_abs_max_tec_i(&mut self) -> f64 {
-        max_by(self.get_tec_i(0).abs().get::<ampere>(),
-               self.get_tec_i(1).abs().get::<ampere>(),
-               |a, b| a.partial_cmp(b).unwrap_or(core::cmp::Ordering::Equal))
+    fn sensor_summary(&mut self, channel: usize) -> SensorSummary {
+        let state = self.channel_state(channel);
+        SensorSummary {
+            channel,
+            sensor: state.sensor,
+            rtd: state.rtd.clone(),
+        }
+    }
+
+    pub fn sensor_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, U2>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.sensor_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    /// Temperature of all channels in °C, `None` without a valid reading
+    pub fn current_temperatures(&mut self) -> [Option<f32>; CHANNELS] {
+        let mut temperatures = [None; CHANNELS];
+        for (channel, temperature) in temperatures.iter_mut().enumerate() {
+            *temperature = self.channel_state(channel).get_temperature()
+                .map(|temperature| temperature.get::<degree_celsius>() as f32);
+        }
+        temperatures
+    }
+
+    /// TEC output current of all channels in A
+    pub fn current_tec_i(&mut self) -> [f32; CHANNELS] {
+        let mut tec_i = [0.0; CHANNELS];
+        for (channel, i) in tec_i.iter_mut().enumerate() {
+            *i = self.get_tec_i(channel).get::<ampere>() as f32;
+        }
+        tec_i
     }
 }
 
+/// Program both TEC channels and start the conversions, returns their
+/// calibration
+fn setup_adc(adc: &mut ad7172::Adc<pins::AdcSpi, pins::AdcNss>) -> Result<[ad7172::ChannelCalibration; CHANNELS], spi::Error> {
+    // Feature not used
+    adc.set_sync_enable(false)?;
+
+    adc.setup_channel(0, ad7172::Input::Ain2, ad7172::Input::Ain3)?;
+    let adc_calibration0 = adc.get_calibration(0)?;
+    adc.setup_channel(1, ad7172::Input::Ain0, ad7172::Input::Ain1)?;
+    let adc_calibration1 = adc.get_calibration(1)?;
+    adc.start_continuous_conversion()?;
+    Ok([adc_calibration0, adc_calibration1])
+}
+
+/// Set a PWM duty cycle fraction, returns the fraction actually set
+fn set_duty(pin: &mut dyn hal::PwmPin<Duty=u16>, duty: f64) -> f64 {
+    let max = pin.get_max_duty();
+    let value = ((duty * (max as f64)) as u16).min(max);
+    pin.set_duty(value);
+    value as f64 / (max as f64)
+}
+
+fn get_duty_raw(pin: &dyn hal::PwmPin<Duty=u16>) -> PwmSummaryField<u16> {
+    (pin.get_duty(), pin.get_max_duty()).into()
+}
+
 #[derive(Serialize)]
 pub struct Report {
     channel: usize,
@@ -538,15 +1201,89 @@ pub struct Report {
     interval: Time,
     adc: Option<ElectricPotential>,
     sens: Option<ElectricalResistance>,
+    /// In `temp_unit`
     temperature: Option<f64>,
+    /// In `temp_unit` per second
+    d_temperature: Option<f64>,
+    temp_unit: TempUnit,
+    /// `false` while the channel is disabled
+    enabled: bool,
+    sensor_fault: Option<SensorFault>,
+    fault: Option<ChannelFault>,
     pid_engaged: bool,
     i_set: ElectricCurrent,
     dac_value: ElectricPotential,
     dac_feedback: ElectricPotential,
+    /// `dac_feedback` minus `dac_value` at the last write with
+    /// `dac <0/1> verify on`
+    dac_error: Option<ElectricPotential>,
     i_tec: ElectricPotential,
     tec_i: ElectricCurrent,
     tec_u_meas: ElectricPotential,
     pid_output: ElectricCurrent,
+    /// Share of the cooling PID parameters, `None` without them
+    pid_cooling: Option<f64>,
+    /// Milliseconds since boot
+    uptime_ms: u64,
+    /// Unix time in milliseconds, `None` without SNTP synchronization
+    epoch_ms: Option<u64>,
+}
+
+impl Report {
+    /// One line with the columns of `REPORT_CSV_HEADER`, missing
+    /// values are left empty
+    pub fn write_csv<W: Write>(&self, w: &mut W) -> fmt::Result {
+        write!(w, "{},", self.channel)?;
+        write_csv_value(w, Some(self.time.get::<second>()))?;
+        write_csv_value(w, Some(self.interval.get::<second>()))?;
+        write_csv_value(w, self.adc.map(|adc| adc.get::<volt>()))?;
+        write_csv_value(w, self.sens.map(|sens| sens.get::<ohm>()))?;
+        write_csv_value(w, self.temperature)?;
+        write_csv_value(w, self.d_temperature)?;
+        match self.sensor_fault {
+            Some(fault) => write!(w, "{:?},", fault)?,
+            None => w.write_char(',')?,
+        }
+        match self.fault {
+            Some(ChannelFault::TempLimit(_)) => w.write_str("TempLimit,")?,
+            Some(ChannelFault::ThermalRunaway) => w.write_str("ThermalRunaway,")?,
+            None => w.write_char(',')?,
+        }
+        write!(w, "{},{},", self.enabled, self.pid_engaged)?;
+        write_csv_value(w, Some(self.i_set.get::<ampere>()))?;
+        write_csv_value(w, Some(self.dac_value.get::<volt>()))?;
+        write_csv_value(w, Some(self.dac_feedback.get::<volt>()))?;
+        write_csv_value(w, self.dac_error.map(|dac_error| dac_error.get::<volt>()))?;
+        write_csv_value(w, Some(self.i_tec.get::<volt>()))?;
+        write_csv_value(w, Some(self.tec_i.get::<ampere>()))?;
+        write_csv_value(w, Some(self.tec_u_meas.get::<volt>()))?;
+        write_csv_value(w, Some(self.pid_output.get::<ampere>()))?;
+        write_csv_value(w, self.pid_cooling)?;
+        write!(w, "{},", self.uptime_ms)?;
+        if let Some(epoch_ms) = self.epoch_ms {
+            write!(w, "{}", epoch_ms)?;
+        }
+        writeln!(w)
+    }
+}
+
+/// DAC code of an output voltage, clamped to the DAC range. `None`
+/// if the voltage is not finite.
+fn dac_code(voltage: ElectricPotential) -> Option<u32> {
+    let fraction = (voltage / ElectricPotential::new::<volt>(DAC_OUT_V_MAX)).get::<ratio>();
+    if !fraction.is_finite() {
+        return None;
+    }
+    Some((fraction.max(0.0).min(1.0) * (ad5680::MAX_VALUE as f64)) as u32)
+}
+
+/// A number and its separator. The exponent notation bounds the
+/// length, while `{}` would print every digit of large values.
+fn write_csv_value<W: Write>(w: &mut W, value: Option<f64>) -> fmt::Result {
+    match value {
+        Some(value) => write!(w, "{:e},", value),
+        None => w.write_char(','),
+    }
 }
 
 pub struct CenterPointJson(CenterPoint);
@@ -578,6 +1315,15 @@ impl<T: Serialize> From<(T, T)> for PwmSummaryField<T> {
     }
 }
 
+#[derive(Serialize)]
+pub struct PwmRawSummary {
+    channel: usize,
+    max_v: PwmSummaryField<u16>,
+    max_i_pos: PwmSummaryField<u16>,
+    max_i_neg: PwmSummaryField<u16>,
+    fan: Option<PwmSummaryField<u16>>,
+}
+
 #[derive(Serialize)]
 pub struct PwmSummary {
     channel: usize,
@@ -586,16 +1332,288 @@ pub struct PwmSummary {
     max_v: PwmSummaryField<ElectricPotential>,
     max_i_pos: PwmSummaryField<ElectricCurrent>,
     max_i_neg: PwmSummaryField<ElectricCurrent>,
+    /// ms
+    softstart: u32,
+    invert: bool,
 }
 
 #[derive(Serialize)]
 pub struct PostFilterSummary {
     channel: usize,
     rate: Option<f32>,
+    filter: &'static str,
+    /// Rate of `filter`, effective without postfilter
+    odr: Option<f32>,
+    /// Settling time in s of the active filter
+    settling: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct AdcSummary {
+    channel: usize,
+    read_mode: AdcReadMode,
+    discarded: u32,
+    interval: IntervalStats,
+    interval_tolerance: f64,
+    noise_floor: Option<NoiseFloor>,
+    /// Failed SPI transfers of all channels since boot
+    spi_errors: u32,
+    /// Shared by all channels
+    checksum_mode: ad7172::ChecksumMode,
+    read_checksum_errors: u32,
+    write_crc_errors: u32,
+    /// Conversions averaged per read of the stm32f4 integrated adc
+    avg: u16,
+}
+
+/// In `temp_unit`
+#[derive(Serialize)]
+pub struct TemperatureStatsSummary {
+    channel: usize,
+    count: u32,
+    min: f64,
+    max: f64,
+    mean: f64,
+    moving_mean: f64,
+    std: f64,
+}
+
+#[derive(Serialize)]
+pub struct ProfileSummary {
+    channel: usize,
+    mode: profile::ProfileMode,
+    segments: profile::Segments,
+    /// Index of the running segment, `None` if stopped
+    segment: Option<usize>,
+    /// Of the running segment, estimated while ramping
+    remaining_ms: Option<u32>,
+}
+
+/// Breakdown of the PID output, in A
+#[derive(Serialize)]
+pub struct PidTermsSummary {
+    channel: usize,
+    engaged: bool,
+    terms: pid::Terms,
+    /// Limited output, not the plain sum of the terms
+    output: f64,
+}
+
+#[derive(Serialize)]
+pub struct PidSummary {
+    channel: usize,
+    /// Hysteresis thresholds in `temp_unit`
+    mode: ControlMode,
+    parameters: pid::Parameters,
+    /// Parameters above the target, `parameters` are used for heating
+    cool: Option<pid::Parameters>,
+    /// Instantaneous, approaching `ramp.target`
+    target: f64,
+    ramp: Option<SetpointRamp>,
+    setpoint: SetpointInput,
+}
+
+#[derive(Serialize)]
+pub struct AutotuneSummary {
+    channel: usize,
+    autotune: Option<Autotune>,
+}
+
+#[derive(Serialize)]
+pub struct TecSummary {
+    channel: usize,
+    tec: ThermalEnergy,
+}
+
+#[derive(Serialize)]
+pub struct DacCalibrationSummary {
+    channel: usize,
+    stored: Option<f64>,
+    measured: f64,
+}
+
+#[derive(Serialize)]
+pub struct TempLimitsSummary {
+    channel: usize,
+    temp_min: Option<f32>,
+    temp_max: Option<f32>,
+    runaway: RunawayParameters,
+    fault: Option<ChannelFault>,
 }
 
 #[derive(Serialize)]
 pub struct SteinhartHartSummary {
     channel: usize,
     params: steinhart_hart::Parameters,
+    /// Consecutive open-sensor readings
+    fault_streak: u32,
+}
+
+#[derive(Serialize)]
+pub struct SensorSummary {
+    channel: usize,
+    sensor: SensorType,
+    /// Callendar–Van Dusen parameters, used with `SensorType::Rtd`
+    rtd: rtd::Parameters,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestPwmPin {
+        duty: u16,
+        max: u16,
+    }
+
+    impl hal::PwmPin for TestPwmPin {
+        type Duty = u16;
+
+        fn disable(&mut self) {}
+        fn enable(&mut self) {}
+
+        fn get_duty(&self) -> u16 {
+            self.duty
+        }
+
+        fn get_max_duty(&self) -> u16 {
+            self.max
+        }
+
+        fn set_duty(&mut self, duty: u16) {
+            self.duty = duty;
+        }
+    }
+
+    fn report() -> Report {
+        Report {
+            channel: 1,
+            time: Time::new::<second>(2.5),
+            interval: Time::new::<second>(0.1),
+            adc: Some(ElectricPotential::new::<volt>(1.5)),
+            sens: None,
+            temperature: None,
+            d_temperature: None,
+            temp_unit: TempUnit::Celsius,
+            enabled: true,
+            sensor_fault: Some(SensorFault::Open),
+            fault: None,
+            pid_engaged: false,
+            i_set: ElectricCurrent::new::<ampere>(0.5),
+            dac_value: ElectricPotential::new::<volt>(1.0),
+            dac_feedback: ElectricPotential::new::<volt>(1.25),
+            dac_error: None,
+            i_tec: ElectricPotential::new::<volt>(1.5),
+            tec_i: ElectricCurrent::new::<ampere>(-0.25),
+            tec_u_meas: ElectricPotential::new::<volt>(2.0),
+            pid_output: ElectricCurrent::new::<ampere>(0.0),
+            pid_cooling: None,
+            uptime_ms: 12345,
+            epoch_ms: None,
+        }
+    }
+
+    #[test]
+    fn report_csv_columns() {
+        let report = report();
+        let mut line = heapless::String::<heapless::consts::U256>::new();
+        report.write_csv(&mut line).unwrap();
+        assert_eq!(line.as_str(), "1,2.5e0,1e-1,1.5e0,,,,Open,,true,false,5e-1,1e0,1.25e0,,1.5e0,-2.5e-1,2e0,0e0,,12345,\n");
+        assert_eq!(line.split(',').count(), REPORT_CSV_HEADER.split(',').count());
+    }
+
+    #[test]
+    fn report_csv_fits() {
+        let mut line = heapless::String::<heapless::consts::U1024>::new();
+        longest_report(1).write_csv(&mut line).unwrap();
+        assert!(line.len() <= REPORT_CSV_MAX_LEN);
+
+        let mut line = heapless::String::<heapless::consts::U1024>::new();
+        let report = Report {
+            temperature: Some(f64::MAX),
+            ..longest_report(1)
+        };
+        report.write_csv(&mut line).unwrap();
+        assert!(line.len() <= REPORT_CSV_MAX_LEN);
+    }
+
+    /// Every field present, every number at its longest
+    fn longest_report(channel: usize) -> Report {
+        let value = -f64::MIN_POSITIVE;
+        Report {
+            channel,
+            time: Time::new::<second>(value),
+            interval: Time::new::<second>(value),
+            adc: Some(ElectricPotential::new::<volt>(value)),
+            sens: Some(ElectricalResistance::new::<ohm>(value)),
+            temperature: Some(value),
+            d_temperature: Some(value),
+            temp_unit: TempUnit::Fahrenheit,
+            enabled: false,
+            sensor_fault: Some(SensorFault::OutOfRange),
+            fault: Some(ChannelFault::TempLimit(value)),
+            pid_engaged: false,
+            i_set: ElectricCurrent::new::<ampere>(value),
+            dac_value: ElectricPotential::new::<volt>(value),
+            dac_feedback: ElectricPotential::new::<volt>(value),
+            dac_error: Some(ElectricPotential::new::<volt>(value)),
+            i_tec: ElectricPotential::new::<volt>(value),
+            tec_i: ElectricCurrent::new::<ampere>(value),
+            tec_u_meas: ElectricPotential::new::<volt>(value),
+            pid_output: ElectricCurrent::new::<ampere>(value),
+            pid_cooling: Some(value),
+            uptime_ms: u64::MAX,
+            epoch_ms: Some(u64::MAX),
+        }
+    }
+
+    #[test]
+    fn report_latched_fault() {
+        let report = Report {
+            fault: Some(ChannelFault::ThermalRunaway),
+            ..report()
+        };
+        let json: JsonBuffer = serde_json_core::to_vec(&report).unwrap();
+        let json = core::str::from_utf8(&json).unwrap();
+        assert!(json.contains(r#""fault":"ThermalRunaway""#));
+        let mut line = heapless::String::<heapless::consts::U256>::new();
+        report.write_csv(&mut line).unwrap();
+        assert_eq!(line.split(',').nth(8), Some("ThermalRunaway"));
+    }
+
+    #[test]
+    fn report_json_fits() {
+        let json: JsonBuffer = serde_json_core::to_vec(&longest_report(1)).unwrap();
+        assert!(json.len() <= REPORT_JSON_MAX_LEN);
+
+        // `[`, the reports separated by `,`, and `]`
+        assert!(CHANNELS * (REPORT_JSON_MAX_LEN + 1) + 1 <= JsonBuffer::new().capacity());
+        let mut reports = Vec::<_, U2>::new();
+        for channel in 0..CHANNELS {
+            let _ = reports.push(longest_report(channel));
+        }
+        let json: Result<JsonBuffer, _> = serde_json_core::to_vec(&reports);
+        assert!(json.is_ok());
+    }
+
+    #[test]
+    fn dac_code_of_non_finite_voltage() {
+        assert_eq!(dac_code(ElectricPotential::new::<volt>(f64::NAN)), None);
+        assert_eq!(dac_code(ElectricPotential::new::<volt>(f64::INFINITY)), None);
+        assert_eq!(dac_code(ElectricPotential::new::<volt>(-1.0)), Some(0));
+        assert_eq!(dac_code(ElectricPotential::new::<volt>(2.0 * DAC_OUT_V_MAX)), Some(ad5680::MAX_VALUE));
+    }
+
+    #[test]
+    fn raw_duty_matches_set_duty() {
+        let mut pin = TestPwmPin { duty: 0, max: 0xffff };
+        let duty = set_duty(&mut pin, 0.25);
+        let raw = get_duty_raw(&pin);
+        assert_eq!(raw.value, 0x3fff);
+        assert_eq!(raw.max, 0xffff);
+        assert_eq!(duty, f64::from(raw.value) / f64::from(raw.max));
+
+        set_duty(&mut pin, 2.0);
+        assert_eq!(get_duty_raw(&pin).value, 0xffff);
+    }
 }
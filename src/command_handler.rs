@@ -1,30 +1,63 @@
-use smoltcp::socket::TcpSocket;
+use smoltcp::{
+    socket::TcpSocket,
+    wire::{EthernetAddress, Ipv4Address, Ipv4Cidr},
+};
 use log::{error, warn};
-use core::fmt::Write;
-use heapless::{consts::U1024, Vec};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use heapless::{consts::{U2, U256, U2048}, String, Vec};
 use super::{
     net,
+    autotune::Autotune,
     command_parser::{
         Ipv4Config, 
+        AdcReadMode,
         Command, 
         ShowCommand, 
         CenterPoint, 
         PidParameter, 
+        ProfileCommand,
         PwmPin, 
-        ShParameter
+        FanSource,
+        HardwareParameter,
+        MqttConfig,
+        ReportFormat,
+        ReportOnConnect,
+        RtdParameter,
+        SensorType,
+        SetpointSource,
+        ShParameter,
+        TempLimit,
+        UdpTelemetry,
     },
     ad7172,
+    channel_state::{ControlMode, Hysteresis, NoiseFloor, TempUnit, NOISE_FLOOR_SAMPLES},
     CHANNEL_CONFIG_KEY,
+    DAC_CALIBRATION_KEY,
     channels::{
         Channels, 
-        CHANNELS
+        CHANNELS,
+        PINS_ADC_SAMPLES_MAX,
+        REPORT_CSV_HEADER,
+        REPORT_CSV_MAX_LEN,
     },
-    config::ChannelConfig,
+    config::{ChannelConfig, ConfigDiff, ConfigVerification, DeviceConfig, SaveSummary, read_ipv4_config},
     dfu,
+    fan_ctrl::FanConfig,
     flash_store::FlashStore,
-    session::Session,
+    help,
+    netstat,
+    pid,
+    watchdog,
+    profile,
+    server,
+    telemetry,
+    session::{Session, all_part_prefix, all_part_suffix, CONFIG_EXPORT_PREFIXES},
     FanCtrl,
-    hw_rev::HWRev,
+    hw_rev::{HardwareParams, HWRev},
+    timer,
 };
 
 use uom::{
@@ -47,6 +80,15 @@ pub enum Handler {
     Handled,
     CloseSocket,
     NewIPV4(Ipv4Config),
+    NewPort(u16),
+    NewReportOnConnect(ReportOnConnect),
+    NewTelemetry(Option<UdpTelemetry>),
+    NewMqtt(Option<MqttConfig>),
+    NewNtp(Option<[u8; 4]>),
+    /// Close TCP sessions idle for this many seconds
+    NewTcpTimeout(Option<u32>),
+    /// Restart the watchdog with this interval in ms
+    NewWatchdog(u32),
     Reset,
 }
 
@@ -54,16 +96,54 @@ pub enum Handler {
 pub enum Error {
     ReportError,
     PostFilterRateError,
-    FlashError
+    FlashError,
+    /// A value of the command is out of range
+    ArgumentError,
+    /// The command cannot be carried out in the current state of the
+    /// channel
+    StateError,
+    /// There is no data to show
+    UnavailableError,
+    /// Reading or writing the ADC setup over SPI failed
+    AdcError,
+}
+
+impl Error {
+    /// Stable identifier for the `code` field of error replies
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::ReportError => "report",
+            Error::PostFilterRateError => "postfilter_rate",
+            Error::FlashError => "flash",
+            Error::ArgumentError => "argument",
+            Error::StateError => "state",
+            Error::UnavailableError => "unavailable",
+            Error::AdcError => "adc",
+        }
+    }
 }
 
-pub type JsonBuffer = Vec<u8, U1024>;
+pub type JsonBuffer = Vec<u8, U2048>;
+
+/// Set while applying staged commands, which get one reply for the
+/// whole `stage commit` instead of their own
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
 
-fn send_line(socket: &mut TcpSocket, data: &[u8]) -> bool {
+/// Send `data` and a newline completely or not at all, counting lines
+/// skipped for lack of buffer space
+pub fn send_line(socket: &mut TcpSocket, data: &[u8]) -> bool {
+    if quiet() {
+        return true;
+    }
     let send_free = socket.send_capacity() - socket.send_queue();
     if data.len() > send_free + 1 {
         // Not enough buffer space, skip report for now,
         // instead of sending incomplete line
+        netstat::count_dropped_line();
         warn!(
             "TCP socket has only {}/{} needed {}",
             send_free + 1, socket.send_capacity(), data.len(),
@@ -85,6 +165,77 @@ fn send_line(socket: &mut TcpSocket, data: &[u8]) -> bool {
     false
 }
 
+/// Send report lines of one or all channels in `ReportFormat::Csv`,
+/// returns `false` when the socket has no space for them yet.
+pub fn send_reports_csv(socket: &mut TcpSocket, channels: &mut Channels, channel: Option<usize>) -> bool {
+    let lines = if channel.is_some() { 1 } else { CHANNELS };
+    let send_free = socket.send_capacity() - socket.send_queue();
+    if send_free < lines * REPORT_CSV_MAX_LEN {
+        warn!("TCP socket has only {}/{} for CSV reports", send_free, socket.send_capacity());
+        return false;
+    }
+    channels.write_reports_csv(channel, socket).is_ok()
+}
+
+/// Send `prefix`, `data`, `suffix` completely or not at all
+fn send_part(socket: &mut TcpSocket, prefix: &[u8], data: &[u8], suffix: &[u8]) -> bool {
+    let send_free = socket.send_capacity() - socket.send_queue();
+    if prefix.len() + data.len() + suffix.len() > send_free {
+        return false;
+    }
+    let _ = socket.send_slice(prefix);
+    let _ = socket.send_slice(data);
+    let _ = socket.send_slice(suffix);
+    true
+}
+
+/// Reply `{"error":"<message>","code":"<code>"}` and fail with `error`
+fn send_error(socket: &mut TcpSocket, error: Error, message: impl fmt::Display) -> Result<Handler, Error> {
+    if !quiet() {
+        let _ = writeln!(socket, "{{\"error\":\"{}\",\"code\":\"{}\"}}", message, error.code());
+    }
+    Err(error)
+}
+
+/// Refuse to drive a channel with a latched fault
+fn fault_latched(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> bool {
+    let latched = channels.channel_state(channel).fault.is_some();
+    if latched {
+        let _ = send_error(socket, Error::StateError, "channel fault, clear it first");
+    }
+    latched
+}
+
+fn channel_disabled(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> bool {
+    let disabled = !channels.channel_state(channel).enabled;
+    if disabled {
+        let _ = send_error(socket, Error::StateError, "channel disabled, enable it first");
+    }
+    disabled
+}
+
+/// Set a field of `pid::Parameters`, `false` for the other PID
+/// settings
+fn set_pid_parameter(parameters: &mut pid::Parameters, parameter: &PidParameter, value: f64) -> bool {
+    let value = value as f32;
+    match parameter {
+        PidParameter::KP => parameters.kp = value,
+        PidParameter::KI => parameters.ki = value,
+        PidParameter::KD => parameters.kd = value,
+        PidParameter::KdFilter => parameters.kd_filter = value,
+        PidParameter::OutputMin => parameters.output_min = value,
+        PidParameter::OutputMax => parameters.output_max = value,
+        PidParameter::IntegralMin => parameters.integral_min = value,
+        PidParameter::IntegralMax => parameters.integral_max = value,
+        PidParameter::Slew => parameters.slew = value,
+        PidParameter::FeedForward => parameters.ff = value,
+        PidParameter::FeedForwardTarget => parameters.ff_target = value,
+        PidParameter::Target | PidParameter::SetpointScale | PidParameter::SetpointOffset =>
+            return false,
+    }
+    true
+}
+
 impl Handler {
 
     fn reporting(socket: &mut TcpSocket) -> Result<Handler, Error> {
@@ -92,20 +243,130 @@ impl Handler {
         Ok(Handler::Handled)
     }
 
+    fn report_format(socket: &mut TcpSocket, format: ReportFormat) -> Result<Handler, Error> {
+        // format is tracked by the Session, CSV starts with a header
+        match format {
+            ReportFormat::Json => send_line(socket, b"{}"),
+            ReportFormat::Csv => send_line(socket, REPORT_CSV_HEADER.as_bytes()),
+        };
+        Ok(Handler::Handled)
+    }
+
+    fn stage(socket: &mut TcpSocket) -> Result<Handler, Error> {
+        // staging state is tracked by the Session
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_report_on_connect(socket: &mut TcpSocket, on_connect: ReportOnConnect) -> Result<Handler, Error> {
+        send_line(socket, b"{}");
+        Ok(Handler::NewReportOnConnect(on_connect))
+    }
+
+    fn set_telemetry(socket: &mut TcpSocket, config: Option<UdpTelemetry>) -> Result<Handler, Error> {
+        if config.as_ref().map_or(false, |config| config.port == 0 || !telemetry::is_valid_interval(config.interval)) {
+            return send_error(socket, Error::ArgumentError, "port must be non-zero and interval 0 or at least 1 ms");
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::NewTelemetry(config))
+    }
+
+    fn set_mqtt(socket: &mut TcpSocket, store: &mut FlashStore, config: Option<MqttConfig>) -> Result<Handler, Error> {
+        if config.as_ref().map_or(false, |config| config.port == 0) {
+            return send_error(socket, Error::ArgumentError, "port must be non-zero");
+        }
+        if let Err(e) = store.write_value("mqtt", &config, [0; 64]) {
+            error!("unable to save mqtt config to flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::NewMqtt(config))
+    }
+
+    fn set_ntp(socket: &mut TcpSocket, store: &mut FlashStore, server: Option<[u8; 4]>) -> Result<Handler, Error> {
+        if let Err(e) = store.write_value("ntp", &server, [0; 16]) {
+            error!("unable to save ntp server to flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::NewNtp(server))
+    }
+
+    fn set_mac(socket: &mut TcpSocket, store: &mut FlashStore, mac: Option<[u8; 6]>) -> Result<Handler, Error> {
+        let result = match mac {
+            Some(mac) if !EthernetAddress(mac).is_unicast() => {
+                return send_error(socket, Error::ArgumentError, "MAC address must be unicast");
+            }
+            Some(mac) => store.write_value("mac", &mac, [0; 16]),
+            None => store.remove("mac"),
+        };
+        if let Err(e) = result {
+            error!("unable to save mac address to flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        match mac.map(EthernetAddress) {
+            Some(mac) if !mac.is_local() => {
+                warn!("MAC address {} is not locally administered", mac);
+                send_line(socket, b"{ \"warning\": \"not a locally administered address\" }");
+            }
+            _ => send_line(socket, b"{}"),
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn set_tcp_timeout(socket: &mut TcpSocket, store: &mut FlashStore, timeout: Option<u32>) -> Result<Handler, Error> {
+        if timeout.map_or(false, |timeout| timeout == 0 || timeout > server::IDLE_TIMEOUT_MAX) {
+            return send_error(
+                socket, Error::ArgumentError,
+                format_args!("timeout must be 1 to {} s", server::IDLE_TIMEOUT_MAX),
+            );
+        }
+        if let Err(e) = store.write_value("tcp_timeout", &timeout, [0; 16]) {
+            error!("unable to save tcp timeout to flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::NewTcpTimeout(timeout))
+    }
+
     fn show_report_mode(socket: &mut TcpSocket, session: &Session) -> Result<Handler, Error> {
         let _ = writeln!(socket, "{{ \"report\": {:?} }}", session.reporting());
         Ok(Handler::Handled)
     }
 
-    fn show_report(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+    fn show_channel_report(socket: &mut TcpSocket, channels: &mut Channels, session: &Session, channel: usize) -> Result<Handler, Error> {
+        if session.report_format() == ReportFormat::Csv {
+            if !send_reports_csv(socket, channels, Some(channel)) {
+                return send_error(socket, Error::ReportError, "CSV report does not fit the send buffer");
+            }
+            return Ok(Handler::Handled);
+        }
+        match channels.report_json(channel) {
+            Ok(buf) => {
+                send_line(socket, &buf[..]);
+            }
+            Err(e) => {
+                error!("unable to serialize report: {:?}", e);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn show_report(socket: &mut TcpSocket, channels: &mut Channels, session: &Session) -> Result<Handler, Error> {
+        if session.report_format() == ReportFormat::Csv {
+            if !send_reports_csv(socket, channels, None) {
+                return send_error(socket, Error::ReportError, "CSV report does not fit the send buffer");
+            }
+            return Ok(Handler::Handled);
+        }
         match channels.reports_json() {
             Ok(buf) => {
                 send_line(socket, &buf[..]);
             }
             Err(e) => {
                 error!("unable to serialize report: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::ReportError);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
             }
         }
         Ok(Handler::Handled)
@@ -118,8 +379,7 @@ impl Handler {
             }
             Err(e) => {
                 error!("unable to serialize pid summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::ReportError);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
             }
         }
         Ok(Handler::Handled)
@@ -132,8 +392,7 @@ impl Handler {
             }
             Err(e) => {
                 error!("unable to serialize pwm summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::ReportError);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
             }
         }
         Ok(Handler::Handled)
@@ -146,234 +405,1023 @@ impl Handler {
             }
             Err(e) => {
                 error!("unable to serialize steinhart-hart summaries: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::ReportError);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
             }
         }
         Ok(Handler::Handled)
     }
 
-    fn show_post_filter (socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
-        match channels.postfilter_summaries_json() {
+    fn show_sensor(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        match channels.sensor_summaries_json() {
             Ok(buf) => {
                 send_line(socket, &buf);
             }
             Err(e) => {
-                error!("unable to serialize postfilter summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::ReportError);
+                error!("unable to serialize sensor summaries: {:?}", e);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
             }
         }
         Ok(Handler::Handled)
     }
 
-    fn show_ipv4 (socket: &mut TcpSocket, ipv4_config: &mut Ipv4Config) -> Result<Handler, Error> {
-        let (cidr, gateway) = net::split_ipv4_config(ipv4_config.clone());
-        let _ = write!(socket, "{{\"addr\":\"{}\"", cidr);
-        gateway.map(|gateway| write!(socket, ",\"gateway\":\"{}\"", gateway));
-        let _ = writeln!(socket, "}}");
+    fn show_post_filter (socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        match channels.postfilter_summaries_json() {
+            Ok(buf) => {
+                send_line(socket, &buf);
+            }
+            Err(e) => {
+                error!("unable to serialize postfilter summary: {:?}", e);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
+            }
+        }
         Ok(Handler::Handled)
     }
 
-    fn engage_pid (socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
-        channels.channel_state(channel).pid_engaged = true;
-        send_line(socket, b"{}");
+    fn show_tec(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        match channels.tec_summaries_json() {
+            Ok(buf) => {
+                send_line(socket, &buf);
+            }
+            Err(e) => {
+                error!("unable to serialize tec summary: {:?}", e);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
+            }
+        }
         Ok(Handler::Handled)
     }
 
-    fn set_pwm (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, pin: PwmPin, value: f64) -> Result<Handler, Error> {
-        match pin {
-            PwmPin::ISet => {
-                channels.channel_state(channel).pid_engaged = false;
-                let current = ElectricCurrent::new::<ampere>(value);
-                channels.set_i(channel, current);
-                channels.power_up(channel);
-            }
-            PwmPin::MaxV => {
-                let voltage = ElectricPotential::new::<volt>(value);
-                channels.set_max_v(channel, voltage);
-            }
-            PwmPin::MaxIPos => {
-                let current = ElectricCurrent::new::<ampere>(value);
-                channels.set_max_i_pos(channel, current);
+    fn show_temp_limits(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        match channels.temp_limits_summaries_json() {
+            Ok(buf) => {
+                send_line(socket, &buf);
             }
-            PwmPin::MaxINeg => {
-                let current = ElectricCurrent::new::<ampere>(value);
-                channels.set_max_i_neg(channel, current);
+            Err(e) => {
+                error!("unable to serialize temperature limits summary: {:?}", e);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
             }
         }
-        send_line(socket, b"{}");
         Ok(Handler::Handled)
     }
 
-    fn set_center_point(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, center: CenterPoint) -> Result<Handler, Error> {
-        let i_tec = channels.get_i(channel);
-        let state = channels.channel_state(channel);
-        state.center = center;
-        if !state.pid_engaged {
-            channels.set_i(channel, i_tec);
+    fn show_hardware_params(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        let json: Result<JsonBuffer, _> = serde_json_core::to_vec(channels.hardware_params());
+        match json {
+            Ok(buf) => {
+                send_line(socket, &buf);
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to serialize hardware params: {:?}", e);
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
         }
-        send_line(socket, b"{}");
-        Ok(Handler::Handled)
     }
 
-    fn set_pid (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, parameter: PidParameter, value: f64) -> Result<Handler, Error> {
-        let pid = &mut channels.channel_state(channel).pid;
-        use super::command_parser::PidParameter::*;
+    fn set_hardware_param(socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore, parameter: HardwareParameter, value: f64) -> Result<Handler, Error> {
+        if value <= 0.0 || !value.is_finite() {
+            return send_error(socket, Error::ArgumentError, "value must be positive");
+        }
+        let mut hardware = channels.hardware_params().clone();
         match parameter {
-            Target =>
-                pid.target = value,
-            KP =>
-                pid.parameters.kp = value as f32,
-            KI => 
-                pid.update_ki(value as f32),
-            KD =>
-                pid.parameters.kd = value as f32,
-            OutputMin =>
-                pid.parameters.output_min = value as f32,
-            OutputMax =>
-                pid.parameters.output_max = value as f32,
+            HardwareParameter::RSense => hardware.r_sense = value,
+            HardwareParameter::VRef => hardware.v_ref = value,
+            HardwareParameter::RInner => hardware.r_inner = value,
+            HardwareParameter::VRefSens => hardware.vref_sens = value,
         }
-        send_line(socket, b"{}");
-        Ok(Handler::Handled)
+        Handler::save_hardware_params(socket, channels, store, hardware)
     }
 
-    fn set_steinhart_hart (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, parameter: ShParameter, value: f64) -> Result<Handler, Error> {
-        let sh = &mut channels.channel_state(channel).sh;
-        use super::command_parser::ShParameter::*;
-        match parameter {
-            T0 => sh.t0 = ThermodynamicTemperature::new::<degree_celsius>(value),
-            B => sh.b = value,
-            R0 => sh.r0 = ElectricalResistance::new::<ohm>(value),
+    fn save_hardware_params(socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore, hardware: HardwareParams) -> Result<Handler, Error> {
+        if let Err(e) = store.write_value("hw_params", &hardware, [0; 64]) {
+            error!("unable to save hardware params to flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        if let Err(e) = channels.set_hardware_params(hardware) {
+            error!("unable to read back the ADC calibration: {:?}", e);
+            return send_error(socket, Error::AdcError, format_args!("{:?}", e));
         }
         send_line(socket, b"{}");
         Ok(Handler::Handled)
     }
 
-    fn reset_post_filter (socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
-        channels.adc.set_postfilter(channel as u8, None).unwrap();
-        send_line(socket, b"{}");
-        Ok(Handler::Handled)
+    fn calibrate_dac(socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore, channel: usize, reset: bool) -> Result<Handler, Error> {
+        let stored = match store.read_value::<f64>(DAC_CALIBRATION_KEY[channel]) {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!("unable to load DAC calibration {} from flash: {:?}", channel, e);
+                None
+            }
+        };
+        channels.channel_state(channel).pid_engaged = false;
+        channels.channel_state(channel).autotune = None;
+        channels.power_down(channel);
+        channels.calibrate_dac_value(channel);
+        channels.set_i(channel, ElectricCurrent::new::<ampere>(0.0));
+
+        let result = if reset {
+            // boot searches again
+            store.remove(DAC_CALIBRATION_KEY[channel])
+        } else {
+            let vref_meas = channels.get_vref_meas(channel).get::<volt>();
+            store.write_value(DAC_CALIBRATION_KEY[channel], &vref_meas, [0; 16])
+        };
+        if let Err(e) = result {
+            error!("unable to save DAC calibration {} to flash: {:?}", channel, e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+
+        match channels.dac_calibration_json(channel, stored) {
+            Ok(buf) => {
+                send_line(socket, &buf);
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to serialize DAC calibration: {:?}", e);
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
+        }
     }
 
-    fn set_post_filter (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, rate: f32) -> Result<Handler, Error> {
-        let filter = ad7172::PostFilter::closest(rate);
-        match filter {
-            Some(filter) => {
-                channels.adc.set_postfilter(channel as u8, Some(filter)).unwrap();
-                send_line(socket, b"{}");
+    fn show_adc_temperature(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        match channels.read_adc_temperature() {
+            Some(temperature) => {
+                let _ = writeln!(socket, "{{\"temperature\":{}}}", temperature.get::<degree_celsius>());
             }
             None => {
-                error!("unable to choose postfilter for rate {:.3}", rate);
-                send_line(socket, b"{{\"error\": \"unable to choose postfilter rate\"}}");
-                return Err(Error::PostFilterRateError);
+                return send_error(socket, Error::UnavailableError, "no adc temperature conversion");
             }
         }
         Ok(Handler::Handled)
     }
 
-    fn load_channel (socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore, channel: Option<usize>) -> Result<Handler, Error> {
-        for c in 0..CHANNELS {
-            if channel.is_none() || channel == Some(c) {
-                match store.read_value::<ChannelConfig>(CHANNEL_CONFIG_KEY[c]) {
-                    Ok(Some(config)) => {
-                        config.apply(channels, c);
-                        send_line(socket, b"{}");
-                    }
-                    Ok(None) => {
-                        error!("flash config not found");
-                        send_line(socket, b"{{\"error\": \"flash config not found\"}}");
-                    }
-                    Err(e) => {
-                        error!("unable to load config from flash: {:?}", e);
-                        let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                        return Err(Error::FlashError);
-                    }
-                }
+    fn show_supply(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        match channels.read_analog_supply() {
+            Some(voltage) => {
+                let _ = writeln!(socket, "{{\"analog_supply\":{}}}", voltage.get::<volt>());
+            }
+            None => {
+                return send_error(socket, Error::UnavailableError, "no analog supply conversion");
             }
         }
         Ok(Handler::Handled)
     }
 
-    fn save_channel (socket: &mut TcpSocket, channels: &mut Channels, channel: Option<usize>, store: &mut FlashStore) -> Result<Handler, Error> {
-        for c in 0..CHANNELS {
-            let mut store_value_buf = [0u8; 256];
-            if channel.is_none() || channel == Some(c) {
-                let config = ChannelConfig::new(channels, c);
-                match store.write_value(CHANNEL_CONFIG_KEY[c], &config, &mut store_value_buf) {
-                    Ok(()) => {
-                        send_line(socket, b"{}");
-                    }
-                    Err(e) => {
-                        error!("unable to save channel {} config to flash: {:?}", c, e);
-                        let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                        return Err(Error::FlashError);
-                    }
-                }
+    fn show_adc(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        match channels.adc_summaries_json() {
+            Ok(buf) => {
+                send_line(socket, &buf);
+            }
+            Err(e) => {
+                error!("unable to serialize adc summary: {:?}", e);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
             }
         }
         Ok(Handler::Handled)
     }
 
-    fn set_ipv4 (socket: &mut TcpSocket, store: &mut FlashStore, config: Ipv4Config) -> Result<Handler, Error> {
-        let _ = store
-            .write_value("ipv4", &config, [0; 16])
-            .map_err(|e| error!("unable to save ipv4 config to flash: {:?}", e));
-        let new_ipv4_config = Some(config);
+    fn set_adc_read_mode(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, mode: AdcReadMode) -> Result<Handler, Error> {
+        channels.channel_state(channel).set_adc_read_mode(mode);
         send_line(socket, b"{}");
-        Ok(Handler::NewIPV4(new_ipv4_config.unwrap()))
+        Ok(Handler::Handled)
     }
 
-    fn reset (channels: &mut Channels) -> Result<Handler, Error> {
-        for i in 0..CHANNELS {
-            channels.power_down(i);
-        }
-        // should_reset = true;
-        Ok(Handler::Reset)
+    fn set_adc_interval_tolerance(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, tolerance: f64) -> Result<Handler, Error> {
+        let state = channels.channel_state(channel);
+        state.adc_interval_tolerance = tolerance;
+        state.adc_interval_stats = Default::default();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
     }
 
-    fn dfu (channels: &mut Channels) -> Result<Handler, Error> {
-        for i in 0..CHANNELS {
-            channels.power_down(i);
+    fn set_adc_ref_source(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, ref_source: ad7172::RefSource) -> Result<Handler, Error> {
+        if let Err(e) = channels.set_adc_ref_source(channel, ref_source) {
+            return send_error(socket, Error::AdcError, format_args!("{:?}", e));
         }
-        unsafe {
-            dfu::set_dfu_trigger();
-        }
-        // should_reset = true;
-        Ok(Handler::Reset)
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
     }
 
-    fn set_fan(socket: &mut TcpSocket, fan_pwm: u32, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
-        if !fan_ctrl.fan_available() {
-            send_line(socket, b"{ \"warning\": \"this thermostat doesn't have fan!\" }");
-            return Ok(Handler::Handled);
+    fn set_adc_filter(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, filter: ad7172::Filter) -> Result<Handler, Error> {
+        if let ad7172::Filter::Sinc3Map(decimation) = filter {
+            if decimation == 0 || decimation > 0x7FFF {
+                return send_error(socket, Error::ArgumentError, "decimation rate must be 1 to 32767");
+            }
         }
-        fan_ctrl.set_auto_mode(false);
-        fan_ctrl.set_pwm(fan_pwm);
-        if fan_ctrl.fan_pwm_recommended() {
-            send_line(socket, b"{}");
-        } else {
-            send_line(socket, b"{ \"warning\": \"this fan doesn't have full PWM support. Use it at your own risk!\" }");
+        if let Err(e) = channels.adc.set_filter(channel as u8, filter) {
+            error!("unable to set channel {} ADC filter: {:?}", channel, e);
+            return send_error(socket, Error::AdcError, format_args!("{:?}", e));
         }
+        channels.channel_state(channel).adc_interval_stats = Default::default();
+        send_line(socket, b"{}");
         Ok(Handler::Handled)
     }
 
-    fn show_fan(socket: &mut TcpSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
-        match fan_ctrl.summary() {
-            Ok(buf) => {
-                send_line(socket, &buf);
-                Ok(Handler::Handled)
-            }
-            Err(e) => {
-                error!("unable to serialize fan summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                Err(Error::ReportError)
-            }
+    fn set_adc_bipolar(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, bipolar: bool) -> Result<Handler, Error> {
+        if let Err(e) = channels.set_adc_bipolar(channel, bipolar) {
+            return send_error(socket, Error::AdcError, format_args!("{:?}", e));
         }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
     }
 
-    fn fan_auto(socket: &mut TcpSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
-        if !fan_ctrl.fan_available() {
+    fn set_adc_checksum(socket: &mut TcpSocket, channels: &mut Channels, mode: ad7172::ChecksumMode) -> Result<Handler, Error> {
+        if let Err(e) = channels.set_adc_checksum_mode(mode) {
+            return send_error(socket, Error::AdcError, format_args!("{:?}", e));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_adc_average(socket: &mut TcpSocket, channels: &mut Channels, samples: u32) -> Result<Handler, Error> {
+        if samples < 1 || samples > u32::from(PINS_ADC_SAMPLES_MAX) {
+            return send_error(socket, Error::ArgumentError, "avg must be 1 to 256");
+        }
+        channels.set_pins_adc_samples(samples as u16);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn measure_adc_noise_floor(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        channels.channel_state(channel).noise_floor = Some(NoiseFloor::new(NOISE_FLOOR_SAMPLES));
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn read_adc(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        if channels.channel_state(channel).adc_read_mode == AdcReadMode::OnDemand
+            && !channels.read_held_adc(channel)
+        {
+            return send_error(socket, Error::UnavailableError, "no new adc data");
+        }
+        Handler::show_report(socket, channels)
+    }
+
+    fn ipv4_json(ipv4_config: &Ipv4Config, dhcp_lease: Option<(Ipv4Cidr, Option<Ipv4Address>)>) -> String<U256> {
+        let mut buf = String::new();
+        let (cidr, gateway) = net::split_ipv4_config(ipv4_config.clone());
+        let _ = write!(buf, "{{\"addr\":\"{}\"", cidr);
+        gateway.map(|gateway| write!(buf, ",\"gateway\":\"{}\"", gateway));
+        if ipv4_config.dhcp {
+            let _ = write!(buf, ",\"dhcp\":true");
+            if let Some((cidr, gateway)) = dhcp_lease {
+                let _ = write!(buf, ",\"dhcp_addr\":\"{}\"", cidr);
+                gateway.map(|gateway| write!(buf, ",\"dhcp_gateway\":\"{}\"", gateway));
+            }
+        }
+        let _ = write!(buf, "}}");
+        buf
+    }
+
+    fn show_ipv4 (socket: &mut TcpSocket, ipv4_config: &mut Ipv4Config, dhcp_lease: Option<(Ipv4Cidr, Option<Ipv4Address>)>) -> Result<Handler, Error> {
+        send_line(socket, Handler::ipv4_json(ipv4_config, dhcp_lease).as_bytes());
+        Ok(Handler::Handled)
+    }
+
+    fn show_port(socket: &mut TcpSocket, tcp_port: u16) -> Result<Handler, Error> {
+        let _ = writeln!(socket, "{{\"port\":{}}}", tcp_port);
+        Ok(Handler::Handled)
+    }
+
+    fn set_port(socket: &mut TcpSocket, store: &mut FlashStore, port: u16) -> Result<Handler, Error> {
+        if port == 0 {
+            return send_error(socket, Error::ArgumentError, "port must be non-zero");
+        }
+        if let Err(e) = store.write_value("port", &port, [0; 16]) {
+            error!("unable to save port to flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        if port < 1024 && port != crate::TCP_PORT {
+            warn!("port {} collides with a well-known service", port);
+            let _ = writeln!(socket, "{{ \"warning\": \"port {} is reserved for a well-known service\" }}", port);
+        } else {
+            send_line(socket, b"{}");
+        }
+        Ok(Handler::NewPort(port))
+    }
+
+    fn show_pwm_raw (socket: &mut TcpSocket, channels: &mut Channels, fan_ctrl: &FanCtrl, channel: usize) -> Result<Handler, Error> {
+        let fan = fan_ctrl.get_pwm_raw()
+            .map(|fan| fan.into());
+        match channels.pwm_raw_summary_json(channel, fan) {
+            Ok(buf) => {
+                send_line(socket, &buf);
+            }
+            Err(e) => {
+                error!("unable to serialize pwm raw summary: {:?}", e);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn engage_pid (socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        if fault_latched(socket, channels, channel) || channel_disabled(socket, channels, channel) {
+            return Err(Error::StateError);
+        }
+        // Continue from the manual operating point
+        let i_set = channels.get_i(channel);
+        let state = channels.channel_state(channel);
+        if !state.pid_engaged {
+            state.pid.preload(i_set.get::<ampere>());
+        }
+        state.pid_engaged = true;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_control_mode(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, mode: ControlMode) -> Result<Handler, Error> {
+        if fault_latched(socket, channels, channel) || channel_disabled(socket, channels, channel) {
+            return Err(Error::StateError);
+        }
+        let temp_unit = channels.temp_unit();
+        let mode = match mode {
+            ControlMode::Hysteresis(hysteresis) => {
+                let celsius = |value: f32| temp_unit.temperature(value.into()).get::<degree_celsius>() as f32;
+                let (low, high) = (celsius(hysteresis.low), celsius(hysteresis.high));
+                if low >= high {
+                    return send_error(socket, Error::ArgumentError, "hysteresis low must be below high");
+                }
+                ControlMode::Hysteresis(Hysteresis { low, high, heat: hysteresis.heat })
+            }
+            mode => mode,
+        };
+        channels.channel_state(channel).control_mode = mode;
+        Handler::engage_pid(socket, channels, channel)
+    }
+
+    fn set_pwm (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, pin: PwmPin, value: f64) -> Result<Handler, Error> {
+        match pin {
+            PwmPin::ISet => {
+                if fault_latched(socket, channels, channel) || channel_disabled(socket, channels, channel) {
+                    return Err(Error::StateError);
+                }
+                channels.channel_state(channel).pid_engaged = false;
+                channels.channel_state(channel).autotune = None;
+                let current = ElectricCurrent::new::<ampere>(value);
+                channels.set_i(channel, current);
+                channels.power_up(channel);
+            }
+            PwmPin::MaxV => {
+                let voltage = ElectricPotential::new::<volt>(value);
+                channels.set_max_v(channel, voltage);
+            }
+            PwmPin::MaxIPos => {
+                let current = ElectricCurrent::new::<ampere>(value);
+                channels.set_max_i_pos(channel, current);
+            }
+            PwmPin::MaxINeg => {
+                let current = ElectricCurrent::new::<ampere>(value);
+                channels.set_max_i_neg(channel, current);
+            }
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_softstart(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, duration: u32) -> Result<Handler, Error> {
+        channels.channel_state(channel).softstart = duration;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_pwm_invert(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, invert: bool) -> Result<Handler, Error> {
+        channels.set_inverted(channel, invert);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_center_point(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, center: CenterPoint) -> Result<Handler, Error> {
+        let i_tec = channels.get_i(channel);
+        let state = channels.channel_state(channel);
+        state.center = center;
+        if !state.pid_engaged {
+            channels.set_i(channel, i_tec);
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_pid (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, parameter: PidParameter, value: f64) -> Result<Handler, Error> {
+        let temp_unit = channels.temp_unit();
+        let state = channels.channel_state(channel);
+        let pid = &mut state.pid;
+        use super::command_parser::PidParameter::*;
+        match parameter {
+            Target => {
+                pid.target = temp_unit.temperature(value).get::<degree_celsius>();
+                state.ramp = None;
+                state.profile.stop();
+            }
+            SetpointScale =>
+                state.setpoint.scale = value,
+            SetpointOffset =>
+                state.setpoint.offset = value,
+            KI =>
+                pid.update_ki(value as f32),
+            _ => {
+                set_pid_parameter(&mut pid.parameters, &parameter, value);
+            }
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_pid_cool(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, parameter: PidParameter, value: f64) -> Result<Handler, Error> {
+        let pid = &mut channels.channel_state(channel).pid;
+        // Start out from the common parameters
+        let mut cool = pid.cool.clone().unwrap_or_else(|| pid.parameters.clone());
+        if !set_pid_parameter(&mut cool, &parameter, value) {
+            return send_error(socket, Error::ArgumentError, "not a parameter of the cooling set");
+        }
+        pid.cool = Some(cool);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn clear_pid_cool(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        channels.channel_state(channel).pid.cool = None;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn ramp_pid(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, target: f64, rate: f64) -> Result<Handler, Error> {
+        if rate < 0.0 {
+            return send_error(socket, Error::ArgumentError, "ramp rate must not be negative");
+        }
+        let temp_unit = channels.temp_unit();
+        let state = channels.channel_state(channel);
+        if state.setpoint.source == SetpointSource::Analog {
+            return send_error(socket, Error::StateError, "target follows the analog setpoint input");
+        }
+        let target = temp_unit.temperature(target).get::<degree_celsius>();
+        state.profile.stop();
+        state.start_ramp(target, temp_unit.interval_kelvin(rate));
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn profile(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, command: ProfileCommand) -> Result<Handler, Error> {
+        let temp_unit = channels.temp_unit();
+        let state = channels.channel_state(channel);
+        match command {
+            ProfileCommand::Show => {
+                match channels.profile_json(channel) {
+                    Ok(buf) => send_line(socket, &buf),
+                    Err(e) => {
+                        error!("unable to serialize profile: {:?}", e);
+                        return send_error(socket, Error::ReportError, format_args!("{:?}", e));
+                    }
+                };
+                return Ok(Handler::Handled);
+            }
+            ProfileCommand::Add { rate, .. } if rate < 0.0 => {
+                return send_error(socket, Error::ArgumentError, "ramp rate must not be negative");
+            }
+            ProfileCommand::Add { target, rate, hold_ms } => {
+                let segment = profile::Segment {
+                    target: temp_unit.temperature(target).get::<degree_celsius>(),
+                    rate: temp_unit.interval_kelvin(rate),
+                    hold_ms,
+                };
+                if state.profile.segments.push(segment).is_err() {
+                    return send_error(socket, Error::StateError, "profile is full");
+                }
+            }
+            ProfileCommand::Clear => {
+                state.profile.stop();
+                state.profile.segments.clear();
+            }
+            ProfileCommand::Start => {
+                if state.setpoint.source == SetpointSource::Analog {
+                    return send_error(socket, Error::StateError, "target follows the analog setpoint input");
+                }
+                if !channels.start_profile(channel) {
+                    return send_error(socket, Error::StateError, "profile is empty");
+                }
+            }
+            ProfileCommand::Stop => {
+                state.profile.stop();
+                state.ramp = None;
+            }
+            ProfileCommand::Mode(mode) =>
+                state.profile.mode = mode,
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn autotune_pid (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, amplitude: f64) -> Result<Handler, Error> {
+        if amplitude <= 0.0 {
+            return send_error(socket, Error::ArgumentError, "autotune amplitude must be positive");
+        }
+        if fault_latched(socket, channels, channel) || channel_disabled(socket, channels, channel) {
+            return Err(Error::StateError);
+        }
+        let state = channels.channel_state(channel);
+        state.autotune = Some(Autotune::new(state.pid.target, amplitude));
+        state.pid_engaged = true;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_setpoint_source (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, source: SetpointSource) -> Result<Handler, Error> {
+        channels.channel_state(channel).setpoint.source = source;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn reset_pid (socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        channels.channel_state(channel).pid.reset_state();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_temp_limit(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, limit: TempLimit, value: Option<f64>) -> Result<Handler, Error> {
+        let temp_unit = channels.temp_unit();
+        let celsius = |value: f64| temp_unit.temperature(value).get::<degree_celsius>() as f32;
+        let state = channels.channel_state(channel);
+        let mut limits = state.temp_limits.clone();
+        match limit {
+            TempLimit::Min => limits.min = value.map(celsius),
+            TempLimit::Max => limits.max = value.map(celsius),
+            TempLimit::RunawayWindow | TempLimit::RunawaySlope => {
+                let value = value.unwrap_or(0.0);
+                if value < 0.0 {
+                    return send_error(socket, Error::ArgumentError, "runaway parameters must not be negative");
+                }
+                if limit == TempLimit::RunawayWindow {
+                    state.runaway.window = value as f32;
+                } else {
+                    state.runaway.slope = value as f32;
+                }
+                send_line(socket, b"{}");
+                return Ok(Handler::Handled);
+            }
+        }
+        if let (Some(min), Some(max)) = (limits.min, limits.max) {
+            if min >= max {
+                return send_error(socket, Error::ArgumentError, "temp_min must be below temp_max");
+            }
+        }
+        channels.channel_state(channel).temp_limits = limits;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn clear_fault(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        channels.clear_fault(channel);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn show_pid_terms(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        match channels.pid_terms_json(channel) {
+            Ok(buf) => {
+                send_line(socket, &buf);
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to serialize pid terms: {:?}", e);
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
+        }
+    }
+
+    fn show_temperature_stats(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        match channels.temperature_stats_json(channel) {
+            Ok(buf) => {
+                send_line(socket, &buf);
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to serialize temperature stats: {:?}", e);
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
+        }
+    }
+
+    fn reset_temperature_stats(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        channels.channel_state(channel).temp_stats = Default::default();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_channel_enabled(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, enabled: bool) -> Result<Handler, Error> {
+        if let Err(e) = channels.set_enabled(channel, enabled) {
+            return send_error(socket, Error::AdcError, format_args!("{:?}", e));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn reset_to_defaults(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        channels.reset_to_defaults(channel);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_tec_peltier(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, coefficient: f64) -> Result<Handler, Error> {
+        channels.channel_state(channel).tec.peltier = coefficient;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn reset_tec(socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        channels.channel_state(channel).tec.reset();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn show_units(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        let _ = writeln!(socket, "{{\"temp\":\"{:?}\"}}", channels.temp_unit());
+        Ok(Handler::Handled)
+    }
+
+    fn set_temp_unit(socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore, unit: TempUnit) -> Result<Handler, Error> {
+        channels.set_temp_unit(unit);
+        if let Err(e) = store.write_value("temp_unit", &unit, [0; 16]) {
+            error!("unable to save temperature unit to flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_dac_raw(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, value: u32) -> Result<Handler, Error> {
+        if fault_latched(socket, channels, channel) || channel_disabled(socket, channels, channel) {
+            return Err(Error::StateError);
+        }
+        channels.channel_state(channel).pid_engaged = false;
+        channels.channel_state(channel).autotune = None;
+        let dac_value = channels.set_dac_raw(channel, value);
+        let _ = writeln!(socket, "{{\"dac_value\":{}}}", dac_value.get::<volt>());
+        Ok(Handler::Handled)
+    }
+
+    fn set_dac_verify(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, verify: bool) -> Result<Handler, Error> {
+        channels.set_dac_verify(channel, verify);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_sensor_type(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, sensor: SensorType) -> Result<Handler, Error> {
+        channels.channel_state(channel).sensor = sensor;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_rtd(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, parameter: RtdParameter, value: f64) -> Result<Handler, Error> {
+        if !value.is_finite() || (parameter == RtdParameter::R0 && value <= 0.0) {
+            return send_error(socket, Error::ArgumentError, "invalid RTD parameter");
+        }
+        let rtd = &mut channels.channel_state(channel).rtd;
+        match parameter {
+            RtdParameter::R0 => rtd.r0 = ElectricalResistance::new::<ohm>(value),
+            RtdParameter::A => rtd.a = value,
+            RtdParameter::B => rtd.b = value,
+            RtdParameter::C => rtd.c = value,
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_steinhart_hart (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, parameter: ShParameter, value: f64) -> Result<Handler, Error> {
+        let temp_unit = channels.temp_unit();
+        let sh = &mut channels.channel_state(channel).sh;
+        use super::command_parser::ShParameter::*;
+        use super::steinhart_hart::Model;
+        match parameter {
+            T0 => {
+                sh.t0 = temp_unit.temperature(value);
+                sh.model = Model::Beta;
+            }
+            B => {
+                sh.b = value;
+                sh.model = Model::Beta;
+            }
+            R0 => {
+                sh.r0 = ElectricalResistance::new::<ohm>(value);
+                sh.model = Model::Beta;
+            }
+            TMin => sh.t_min = ThermodynamicTemperature::new::<degree_celsius>(value),
+            TMax => sh.t_max = ThermodynamicTemperature::new::<degree_celsius>(value),
+            FaultHoldoff => sh.fault_holdoff = value as u32,
+            A => {
+                sh.a = value;
+                sh.model = Model::Abc;
+            }
+            B3 => {
+                sh.b3 = value;
+                sh.model = Model::Abc;
+            }
+            C => {
+                sh.c = value;
+                sh.model = Model::Abc;
+            }
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_temp_correction (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, gain: f64, offset: f64) -> Result<Handler, Error> {
+        let sh = &mut channels.channel_state(channel).sh;
+        sh.correction_gain = gain;
+        sh.correction_offset = offset;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn calibrate_steinhart_hart (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, points: [(f64, f64); 3]) -> Result<Handler, Error> {
+        let mut measured = [(ElectricalResistance::new::<ohm>(0.0), ThermodynamicTemperature::new::<degree_celsius>(0.0)); 3];
+        for ((r, t), point) in points.iter().zip(measured.iter_mut()) {
+            *point = (ElectricalResistance::new::<ohm>(*r), ThermodynamicTemperature::new::<degree_celsius>(*t));
+        }
+        let sh = &mut channels.channel_state(channel).sh;
+        match sh.calibrate(&measured) {
+            Some(()) => {
+                if !quiet() {
+                    let _ = writeln!(socket, "{{\"a\":{},\"b3\":{},\"c\":{}}}", sh.a, sh.b3, sh.c);
+                }
+                Ok(Handler::Handled)
+            }
+            None => {
+                send_error(socket, Error::ArgumentError, "calibration points are singular")
+            }
+        }
+    }
+
+    fn reset_post_filter (socket: &mut TcpSocket, channels: &mut Channels, channel: usize) -> Result<Handler, Error> {
+        channels.adc.set_postfilter(channel as u8, None).unwrap();
+        channels.channel_state(channel).adc_interval_stats = Default::default();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_post_filter (socket: &mut TcpSocket, channels: &mut Channels, channel: usize, rate: f32) -> Result<Handler, Error> {
+        let filter = ad7172::PostFilter::closest(rate);
+        match filter {
+            Some(filter) => {
+                channels.adc.set_postfilter(channel as u8, Some(filter)).unwrap();
+                channels.channel_state(channel).adc_interval_stats = Default::default();
+                send_line(socket, b"{}");
+            }
+            None => {
+                error!("unable to choose postfilter for rate {:.3}", rate);
+                return send_error(socket, Error::PostFilterRateError, "unable to choose postfilter rate");
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn set_odr(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, rate: f32) -> Result<Handler, Error> {
+        let index = channel as u8;
+        let postfilter = channels.adc.get_filter(index)
+            .and_then(|filter| channels.adc.set_odr(index, filter.closest_odr(rate)))
+            .and_then(|_| channels.adc.get_postfilter(index));
+        channels.channel_state(channel).adc_interval_stats = Default::default();
+        let postfilter = match postfilter {
+            Ok(postfilter) => postfilter,
+            Err(e) => {
+                error!("unable to set channel {} ODR: {:?}", channel, e);
+                return send_error(socket, Error::AdcError, format_args!("{:?}", e));
+            }
+        };
+        if postfilter.is_some() {
+            send_line(socket, b"{ \"warning\": \"postfilter is enabled, output data rate applies after postfilter off\" }");
+        } else {
+            send_line(socket, b"{}");
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn set_adc_rate(socket: &mut TcpSocket, channels: &mut Channels, channel: usize, rate: f32) -> Result<Handler, Error> {
+        let (mechanism, rate) = match channels.set_adc_rate(channel, rate) {
+            Ok(selected) => selected,
+            Err(e) => return send_error(socket, Error::AdcError, format_args!("{:?}", e)),
+        };
+        if quiet() {
+            return Ok(Handler::Handled);
+        }
+        let _ = match rate {
+            Some(rate) =>
+                writeln!(socket, "{{\"channel\":{},\"mechanism\":\"{}\",\"rate\":{}}}", channel, mechanism, rate),
+            None =>
+                writeln!(socket, "{{\"channel\":{},\"mechanism\":\"{}\",\"rate\":null}}", channel, mechanism),
+        };
+        Ok(Handler::Handled)
+    }
+
+    fn load_channel (socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore, channel: Option<usize>) -> Result<Handler, Error> {
+        for c in 0..CHANNELS {
+            if channel.is_none() || channel == Some(c) {
+                match ChannelConfig::read(store, CHANNEL_CONFIG_KEY[c]) {
+                    Ok(Some(config)) => {
+                        config.apply(channels, c);
+                        send_line(socket, b"{}");
+                    }
+                    Ok(None) => {
+                        error!("flash config not found");
+                        let _ = send_error(socket, Error::UnavailableError, "flash config not found");
+                    }
+                    Err(e) => {
+                        error!("unable to load config from flash: {:?}", e);
+                        return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+                    }
+                }
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn verify_channel (socket: &mut TcpSocket, store: &mut FlashStore, channel: usize) -> Result<Handler, Error> {
+        let stored = ChannelConfig::read(store, CHANNEL_CONFIG_KEY[channel]);
+        match ConfigVerification::new(channel, stored) {
+            Some(verification) => match verification.summary_json() {
+                Ok(buf) => {
+                    send_line(socket, &buf);
+                }
+                Err(e) => {
+                    error!("unable to serialize config verification: {:?}", e);
+                    return send_error(socket, Error::ReportError, format_args!("{:?}", e));
+                }
+            }
+            None => {
+                return send_error(socket, Error::UnavailableError, "flash config not found");
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn diff_channel (socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore, channel: usize) -> Result<Handler, Error> {
+        match ChannelConfig::read(store, CHANNEL_CONFIG_KEY[channel]) {
+            Ok(Some(stored)) => {
+                let live = match ChannelConfig::new(channels, channel) {
+                    Ok(live) => live,
+                    Err(e) => {
+                        error!("unable to read channel {} ADC setup: {:?}", channel, e);
+                        return send_error(socket, Error::AdcError, format_args!("{:?}", e));
+                    }
+                };
+                match ConfigDiff::new(channel, &live, &stored).summary_json() {
+                    Ok(buf) => {
+                        send_line(socket, &buf);
+                    }
+                    Err(e) => {
+                        error!("unable to serialize config diff: {:?}", e);
+                        return send_error(socket, Error::ReportError, format_args!("{:?}", e));
+                    }
+                }
+            }
+            Ok(None) => {
+                return send_error(socket, Error::UnavailableError, "flash config not found");
+            }
+            Err(e) => {
+                error!("unable to load config from flash: {:?}", e);
+                return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn save_channel (socket: &mut TcpSocket, channels: &mut Channels, channel: Option<usize>, store: &mut FlashStore) -> Result<Handler, Error> {
+        for c in 0..CHANNELS {
+            let mut store_value_buf = [0u8; 512];
+            if channel.is_none() || channel == Some(c) {
+                let config = match ChannelConfig::new(channels, c) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        error!("unable to read channel {} ADC setup: {:?}", c, e);
+                        return send_error(socket, Error::AdcError, format_args!("{:?}", e));
+                    }
+                };
+                match store.write_value(CHANNEL_CONFIG_KEY[c], &config, &mut store_value_buf) {
+                    Ok(()) => {
+                        send_line(socket, b"{}");
+                    }
+                    Err(e) => {
+                        error!("unable to save channel {} config to flash: {:?}", c, e);
+                        return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+                    }
+                }
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn save_all(socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore, ipv4_config: &Ipv4Config, fan_ctrl: &FanCtrl) -> Result<Handler, Error> {
+        let mut summary = SaveSummary::default();
+        let mut store_value_buf = [0u8; 512];
+        for c in 0..CHANNELS {
+            match ChannelConfig::new(channels, c) {
+                Ok(config) =>
+                    summary.record(CHANNEL_CONFIG_KEY[c], store.write_value(CHANNEL_CONFIG_KEY[c], &config, &mut store_value_buf)),
+                Err(e) =>
+                    summary.record(CHANNEL_CONFIG_KEY[c], Err(e)),
+            }
+        }
+        summary.record("ipv4", store.write_value("ipv4", ipv4_config, [0; 16]));
+        summary.record("temp_unit", store.write_value("temp_unit", &channels.temp_unit(), [0; 16]));
+        let fan = fan_ctrl.config();
+        summary.record("fan_source", store.write_value("fan_source", &fan.source, [0; 16]));
+        if fan_ctrl.fan_available() {
+            summary.record("fan", store.write_value("fan", &fan, [0; 64]));
+        }
+
+        match summary.summary_json() {
+            Ok(buf) => {
+                send_line(socket, &buf);
+            }
+            Err(e) => {
+                error!("unable to serialize save summary: {:?}", e);
+                return send_error(socket, Error::ReportError, format_args!("{:?}", e));
+            }
+        }
+        if summary.is_complete() {
+            Ok(Handler::Handled)
+        } else {
+            Err(Error::FlashError)
+        }
+    }
+
+    fn set_ipv4 (socket: &mut TcpSocket, store: &mut FlashStore, config: Ipv4Config) -> Result<Handler, Error> {
+        let _ = store
+            .write_value("ipv4", &config, [0; 16])
+            .map_err(|e| error!("unable to save ipv4 config to flash: {:?}", e));
+        let new_ipv4_config = Some(config);
+        send_line(socket, b"{}");
+        Ok(Handler::NewIPV4(new_ipv4_config.unwrap()))
+    }
+
+    fn set_ipv4_dhcp (socket: &mut TcpSocket, store: &mut FlashStore, ipv4_config: &Ipv4Config) -> Result<Handler, Error> {
+        let config = Ipv4Config {
+            dhcp: true,
+            ..ipv4_config.clone()
+        };
+        Handler::set_ipv4(socket, store, config)
+    }
+
+    fn reset (channels: &mut Channels) -> Result<Handler, Error> {
+        for i in 0..CHANNELS {
+            channels.power_down(i);
+        }
+        // should_reset = true;
+        Ok(Handler::Reset)
+    }
+
+    fn factory_reset(socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore) -> Result<Handler, Error> {
+        for i in 0..CHANNELS {
+            channels.power_down(i);
+        }
+        // Reply once, with the outcome of the erase
+        if let Err(e) = store.erase() {
+            error!("unable to erase flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        send_line(socket, b"{\"factory_reset\": \"flash erased, resetting\"}");
+        Ok(Handler::Reset)
+    }
+
+    fn dfu (channels: &mut Channels) -> Result<Handler, Error> {
+        for i in 0..CHANNELS {
+            channels.power_down(i);
+        }
+        unsafe {
+            dfu::set_dfu_trigger();
+        }
+        // should_reset = true;
+        Ok(Handler::Reset)
+    }
+
+    fn set_fan(socket: &mut TcpSocket, fan_pwm: u32, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
+        if !fan_ctrl.fan_available() {
+            send_line(socket, b"{ \"warning\": \"this thermostat doesn't have fan!\" }");
+            return Ok(Handler::Handled);
+        }
+        fan_ctrl.set_auto_mode(false);
+        fan_ctrl.set_pwm(fan_pwm);
+        if fan_ctrl.fan_pwm_recommended() {
+            send_line(socket, b"{}");
+        } else {
+            send_line(socket, b"{ \"warning\": \"this fan doesn't have full PWM support. Use it at your own risk!\" }");
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn show_fan(socket: &mut TcpSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
+        match fan_ctrl.summary() {
+            Ok(buf) => {
+                send_line(socket, &buf);
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to serialize fan summary: {:?}", e);
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
+        }
+    }
+
+    fn fan_auto(socket: &mut TcpSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
+        if !fan_ctrl.fan_available() {
             send_line(socket, b"{ \"warning\": \"this thermostat doesn't have fan!\" }");
             return Ok(Handler::Handled);
         }
@@ -392,12 +1440,65 @@ impl Handler {
         Ok(Handler::Handled)
     }
 
+    fn fan_source(socket: &mut TcpSocket, store: &mut FlashStore, fan_ctrl: &mut FanCtrl, source: FanSource) -> Result<Handler, Error> {
+        fan_ctrl.set_source(source);
+        if let Err(e) = store.write_value("fan_source", &source, [0; 16]) {
+            error!("unable to save fan source to flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn fan_save(socket: &mut TcpSocket, store: &mut FlashStore, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
+        if !fan_ctrl.fan_available() {
+            send_line(socket, b"{ \"warning\": \"this thermostat doesn't have fan!\" }");
+            return Ok(Handler::Handled);
+        }
+        if let Err(e) = store.write_value("fan", &fan_ctrl.config(), [0; 64]) {
+            error!("unable to save fan settings to flash: {:?}", e);
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn fan_freq(socket: &mut TcpSocket, fan_ctrl: &mut FanCtrl, freq_hz: u32) -> Result<Handler, Error> {
+        if !fan_ctrl.fan_available() {
+            send_line(socket, b"{ \"warning\": \"this thermostat doesn't have fan!\" }");
+            return Ok(Handler::Handled);
+        }
+        if fan_ctrl.set_pwm_freq(freq_hz) != freq_hz {
+            send_line(socket, b"{ \"warning\": \"frequency has been clamped to the supported range\" }");
+        } else {
+            send_line(socket, b"{}");
+        }
+        Ok(Handler::Handled)
+    }
+
     fn fan_defaults(socket: &mut TcpSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
         fan_ctrl.restore_defaults();
         send_line(socket, b"{}");
         Ok(Handler::Handled)
     }
 
+    fn help(socket: &mut TcpSocket, topic: Option<&'static str>) -> Result<Handler, Error> {
+        let json = match topic {
+            None => serde_json_core::to_vec(&help::Topics::new()),
+            Some(topic) => serde_json_core::to_vec(&help::Topic::new(topic)),
+        };
+        match json {
+            Ok(buf) => {
+                send_line(socket, &buf);
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to serialize help: {:?}", e);
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
+        }
+    }
+
     fn show_hwrev(socket: &mut TcpSocket, hwrev: HWRev) -> Result<Handler, Error> {
         match hwrev.summary() {
             Ok(buf) => {
@@ -406,41 +1507,325 @@ impl Handler {
             }
             Err(e) => {
                 error!("unable to serialize HWRev summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                Err(Error::ReportError)
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
+        }
+    }
+
+    fn show_version(socket: &mut TcpSocket, hwrev: HWRev) -> Result<Handler, Error> {
+        match hwrev.version_summary() {
+            Ok(buf) => {
+                send_line(socket, &buf);
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to serialize version summary: {:?}", e);
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
+        }
+    }
+
+    fn show_uptime(socket: &mut TcpSocket) -> Result<Handler, Error> {
+        let _ = writeln!(socket, "{{\"uptime_ms\":{}}}", timer::uptime());
+        Ok(Handler::Handled)
+    }
+
+    fn show_netstat(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
+        let json: Result<JsonBuffer, _> = serde_json_core::to_vec(&netstat::summary(channels.spi_errors()));
+        match json {
+            Ok(buf) => {
+                send_line(socket, &buf);
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to serialize netstat summary: {:?}", e);
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
+        }
+    }
+
+    fn show_watchdog(socket: &mut TcpSocket) -> Result<Handler, Error> {
+        let json: Result<JsonBuffer, _> = serde_json_core::to_vec(&watchdog::summary());
+        match json {
+            Ok(buf) => {
+                send_line(socket, &buf);
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to serialize watchdog summary: {:?}", e);
+                send_error(socket, Error::ReportError, format_args!("{:?}", e))
+            }
+        }
+    }
+
+    fn set_watchdog(socket: &mut TcpSocket, interval: u32) -> Result<Handler, Error> {
+        if !watchdog::is_valid_interval(interval) {
+            return send_error(
+                socket, Error::ArgumentError,
+                format_args!("watchdog interval must be {} to {} ms", watchdog::INTERVAL_MIN, watchdog::INTERVAL_MAX),
+            );
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::NewWatchdog(interval))
+    }
+
+    /// Send the next part of the `all` object, returns `false` when
+    /// the socket has no space for it yet.
+    pub fn send_all_part(part: usize, socket: &mut TcpSocket, channels: &mut Channels, ipv4_config: &Ipv4Config, dhcp_lease: Option<(Ipv4Cidr, Option<Ipv4Address>)>, fan_ctrl: &mut FanCtrl, hwrev: HWRev) -> bool {
+        let prefix = all_part_prefix(part);
+        let suffix = all_part_suffix(part);
+        let data = match part {
+            0 => channels.reports_json(),
+            1 => channels.pid_summaries_json(),
+            2 => channels.pwm_summaries_json(),
+            3 => channels.steinhart_hart_summaries_json(),
+            4 => channels.postfilter_summaries_json(),
+            5 => channels.adc_summaries_json(),
+            6 => channels.tec_summaries_json(),
+            7 => fan_ctrl.summary(),
+            8 => {
+                let ipv4 = Handler::ipv4_json(ipv4_config, dhcp_lease);
+                return send_part(socket, &prefix, ipv4.as_bytes(), suffix);
+            }
+            9 => hwrev.summary(),
+            _ => unreachable!(),
+        };
+        match data {
+            Ok(buf) => send_part(socket, &prefix, &buf, suffix),
+            Err(e) => {
+                error!("unable to serialize part {} of all: {:?}", part, e);
+                send_part(socket, &prefix, b"null", suffix)
+            }
+        }
+    }
+
+    /// Send the next part of the `config export` document, returns
+    /// `false` when the socket has no space for it yet.
+    pub fn send_config_export_part(part: usize, socket: &mut TcpSocket, channels: &mut Channels, ipv4_config: &Ipv4Config, fan_ctrl: &FanCtrl) -> bool {
+        let prefix = CONFIG_EXPORT_PREFIXES[part];
+        let suffix: &[u8] = if part == CONFIG_EXPORT_PREFIXES.len() - 1 {
+            b"}\n"
+        } else {
+            b""
+        };
+        let data = match part {
+            0 | 1 => match ChannelConfig::new(channels, part) {
+                Ok(config) => serde_json_core::to_vec(&config),
+                Err(e) => {
+                    error!("unable to read channel {} ADC setup: {:?}", part, e);
+                    return send_part(socket, prefix, b"null", suffix);
+                }
+            },
+            2 => serde_json_core::to_vec(ipv4_config),
+            3 => serde_json_core::to_vec(&fan_ctrl.config()),
+            _ => unreachable!(),
+        };
+        match data {
+            Ok(buf) => send_part(socket, prefix, &buf, suffix),
+            Err(e) => {
+                error!("unable to serialize part {} of config export: {:?}", part, e);
+                send_part(socket, prefix, b"null", suffix)
+            }
+        }
+    }
+
+    /// Apply and save a `config import` document, all or nothing
+    pub fn import_config(document: &[u8], socket: &mut TcpSocket, channels: &mut Channels, store: &mut FlashStore, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
+        let config: DeviceConfig = match serde_json_core::from_slice(document) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("unable to parse config import: {:?}", e);
+                return send_error(socket, Error::ArgumentError, format_args!("{:?}", e));
+            }
+        };
+        if let Err(e) = config.validate() {
+            return send_error(socket, Error::ArgumentError, e);
+        }
+
+        // Put back what was stored if writing fails half way
+        let mut previous: Vec<Option<ChannelConfig>, U2> = Vec::new();
+        for c in 0..CHANNELS {
+            let _ = previous.push(ChannelConfig::read(store, CHANNEL_CONFIG_KEY[c]).ok().flatten());
+        }
+        let previous_ipv4: Option<Ipv4Config> = read_ipv4_config(store).ok().flatten();
+        let previous_source: Option<FanSource> = store.read_value("fan_source").ok().flatten();
+        let previous_fan: Option<FanConfig> = store.read_value("fan").ok().flatten();
+
+        let mut store_value_buf = [0u8; 512];
+        let mut result = Ok(());
+        for (c, channel_config) in config.channels.iter().enumerate() {
+            result = result.and_then(|()| store.write_value(CHANNEL_CONFIG_KEY[c], channel_config, &mut store_value_buf));
+        }
+        result = result
+            .and_then(|()| store.write_value("ipv4", &config.ipv4, [0; 16]))
+            .and_then(|()| store.write_value("fan_source", &config.fan.source, [0; 16]))
+            .and_then(|()| if fan_ctrl.fan_available() {
+                store.write_value("fan", &config.fan, [0; 64])
+            } else {
+                Ok(())
+            });
+        if let Err(e) = result {
+            error!("unable to save imported config to flash: {:?}", e);
+            // Keys that were not stored before are removed again
+            for (c, channel_config) in previous.iter().enumerate() {
+                let _ = match channel_config {
+                    Some(channel_config) =>
+                        store.write_value(CHANNEL_CONFIG_KEY[c], channel_config, &mut store_value_buf),
+                    None => store.remove(CHANNEL_CONFIG_KEY[c]),
+                };
+            }
+            let _ = match previous_ipv4 {
+                Some(ipv4) => store.write_value("ipv4", &ipv4, [0; 16]),
+                None => store.remove("ipv4"),
+            };
+            let _ = match previous_source {
+                Some(source) => store.write_value("fan_source", &source, [0; 16]),
+                None => store.remove("fan_source"),
+            };
+            let _ = match previous_fan {
+                Some(fan) => store.write_value("fan", &fan, [0; 64]),
+                None => store.remove("fan"),
+            };
+            return send_error(socket, Error::FlashError, format_args!("{:?}", e));
+        }
+
+        config.apply(channels, fan_ctrl);
+        send_line(socket, b"{}");
+        Ok(Handler::NewIPV4(config.ipv4))
+    }
+
+    /// Handle a command of `stage commit` without a reply of its own,
+    /// see `commit_reply`
+    pub fn handle_staged_command(command: Command, socket: &mut TcpSocket, channels: &mut Channels, session: &Session, store: &mut FlashStore, ipv4_config: &mut Ipv4Config, dhcp_lease: Option<(Ipv4Cidr, Option<Ipv4Address>)>, tcp_port: u16, fan_ctrl: &mut FanCtrl, hwrev: HWRev) -> Result<Self, Error> {
+        QUIET.store(true, Ordering::Relaxed);
+        let result = Handler::handle_command(command, socket, channels, session, store, ipv4_config, dhcp_lease, tcp_port, fan_ctrl, hwrev);
+        QUIET.store(false, Ordering::Relaxed);
+        result
+    }
+
+    /// The one reply to `stage commit`, with the 0-based index of the
+    /// first staged command that failed
+    pub fn commit_reply(socket: &mut TcpSocket, failed: Option<(usize, Error)>) {
+        match failed {
+            None => {
+                send_line(socket, b"{}");
+            }
+            Some((index, error)) => {
+                let _ = send_error(socket, error, format_args!("staged command {} failed", index));
             }
         }
     }
 
-    pub fn handle_command(command: Command, socket: &mut TcpSocket, channels: &mut Channels, session: &Session, store: &mut FlashStore, ipv4_config: &mut Ipv4Config, fan_ctrl: &mut FanCtrl, hwrev: HWRev) -> Result<Self, Error> {
+    pub fn handle_command(command: Command, socket: &mut TcpSocket, channels: &mut Channels, session: &Session, store: &mut FlashStore, ipv4_config: &mut Ipv4Config, dhcp_lease: Option<(Ipv4Cidr, Option<Ipv4Address>)>, tcp_port: u16, fan_ctrl: &mut FanCtrl, hwrev: HWRev) -> Result<Self, Error> {
         match command {
             Command::Quit => Ok(Handler::CloseSocket),
-            Command::Reporting(_reporting) => Handler::reporting(socket),            
+            Command::Reporting(_reporting) => Handler::reporting(socket),
+            Command::LogToTcp(_enabled) => Handler::reporting(socket),
+            Command::ReportFormat(format) => Handler::report_format(socket, format),
+            Command::ReportInterval(_interval) => Handler::reporting(socket),
+            Command::ReportOnConnect(on_connect) => Handler::set_report_on_connect(socket, on_connect),
+            Command::Telemetry(config) => Handler::set_telemetry(socket, config),
+            Command::Mqtt(config) => Handler::set_mqtt(socket, store, config),
+            Command::Ntp(server) => Handler::set_ntp(socket, store, server),
+            Command::Mac(mac) => Handler::set_mac(socket, store, mac),
+            Command::TcpTimeout(timeout) => Handler::set_tcp_timeout(socket, store, timeout),
+            Command::Stage(_stage) => Handler::stage(socket),
             Command::Show(ShowCommand::Reporting) => Handler::show_report_mode(socket, session),            
-            Command::Show(ShowCommand::Input) => Handler::show_report(socket, channels),
+            Command::Show(ShowCommand::Input) => Handler::show_report(socket, channels, session),
+            Command::Show(ShowCommand::ChannelReport(channel)) => Handler::show_channel_report(socket, channels, session, channel),
             Command::Show(ShowCommand::Pid) => Handler::show_pid(socket, channels),
             Command::Show(ShowCommand::Pwm) => Handler::show_pwm(socket, channels),
             Command::Show(ShowCommand::SteinhartHart) => Handler::show_steinhart_hart(socket, channels),
+            Command::Show(ShowCommand::Sensor) => Handler::show_sensor(socket, channels),
             Command::Show(ShowCommand::PostFilter) => Handler::show_post_filter(socket, channels),
-            Command::Show(ShowCommand::Ipv4) => Handler::show_ipv4(socket, ipv4_config),
+            Command::Show(ShowCommand::Ipv4) => Handler::show_ipv4(socket, ipv4_config, dhcp_lease),
+            Command::Show(ShowCommand::Adc) => Handler::show_adc(socket, channels),
+            Command::Show(ShowCommand::Port) => Handler::show_port(socket, tcp_port),
+            Command::Show(ShowCommand::Tec) => Handler::show_tec(socket, channels),
+            Command::Show(ShowCommand::TempLimits) => Handler::show_temp_limits(socket, channels),
+            Command::Show(ShowCommand::AdcTemperature) => Handler::show_adc_temperature(socket, channels),
+            Command::Show(ShowCommand::Supply) => Handler::show_supply(socket, channels),
+            // Streamed in parts as tracked by the Session
+            Command::Show(ShowCommand::All) => Ok(Handler::Handled),
+            Command::ConfigExport => Ok(Handler::Handled),
+            // The document is collected by the Session
+            Command::ConfigImport => Ok(Handler::Handled),
             Command::PwmPid { channel } => Handler::engage_pid(socket, channels, channel),
+            Command::PwmRaw { channel } => Handler::show_pwm_raw(socket, channels, fan_ctrl, channel),
+            Command::PwmSoftStart { channel, duration } => Handler::set_softstart(socket, channels, channel, duration),
+            Command::PwmInvert { channel, invert } => Handler::set_pwm_invert(socket, channels, channel, invert),
             Command::Pwm { channel, pin, value } => Handler::set_pwm(socket, channels, channel, pin, value),
             Command::CenterPoint { channel, center } => Handler::set_center_point(socket, channels, channel, center),
             Command::Pid { channel, parameter, value } => Handler::set_pid(socket, channels, channel, parameter, value),
+            Command::PidCool { channel, parameter, value } => Handler::set_pid_cool(socket, channels, channel, parameter, value),
+            Command::PidCoolOff { channel } => Handler::clear_pid_cool(socket, channels, channel),
+            Command::PidReset { channel } => Handler::reset_pid(socket, channels, channel),
+            Command::PidTerms { channel } => Handler::show_pid_terms(socket, channels, channel),
+            Command::PidAutotune { channel, amplitude } => Handler::autotune_pid(socket, channels, channel, amplitude),
+            Command::PidRamp { channel, target, rate } => Handler::ramp_pid(socket, channels, channel, target, rate),
+            Command::Profile { channel, command } => Handler::profile(socket, channels, channel, command),
+            Command::PidSetpointSource { channel, source } => Handler::set_setpoint_source(socket, channels, channel, source),
+            Command::TecPeltier { channel, coefficient } => Handler::set_tec_peltier(socket, channels, channel, coefficient),
+            Command::TecReset { channel } => Handler::reset_tec(socket, channels, channel),
+            Command::TempLimit { channel, limit, value } => Handler::set_temp_limit(socket, channels, channel, limit, value),
+            Command::ClearFault { channel } => Handler::clear_fault(socket, channels, channel),
+            Command::ControlMode { channel, mode } => Handler::set_control_mode(socket, channels, channel, mode),
+            Command::ChannelEnable { channel, enabled } => Handler::set_channel_enabled(socket, channels, channel, enabled),
+            Command::Defaults { channel } => Handler::reset_to_defaults(socket, channels, channel),
+            Command::Stats { channel } => Handler::show_temperature_stats(socket, channels, channel),
+            Command::StatsReset { channel } => Handler::reset_temperature_stats(socket, channels, channel),
+            Command::Show(ShowCommand::HardwareParams) => Handler::show_hardware_params(socket, channels),
+            Command::Cal { parameter, value } => Handler::set_hardware_param(socket, channels, store, parameter, value),
+            Command::CalDefaults => Handler::save_hardware_params(socket, channels, store, hwrev.hardware_params()),
+            Command::CalDac { channel, reset } => Handler::calibrate_dac(socket, channels, store, channel, reset),
             Command::SteinhartHart { channel, parameter, value } => Handler::set_steinhart_hart(socket, channels, channel, parameter, value),
+            Command::Show(ShowCommand::Units) => Handler::show_units(socket, channels),
+            Command::TempUnit(unit) => Handler::set_temp_unit(socket, channels, store, unit),
+            Command::DacRaw { channel, value } => Handler::set_dac_raw(socket, channels, channel, value),
+            Command::DacVerify { channel, verify } => Handler::set_dac_verify(socket, channels, channel, verify),
+            Command::SensorType { channel, sensor } => Handler::set_sensor_type(socket, channels, channel, sensor),
+            Command::Rtd { channel, parameter, value } => Handler::set_rtd(socket, channels, channel, parameter, value),
+            Command::TempCorrection { channel, gain, offset } => Handler::set_temp_correction(socket, channels, channel, gain, offset),
+            Command::ShCalibrate { channel, points } => Handler::calibrate_steinhart_hart(socket, channels, channel, points),
             Command::PostFilter { channel, rate: None } => Handler::reset_post_filter(socket, channels, channel),
             Command::PostFilter { channel, rate: Some(rate) } => Handler::set_post_filter(socket, channels, channel, rate),
+            Command::Odr { channel, rate } => Handler::set_odr(socket, channels, channel, rate),
+            Command::AdcRate { channel, rate } => Handler::set_adc_rate(socket, channels, channel, rate),
+            Command::AdcReadMode { channel, mode } => Handler::set_adc_read_mode(socket, channels, channel, mode),
+            Command::AdcRead { channel } => Handler::read_adc(socket, channels, channel),
+            Command::AdcIntervalTolerance { channel, tolerance } => Handler::set_adc_interval_tolerance(socket, channels, channel, tolerance),
+            Command::AdcRefSource { channel, ref_source } => Handler::set_adc_ref_source(socket, channels, channel, ref_source),
+            Command::AdcFilter { channel, filter } => Handler::set_adc_filter(socket, channels, channel, filter),
+            Command::AdcBipolar { channel, bipolar } => Handler::set_adc_bipolar(socket, channels, channel, bipolar),
+            Command::AdcAverage(samples) => Handler::set_adc_average(socket, channels, samples),
+            Command::AdcChecksum(mode) => Handler::set_adc_checksum(socket, channels, mode),
+            Command::AdcNoiseFloor { channel } => Handler::measure_adc_noise_floor(socket, channels, channel),
             Command::Load { channel } => Handler::load_channel(socket, channels, store, channel),
             Command::Save { channel } => Handler::save_channel(socket, channels, channel, store),
+            Command::SaveAll => Handler::save_all(socket, channels, store, ipv4_config, fan_ctrl),
+            Command::FlashVerify { channel } => Handler::verify_channel(socket, store, channel),
+            Command::Diff { channel } => Handler::diff_channel(socket, channels, store, channel),
             Command::Ipv4(config) => Handler::set_ipv4(socket, store, config),
+            Command::Ipv4Dhcp => Handler::set_ipv4_dhcp(socket, store, ipv4_config),
+            Command::Port(port) => Handler::set_port(socket, store, port),
             Command::Reset => Handler::reset(channels),
+            Command::FactoryReset => Handler::factory_reset(socket, channels, store),
             Command::Dfu => Handler::dfu(channels),
             Command::FanSet {fan_pwm} => Handler::set_fan(socket, fan_pwm, fan_ctrl),
             Command::ShowFan => Handler::show_fan(socket, fan_ctrl),
             Command::FanAuto => Handler::fan_auto(socket, fan_ctrl),
             Command::FanCurve { k_a, k_b, k_c } => Handler::fan_curve(socket, fan_ctrl, k_a, k_b, k_c),
             Command::FanCurveDefaults => Handler::fan_defaults(socket, fan_ctrl),
+            Command::FanSource(source) => Handler::fan_source(socket, store, fan_ctrl, source),
+            Command::FanSave => Handler::fan_save(socket, store, fan_ctrl),
+            Command::FanFreq { freq_hz } => Handler::fan_freq(socket, fan_ctrl, freq_hz),
             Command::ShowHWRev => Handler::show_hwrev(socket, hwrev),
+            Command::Version => Handler::show_version(socket, hwrev),
+            Command::Uptime => Handler::show_uptime(socket),
+            Command::Netstat => Handler::show_netstat(socket, channels),
+            Command::Watchdog => Handler::show_watchdog(socket),
+            Command::WatchdogInterval(interval) => Handler::set_watchdog(socket, interval),
+            Command::Help(topic) => Handler::help(socket, topic),
         }
     }
 }
\ No newline at end of file
@@ -1,29 +1,39 @@
 use core::fmt;
-use core::num::ParseIntError;
-use core::str::{from_utf8, Utf8Error};
+use core::num::{ParseFloatError, ParseIntError};
+use core::str::{from_utf8, FromStr, Utf8Error};
 use nom::{
     IResult,
     branch::alt,
-    bytes::complete::{is_a, tag, take_while1},
-    character::{is_digit, complete::{char, one_of}},
-    combinator::{complete, map, opt, value},
-    sequence::preceded,
+    bytes::complete::{is_a, tag, take_while, take_while1, take_while_m_n},
+    character::{is_digit, is_hex_digit, complete::{char, one_of}},
+    combinator::{complete, map, opt, recognize, value},
+    sequence::{preceded, tuple},
     multi::{fold_many0, fold_many1},
     error::ErrorKind,
     Needed,
 };
-use num_traits::{Num, ParseFloatError};
 use serde::{Serialize, Deserialize};
+use heapless::{consts::U32, String};
+use crate::{
+    ad7172::{ChecksumMode, Filter, RefSource},
+    channel_state::{ControlMode, Hysteresis, TempUnit},
+    help,
+    profile::ProfileMode,
+};
 
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Error {
     Parser(ErrorKind),
     Incomplete,
-    UnexpectedInput(u8),
+    /// Input left after a complete command
+    UnexpectedInput {
+        byte: u8,
+        /// Offset into the command
+        position: usize,
+    },
     Utf8(Utf8Error),
     ParseInt(ParseIntError),
-    // `num_traits::ParseFloatError` does not impl Clone
     ParseFloat,
 }
 
@@ -58,14 +68,34 @@ impl From<ParseFloatError> for Error {
     }
 }
 
+impl Error {
+    /// Stable identifier for the `code` field of error replies
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Parser(_) => "parser",
+            Error::Incomplete => "incomplete",
+            Error::UnexpectedInput { .. } => "unexpected_input",
+            Error::Utf8(_) => "utf8",
+            Error::ParseInt(_) => "parse_int",
+            Error::ParseFloat => "parse_float",
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
             Error::Incomplete =>
                 "incomplete input".fmt(fmt),
-            Error::UnexpectedInput(c) => {
-                "unexpected input: ".fmt(fmt)?;
-                c.fmt(fmt)
+            Error::UnexpectedInput { byte, position } => {
+                write!(fmt, "unexpected input at position {}: ", position)?;
+                // Quotes and backslashes would need escaping in JSON
+                match char::from(*byte) {
+                    c if c.is_ascii_graphic() && c != '"' && c != '\\' =>
+                        write!(fmt, "'{}'", c),
+                    _ =>
+                        write!(fmt, "0x{:02x}", byte),
+                }
             }
             Error::Parser(e) => {
                 "parser: ".fmt(fmt)?;
@@ -73,11 +103,11 @@ impl fmt::Display for Error {
             }
             Error::Utf8(e) => {
                 "utf8: ".fmt(fmt)?;
-                (e as &dyn core::fmt::Debug).fmt(fmt)
+                (e as &dyn fmt::Display).fmt(fmt)
             }
             Error::ParseInt(e) => {
                 "parsing int: ".fmt(fmt)?;
-                (e as &dyn core::fmt::Debug).fmt(fmt)
+                (e as &dyn fmt::Display).fmt(fmt)
             }
             Error::ParseFloat => {
                 "parsing float".fmt(fmt)
@@ -91,17 +121,86 @@ pub struct Ipv4Config {
     pub address: [u8; 4],
     pub mask_len: u8,
     pub gateway: Option<[u8; 4]>,
+    /// Obtain address and gateway by DHCP, using the static
+    /// configuration above only as a fallback
+    pub dhcp: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ShowCommand {
     Input,
+    /// Report of a single channel
+    ChannelReport(usize),
     Reporting,
     Pwm,
     Pid,
     SteinhartHart,
+    Sensor,
     PostFilter,
     Ipv4,
+    Adc,
+    Port,
+    Tec,
+    All,
+    /// AD7172 die temperature
+    AdcTemperature,
+    /// AD7172 analog supply voltage
+    Supply,
+    TempLimits,
+    HardwareParams,
+    Units,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StageCommand {
+    /// Start buffering parameter changes
+    Begin,
+    /// Apply buffered parameter changes together
+    Commit,
+    /// Discard buffered parameter changes
+    Abort,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProfileCommand {
+    Show,
+    /// Append a segment, in the `units temp` unit
+    Add {
+        target: f64,
+        rate: f64,
+        hold_ms: u32,
+    },
+    Clear,
+    Start,
+    Stop,
+    Mode(ProfileMode),
+}
+
+/// Where the PID target temperature comes from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SetpointSource {
+    /// Set with `pid <0-1> target`
+    Internal,
+    /// Scaled from the voltage on the spare analog input
+    Analog,
+}
+
+/// Temperature sensor model of a channel
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SensorType {
+    /// NTC thermistor, converted with `s-h` parameters
+    Thermistor,
+    /// Platinum RTD, converted with the Callendar–Van Dusen equation
+    Rtd,
+}
+
+/// Callendar–Van Dusen equation parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtdParameter {
+    R0,
+    A,
+    B,
+    C,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -110,8 +209,16 @@ pub enum PidParameter {
     KP,
     KI,
     KD,
+    KdFilter,
     OutputMin,
     OutputMax,
+    IntegralMin,
+    IntegralMax,
+    Slew,
+    FeedForward,
+    FeedForwardTarget,
+    SetpointScale,
+    SetpointOffset,
 }
 
 /// Steinhart-Hart equation parameter
@@ -120,6 +227,12 @@ pub enum ShParameter {
     T0,
     B,
     R0,
+    TMin,
+    TMax,
+    FaultHoldoff,
+    A,
+    B3,
+    C,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -130,12 +243,149 @@ pub enum PwmPin {
     MaxV,
 }
 
+/// Serialized like `Option<f32>`, `null` for `Vref`, as
+/// `serde_json_core` cannot parse newtype variants. The flash encoding
+/// is that of the enum.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Option<f32>", into = "Option<f32>")]
 pub enum CenterPoint {
     Vref,
     Override(f32),
 }
 
+impl From<Option<f32>> for CenterPoint {
+    fn from(center: Option<f32>) -> Self {
+        center.map_or(CenterPoint::Vref, CenterPoint::Override)
+    }
+}
+
+impl From<CenterPoint> for Option<f32> {
+    fn from(center: CenterPoint) -> Self {
+        match center {
+            CenterPoint::Vref => None,
+            CenterPoint::Override(center) => Some(center),
+        }
+    }
+}
+
+/// Destination of periodic UDP reports
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpTelemetry {
+    pub address: [u8; 4],
+    pub port: u16,
+    /// Seconds between reports, 0 for a report of every new sample
+    pub interval: f64,
+}
+
+/// Bound of the absolute temperature window, or a thermal runaway
+/// detection parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempLimit {
+    Min,
+    Max,
+    RunawayWindow,
+    RunawaySlope,
+}
+
+/// Constant of the analog front end, see `HardwareParams`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HardwareParameter {
+    RSense,
+    VRef,
+    RInner,
+    VRefSens,
+}
+
+/// Longest MQTT topic prefix
+pub const MQTT_PREFIX_MAX_LEN: usize = 32;
+
+/// Broker that reports are published to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MqttConfig {
+    pub address: [u8; 4],
+    pub port: u16,
+    /// Reports go to `<prefix>/<channel>/report`
+    pub prefix: String<U32>,
+}
+
+/// TEC current or temperature that the fan curve follows
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(from = "FanSourceName", into = "FanSourceName")]
+pub enum FanSource {
+    /// Current of one channel
+    Channel(usize),
+    /// Larger current of both channels
+    Max,
+    /// Sum of both channels' currents
+    Sum,
+    /// Hottest measured channel temperature
+    Temperature,
+}
+
+/// `FanSource` without data, which `serde_json_core` can parse
+#[derive(Clone, Copy, Serialize, Deserialize)]
+enum FanSourceName {
+    Channel0,
+    Channel1,
+    Max,
+    Sum,
+    Temperature,
+}
+
+impl From<FanSourceName> for FanSource {
+    fn from(name: FanSourceName) -> Self {
+        match name {
+            FanSourceName::Channel0 => FanSource::Channel(0),
+            FanSourceName::Channel1 => FanSource::Channel(1),
+            FanSourceName::Max => FanSource::Max,
+            FanSourceName::Sum => FanSource::Sum,
+            FanSourceName::Temperature => FanSource::Temperature,
+        }
+    }
+}
+
+impl From<FanSource> for FanSourceName {
+    fn from(source: FanSource) -> Self {
+        match source {
+            FanSource::Channel(0) => FanSourceName::Channel0,
+            // Only channel 1 remains, the parser accepts no others
+            FanSource::Channel(_) => FanSourceName::Channel1,
+            FanSource::Max => FanSourceName::Max,
+            FanSource::Sum => FanSourceName::Sum,
+            FanSource::Temperature => FanSourceName::Temperature,
+        }
+    }
+}
+
+/// Reporting of a newly connected session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportOnConnect {
+    /// Reporting is off until `report mode on`
+    Off,
+    /// Reporting starts on
+    On,
+    /// Reporting is off, one report is sent right away
+    Snapshot,
+}
+
+/// Encoding of reports sent to a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Json,
+    /// One line per channel, columns as in `channels::REPORT_CSV_HEADER`
+    Csv,
+}
+
+/// How ADC conversion results are consumed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AdcReadMode {
+    /// Every conversion result is used for measurement and control
+    Continuous,
+    /// The ADC keeps converting, but only the latest result is
+    /// consumed on an explicit `adc <0-1> read`
+    OnDemand,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Quit,
@@ -145,10 +395,52 @@ pub enum Command {
     Save {
         channel: Option<usize>,
     },
+    /// Save the settings of both channels, the network, the fan and
+    /// the temperature unit
+    SaveAll,
+    FlashVerify {
+        channel: usize,
+    },
+    /// Show which settings differ from the config saved in flash
+    Diff {
+        channel: usize,
+    },
     Reset,
+    /// Erase all settings saved in flash, then reset
+    FactoryReset,
     Ipv4(Ipv4Config),
+    /// Switch to DHCP, keeping the static address as fallback
+    Ipv4Dhcp,
+    /// Set the TCP port to listen on
+    Port(u16),
     Show(ShowCommand),
     Reporting(bool),
+    ReportOnConnect(ReportOnConnect),
+    ReportFormat(ReportFormat),
+    /// Report every interval in ms instead of on new samples, 0 to
+    /// report on new samples again
+    ReportInterval(u32),
+    /// Send reports to a UDP endpoint, `None` to stop
+    Telemetry(Option<UdpTelemetry>),
+    /// Publish reports to an MQTT broker, `None` to stop
+    Mqtt(Option<MqttConfig>),
+    /// Synchronize the time with an SNTP server, `None` to stop
+    Ntp(Option<[u8; 4]>),
+    /// MAC address used from the next boot on, `None` for the one
+    /// in the EEPROM
+    Mac(Option<[u8; 6]>),
+    /// Close TCP sessions idle for this many seconds, `None` to keep
+    /// them open
+    TcpTimeout(Option<u32>),
+    /// Mirror log records to this TCP session
+    LogToTcp(bool),
+    Stage(StageCommand),
+    /// Stream the configuration of all channels, network and fan as
+    /// one JSON document
+    ConfigExport,
+    /// Read such a document from the following lines, up to a line
+    /// `end`
+    ConfigImport,
     /// PWM parameter setting
     Pwm {
         channel: usize,
@@ -159,6 +451,19 @@ pub enum Command {
     PwmPid {
         channel: usize,
     },
+    PwmRaw {
+        channel: usize,
+    },
+    /// Ramp `i_set` up over `duration` ms after power up, 0 to disable
+    PwmSoftStart {
+        channel: usize,
+        duration: u32,
+    },
+    /// Negate the output of a TEC that is wired backwards
+    PwmInvert {
+        channel: usize,
+        invert: bool,
+    },
     CenterPoint {
         channel: usize,
         center: CenterPoint,
@@ -169,15 +474,182 @@ pub enum Command {
         parameter: PidParameter,
         value: f64,
     },
+    /// Parameter of the set used above the target
+    PidCool {
+        channel: usize,
+        parameter: PidParameter,
+        value: f64,
+    },
+    /// Use the same parameters for heating and cooling again
+    PidCoolOff {
+        channel: usize,
+    },
+    PidReset {
+        channel: usize,
+    },
+    /// Show the P, I and D contributions to the last output
+    PidTerms {
+        channel: usize,
+    },
+    PidAutotune {
+        channel: usize,
+        amplitude: f64,
+    },
+    /// Approach `target` at `rate` per second
+    PidRamp {
+        channel: usize,
+        target: f64,
+        rate: f64,
+    },
+    PidSetpointSource {
+        channel: usize,
+        source: SetpointSource,
+    },
+    TecPeltier {
+        channel: usize,
+        coefficient: f64,
+    },
+    TecReset {
+        channel: usize,
+    },
+    /// Set a hard temperature limit in °C or a runaway detection
+    /// parameter, `None` to remove it
+    TempLimit {
+        channel: usize,
+        limit: TempLimit,
+        value: Option<f64>,
+    },
+    /// Select the controller and engage closed-loop control,
+    /// hysteresis thresholds are in the temperature unit
+    ControlMode {
+        channel: usize,
+        mode: ControlMode,
+    },
+    /// Stop or resume the ADC conversions and output of a channel
+    ChannelEnable {
+        channel: usize,
+        enabled: bool,
+    },
+    /// Clear a latched temperature limit fault
+    ClearFault {
+        channel: usize,
+    },
+    /// Temperature statistics since the last reset
+    Stats {
+        channel: usize,
+    },
+    StatsReset {
+        channel: usize,
+    },
+    /// Temperature profile of a channel
+    Profile {
+        channel: usize,
+        command: ProfileCommand,
+    },
+    /// Power down and restore the boot-time PID, Steinhart-Hart,
+    /// center point and PWM limit settings, without touching flash
+    Defaults {
+        channel: usize,
+    },
+    /// Override a constant of the analog front end
+    Cal {
+        parameter: HardwareParameter,
+        value: f64,
+    },
+    /// Restore the nominal constants of the analog front end
+    CalDefaults,
+    /// Calibrate the DAC center anew and save it, or with `reset`
+    /// forget the saved value so that boot calibrates again
+    CalDac {
+        channel: usize,
+        reset: bool,
+    },
     SteinhartHart {
         channel: usize,
         parameter: ShParameter,
         value: f64,
     },
+    TempCorrection {
+        channel: usize,
+        gain: f64,
+        offset: f64,
+    },
+    /// Pairs of resistance in Ω and temperature in °C
+    ShCalibrate {
+        channel: usize,
+        points: [(f64, f64); 3],
+    },
+    SensorType {
+        channel: usize,
+        sensor: SensorType,
+    },
+    /// Unit of reported temperatures and temperature inputs
+    TempUnit(TempUnit),
+    /// AD5680 code, bypassing the current model
+    DacRaw {
+        channel: usize,
+        value: u32,
+    },
+    /// Check DAC writes against the feedback input
+    DacVerify {
+        channel: usize,
+        verify: bool,
+    },
+    Rtd {
+        channel: usize,
+        parameter: RtdParameter,
+        value: f64,
+    },
     PostFilter {
         channel: usize,
         rate: Option<f32>,
     },
+    /// Sinc5 + sinc1 output data rate in SPS
+    Odr {
+        channel: usize,
+        rate: f32,
+    },
+    /// Output data rate in SPS by either a postfilter or the ODR
+    AdcRate {
+        channel: usize,
+        rate: f32,
+    },
+    AdcReadMode {
+        channel: usize,
+        mode: AdcReadMode,
+    },
+    /// Consume the latest ADC sample in `AdcReadMode::OnDemand`
+    AdcRead {
+        channel: usize,
+    },
+    /// Measure the noise floor over a block of ADC samples
+    AdcNoiseFloor {
+        channel: usize,
+    },
+    /// Reference voltage of the ADC conversions
+    AdcRefSource {
+        channel: usize,
+        ref_source: RefSource,
+    },
+    AdcFilter {
+        channel: usize,
+        filter: Filter,
+    },
+    /// Bipolar coding for differential inputs that swing negative
+    AdcBipolar {
+        channel: usize,
+        bipolar: bool,
+    },
+    /// SPI checksum of the ADC, shared by both channels
+    AdcChecksum(ChecksumMode),
+    /// Conversions averaged per read of the stm32f4 integrated adc
+    AdcAverage(u32),
+    /// Relative deviation from the nominal sample interval beyond
+    /// which samples are not used for control, `0` to disable
+    AdcIntervalTolerance {
+        channel: usize,
+        tolerance: f64,
+    },
     Dfu,
     FanSet {
         fan_pwm: u32
@@ -190,7 +662,25 @@ pub enum Command {
         k_c: f32,
     },
     FanCurveDefaults,
+    FanSource(FanSource),
+    FanFreq {
+        freq_hz: u32,
+    },
+    /// Save the fan settings to flash, applied at boot
+    FanSave,
     ShowHWRev,
+    /// Firmware version, commit and hardware revision
+    Version,
+    /// Milliseconds since boot
+    Uptime,
+    /// Network and SPI error counters
+    Netstat,
+    /// Show the watchdog interval and longest main loop iteration
+    Watchdog,
+    /// Restart the watchdog with an interval in ms
+    WatchdogInterval(u32),
+    /// List help topics, or the commands of one
+    Help(Option<&'static str>),
 }
 
 fn end(input: &[u8]) -> IResult<&[u8], ()> {
@@ -222,11 +712,19 @@ fn unsigned(input: &[u8]) -> IResult<&[u8], Result<u32, Error>> {
 fn float(input: &[u8]) -> IResult<&[u8], Result<f64, Error>> {
     let (input, sign) = opt(is_a("-"))(input)?;
     let negative = sign.is_some();
-    let (input, digits) = take_while1(|c| is_digit(c) || c == '.' as u8)(input)?;
+    let (input, digits) = recognize(tuple((
+        take_while1(|c| is_digit(c) || c == '.' as u8),
+        // optional exponent, left for `f64::from_str` to validate
+        opt(tuple((
+            one_of("eE"),
+            opt(one_of("+-")),
+            take_while(is_digit),
+        ))),
+    )))(input)?;
     let result =
         from_utf8(digits)
         .map_err(|e| e.into())
-        .and_then(|digits| f64::from_str_radix(digits, 10)
+        .and_then(|digits| f64::from_str(digits)
                   .map_err(|e| e.into())
         )
         .map(|result: f64| if negative { -result } else { result });
@@ -261,12 +759,125 @@ fn report(input: &[u8]) -> IResult<&[u8], Command> {
                         value(Command::Show(ShowCommand::Reporting), end)
                     ))
                 )),
+            preceded(
+                whitespace,
+                preceded(
+                    tag("connect"),
+                    preceded(
+                        whitespace,
+                        // `report connect <off | on | snapshot>` - Reporting of new sessions
+                        map(
+                            alt((
+                                value(ReportOnConnect::Off, tag("off")),
+                                value(ReportOnConnect::On, tag("on")),
+                                value(ReportOnConnect::Snapshot, tag("snapshot")),
+                            )),
+                            Command::ReportOnConnect
+                        )
+                    )
+                )),
+            preceded(
+                whitespace,
+                preceded(
+                    tag("interval"),
+                    preceded(
+                        whitespace,
+                        // `report interval <ms>` - Periodic reporting
+                        |input| {
+                            let (rest, digits) = take_while1(is_digit)(input)?;
+                            let interval = from_utf8(digits).ok()
+                                .and_then(|digits| u32::from_str_radix(digits, 10).ok())
+                                .ok_or(nom::Err::Error((input, ErrorKind::Digit)))?;
+                            let (rest, _) = end(rest)?;
+                            Ok((rest, Command::ReportInterval(interval)))
+                        }
+                    )
+                )),
+            preceded(
+                whitespace,
+                preceded(
+                    tag("format"),
+                    preceded(
+                        whitespace,
+                        // `report format <json | csv>` - Encoding of reports
+                        map(
+                            alt((
+                                value(ReportFormat::Json, tag("json")),
+                                value(ReportFormat::Csv, tag("csv")),
+                            )),
+                            Command::ReportFormat
+                        )
+                    )
+                )),
+            preceded(
+                whitespace,
+                // `report <0 | 1>` - Report one channel once
+                map(
+                    |input| {
+                        let (input, channel) = channel(input)?;
+                        let (input, _) = end(input)?;
+                        Ok((input, channel))
+                    },
+                    |channel| Command::Show(ShowCommand::ChannelReport(channel))
+                )
+            ),
             // `report` - Report once
             value(Command::Show(ShowCommand::Input), end)
         ))
     )(input)
 }
 
+/// `help` | `help <topic>`
+fn help(input: &[u8]) -> IResult<&[u8], Command> {
+    let (input, _) = tag("help")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (rest, name) = take_while1(|c| c != b' ')(input)?;
+            let topic = help::topic(name)
+                .ok_or(nom::Err::Error((input, ErrorKind::Tag)))?;
+            let (rest, _) = end(rest)?;
+            Ok((rest, Command::Help(Some(topic))))
+        },
+        value(Command::Help(None), end),
+    ))(input)
+}
+
+/// `log to_tcp <on | off>`
+fn log(input: &[u8]) -> IResult<&[u8], Command> {
+    let (input, _) = tag("log")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("to_tcp")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, enabled) = off_on(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Command::LogToTcp(enabled)))
+}
+
+/// `stage <begin|commit|abort>`
+fn stage(input: &[u8]) -> IResult<&[u8], Command> {
+    let (input, _) = tag("stage")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, command) = alt((
+        value(StageCommand::Begin, tag("begin")),
+        value(StageCommand::Commit, tag("commit")),
+        value(StageCommand::Abort, tag("abort")),
+    ))(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Command::Stage(command)))
+}
+
+fn config(input: &[u8]) -> IResult<&[u8], Command> {
+    let (input, _) = tag("config")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, command) = alt((
+        value(Command::ConfigExport, tag("export")),
+        value(Command::ConfigImport, tag("import")),
+    ))(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, command))
+}
+
 fn pwm_setup(input: &[u8]) -> IResult<&[u8], Result<(PwmPin, f64), Error>> {
     let result_with_pin = |pin: PwmPin|
         move |result: Result<f64, Error>|
@@ -333,6 +944,21 @@ fn pwm(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
                     let (input, ()) = pwm_pid(input)?;
                     Ok((input, Ok(Command::PwmPid { channel })))
                 },
+                value(Ok(Command::PwmRaw { channel }), tag("raw")),
+                |input| {
+                    let (input, _) = tag("softstart")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, duration) = unsigned(input)?;
+                    let result = duration
+                        .map(|duration| Command::PwmSoftStart { channel, duration });
+                    Ok((input, result))
+                },
+                |input| {
+                    let (input, _) = tag("invert")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, invert) = off_on(input)?;
+                    Ok((input, Ok(Command::PwmInvert { channel, invert })))
+                },
                 |input| {
                     let (input, config) = pwm_setup(input)?;
                     match config {
@@ -373,28 +999,127 @@ fn center_point(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
 fn pid_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, channel) = channel(input)?;
     let (input, _) = whitespace(input)?;
+    let (input, (parameter, value)) = pid_parameter_value(input)?;
+    let result = value
+        .map(|value| Command::Pid { channel, parameter, value });
+    Ok((input, result))
+}
+
+/// `pid <0-1> heat <parameter> <value>` | `pid <0-1> cool <parameter> <value>` | `pid <0-1> cool off`
+fn pid_gain_schedule(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        move |input| {
+            let (input, _) = tag("heat")(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, (parameter, value)) = pid_parameter_value(input)?;
+            Ok((input, value.map(|value| Command::Pid { channel, parameter, value })))
+        },
+        move |input| {
+            let (input, _) = tag("cool")(input)?;
+            let (input, _) = whitespace(input)?;
+            alt((
+                value(Ok(Command::PidCoolOff { channel }), tag("off")),
+                move |input| {
+                    let (input, (parameter, value)) = pid_parameter_value(input)?;
+                    Ok((input, value.map(|value| Command::PidCool { channel, parameter, value })))
+                },
+            ))(input)
+        },
+    ))(input)
+}
+
+/// `<parameter> <value>` of `pid`
+fn pid_parameter_value(input: &[u8]) -> IResult<&[u8], (PidParameter, Result<f64, Error>)> {
     let (input, parameter) =
         alt((value(PidParameter::Target, tag("target")),
              value(PidParameter::KP, tag("kp")),
              value(PidParameter::KI, tag("ki")),
+             value(PidParameter::KdFilter, tag("kd_filter")),
              value(PidParameter::KD, tag("kd")),
              value(PidParameter::OutputMin, tag("output_min")),
              value(PidParameter::OutputMax, tag("output_max")),
+             value(PidParameter::IntegralMin, tag("integral_min")),
+             value(PidParameter::IntegralMax, tag("integral_max")),
+             value(PidParameter::Slew, tag("slew")),
+             value(PidParameter::FeedForwardTarget, tag("ff_target")),
+             value(PidParameter::FeedForward, tag("ff")),
+             value(PidParameter::SetpointScale, tag("setpoint_scale")),
+             value(PidParameter::SetpointOffset, tag("setpoint_offset")),
         ))(input)?;
     let (input, _) = whitespace(input)?;
     let (input, value) = float(input)?;
-    let result = value
-        .map(|value| Command::Pid { channel, parameter, value });
+    Ok((input, (parameter, value)))
+}
+
+/// `pid <0-1> reset`
+fn pid_reset(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("reset")(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(Command::PidReset { channel })))
+}
+
+/// `pid <0-1> terms`
+fn pid_terms(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("terms")(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(Command::PidTerms { channel })))
+}
+
+/// `pid <0-1> autotune <amplitude>`
+fn pid_autotune(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("autotune")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, amplitude) = float(input)?;
+    let result = amplitude
+        .map(|amplitude| Command::PidAutotune { channel, amplitude });
+    Ok((input, result))
+}
+
+/// `pid <0-1> ramp <target> <rate>`
+fn pid_ramp(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("ramp")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, target) = float(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, rate) = float(input)?;
+    let result = target.and_then(|target| rate.map(|rate|
+        Command::PidRamp { channel, target, rate }
+    ));
     Ok((input, result))
 }
 
-/// `pid` | `pid <pid_parameter>`
+/// `pid <0-1> setpoint_source <internal|analog>`
+fn pid_setpoint_source(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("setpoint_source")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, source) = alt((
+        value(SetpointSource::Internal, tag("internal")),
+        value(SetpointSource::Analog, tag("analog")),
+    ))(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(Command::PidSetpointSource { channel, source })))
+}
+
+/// `pid` | `pid <pid_parameter>` | `pid <0-1> reset` | `pid <0-1> terms` | `pid <0-1> autotune <amplitude>`
+/// | `pid <0-1> ramp <target> <rate>` | `pid <0-1> setpoint_source <internal|analog>`
 fn pid(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, _) = tag("pid")(input)?;
     alt((
         preceded(
             whitespace,
-            pid_parameter
+            alt((pid_reset, pid_terms, pid_autotune, pid_ramp, pid_setpoint_source, pid_gain_schedule, pid_parameter))
         ),
         value(Ok(Command::Show(ShowCommand::Pid)), end)
     ))(input)
@@ -406,8 +1131,14 @@ fn steinhart_hart_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Erro
     let (input, _) = whitespace(input)?;
     let (input, parameter) =
         alt((value(ShParameter::T0, tag("t0")),
+             value(ShParameter::B3, tag("b3")),
              value(ShParameter::B, tag("b")),
-             value(ShParameter::R0, tag("r0"))
+             value(ShParameter::R0, tag("r0")),
+             value(ShParameter::TMin, tag("t_min")),
+             value(ShParameter::TMax, tag("t_max")),
+             value(ShParameter::FaultHoldoff, tag("fault_holdoff")),
+             value(ShParameter::A, tag("a")),
+             value(ShParameter::C, tag("c")),
         ))(input)?;
     let (input, _) = whitespace(input)?;
     let (input, value) = float(input)?;
@@ -416,20 +1147,58 @@ fn steinhart_hart_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Erro
     Ok((input, result))
 }
 
-/// `s-h` | `s-h <steinhart_hart_parameter>`
+/// `s-h <0-1> temp_correction <gain> <offset>`
+fn steinhart_hart_temp_correction(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("temp_correction")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, gain) = float(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, offset) = float(input)?;
+    let result = gain.and_then(|gain| offset.map(|offset|
+        Command::TempCorrection { channel, gain, offset }
+    ));
+    Ok((input, result))
+}
+
+/// `s-h <0-1> calibrate <r1> <t1> <r2> <t2> <r3> <t3>`
+fn steinhart_hart_calibrate(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (mut input, _) = tag("calibrate")(input)?;
+    let mut points = [(0.0, 0.0); 3];
+    for point in points.iter_mut() {
+        let (rest, _) = whitespace(input)?;
+        let (rest, r) = float(rest)?;
+        let (rest, _) = whitespace(rest)?;
+        let (rest, t) = float(rest)?;
+        input = rest;
+        match r.and_then(|r| t.map(|t| (r, t))) {
+            Ok(result) => *point = result,
+            Err(e) => return Ok((input, Err(e))),
+        }
+    }
+    Ok((input, Ok(Command::ShCalibrate { channel, points })))
+}
+
+/// `s-h` | `s-h <steinhart_hart_parameter>` | `s-h <0-1> temp_correction <gain> <offset>`
+/// | `s-h <0-1> calibrate <r1> <t1> <r2> <t2> <r3> <t3>`
 fn steinhart_hart(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, _) = tag("s-h")(input)?;
     alt((
         preceded(
             whitespace,
-            steinhart_hart_parameter
+            alt((steinhart_hart_temp_correction, steinhart_hart_calibrate, steinhart_hart_parameter))
         ),
         value(Ok(Command::Show(ShowCommand::SteinhartHart)), end)
     ))(input)
 }
 
-fn postfilter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("postfilter")(input)?;
+/// `sensor` | `sensor <0-1> type <thermistor|rtd>`
+/// | `sensor <0-1> <r0|a|b|c> <value>`
+fn sensor(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("sensor")(input)?;
     alt((
         preceded(
             whitespace,
@@ -437,15 +1206,54 @@ fn postfilter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
                 let (input, channel) = channel(input)?;
                 let (input, _) = whitespace(input)?;
                 alt((
-                    value(Ok(Command::PostFilter {
-                        channel,
-                        rate: None,
-                    }), tag("off")),
                     move |input| {
-                        let (input, _) = tag("rate")(input)?;
+                        let (input, _) = tag("type")(input)?;
                         let (input, _) = whitespace(input)?;
-                        let (input, rate) = float(input)?;
-                        let result = rate
+                        let (input, sensor) = alt((
+                            value(SensorType::Thermistor, tag("thermistor")),
+                            value(SensorType::Rtd, tag("rtd")),
+                        ))(input)?;
+                        end(input)?;
+                        Ok((input, Ok(Command::SensorType { channel, sensor })))
+                    },
+                    move |input| {
+                        let (input, parameter) = alt((
+                            value(RtdParameter::R0, tag("r0")),
+                            value(RtdParameter::A, tag("a")),
+                            value(RtdParameter::B, tag("b")),
+                            value(RtdParameter::C, tag("c")),
+                        ))(input)?;
+                        let (input, _) = whitespace(input)?;
+                        let (input, value) = float(input)?;
+                        let result = value
+                            .map(|value| Command::Rtd { channel, parameter, value });
+                        Ok((input, result))
+                    },
+                ))(input)
+            }
+        ),
+        value(Ok(Command::Show(ShowCommand::Sensor)), end)
+    ))(input)
+}
+
+fn postfilter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("postfilter")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            |input| {
+                let (input, channel) = channel(input)?;
+                let (input, _) = whitespace(input)?;
+                alt((
+                    value(Ok(Command::PostFilter {
+                        channel,
+                        rate: None,
+                    }), tag("off")),
+                    move |input| {
+                        let (input, _) = tag("rate")(input)?;
+                        let (input, _) = whitespace(input)?;
+                        let (input, rate) = float(input)?;
+                        let result = rate
                             .map(|rate| Command::PostFilter {
                                 channel,
                                 rate: Some(rate as f32),
@@ -459,6 +1267,396 @@ fn postfilter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     ))(input)
 }
 
+/// `odr <0-1> <rate>`
+fn odr(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("odr")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, rate) = float(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, rate.map(|rate| Command::Odr {
+        channel,
+        rate: rate as f32,
+    })))
+}
+
+/// `sinc5` | `sinc3` | `sinc3 <decimation>`
+fn adc_filter(input: &[u8]) -> IResult<&[u8], Result<Filter, Error>> {
+    alt((
+        value(Ok(Filter::Sinc5Sinc1), tag("sinc5")),
+        |input| {
+            let (input, _) = tag("sinc3")(input)?;
+            alt((
+                |input| {
+                    let (input, _) = whitespace(input)?;
+                    let (input, digits) = take_while1(is_digit)(input)?;
+                    let result = from_utf8(digits)
+                        .map_err(|e| e.into())
+                        .and_then(|digits| u16::from_str_radix(digits, 10)
+                                  .map_err(|e| e.into())
+                        )
+                        .map(Filter::Sinc3Map);
+                    Ok((input, result))
+                },
+                value(Ok(Filter::Sinc3), end),
+            ))(input)
+        },
+    ))(input)
+}
+
+/// `adc` | `adc <0-1> mode <continuous|on_demand>` | `adc <0-1> read` |
+/// `adc <0-1> interval_tolerance <ratio>` |
+/// `adc <0-1> refsource <external|internal|avdd>` | `adc <0-1> bipolar <on|off>` |
+/// `adc <0-1> filter <sinc5|sinc3> [decimation]` | `adc checksum <off|xor|crc>`
+fn adc(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("adc")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            |input| {
+                let (input, _) = tag("checksum")(input)?;
+                let (input, _) = whitespace(input)?;
+                let (input, mode) = alt((
+                    value(ChecksumMode::Off, tag("off")),
+                    value(ChecksumMode::Xor, tag("xor")),
+                    value(ChecksumMode::Crc, tag("crc")),
+                ))(input)?;
+                end(input)?;
+                Ok((input, Ok(Command::AdcChecksum(mode))))
+            }
+        ),
+        preceded(
+            whitespace,
+            |input| {
+                let (input, _) = tag("avg")(input)?;
+                let (input, _) = whitespace(input)?;
+                let (input, samples) = unsigned(input)?;
+                end(input)?;
+                Ok((input, samples.map(Command::AdcAverage)))
+            }
+        ),
+        preceded(
+            whitespace,
+            |input| {
+                let (input, channel) = channel(input)?;
+                let (input, _) = whitespace(input)?;
+                let (input, result) = alt((
+                    |input| {
+                        let (input, _) = tag("mode")(input)?;
+                        let (input, _) = whitespace(input)?;
+                        let (input, mode) = alt((
+                            value(AdcReadMode::Continuous, tag("continuous")),
+                            value(AdcReadMode::OnDemand, tag("on_demand")),
+                        ))(input)?;
+                        Ok((input, Ok(Command::AdcReadMode { channel, mode })))
+                    },
+                    value(Ok(Command::AdcRead { channel }), tag("read")),
+                    value(Ok(Command::AdcNoiseFloor { channel }), tag("noisefloor")),
+                    |input| {
+                        let (input, _) = tag("filter")(input)?;
+                        let (input, _) = whitespace(input)?;
+                        let (input, filter) = adc_filter(input)?;
+                        Ok((input, filter.map(|filter| Command::AdcFilter { channel, filter })))
+                    },
+                    |input| {
+                        let (input, _) = tag("bipolar")(input)?;
+                        let (input, _) = whitespace(input)?;
+                        let (input, bipolar) = off_on(input)?;
+                        Ok((input, Ok(Command::AdcBipolar { channel, bipolar })))
+                    },
+                    |input| {
+                        let (input, _) = tag("refsource")(input)?;
+                        let (input, _) = whitespace(input)?;
+                        let (input, ref_source) = alt((
+                            value(RefSource::External, tag("external")),
+                            value(RefSource::Internal, tag("internal")),
+                            value(RefSource::Avdd1MinusAvss, tag("avdd")),
+                        ))(input)?;
+                        Ok((input, Ok(Command::AdcRefSource { channel, ref_source })))
+                    },
+                    |input| {
+                        let (input, _) = tag("rate")(input)?;
+                        let (input, _) = whitespace(input)?;
+                        let (input, rate) = float(input)?;
+                        Ok((input, rate.map(|rate| Command::AdcRate { channel, rate: rate as f32 })))
+                    },
+                    |input| {
+                        let (input, _) = tag("interval_tolerance")(input)?;
+                        let (input, _) = whitespace(input)?;
+                        let (input, tolerance) = float(input)?;
+                        let result = tolerance
+                            .map(|tolerance| Command::AdcIntervalTolerance { channel, tolerance });
+                        Ok((input, result))
+                    },
+                ))(input)?;
+                end(input)?;
+                Ok((input, result))
+            }
+        ),
+        value(Ok(Command::Show(ShowCommand::Adc)), end)
+    ))(input)
+}
+
+/// `tec` | `tec <0-1> peltier <coefficient>` | `tec <0-1> reset`
+fn tec(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("tec")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            |input| {
+                let (input, channel) = channel(input)?;
+                let (input, _) = whitespace(input)?;
+                let (input, result) = alt((
+                    |input| {
+                        let (input, _) = tag("peltier")(input)?;
+                        let (input, _) = whitespace(input)?;
+                        let (input, coefficient) = float(input)?;
+                        let result = coefficient
+                            .map(|coefficient| Command::TecPeltier { channel, coefficient });
+                        Ok((input, result))
+                    },
+                    value(Ok(Command::TecReset { channel }), tag("reset")),
+                ))(input)?;
+                end(input)?;
+                Ok((input, result))
+            }
+        ),
+        value(Ok(Command::Show(ShowCommand::Tec)), end)
+    ))(input)
+}
+
+/// `limit <ch> <temp_min|temp_max> <degC|off>` |
+/// `limit <ch> <runaway_window|runaway_slope> <value|off>`
+fn limit(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("limit")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            |input| {
+                let (input, channel) = channel(input)?;
+                let (input, _) = whitespace(input)?;
+                let (input, limit) = alt((
+                    value(TempLimit::Min, tag("temp_min")),
+                    value(TempLimit::Max, tag("temp_max")),
+                    value(TempLimit::RunawayWindow, tag("runaway_window")),
+                    value(TempLimit::RunawaySlope, tag("runaway_slope")),
+                ))(input)?;
+                let (input, _) = whitespace(input)?;
+                let (input, value) = alt((
+                    value(Ok(None), tag("off")),
+                    map(float, |value| value.map(Some)),
+                ))(input)?;
+                end(input)?;
+                let result = value
+                    .map(|value| Command::TempLimit { channel, limit, value });
+                Ok((input, result))
+            }
+        ),
+        value(Ok(Command::Show(ShowCommand::TempLimits)), end)
+    ))(input)
+}
+
+fn clear(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("clear")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(Command::ClearFault { channel })))
+}
+
+/// `profile <0-1>` | `profile <0-1> add <target> <rate> <hold_ms>` |
+/// `profile <0-1> <clear|start|stop>` | `profile <0-1> mode <once|loop>`
+fn profile(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("profile")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, result) = alt((
+        preceded(
+            whitespace,
+            alt((
+                |input| {
+                    let (input, _) = tag("add")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, target) = float(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, rate) = float(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, hold_ms) = unsigned(input)?;
+                    let result = target.and_then(|target| rate.and_then(|rate| hold_ms.map(|hold_ms|
+                        ProfileCommand::Add { target, rate, hold_ms }
+                    )));
+                    Ok((input, result))
+                },
+                |input| {
+                    let (input, _) = tag("mode")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, mode) = alt((
+                        value(ProfileMode::Once, tag("once")),
+                        value(ProfileMode::Loop, tag("loop")),
+                    ))(input)?;
+                    Ok((input, Ok(ProfileCommand::Mode(mode))))
+                },
+                value(Ok(ProfileCommand::Clear), tag("clear")),
+                value(Ok(ProfileCommand::Start), tag("start")),
+                value(Ok(ProfileCommand::Stop), tag("stop")),
+            ))
+        ),
+        value(Ok(ProfileCommand::Show), end),
+    ))(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, result.map(|command| Command::Profile { channel, command })))
+}
+
+/// `stats <0-1>` | `stats <0-1> reset`
+fn stats(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("stats")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, command) = alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, _) = tag("reset")(input)?;
+            Ok((input, Command::StatsReset { channel }))
+        },
+        value(Command::Stats { channel }, end),
+    ))(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(command)))
+}
+
+/// `mode <0-1> pid` | `mode <0-1> hysteresis <low> <high> [heat]`
+fn control_mode(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("mode")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, mode) = alt((
+        value(Ok(ControlMode::Pid), tag("pid")),
+        |input| {
+            let (input, _) = tag("hysteresis")(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, low) = float(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, high) = float(input)?;
+            let (input, heat) = alt((
+                value(true, preceded(whitespace, tag("heat"))),
+                value(false, tag("")),
+            ))(input)?;
+            let result = low.and_then(|low| high.map(|high| ControlMode::Hysteresis(Hysteresis {
+                low: low as f32,
+                high: high as f32,
+                heat,
+            })));
+            Ok((input, result))
+        },
+    ))(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, mode.map(|mode| Command::ControlMode { channel, mode })))
+}
+
+/// `channel <0-1> <enable|disable>`
+fn channel_enable(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("channel")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, enabled) = alt((
+        value(true, tag("enable")),
+        value(false, tag("disable")),
+    ))(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(Command::ChannelEnable { channel, enabled })))
+}
+
+fn defaults(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("defaults")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(Command::Defaults { channel })))
+}
+
+/// `units` | `units temp <celsius|kelvin|fahrenheit>`
+fn units(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("units")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, _) = tag("temp")(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, unit) = alt((
+                value(TempUnit::Celsius, tag("celsius")),
+                value(TempUnit::Kelvin, tag("kelvin")),
+                value(TempUnit::Fahrenheit, tag("fahrenheit")),
+            ))(input)?;
+            let (input, _) = end(input)?;
+            Ok((input, Ok(Command::TempUnit(unit))))
+        },
+        value(Ok(Command::Show(ShowCommand::Units)), end)
+    ))(input)
+}
+
+/// `dac <0-1> raw <0-262143>`
+fn dac(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("dac")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, result) = alt((
+        |input| {
+            let (input, _) = tag("raw")(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, value) = unsigned(input)?;
+            Ok((input, value.map(|value| Command::DacRaw { channel, value })))
+        },
+        |input| {
+            let (input, _) = tag("verify")(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, verify) = off_on(input)?;
+            Ok((input, Ok(Command::DacVerify { channel, verify })))
+        },
+    ))(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, result))
+}
+
+fn cal(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("cal")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            alt((
+                value(Ok(Command::CalDefaults), preceded(tag("default"), end)),
+                |input| {
+                    let (input, _) = tag("dac")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, channel) = channel(input)?;
+                    let (input, reset) = alt((
+                        value(true, preceded(whitespace, tag("reset"))),
+                        value(false, tag("")),
+                    ))(input)?;
+                    end(input)?;
+                    Ok((input, Ok(Command::CalDac { channel, reset })))
+                },
+                |input| {
+                    let (input, parameter) = alt((
+                        value(HardwareParameter::RSense, tag("rsense")),
+                        value(HardwareParameter::VRef, tag("v_ref")),
+                        value(HardwareParameter::RInner, tag("r_inner")),
+                        value(HardwareParameter::VRefSens, tag("vref_sens")),
+                    ))(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, value) = float(input)?;
+                    end(input)?;
+                    Ok((input, value.map(|value| Command::Cal { parameter, value })))
+                },
+            ))
+        ),
+        value(Ok(Command::Show(ShowCommand::HardwareParams)), end)
+    ))(input)
+}
+
 fn load(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, _) = tag("load")(input)?;
     let (input, channel) = alt((
@@ -477,18 +1675,43 @@ fn load(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
 
 fn save(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, _) = tag("save")(input)?;
-    let (input, channel) = alt((
+    let (input, command) = alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, _) = tag("all")(input)?;
+            let (input, _) = end(input)?;
+            Ok((input, Command::SaveAll))
+        },
         |input| {
             let (input, _) = whitespace(input)?;
             let (input, channel) = channel(input)?;
             let (input, _) = end(input)?;
-            Ok((input, Some(channel)))
+            Ok((input, Command::Save { channel: Some(channel) }))
         },
-        value(None, end)
+        value(Command::Save { channel: None }, end)
     ))(input)?;
 
-    let result = Ok(Command::Save { channel });
-    Ok((input, result))
+    Ok((input, Ok(command)))
+}
+
+/// `flash verify <0-1>`
+fn flash(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("flash")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("verify")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(Command::FlashVerify { channel })))
+}
+
+/// `diff <0-1>`
+fn diff(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("diff")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(Command::Diff { channel })))
 }
 
 fn ipv4_addr(input: &[u8]) -> IResult<&[u8], Result<[u8; 4], Error>> {
@@ -506,6 +1729,12 @@ fn ipv4_addr(input: &[u8]) -> IResult<&[u8], Result<[u8; 4], Error>> {
 fn ipv4(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, _) = tag("ipv4")(input)?;
     alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, _) = tag("dhcp")(input)?;
+            let (input, _) = end(input)?;
+            Ok((input, Ok(Command::Ipv4Dhcp)))
+        },
         |input| {
             let (input, _) = whitespace(input)?;
             let (input, address) = ipv4_addr(input)?;
@@ -525,6 +1754,7 @@ fn ipv4(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
                     address: address?,
                     mask_len: mask_len? as u8,
                     gateway: gateway?,
+                    dhcp: false,
                 }))
             };
             Ok((input, result()))
@@ -533,6 +1763,203 @@ fn ipv4(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     ))(input)
 }
 
+/// `reset` | `reset factory`
+fn reset(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("reset")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, _) = tag("factory")(input)?;
+            let (input, _) = end(input)?;
+            Ok((input, Ok(Command::FactoryReset)))
+        },
+        value(Ok(Command::Reset), end),
+    ))(input)
+}
+
+/// `port` | `port <n>`
+fn port(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("port")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, digits) = take_while1(is_digit)(input)?;
+            let (input, _) = end(input)?;
+            let result = from_utf8(digits)
+                .map_err(|e| e.into())
+                .and_then(|digits| u16::from_str_radix(digits, 10)
+                          .map_err(|e| e.into())
+                )
+                .map(Command::Port);
+            Ok((input, result))
+        },
+        value(Ok(Command::Show(ShowCommand::Port)), end),
+    ))(input)
+}
+
+/// `telemetry udp <ip> <port> <interval>` | `telemetry off`
+fn telemetry(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("telemetry")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        |input| {
+            let (input, _) = tag("udp")(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, address) = ipv4_addr(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, digits) = take_while1(is_digit)(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, interval) = float(input)?;
+            let (input, _) = end(input)?;
+            let port = from_utf8(digits)
+                .map_err(|e| e.into())
+                .and_then(|digits| u16::from_str_radix(digits, 10)
+                          .map_err(|e| e.into())
+                );
+            let result = address.and_then(|address| port.and_then(|port| interval.map(|interval|
+                Command::Telemetry(Some(UdpTelemetry { address, port, interval }))
+            )));
+            Ok((input, result))
+        },
+        value(Ok(Command::Telemetry(None)), preceded(tag("off"), end)),
+    ))(input)
+}
+
+fn is_topic_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"/-_.".contains(&c)
+}
+
+/// `report udp <ip> <port>` | `report udp off`
+fn report_udp(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("report")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("udp")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        value(Ok(Command::Telemetry(None)), preceded(tag("off"), end)),
+        |input| {
+            let (input, address) = ipv4_addr(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, digits) = take_while1(is_digit)(input)?;
+            let (input, _) = end(input)?;
+            let port = from_utf8(digits)
+                .map_err(|e| e.into())
+                .and_then(|digits| u16::from_str_radix(digits, 10)
+                          .map_err(|e| e.into())
+                );
+            // Interval 0 for every new sample
+            let result = address.and_then(|address| port.map(|port|
+                Command::Telemetry(Some(UdpTelemetry { address, port, interval: 0.0 }))
+            ));
+            Ok((input, result))
+        },
+    ))(input)
+}
+
+/// `mqtt <ip> <port> <prefix>` | `mqtt off`
+fn mqtt(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("mqtt")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        |input| {
+            let (input, address) = ipv4_addr(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, digits) = take_while1(is_digit)(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, prefix) = take_while_m_n(1, MQTT_PREFIX_MAX_LEN, is_topic_char)(input)?;
+            let (input, _) = end(input)?;
+            let port = from_utf8(digits)
+                .map_err(|e| e.into())
+                .and_then(|digits| u16::from_str_radix(digits, 10)
+                          .map_err(|e| e.into())
+                );
+            let result = address.and_then(|address| port.and_then(|port| {
+                // only ASCII after is_topic_char
+                let mut config = MqttConfig { address, port, prefix: String::new() };
+                let _ = config.prefix.push_str(from_utf8(prefix)?);
+                Ok(Command::Mqtt(Some(config)))
+            }));
+            Ok((input, result))
+        },
+        value(Ok(Command::Mqtt(None)), preceded(tag("off"), end)),
+    ))(input)
+}
+
+/// `watchdog` | `watchdog <ms>`
+fn watchdog(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("watchdog")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            |input| {
+                let (input, interval) = unsigned(input)?;
+                let (input, _) = end(input)?;
+                Ok((input, interval.map(Command::WatchdogInterval)))
+            }
+        ),
+        value(Ok(Command::Watchdog), end)
+    ))(input)
+}
+
+/// `xx:xx:xx:xx:xx:xx`
+fn mac_addr(input: &[u8]) -> IResult<&[u8], [u8; 6]> {
+    let mut mac = [0; 6];
+    let mut input = input;
+    for (i, byte) in mac.iter_mut().enumerate() {
+        if i > 0 {
+            input = char(':')(input)?.0;
+        }
+        let (rest, digits) = take_while_m_n(2, 2, is_hex_digit)(input)?;
+        *byte = from_utf8(digits).ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or(nom::Err::Error((input, ErrorKind::HexDigit)))?;
+        input = rest;
+    }
+    Ok((input, mac))
+}
+
+/// `mac <xx:xx:xx:xx:xx:xx>` | `mac default`
+fn mac(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("mac")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, mac) = alt((
+        value(None, tag("default")),
+        map(mac_addr, Some),
+    ))(input)?;
+    let (input, _) = end(input)?;
+    Ok((input, Ok(Command::Mac(mac))))
+}
+
+/// `tcp timeout <s>` | `tcp timeout off`
+fn tcp(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("tcp")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag("timeout")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        value(Ok(Command::TcpTimeout(None)), preceded(tag("off"), end)),
+        |input| {
+            let (input, timeout) = unsigned(input)?;
+            let (input, _) = end(input)?;
+            Ok((input, timeout.map(|timeout| Command::TcpTimeout(Some(timeout)))))
+        },
+    ))(input)
+}
+
+/// `ntp <ip>` | `ntp off`
+fn ntp(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("ntp")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        value(Ok(Command::Ntp(None)), preceded(tag("off"), end)),
+        |input| {
+            let (input, address) = ipv4_addr(input)?;
+            let (input, _) = end(input)?;
+            Ok((input, address.map(|address| Command::Ntp(Some(address)))))
+        },
+    ))(input)
+}
+
 fn fan(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, _) = tag("fan")(input)?;
     alt((
@@ -544,6 +1971,25 @@ fn fan(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
                     let (input, _) = tag("auto")(input)?;
                     Ok((input, Ok(Command::FanAuto)))
                 },
+                |input| {
+                    let (input, _) = tag("source")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, source) = alt((
+                        value(FanSource::Max, tag("max")),
+                        value(FanSource::Max, tag("current")),
+                        value(FanSource::Sum, tag("sum")),
+                        value(FanSource::Temperature, tag("temperature")),
+                        map(channel, FanSource::Channel),
+                    ))(input)?;
+                    Ok((input, Ok(Command::FanSource(source))))
+                },
+                |input| {
+                    let (input, _) = tag("freq")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, freq_hz) = unsigned(input)?;
+                    Ok((input, freq_hz.map(|freq_hz| Command::FanFreq { freq_hz })))
+                },
+                value(Ok(Command::FanSave), tag("save")),
                 |input| {
                     let (input, value) = unsigned(input)?;
                     Ok((input, Ok(Command::FanSet { fan_pwm: value.unwrap_or(0)})))
@@ -585,119 +2031,508 @@ fn fan_curve(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
 }
 
 fn command(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    alt((value(Ok(Command::Quit), tag("quit")),
-         load,
-         save,
-         value(Ok(Command::Reset), tag("reset")),
-         ipv4,
-         map(report, Ok),
-         pwm,
-         center_point,
-         pid,
-         steinhart_hart,
-         postfilter,
-         value(Ok(Command::Dfu), tag("dfu")),
-         fan,
-         fan_curve,
-         value(Ok(Command::ShowHWRev), tag("hwrev")),
+    alt((
+        // before `report`, which would stop after the first word
+        report_udp,
+        alt((value(Ok(Command::Quit), tag("quit")),
+             load,
+             save,
+             flash,
+             diff,
+             reset,
+             ipv4,
+             port,
+             map(report, Ok),
+             telemetry,
+             mqtt,
+             map(log, Ok),
+             map(help, Ok),
+             map(stage, Ok),
+             map(config, Ok),
+             cal,
+             units,
+             value(Ok(Command::Version), tag("version")),
+             value(Ok(Command::Uptime), tag("uptime")),
+             value(Ok(Command::Netstat), tag("netstat")),
+             value(Ok(Command::Show(ShowCommand::All)), tag("all")),
+        )),
+        alt((pwm,
+             center_point,
+             pid,
+             steinhart_hart,
+             sensor,
+             postfilter,
+             odr,
+             value(Ok(Command::Show(ShowCommand::AdcTemperature)), tag("adc_temp")),
+             adc,
+             tec,
+             value(Ok(Command::Dfu), tag("dfu")),
+             fan,
+             fan_curve,
+             value(Ok(Command::ShowHWRev), tag("hwrev")),
+             value(Ok(Command::Show(ShowCommand::Supply)), tag("supply")),
+             limit,
+             clear,
+             dac,
+             defaults,
+             profile,
+             stats,
+        )),
+        channel_enable,
+        control_mode,
+        ntp,
+        watchdog,
+        mac,
+        tcp,
     ))(input)
 }
 
 impl Command {
+    /// Channel parameter settings that can be buffered with `stage`
+    pub fn is_stageable(&self) -> bool {
+        match self {
+            Command::Pwm { .. } |
+            Command::PwmPid { .. } |
+            Command::CenterPoint { .. } |
+            Command::Pid { .. } |
+            Command::PidCool { .. } |
+            Command::PidCoolOff { .. } |
+            Command::SteinhartHart { .. } |
+            Command::TempCorrection { .. } |
+            Command::ShCalibrate { .. } |
+            Command::SensorType { .. } |
+            Command::Rtd { .. } |
+            Command::PwmSoftStart { .. } |
+            Command::PwmInvert { .. } |
+            Command::ControlMode { .. } |
+            Command::PostFilter { .. } |
+            Command::Odr { .. } |
+            Command::AdcRate { .. } => true,
+            _ => false,
+        }
+    }
+
     pub fn parse(input: &[u8]) -> Result<Self, Error> {
         match command(input) {
             Ok((input_remain, result)) if input_remain.len() == 0 =>
                 result,
             Ok((input_remain, _)) =>
-                Err(Error::UnexpectedInput(input_remain[0])),
+                Err(Error::UnexpectedInput {
+                    byte: input_remain[0],
+                    position: input.len() - input_remain.len(),
+                }),
             Err(e) =>
                 Err(e.into()),
         }
     }
-}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_quit() {
+        let command = Command::parse(b"quit");
+        assert_eq!(command, Ok(Command::Quit));
+    }
+
+    #[test]
+    fn parse_load() {
+        let command = Command::parse(b"load");
+        assert_eq!(command, Ok(Command::Load { channel: None }));
+    }
+
+    #[test]
+    fn parse_load_channel() {
+        let command = Command::parse(b"load 0");
+        assert_eq!(command, Ok(Command::Load { channel: Some(0) }));
+    }
+
+    #[test]
+    fn parse_save() {
+        let command = Command::parse(b"save");
+        assert_eq!(command, Ok(Command::Save { channel: None }));
+    }
+
+    #[test]
+    fn parse_save_channel() {
+        let command = Command::parse(b"save 0");
+        assert_eq!(command, Ok(Command::Save { channel: Some(0) }));
+    }
+
+    #[test]
+    fn parse_save_all() {
+        let command = Command::parse(b"save all");
+        assert_eq!(command, Ok(Command::SaveAll));
+    }
+
+    #[test]
+    fn parse_show_ipv4() {
+        let command = Command::parse(b"ipv4");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Ipv4)));
+    }
+
+    #[test]
+    fn parse_flash_verify() {
+        let command = Command::parse(b"flash verify 1");
+        assert_eq!(command, Ok(Command::FlashVerify { channel: 1 }));
+    }
+
+    #[test]
+    fn parse_diff() {
+        let command = Command::parse(b"diff 0");
+        assert_eq!(command, Ok(Command::Diff { channel: 0 }));
+    }
+
+    #[test]
+    fn parse_ipv4() {
+        let command = Command::parse(b"ipv4 192.168.1.26/24");
+        assert_eq!(command, Ok(Command::Ipv4(Ipv4Config {
+            address: [192, 168, 1, 26],
+            mask_len: 24,
+            gateway: None,
+            dhcp: false,
+        })));
+    }
+
+    #[test]
+    fn parse_ipv4_and_gateway() {
+        let command = Command::parse(b"ipv4 10.42.0.126/8 10.1.0.1");
+        assert_eq!(command, Ok(Command::Ipv4(Ipv4Config {
+            address: [10, 42, 0, 126],
+            mask_len: 8,
+            gateway: Some([10, 1, 0, 1]),
+            dhcp: false,
+        })));
+    }
+
+    #[test]
+    fn parse_ipv4_dhcp() {
+        let command = Command::parse(b"ipv4 dhcp");
+        assert_eq!(command, Ok(Command::Ipv4Dhcp));
+    }
+
+    #[test]
+    fn parse_show_port() {
+        let command = Command::parse(b"port");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Port)));
+    }
+
+    #[test]
+    fn parse_port() {
+        let command = Command::parse(b"port 2323");
+        assert_eq!(command, Ok(Command::Port(2323)));
+    }
+
+    #[test]
+    fn parse_port_out_of_range() {
+        let command = Command::parse(b"port 65536");
+        assert!(matches!(command, Err(Error::ParseInt(_))));
+    }
+
+    #[test]
+    fn unexpected_input() {
+        let error = Command::parse(b"ipv4 dhcp x").unwrap_err();
+        assert_eq!(error, Error::UnexpectedInput { byte: b'x', position: 10 });
+        assert_eq!(format!("{}", error), "unexpected input at position 10: 'x'");
+        let error = Command::parse(b"quit\"").unwrap_err();
+        assert_eq!(format!("{}", error), "unexpected input at position 4: 0x22");
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(Command::parse(b"port 65536").unwrap_err().code(), "parse_int");
+        assert_eq!(Command::parse(b"pid 0 kp x").unwrap_err().code(), "unexpected_input");
+    }
+
+    #[test]
+    fn parse_report() {
+        let command = Command::parse(b"report");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Input)));
+    }
+
+    #[test]
+    fn parse_float_exponent() {
+        assert_eq!(float(b"1e3"), Ok((&b""[..], Ok(1000.0))));
+        assert_eq!(float(b"2.5E-2"), Ok((&b""[..], Ok(0.025))));
+        assert_eq!(float(b"-1e+2"), Ok((&b""[..], Ok(-100.0))));
+    }
+
+    #[test]
+    fn parse_float_malformed_exponent() {
+        assert_eq!(float(b"1e"), Ok((&b""[..], Err(Error::ParseFloat))));
+    }
+
+    #[test]
+    fn parse_pid_exponent() {
+        let command = Command::parse(b"pid 0 ki 2e-3");
+        assert_eq!(command, Ok(Command::Pid {
+            channel: 0,
+            parameter: PidParameter::KI,
+            value: 0.002,
+        }));
+    }
+
+    #[test]
+    fn parse_report_interval() {
+        let command = Command::parse(b"report interval 1000");
+        assert_eq!(command, Ok(Command::ReportInterval(1000)));
+    }
+
+    #[test]
+    fn parse_report_format() {
+        let command = Command::parse(b"report format csv");
+        assert_eq!(command, Ok(Command::ReportFormat(ReportFormat::Csv)));
+    }
+
+    #[test]
+    fn parse_report_channel() {
+        let command = Command::parse(b"report 1");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::ChannelReport(1))));
+    }
+
+    #[test]
+    fn parse_report_mode() {
+        let command = Command::parse(b"report mode");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Reporting)));
+    }
+
+    #[test]
+    fn parse_report_mode_on() {
+        let command = Command::parse(b"report mode on");
+        assert_eq!(command, Ok(Command::Reporting(true)));
+    }
+
+    #[test]
+    fn parse_report_mode_off() {
+        let command = Command::parse(b"report mode off");
+        assert_eq!(command, Ok(Command::Reporting(false)));
+    }
+
+    #[test]
+    fn parse_help() {
+        assert_eq!(Command::parse(b"help"), Ok(Command::Help(None)));
+        assert_eq!(Command::parse(b"help pid"), Ok(Command::Help(Some("pid"))));
+        assert!(Command::parse(b"help nothing").is_err());
+    }
+
+    #[test]
+    fn parse_log_to_tcp() {
+        let command = Command::parse(b"log to_tcp on");
+        assert_eq!(command, Ok(Command::LogToTcp(true)));
+    }
+
+    #[test]
+    fn parse_report_connect_snapshot() {
+        let command = Command::parse(b"report connect snapshot");
+        assert_eq!(command, Ok(Command::ReportOnConnect(ReportOnConnect::Snapshot)));
+    }
+
+    #[test]
+    fn parse_telemetry_udp() {
+        let command = Command::parse(b"telemetry udp 192.168.1.10 5000 0.5");
+        assert_eq!(command, Ok(Command::Telemetry(Some(UdpTelemetry {
+            address: [192, 168, 1, 10],
+            port: 5000,
+            interval: 0.5,
+        }))));
+    }
+
+    #[test]
+    fn parse_temp_limit() {
+        assert_eq!(Command::parse(b"limit 1 temp_max 60"), Ok(Command::TempLimit {
+            channel: 1,
+            limit: TempLimit::Max,
+            value: Some(60.0),
+        }));
+        assert_eq!(Command::parse(b"limit 0 temp_min off"), Ok(Command::TempLimit {
+            channel: 0,
+            limit: TempLimit::Min,
+            value: None,
+        }));
+        assert_eq!(Command::parse(b"limit 0 runaway_window 30"), Ok(Command::TempLimit {
+            channel: 0,
+            limit: TempLimit::RunawayWindow,
+            value: Some(30.0),
+        }));
+        assert_eq!(Command::parse(b"limit"), Ok(Command::Show(ShowCommand::TempLimits)));
+    }
+
+    #[test]
+    fn parse_clear() {
+        assert_eq!(Command::parse(b"clear 1"), Ok(Command::ClearFault { channel: 1 }));
+    }
+
+    #[test]
+    fn parse_profile() {
+        assert_eq!(Command::parse(b"profile 0"), Ok(Command::Profile {
+            channel: 0,
+            command: ProfileCommand::Show,
+        }));
+        assert_eq!(Command::parse(b"profile 1 add 35 0.5 60000"), Ok(Command::Profile {
+            channel: 1,
+            command: ProfileCommand::Add { target: 35.0, rate: 0.5, hold_ms: 60000 },
+        }));
+        assert_eq!(Command::parse(b"profile 1 mode loop"), Ok(Command::Profile {
+            channel: 1,
+            command: ProfileCommand::Mode(ProfileMode::Loop),
+        }));
+        assert_eq!(Command::parse(b"profile 0 start"), Ok(Command::Profile {
+            channel: 0,
+            command: ProfileCommand::Start,
+        }));
+    }
+
+    #[test]
+    fn parse_stats() {
+        assert_eq!(Command::parse(b"stats 1"), Ok(Command::Stats { channel: 1 }));
+        assert_eq!(Command::parse(b"stats 0 reset"), Ok(Command::StatsReset { channel: 0 }));
+    }
+
+    #[test]
+    fn parse_control_mode() {
+        assert_eq!(Command::parse(b"mode 0 pid"), Ok(Command::ControlMode { channel: 0, mode: ControlMode::Pid }));
+        let command = Command::parse(b"mode 1 hysteresis 24.5 26 heat");
+        assert_eq!(command, Ok(Command::ControlMode {
+            channel: 1,
+            mode: ControlMode::Hysteresis(Hysteresis { low: 24.5, high: 26.0, heat: true }),
+        }));
+    }
+
+    #[test]
+    fn parse_channel_enable() {
+        assert_eq!(Command::parse(b"channel 1 disable"), Ok(Command::ChannelEnable { channel: 1, enabled: false }));
+        assert_eq!(Command::parse(b"channel 1 enable"), Ok(Command::ChannelEnable { channel: 1, enabled: true }));
+    }
+
+    #[test]
+    fn parse_defaults() {
+        assert_eq!(Command::parse(b"defaults 0"), Ok(Command::Defaults { channel: 0 }));
+    }
+
+    #[test]
+    fn parse_cal() {
+        assert_eq!(Command::parse(b"cal"), Ok(Command::Show(ShowCommand::HardwareParams)));
+        assert_eq!(Command::parse(b"cal rsense 0.1"), Ok(Command::Cal {
+            parameter: HardwareParameter::RSense,
+            value: 0.1,
+        }));
+        assert_eq!(Command::parse(b"cal default"), Ok(Command::CalDefaults));
+        assert_eq!(Command::parse(b"cal dac 1"), Ok(Command::CalDac { channel: 1, reset: false }));
+        assert_eq!(Command::parse(b"cal dac 0 reset"), Ok(Command::CalDac { channel: 0, reset: true }));
+    }
+
+    #[test]
+    fn parse_mqtt() {
+        let command = Command::parse(b"mqtt 192.168.1.10 1883 lab/thermostat-1");
+        let mut prefix = String::new();
+        prefix.push_str("lab/thermostat-1").unwrap();
+        assert_eq!(command, Ok(Command::Mqtt(Some(MqttConfig {
+            address: [192, 168, 1, 10],
+            port: 1883,
+            prefix,
+        }))));
+    }
+
+    #[test]
+    fn parse_mqtt_prefix_wildcard() {
+        let command = Command::parse(b"mqtt 192.168.1.10 1883 lab/#");
+        assert!(command.is_err());
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn parse_mqtt_off() {
+        let command = Command::parse(b"mqtt off");
+        assert_eq!(command, Ok(Command::Mqtt(None)));
+    }
 
     #[test]
-    fn parse_quit() {
-        let command = Command::parse(b"quit");
-        assert_eq!(command, Ok(Command::Quit));
+    fn parse_watchdog() {
+        assert_eq!(Command::parse(b"watchdog"), Ok(Command::Watchdog));
+        assert_eq!(Command::parse(b"watchdog 2000"), Ok(Command::WatchdogInterval(2000)));
     }
 
     #[test]
-    fn parse_load() {
-        let command = Command::parse(b"load");
-        assert_eq!(command, Ok(Command::Load { channel: None }));
+    fn parse_mac() {
+        let command = Command::parse(b"mac 02:00:5e:10:Ab:ff");
+        assert_eq!(command, Ok(Command::Mac(Some([0x02, 0x00, 0x5e, 0x10, 0xab, 0xff]))));
+        assert_eq!(Command::parse(b"mac default"), Ok(Command::Mac(None)));
+        assert!(Command::parse(b"mac 02:00:5e:10:ab").is_err());
+        assert!(Command::parse(b"mac 02:00:5e:10:ab:fff").is_err());
     }
 
     #[test]
-    fn parse_load_channel() {
-        let command = Command::parse(b"load 0");
-        assert_eq!(command, Ok(Command::Load { channel: Some(0) }));
+    fn parse_tcp_timeout() {
+        assert_eq!(Command::parse(b"tcp timeout 600"), Ok(Command::TcpTimeout(Some(600))));
+        assert_eq!(Command::parse(b"tcp timeout off"), Ok(Command::TcpTimeout(None)));
+        assert!(Command::parse(b"tcp timeout").is_err());
     }
 
     #[test]
-    fn parse_save() {
-        let command = Command::parse(b"save");
-        assert_eq!(command, Ok(Command::Save { channel: None }));
+    fn parse_ntp() {
+        assert_eq!(Command::parse(b"ntp 192.168.1.1"), Ok(Command::Ntp(Some([192, 168, 1, 1]))));
+        assert_eq!(Command::parse(b"ntp off"), Ok(Command::Ntp(None)));
     }
 
     #[test]
-    fn parse_save_channel() {
-        let command = Command::parse(b"save 0");
-        assert_eq!(command, Ok(Command::Save { channel: Some(0) }));
+    fn parse_report_udp() {
+        let command = Command::parse(b"report udp 192.168.1.10 5000");
+        assert_eq!(command, Ok(Command::Telemetry(Some(UdpTelemetry {
+            address: [192, 168, 1, 10],
+            port: 5000,
+            interval: 0.0,
+        }))));
+        assert_eq!(Command::parse(b"report udp off"), Ok(Command::Telemetry(None)));
     }
 
     #[test]
-    fn parse_show_ipv4() {
-        let command = Command::parse(b"ipv4");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::Ipv4)));
+    fn parse_telemetry_off() {
+        let command = Command::parse(b"telemetry off");
+        assert_eq!(command, Ok(Command::Telemetry(None)));
     }
 
     #[test]
-    fn parse_ipv4() {
-        let command = Command::parse(b"ipv4 192.168.1.26/24");
-        assert_eq!(command, Ok(Command::Ipv4(Ipv4Config {
-            address: [192, 168, 1, 26],
-            mask_len: 24,
-            gateway: None,
-        })));
+    fn parse_stage() {
+        assert_eq!(Command::parse(b"stage begin"), Ok(Command::Stage(StageCommand::Begin)));
+        assert_eq!(Command::parse(b"stage commit"), Ok(Command::Stage(StageCommand::Commit)));
+        assert_eq!(Command::parse(b"stage abort"), Ok(Command::Stage(StageCommand::Abort)));
     }
 
     #[test]
-    fn parse_ipv4_and_gateway() {
-        let command = Command::parse(b"ipv4 10.42.0.126/8 10.1.0.1");
-        assert_eq!(command, Ok(Command::Ipv4(Ipv4Config {
-            address: [10, 42, 0, 126],
-            mask_len: 8,
-            gateway: Some([10, 1, 0, 1]),
-        })));
+    fn parse_config() {
+        assert_eq!(Command::parse(b"config export"), Ok(Command::ConfigExport));
+        assert_eq!(Command::parse(b"config import"), Ok(Command::ConfigImport));
     }
 
     #[test]
-    fn parse_report() {
-        let command = Command::parse(b"report");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::Input)));
+    fn parse_all() {
+        let command = Command::parse(b"all");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::All)));
     }
 
     #[test]
-    fn parse_report_mode() {
-        let command = Command::parse(b"report mode");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::Reporting)));
+    fn parse_pwm_softstart() {
+        let command = Command::parse(b"pwm 0 softstart 500");
+        assert_eq!(command, Ok(Command::PwmSoftStart {
+            channel: 0,
+            duration: 500,
+        }));
     }
 
     #[test]
-    fn parse_report_mode_on() {
-        let command = Command::parse(b"report mode on");
-        assert_eq!(command, Ok(Command::Reporting(true)));
+    fn parse_pwm_invert() {
+        let command = Command::parse(b"pwm 1 invert on");
+        assert_eq!(command, Ok(Command::PwmInvert {
+            channel: 1,
+            invert: true,
+        }));
     }
 
     #[test]
-    fn parse_report_mode_off() {
-        let command = Command::parse(b"report mode off");
-        assert_eq!(command, Ok(Command::Reporting(false)));
+    fn parse_pwm_raw() {
+        let command = Command::parse(b"pwm 1 raw");
+        assert_eq!(command, Ok(Command::PwmRaw { channel: 1 }));
     }
 
     #[test]
@@ -764,6 +2599,127 @@ mod test {
         }));
     }
 
+    #[test]
+    fn parse_pid_gain_schedule() {
+        assert_eq!(Command::parse(b"pid 1 cool kp 0.5"), Ok(Command::PidCool {
+            channel: 1,
+            parameter: PidParameter::KP,
+            value: 0.5,
+        }));
+        assert_eq!(Command::parse(b"pid 1 heat ki 0.1"), Ok(Command::Pid {
+            channel: 1,
+            parameter: PidParameter::KI,
+            value: 0.1,
+        }));
+        assert_eq!(Command::parse(b"pid 0 cool off"), Ok(Command::PidCoolOff { channel: 0 }));
+    }
+
+    #[test]
+    fn parse_pid_integral_max() {
+        let command = Command::parse(b"pid 1 integral_max 2000");
+        assert_eq!(command, Ok(Command::Pid {
+            channel: 1,
+            parameter: PidParameter::IntegralMax,
+            value: 2000.0,
+        }));
+    }
+
+    #[test]
+    fn parse_pid_kd_filter() {
+        let command = Command::parse(b"pid 0 kd_filter 0.5");
+        assert_eq!(command, Ok(Command::Pid {
+            channel: 0,
+            parameter: PidParameter::KdFilter,
+            value: 0.5,
+        }));
+    }
+
+    #[test]
+    fn parse_pid_slew() {
+        let command = Command::parse(b"pid 0 slew 0.5");
+        assert_eq!(command, Ok(Command::Pid {
+            channel: 0,
+            parameter: PidParameter::Slew,
+            value: 0.5,
+        }));
+    }
+
+    #[test]
+    fn parse_pid_ff() {
+        let command = Command::parse(b"pid 1 ff -0.25");
+        assert_eq!(command, Ok(Command::Pid {
+            channel: 1,
+            parameter: PidParameter::FeedForward,
+            value: -0.25,
+        }));
+        let command = Command::parse(b"pid 1 ff_target 0.01");
+        assert_eq!(command, Ok(Command::Pid {
+            channel: 1,
+            parameter: PidParameter::FeedForwardTarget,
+            value: 0.01,
+        }));
+    }
+
+    #[test]
+    fn parse_pid_reset() {
+        let command = Command::parse(b"pid 0 reset");
+        assert_eq!(command, Ok(Command::PidReset { channel: 0 }));
+    }
+
+    #[test]
+    fn parse_pid_terms() {
+        let command = Command::parse(b"pid 1 terms");
+        assert_eq!(command, Ok(Command::PidTerms { channel: 1 }));
+    }
+
+    #[test]
+    fn parse_pid_ramp() {
+        let command = Command::parse(b"pid 1 ramp 30.5 0.1");
+        assert_eq!(command, Ok(Command::PidRamp {
+            channel: 1,
+            target: 30.5,
+            rate: 0.1,
+        }));
+    }
+
+    #[test]
+    fn parse_pid_autotune() {
+        let command = Command::parse(b"pid 1 autotune 0.5");
+        assert_eq!(command, Ok(Command::PidAutotune {
+            channel: 1,
+            amplitude: 0.5,
+        }));
+    }
+
+    #[test]
+    fn parse_pid_setpoint_source() {
+        let command = Command::parse(b"pid 0 setpoint_source analog");
+        assert_eq!(command, Ok(Command::PidSetpointSource {
+            channel: 0,
+            source: SetpointSource::Analog,
+        }));
+    }
+
+    #[test]
+    fn parse_pid_setpoint_scale() {
+        let command = Command::parse(b"pid 1 setpoint_scale 10");
+        assert_eq!(command, Ok(Command::Pid {
+            channel: 1,
+            parameter: PidParameter::SetpointScale,
+            value: 10.0,
+        }));
+    }
+
+    #[test]
+    fn parse_tec() {
+        assert_eq!(Command::parse(b"tec"), Ok(Command::Show(ShowCommand::Tec)));
+        assert_eq!(Command::parse(b"tec 1 peltier 0.05"), Ok(Command::TecPeltier {
+            channel: 1,
+            coefficient: 0.05,
+        }));
+        assert_eq!(Command::parse(b"tec 0 reset"), Ok(Command::TecReset { channel: 0 }));
+    }
+
     #[test]
     fn parse_steinhart_hart() {
         let command = Command::parse(b"s-h");
@@ -780,6 +2736,100 @@ mod test {
         }));
     }
 
+    #[test]
+    fn parse_steinhart_hart_t_max() {
+        let command = Command::parse(b"s-h 0 t_max 120");
+        assert_eq!(command, Ok(Command::SteinhartHart {
+            channel: 0,
+            parameter: ShParameter::TMax,
+            value: 120.0,
+        }));
+    }
+
+    #[test]
+    fn parse_steinhart_hart_fault_holdoff() {
+        let command = Command::parse(b"s-h 1 fault_holdoff 3");
+        assert_eq!(command, Ok(Command::SteinhartHart {
+            channel: 1,
+            parameter: ShParameter::FaultHoldoff,
+            value: 3.0,
+        }));
+    }
+
+    #[test]
+    fn parse_steinhart_hart_b3() {
+        let command = Command::parse(b"s-h 0 b3 0.0002347");
+        assert_eq!(command, Ok(Command::SteinhartHart {
+            channel: 0,
+            parameter: ShParameter::B3,
+            value: 0.0002347,
+        }));
+    }
+
+    #[test]
+    fn parse_steinhart_hart_temp_correction() {
+        let command = Command::parse(b"s-h 1 temp_correction 1.02 -0.5");
+        assert_eq!(command, Ok(Command::TempCorrection {
+            channel: 1,
+            gain: 1.02,
+            offset: -0.5,
+        }));
+    }
+
+    #[test]
+    fn parse_steinhart_hart_calibrate() {
+        let command = Command::parse(b"s-h 0 calibrate 32650 0 10000 25 3603 50");
+        assert_eq!(command, Ok(Command::ShCalibrate {
+            channel: 0,
+            points: [(32650.0, 0.0), (10000.0, 25.0), (3603.0, 50.0)],
+        }));
+    }
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(Command::parse(b"units"), Ok(Command::Show(ShowCommand::Units)));
+        assert_eq!(Command::parse(b"units temp kelvin"), Ok(Command::TempUnit(TempUnit::Kelvin)));
+        assert_eq!(Command::parse(b"units temp fahrenheit"), Ok(Command::TempUnit(TempUnit::Fahrenheit)));
+        assert!(Command::parse(b"units temp rankine").is_err());
+    }
+
+    #[test]
+    fn parse_dac_raw() {
+        let command = Command::parse(b"dac 1 raw 131072");
+        assert_eq!(command, Ok(Command::DacRaw {
+            channel: 1,
+            value: 131072,
+        }));
+    }
+
+    #[test]
+    fn parse_dac_verify() {
+        let command = Command::parse(b"dac 0 verify on");
+        assert_eq!(command, Ok(Command::DacVerify {
+            channel: 0,
+            verify: true,
+        }));
+    }
+
+    #[test]
+    fn parse_sensor() {
+        assert_eq!(Command::parse(b"sensor"), Ok(Command::Show(ShowCommand::Sensor)));
+        assert_eq!(Command::parse(b"sensor 1 type rtd"), Ok(Command::SensorType {
+            channel: 1,
+            sensor: SensorType::Rtd,
+        }));
+        assert_eq!(Command::parse(b"sensor 0 r0 1000"), Ok(Command::Rtd {
+            channel: 0,
+            parameter: RtdParameter::R0,
+            value: 1000.0,
+        }));
+        assert_eq!(Command::parse(b"sensor 0 c -4.183e-12"), Ok(Command::Rtd {
+            channel: 0,
+            parameter: RtdParameter::C,
+            value: -4.183e-12,
+        }));
+    }
+
     #[test]
     fn parse_postfilter() {
         let command = Command::parse(b"postfilter");
@@ -795,6 +2845,24 @@ mod test {
         }));
     }
 
+    #[test]
+    fn parse_adc_rate() {
+        let command = Command::parse(b"adc 0 rate 100");
+        assert_eq!(command, Ok(Command::AdcRate {
+            channel: 0,
+            rate: 100.0,
+        }));
+    }
+
+    #[test]
+    fn parse_odr() {
+        let command = Command::parse(b"odr 1 100");
+        assert_eq!(command, Ok(Command::Odr {
+            channel: 1,
+            rate: 100.0,
+        }));
+    }
+
     #[test]
     fn parse_postfilter_rate() {
         let command = Command::parse(b"postfilter 0 rate 21");
@@ -804,6 +2872,96 @@ mod test {
         }));
     }
 
+    #[test]
+    fn parse_adc() {
+        let command = Command::parse(b"adc");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Adc)));
+    }
+
+    #[test]
+    fn parse_adc_read_mode() {
+        let command = Command::parse(b"adc 1 mode on_demand");
+        assert_eq!(command, Ok(Command::AdcReadMode {
+            channel: 1,
+            mode: AdcReadMode::OnDemand,
+        }));
+    }
+
+    #[test]
+    fn parse_adc_read() {
+        let command = Command::parse(b"adc 0 read");
+        assert_eq!(command, Ok(Command::AdcRead {
+            channel: 0,
+        }));
+    }
+
+    #[test]
+    fn parse_adc_noisefloor() {
+        let command = Command::parse(b"adc 1 noisefloor");
+        assert_eq!(command, Ok(Command::AdcNoiseFloor {
+            channel: 1,
+        }));
+    }
+
+    #[test]
+    fn parse_adc_filter() {
+        let command = Command::parse(b"adc 0 filter sinc3");
+        assert_eq!(command, Ok(Command::AdcFilter {
+            channel: 0,
+            filter: Filter::Sinc3,
+        }));
+    }
+
+    #[test]
+    fn parse_adc_filter_decimation() {
+        let command = Command::parse(b"adc 1 filter sinc3 625");
+        assert_eq!(command, Ok(Command::AdcFilter {
+            channel: 1,
+            filter: Filter::Sinc3Map(625),
+        }));
+    }
+
+    #[test]
+    fn parse_adc_bipolar() {
+        let command = Command::parse(b"adc 0 bipolar on");
+        assert_eq!(command, Ok(Command::AdcBipolar {
+            channel: 0,
+            bipolar: true,
+        }));
+    }
+
+    #[test]
+    fn parse_adc_checksum() {
+        let command = Command::parse(b"adc checksum xor");
+        assert_eq!(command, Ok(Command::AdcChecksum(ChecksumMode::Xor)));
+        let command = Command::parse(b"adc checksum off");
+        assert_eq!(command, Ok(Command::AdcChecksum(ChecksumMode::Off)));
+    }
+
+    #[test]
+    fn parse_adc_average() {
+        let command = Command::parse(b"adc avg 16");
+        assert_eq!(command, Ok(Command::AdcAverage(16)));
+    }
+
+    #[test]
+    fn parse_adc_ref_source() {
+        let command = Command::parse(b"adc 1 refsource internal");
+        assert_eq!(command, Ok(Command::AdcRefSource {
+            channel: 1,
+            ref_source: RefSource::Internal,
+        }));
+    }
+
+    #[test]
+    fn parse_adc_interval_tolerance() {
+        let command = Command::parse(b"adc 1 interval_tolerance 0.5");
+        assert_eq!(command, Ok(Command::AdcIntervalTolerance {
+            channel: 1,
+            tolerance: 0.5,
+        }));
+    }
+
     #[test]
     fn parse_center_point() {
         let command = Command::parse(b"center 0 1.5");
@@ -840,6 +2998,28 @@ mod test {
         assert_eq!(command, Ok(Command::FanAuto));
     }
 
+    #[test]
+    fn parse_fan_source() {
+        let command = Command::parse(b"fan source 1");
+        assert_eq!(command, Ok(Command::FanSource(FanSource::Channel(1))));
+        let command = Command::parse(b"fan source sum");
+        assert_eq!(command, Ok(Command::FanSource(FanSource::Sum)));
+        let command = Command::parse(b"fan source temperature");
+        assert_eq!(command, Ok(Command::FanSource(FanSource::Temperature)));
+    }
+
+    #[test]
+    fn parse_fan_freq() {
+        let command = Command::parse(b"fan freq 20000");
+        assert_eq!(command, Ok(Command::FanFreq { freq_hz: 20000 }));
+    }
+
+    #[test]
+    fn parse_fan_save() {
+        let command = Command::parse(b"fan save");
+        assert_eq!(command, Ok(Command::FanSave));
+    }
+
     #[test]
     fn parse_fcurve_set() {
         let command = Command::parse(b"fcurve 1.2 3.4 5.6");
@@ -856,9 +3036,45 @@ mod test {
         assert_eq!(command, Ok(Command::FanCurveDefaults));
     }
 
+    #[test]
+    fn parse_reset() {
+        assert_eq!(Command::parse(b"reset"), Ok(Command::Reset));
+        assert_eq!(Command::parse(b"reset factory"), Ok(Command::FactoryReset));
+    }
+
+    #[test]
+    fn parse_version() {
+        let command = Command::parse(b"version");
+        assert_eq!(command, Ok(Command::Version));
+    }
+
+    #[test]
+    fn parse_uptime() {
+        let command = Command::parse(b"uptime");
+        assert_eq!(command, Ok(Command::Uptime));
+    }
+
+    #[test]
+    fn parse_netstat() {
+        let command = Command::parse(b"netstat");
+        assert_eq!(command, Ok(Command::Netstat));
+    }
+
     #[test]
     fn parse_hwrev() {
         let command = Command::parse(b"hwrev");
         assert_eq!(command, Ok(Command::ShowHWRev));
     }
+
+    #[test]
+    fn parse_adc_temp() {
+        let command = Command::parse(b"adc_temp");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::AdcTemperature)));
+    }
+
+    #[test]
+    fn parse_supply() {
+        let command = Command::parse(b"supply");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Supply)));
+    }
 }
@@ -1,56 +1,102 @@
+use core::fmt::Debug;
+use heapless::{consts::{U2, U8, U16}, Vec};
+use log::{error, warn};
 use serde::{Serialize, Deserialize};
+use sfkv::{Error as StoreError, Store, StoreBackend};
+use stm32f4xx_hal::spi;
 use uom::si::{
     electric_potential::volt,
     electric_current::ampere,
-    f64::{ElectricCurrent, ElectricPotential},
+    electrical_resistance::ohm,
+    thermodynamic_temperature::kelvin,
+    f64::{ElectricCurrent, ElectricPotential, ElectricalResistance, ThermodynamicTemperature},
 };
 use crate::{
-    ad7172::PostFilter,
-    channels::Channels,
-    command_parser::CenterPoint,
+    ad7172::{PostFilter, RefSource},
+    channel_state::{ControlMode, RunawayParameters, SetpointInput, TempLimits},
+    channels::{Channels, CHANNELS},
+    command_handler::{Error as HandlerError, JsonBuffer},
+    command_parser::{CenterPoint, Ipv4Config, SensorType},
+    fan_ctrl::{FanConfig, FanCtrl},
     pid,
+    rtd,
     steinhart_hart,
 };
 
+/// Ranges of the output limits that `Channels` can set
+const MAX_TEC_V: f64 = 4.0 * 3.3;
+const MAX_TEC_I: f64 = 3.0;
+/// Range of the ADC that measures the TEC current center point
+const MAX_CENTER_V: f32 = 3.3;
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ChannelConfig {
     center: CenterPoint,
     pid: pid::Parameters,
+    /// Parameters above the target, see `pid::Controller::cool`
+    pid_cool: Option<pid::Parameters>,
     pid_target: f32,
     pid_engaged: bool,
+    control_mode: ControlMode,
+    setpoint: SetpointInput,
+    sensor: SensorType,
     sh: steinhart_hart::Parameters,
+    rtd: rtd::Parameters,
     pwm: PwmLimits,
     /// uses variant `PostFilter::Invalid` instead of `None` to save space
     adc_postfilter: PostFilter,
+    adc_ref_source: RefSource,
+    temp_limits: TempLimits,
+    runaway: RunawayParameters,
 }
 
 impl ChannelConfig {
-    pub fn new(channels: &mut Channels, channel: usize) -> Self {
+    /// Settings of `channel`, fails if the ADC setup cannot be read
+    pub fn new(channels: &mut Channels, channel: usize) -> Result<Self, spi::Error> {
         let pwm = PwmLimits::new(channels, channel);
 
-        let adc_postfilter = channels.adc.get_postfilter(channel as u8)
-            .unwrap()
+        let adc_postfilter = channels.adc.get_postfilter(channel as u8)?
             .unwrap_or(PostFilter::Invalid);
+        let adc_ref_source = channels.adc.get_ref_source(channel as u8)?;
 
         let state = channels.channel_state(channel);
-        ChannelConfig {
+        Ok(ChannelConfig {
             center: state.center.clone(),
             pid: state.pid.parameters.clone(),
+            pid_cool: state.pid.cool.clone(),
             pid_target: state.pid.target as f32,
             pid_engaged: state.pid_engaged,
+            control_mode: state.control_mode,
+            setpoint: state.setpoint.clone(),
+            sensor: state.sensor,
             sh: state.sh.clone(),
+            rtd: state.rtd.clone(),
             pwm,
             adc_postfilter,
-        }
+            adc_ref_source,
+            temp_limits: state.temp_limits.clone(),
+            runaway: state.runaway.clone(),
+        })
     }
 
     pub fn apply(&self, channels: &mut Channels, channel: usize) {
         let state = channels.channel_state(channel);
         state.center = self.center.clone();
         state.pid.parameters = self.pid.clone();
+        state.pid.cool = self.pid_cool.clone();
         state.pid.target = self.pid_target.into();
-        state.pid_engaged = self.pid_engaged;
+        state.ramp = None;
+        state.profile.stop();
+        // A latched fault must be cleared first, a disabled channel
+        // enabled
+        state.pid_engaged = self.pid_engaged && state.fault.is_none() && state.enabled;
+        state.control_mode = self.control_mode;
+        state.setpoint = self.setpoint.clone();
+        state.sensor = self.sensor;
         state.sh = self.sh.clone();
+        state.rtd = self.rtd.clone();
+        state.temp_limits = self.temp_limits.clone();
+        state.runaway = self.runaway.clone();
 
         self.pwm.apply(channels, channel);
 
@@ -58,7 +104,253 @@ impl ChannelConfig {
             PostFilter::Invalid => None,
             adc_postfilter => Some(adc_postfilter),
         };
-        let _ = channels.adc.set_postfilter(channel as u8, adc_postfilter);
+        if let Err(e) = channels.adc.set_postfilter(channel as u8, adc_postfilter) {
+            error!("unable to set channel {} postfilter: {:?}", channel, e);
+        }
+        if let Err(e) = channels.set_adc_ref_source(channel, self.adc_ref_source) {
+            error!("unable to set channel {} reference: {:?}", channel, e);
+        }
+    }
+}
+
+impl ChannelConfig {
+    /// Read from flash, migrating a config stored in the layout of the
+    /// initial firmware
+    pub fn read<B: StoreBackend>(store: &Store<B>, key: &str) -> Result<Option<Self>, StoreError<B::Error>> {
+        match store.read_value::<ChannelConfig>(key) {
+            // The old layout is shorter, so it fails to decode as the
+            // current one
+            Err(e) => match store.read_value::<ChannelConfigV0>(key) {
+                Ok(Some(legacy)) => {
+                    warn!("migrating {} from the initial flash layout", key);
+                    Ok(Some(legacy.into()))
+                }
+                _ => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    /// Check a config from an untrusted source before applying it
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !self.pid_target.is_finite() {
+            return Err("PID parameters must be finite");
+        }
+        validate_pid(&self.pid)?;
+        if let Some(pid_cool) = &self.pid_cool {
+            validate_pid(pid_cool)?;
+        }
+        if let CenterPoint::Override(center) = self.center {
+            if !(0.0..=MAX_CENTER_V).contains(&center) {
+                return Err("center point out of range");
+            }
+        }
+        if !self.setpoint.scale.is_finite() || !self.setpoint.offset.is_finite() {
+            return Err("setpoint input parameters must be finite");
+        }
+        validate_sh(&self.sh)?;
+        if let ControlMode::Hysteresis(hysteresis) = self.control_mode {
+            if !hysteresis.low.is_finite() || !hysteresis.high.is_finite() {
+                return Err("hysteresis thresholds must be finite");
+            }
+            if hysteresis.low >= hysteresis.high {
+                return Err("hysteresis low must be below high");
+            }
+        }
+        let rtd = &self.rtd;
+        let r0 = rtd.r0.get::<ohm>();
+        if r0 <= 0.0 || [r0, rtd.a, rtd.b, rtd.c].iter().any(|value| !value.is_finite()) {
+            return Err("invalid RTD parameters");
+        }
+        if !(0.0..=MAX_TEC_V).contains(&self.pwm.max_v) {
+            return Err("max_v out of range");
+        }
+        if !(0.0..=MAX_TEC_I).contains(&self.pwm.max_i_pos) || !(0.0..=MAX_TEC_I).contains(&self.pwm.max_i_neg) {
+            return Err("max_i out of range");
+        }
+        if let (Some(min), Some(max)) = (self.temp_limits.min, self.temp_limits.max) {
+            if min >= max {
+                return Err("temp_min must be below temp_max");
+            }
+        }
+        if !(0.0..).contains(&self.runaway.window) || !(0.0..).contains(&self.runaway.slope) {
+            return Err("runaway parameters must not be negative");
+        }
+        Ok(())
+    }
+}
+
+/// Check the gains and limits of one PID parameter set
+fn validate_pid(pid: &pid::Parameters) -> Result<(), &'static str> {
+    let pid_values = [
+        pid.kp, pid.ki, pid.kd, pid.kd_filter,
+        pid.output_min, pid.output_max, pid.integral_min, pid.integral_max,
+        pid.slew, pid.ff, pid.ff_target,
+    ];
+    if pid_values.iter().any(|value| !value.is_finite()) {
+        return Err("PID parameters must be finite");
+    }
+    if pid.output_min > pid.output_max || pid.integral_min > pid.integral_max {
+        return Err("PID limits are inverted");
+    }
+    if f64::from(pid.output_min) < -MAX_TEC_I || f64::from(pid.output_max) > MAX_TEC_I {
+        return Err("PID output limits exceed the TEC current range");
+    }
+    Ok(())
+}
+
+/// Check that the thermistor conversion is defined over a valid range
+fn validate_sh(sh: &steinhart_hart::Parameters) -> Result<(), &'static str> {
+    let t0 = sh.t0.get::<kelvin>();
+    let r0 = sh.r0.get::<ohm>();
+    let (t_min, t_max) = (sh.t_min.get::<kelvin>(), sh.t_max.get::<kelvin>());
+    let sh_values = [
+        t0, r0, sh.b, sh.a, sh.b3, sh.c, t_min, t_max,
+        sh.correction_gain, sh.correction_offset,
+    ];
+    if sh_values.iter().any(|value| !value.is_finite()) || t0 <= 0.0 || r0 <= 0.0 || sh.b == 0.0 {
+        return Err("invalid Steinhart-Hart parameters");
+    }
+    if sh.correction_gain == 0.0 {
+        return Err("temperature correction gain must not be 0");
+    }
+    if t_min >= t_max {
+        return Err("t_min must be below t_max");
+    }
+    Ok(())
+}
+
+/// Everything that `config export` shows and `config import` restores
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    pub channels: Vec<ChannelConfig, U2>,
+    pub ipv4: Ipv4Config,
+    pub fan: FanConfig,
+}
+
+impl DeviceConfig {
+    /// Validate all parts, so that nothing is applied if any is invalid
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.channels.len() != CHANNELS {
+            return Err("expected a config for each channel");
+        }
+        for config in &self.channels {
+            config.validate()?;
+        }
+        if self.ipv4.mask_len > 32 {
+            return Err("invalid netmask length");
+        }
+        self.fan.validate()
+    }
+
+    /// Apply the channel and fan settings, the network settings take
+    /// effect through the main loop
+    pub fn apply(&self, channels: &mut Channels, fan_ctrl: &mut FanCtrl) {
+        for (channel, config) in self.channels.iter().enumerate() {
+            config.apply(channels, channel);
+        }
+        fan_ctrl.apply_config(&self.fan);
+    }
+}
+
+/// Settings of a channel that differ from its stored `ChannelConfig`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigDiff {
+    channel: usize,
+    changed: Vec<&'static str, U16>,
+}
+
+impl ConfigDiff {
+    pub fn new(channel: usize, live: &ChannelConfig, stored: &ChannelConfig) -> Self {
+        let mut changed = Vec::<&'static str, U16>::new();
+        let mut compare = |field: &'static str, differs: bool| if differs {
+            let _ = changed.push(field);
+        };
+        compare("center", live.center != stored.center);
+        compare("pid", live.pid != stored.pid);
+        compare("pid_cool", live.pid_cool != stored.pid_cool);
+        compare("pid_target", live.pid_target != stored.pid_target);
+        compare("pid_engaged", live.pid_engaged != stored.pid_engaged);
+        compare("control_mode", live.control_mode != stored.control_mode);
+        compare("setpoint", live.setpoint != stored.setpoint);
+        compare("sensor", live.sensor != stored.sensor);
+        compare("sh", live.sh != stored.sh);
+        compare("rtd", live.rtd != stored.rtd);
+        compare("pwm", live.pwm != stored.pwm);
+        compare("adc_postfilter", live.adc_postfilter != stored.adc_postfilter);
+        compare("adc_ref_source", live.adc_ref_source != stored.adc_ref_source);
+        compare("temp_limits", live.temp_limits != stored.temp_limits);
+        compare("runaway", live.runaway != stored.runaway);
+        ConfigDiff { channel, changed }
+    }
+
+    pub fn summary_json(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        serde_json_core::to_vec(self)
+    }
+}
+
+/// Outcome of checking a stored `ChannelConfig` without applying it
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigVerification {
+    channel: usize,
+    valid: bool,
+    config: Option<ChannelConfig>,
+}
+
+impl ConfigVerification {
+    /// Returns `None` if no config has been stored.
+    pub fn new<E: Debug>(channel: usize, stored: Result<Option<ChannelConfig>, E>) -> Option<Self> {
+        match stored {
+            Ok(Some(config)) =>
+                Some(ConfigVerification { channel, valid: true, config: Some(config) }),
+            Ok(None) =>
+                None,
+            Err(e) => {
+                warn!("invalid channel {} config in flash: {:?}", channel, e);
+                Some(ConfigVerification { channel, valid: false, config: None })
+            }
+        }
+    }
+
+    pub fn summary_json(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        serde_json_core::to_vec(self)
+    }
+}
+
+/// Outcome of `save all`, by flash store key
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SaveSummary {
+    saved: Vec<&'static str, U8>,
+    failed: Vec<&'static str, U8>,
+    /// Only present if a key failed, like other error replies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+}
+
+impl SaveSummary {
+    /// Add the result of writing `key` to the flash store
+    pub fn record<E: Debug>(&mut self, key: &'static str, result: Result<(), E>) {
+        match result {
+            Ok(()) => {
+                let _ = self.saved.push(key);
+            }
+            Err(e) => {
+                error!("unable to save {} to flash: {:?}", key, e);
+                let _ = self.failed.push(key);
+                self.error = Some("unable to save all settings");
+                self.code = Some(HandlerError::FlashError.code());
+            }
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    pub fn summary_json(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        serde_json_core::to_vec(self)
     }
 }
 
@@ -67,6 +359,9 @@ struct PwmLimits {
     max_v: f64,
     max_i_pos: f64,
     max_i_neg: f64,
+    /// ms
+    softstart: u32,
+    invert: bool,
 }
 
 impl PwmLimits {
@@ -78,12 +373,461 @@ impl PwmLimits {
             max_v: max_v.get::<volt>(),
             max_i_pos: max_i_pos.get::<ampere>(),
             max_i_neg: max_i_neg.get::<ampere>(),
+            softstart: channels.channel_state(channel).softstart,
+            invert: channels.channel_state(channel).inverted,
         }
     }
 
     pub fn apply(&self, channels: &mut Channels, channel: usize) {
+        // Before the limits which depend on it
+        channels.set_inverted(channel, self.invert);
         channels.set_max_v(channel, ElectricPotential::new::<volt>(self.max_v));
         channels.set_max_i_pos(channel, ElectricCurrent::new::<ampere>(self.max_i_pos));
         channels.set_max_i_neg(channel, ElectricCurrent::new::<ampere>(self.max_i_neg));
+        channels.channel_state(channel).softstart = self.softstart;
+    }
+}
+
+/// Read the network settings from flash, migrating those stored
+/// before DHCP support
+pub fn read_ipv4_config<B: StoreBackend>(store: &Store<B>) -> Result<Option<Ipv4Config>, StoreError<B::Error>> {
+    match store.read_value::<Ipv4Config>("ipv4") {
+        Err(e) => match store.read_value::<Ipv4ConfigV0>("ipv4") {
+            Ok(Some(legacy)) => {
+                warn!("migrating ipv4 from the initial flash layout");
+                Ok(Some(Ipv4Config {
+                    address: legacy.address,
+                    mask_len: legacy.mask_len,
+                    gateway: legacy.gateway,
+                    dhcp: false,
+                }))
+            }
+            _ => Err(e),
+        },
+        result => result,
+    }
+}
+
+/// `ChannelConfig` in the layout of the initial firmware. postcard
+/// encodes fields by position, so it cannot decode as the current
+/// layout.
+#[derive(Deserialize)]
+struct ChannelConfigV0 {
+    center: CenterPoint,
+    pid: PidParametersV0,
+    pid_target: f32,
+    pid_engaged: bool,
+    sh: ShParametersV0,
+    pwm: PwmLimitsV0,
+    adc_postfilter: PostFilter,
+}
+
+#[derive(Deserialize)]
+struct PidParametersV0 {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    output_min: f32,
+    output_max: f32,
+}
+
+#[derive(Deserialize)]
+struct ShParametersV0 {
+    t0: ThermodynamicTemperature,
+    r0: ElectricalResistance,
+    b: f64,
+}
+
+#[derive(Deserialize)]
+struct PwmLimitsV0 {
+    max_v: f64,
+    max_i_pos: f64,
+    max_i_neg: f64,
+}
+
+#[derive(Deserialize)]
+struct Ipv4ConfigV0 {
+    address: [u8; 4],
+    mask_len: u8,
+    gateway: Option<[u8; 4]>,
+}
+
+impl ChannelConfigV0 {
+    /// Sample interval in s of the per-sample gains of the initial
+    /// firmware: the postfilter rate or the 10 Hz ODR
+    fn sample_interval(&self) -> f32 {
+        self.adc_postfilter.output_rate()
+            .map_or(0.1, |rate| 1.0 / rate)
+    }
+}
+
+/// Settings added since the initial firmware take their defaults
+impl From<ChannelConfigV0> for ChannelConfig {
+    fn from(legacy: ChannelConfigV0) -> Self {
+        let dt = legacy.sample_interval();
+        let pid = legacy.pid;
+        ChannelConfig {
+            center: legacy.center,
+            pid: pid::Parameters {
+                kp: pid.kp,
+                // From per sample to per second
+                ki: pid.ki / dt,
+                kd: pid.kd * dt,
+                output_min: pid.output_min,
+                output_max: pid.output_max,
+                // The integral used to be limited by the output only
+                integral_min: pid.output_min,
+                integral_max: pid.output_max,
+                ..pid::Parameters::default()
+            },
+            pid_cool: None,
+            pid_target: legacy.pid_target,
+            pid_engaged: legacy.pid_engaged,
+            control_mode: ControlMode::default(),
+            setpoint: SetpointInput::default(),
+            sensor: SensorType::Thermistor,
+            sh: steinhart_hart::Parameters {
+                t0: legacy.sh.t0,
+                r0: legacy.sh.r0,
+                b: legacy.sh.b,
+                ..steinhart_hart::Parameters::default()
+            },
+            rtd: rtd::Parameters::default(),
+            pwm: PwmLimits {
+                max_v: legacy.pwm.max_v,
+                max_i_pos: legacy.pwm.max_i_pos,
+                max_i_neg: legacy.pwm.max_i_neg,
+                softstart: 0,
+                invert: false,
+            },
+            adc_postfilter: legacy.adc_postfilter,
+            adc_ref_source: RefSource::External,
+            temp_limits: TempLimits::default(),
+            runaway: RunawayParameters::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use heapless::consts::U8192;
+    use sfkv::{Store, StoreBackend};
+    use crate::{
+        channel_state::Hysteresis,
+        command_parser::{FanSource, SetpointSource},
+        session::ImportBuffer,
+    };
+
+    const SIZE: usize = 1024;
+
+    struct TestBackend {
+        data: [u8; SIZE],
+    }
+
+    impl StoreBackend for TestBackend {
+        type Data = [u8];
+
+        fn data(&self) -> &Self::Data {
+            &self.data
+        }
+
+        type Error = ();
+        fn erase(&mut self) -> Result<(), Self::Error> {
+            self.data = [0xff; SIZE];
+            Ok(())
+        }
+
+        fn program(&mut self, offset: usize, payload: &[u8]) -> Result<(), Self::Error> {
+            self.data[offset..(offset + payload.len())].copy_from_slice(payload);
+            Ok(())
+        }
+
+        fn backup_space(&self) -> &'static mut [u8] {
+            Box::leak(Box::new([0; SIZE]))
+        }
+    }
+
+    fn store() -> Store<TestBackend> {
+        Store::new(TestBackend { data: [0xff; SIZE] })
+    }
+
+    fn config() -> ChannelConfig {
+        ChannelConfig {
+            center: CenterPoint::Vref,
+            pid: pid::Parameters::default(),
+            pid_cool: None,
+            pid_target: 25.0,
+            pid_engaged: false,
+            control_mode: ControlMode::Pid,
+            setpoint: SetpointInput::default(),
+            sensor: SensorType::Thermistor,
+            sh: steinhart_hart::Parameters::default(),
+            rtd: rtd::Parameters::default(),
+            pwm: PwmLimits {
+                max_v: 4.0,
+                max_i_pos: 1.0,
+                max_i_neg: 1.0,
+                softstart: 0,
+                invert: false,
+            },
+            adc_postfilter: PostFilter::Invalid,
+            adc_ref_source: RefSource::External,
+            temp_limits: TempLimits::default(),
+            runaway: RunawayParameters::default(),
+        }
+    }
+
+    #[test]
+    fn verify_good_config() {
+        let mut store = store();
+        let mut buf = [0; 512];
+        store.write_value("ch0", &config(), &mut buf).unwrap();
+        let verification = ConfigVerification::new(0, store.read_value::<ChannelConfig>("ch0"));
+        assert_eq!(verification, Some(ConfigVerification {
+            channel: 0,
+            valid: true,
+            config: Some(config()),
+        }));
+    }
+
+    #[test]
+    fn verify_corrupt_config() {
+        let mut store = store();
+        // invalid `CenterPoint` variant, truncated
+        store.write("ch1", &[0x05]).unwrap();
+        let verification = ConfigVerification::new(1, store.read_value::<ChannelConfig>("ch1"));
+        assert_eq!(verification, Some(ConfigVerification {
+            channel: 1,
+            valid: false,
+            config: None,
+        }));
+    }
+
+    #[test]
+    fn diff_unchanged_config() {
+        let diff = ConfigDiff::new(0, &config(), &config());
+        assert!(diff.changed.is_empty());
+    }
+
+    #[test]
+    fn diff_changed_parameter() {
+        let mut live = config();
+        live.pid.kp = 2.0;
+        live.pwm.max_v = 3.0;
+        let diff = ConfigDiff::new(1, &live, &config());
+        assert_eq!(diff.channel, 1);
+        assert_eq!(&diff.changed[..], &["pid", "pwm"]);
+    }
+
+    #[test]
+    fn validate_ranges() {
+        assert_eq!(config().validate(), Ok(()));
+
+        let mut invalid = config();
+        invalid.pwm.max_i_pos = 3.5;
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = config();
+        invalid.pid.output_min = 1.0;
+        invalid.pid.output_max = -1.0;
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = config();
+        invalid.pid.kp = f32::NAN;
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = config();
+        invalid.pid_cool = Some(pid::Parameters { ki: f32::INFINITY, ..pid::Parameters::default() });
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = config();
+        invalid.temp_limits = TempLimits { min: Some(40.0), max: Some(10.0) };
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = config();
+        invalid.rtd.r0 = ElectricalResistance::new::<ohm>(0.0);
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn migrate_initial_layout() {
+        let mut blob = std::vec::Vec::new();
+        // center: CenterPoint::Vref
+        blob.push(0);
+        // pid: kp, ki, kd, output_min, output_max
+        for value in &[1.5f32, 0.25, 0.0, -1.0, 1.0] {
+            blob.extend_from_slice(&value.to_le_bytes());
+        }
+        // pid_target, pid_engaged
+        blob.extend_from_slice(&30.0f32.to_le_bytes());
+        blob.push(1);
+        // sh: t0 in K, r0 in Ω, b; pwm: max_v, max_i_pos, max_i_neg
+        for value in &[298.15f64, 10_000.0, 3950.0, 3.0, 1.5, 1.5] {
+            blob.extend_from_slice(&value.to_le_bytes());
+        }
+        // adc_postfilter: variant index of PostFilter::F21SPS
+        blob.push(1);
+
+        let mut store = store();
+        store.write("ch0", &blob).unwrap();
+        assert!(store.read_value::<ChannelConfig>("ch0").is_err());
+        let migrated = ChannelConfig::read(&store, "ch0").unwrap().unwrap();
+        assert_eq!(migrated.pid.kp, 1.5);
+        // per sample at 21.25 SPS
+        assert!((migrated.pid.ki - 0.25 * 21.25).abs() < 1e-5);
+        assert_eq!((migrated.pid.integral_min, migrated.pid.integral_max), (-1.0, 1.0));
+        assert_eq!(migrated.pid_target, 30.0);
+        assert!(migrated.pid_engaged);
+        assert_eq!(migrated.sh.b, 3950.0);
+        assert_eq!(migrated.sh.r0, ElectricalResistance::new::<ohm>(10_000.0));
+        assert_eq!(migrated.pwm.max_i_neg, 1.5);
+        assert_eq!(migrated.adc_postfilter, PostFilter::F21SPS);
+        assert_eq!(migrated.validate(), Ok(()));
+    }
+
+    #[test]
+    fn migrate_initial_ipv4_layout() {
+        let mut store = store();
+        // address, mask_len, gateway: Some
+        store.write("ipv4", &[10, 0, 0, 2, 24, 1, 10, 0, 0, 1]).unwrap();
+        assert_eq!(read_ipv4_config(&store).unwrap(), Some(Ipv4Config {
+            address: [10, 0, 0, 2],
+            mask_len: 24,
+            gateway: Some([10, 0, 0, 1]),
+            dhcp: false,
+        }));
+    }
+
+    fn device_config(channel: ChannelConfig, fan_source: FanSource) -> DeviceConfig {
+        let mut channels = Vec::new();
+        let _ = channels.push(channel.clone());
+        let _ = channels.push(channel);
+        DeviceConfig {
+            channels,
+            ipv4: Ipv4Config {
+                address: [255; 4],
+                mask_len: 255,
+                gateway: Some([255; 4]),
+                dhcp: false,
+            },
+            fan: FanConfig {
+                auto_mode: false,
+                fan_pwm: u32::MAX,
+                source: fan_source,
+                k_a: 1.0,
+                k_b: 0.0,
+                k_c: 0.0,
+                pwm_freq: u32::MAX,
+            },
+        }
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let mut channel = config();
+        channel.center = CenterPoint::Override(1.5);
+        channel.control_mode = ControlMode::Hysteresis(Hysteresis { low: 24.5, high: 25.5, heat: true });
+        channel.pid_cool = Some(pid::Parameters { kp: 2.0, ..pid::Parameters::default() });
+        channel.setpoint.source = SetpointSource::Analog;
+        channel.sensor = SensorType::Rtd;
+        channel.sh.model = steinhart_hart::Model::Abc;
+        channel.adc_postfilter = PostFilter::F21SPS;
+        channel.temp_limits = TempLimits { min: Some(10.0), max: None };
+        let config = device_config(channel, FanSource::Channel(1));
+
+        let json: JsonBuffer = serde_json_core::to_vec(&config).unwrap();
+        let imported: DeviceConfig = serde_json_core::from_slice(&json).unwrap();
+        assert_eq!(imported, config);
+    }
+
+    /// Every field present, every number at its longest
+    #[test]
+    fn export_fits_import_buffer() {
+        let v32 = -f32::MIN_POSITIVE;
+        let v64 = -f64::MIN_POSITIVE;
+        let pid = pid::Parameters {
+            kp: v32,
+            ki: v32,
+            kd: v32,
+            kd_filter: v32,
+            output_min: v32,
+            output_max: v32,
+            integral_min: v32,
+            integral_max: v32,
+            slew: v32,
+            ff: v32,
+            ff_target: v32,
+        };
+        let channel = ChannelConfig {
+            center: CenterPoint::Override(v32),
+            pid: pid.clone(),
+            pid_cool: Some(pid),
+            pid_target: v32,
+            pid_engaged: false,
+            control_mode: ControlMode::Hysteresis(Hysteresis { low: v32, high: v32, heat: false }),
+            setpoint: SetpointInput { source: SetpointSource::Internal, scale: v64, offset: v64 },
+            sensor: SensorType::Thermistor,
+            sh: steinhart_hart::Parameters {
+                model: steinhart_hart::Model::Beta,
+                t0: ThermodynamicTemperature::new::<kelvin>(v64),
+                r0: ElectricalResistance::new::<ohm>(v64),
+                b: v64,
+                a: v64,
+                b3: v64,
+                c: v64,
+                t_min: ThermodynamicTemperature::new::<kelvin>(v64),
+                t_max: ThermodynamicTemperature::new::<kelvin>(v64),
+                fault_holdoff: u32::MAX,
+                correction_gain: v64,
+                correction_offset: v64,
+            },
+            rtd: rtd::Parameters {
+                r0: ElectricalResistance::new::<ohm>(v64),
+                a: v64,
+                b: v64,
+                c: v64,
+            },
+            pwm: PwmLimits {
+                max_v: v64,
+                max_i_pos: v64,
+                max_i_neg: v64,
+                softstart: u32::MAX,
+                invert: false,
+            },
+            adc_postfilter: PostFilter::Invalid,
+            adc_ref_source: RefSource::Avdd1MinusAvss,
+            temp_limits: TempLimits { min: Some(v32), max: Some(v32) },
+            runaway: RunawayParameters { window: v32, slope: v32 },
+        };
+        // Each channel is sent as one part of `config export`
+        let part: Result<JsonBuffer, _> = serde_json_core::to_vec(&channel);
+        assert!(part.is_ok());
+
+        let config = device_config(channel, FanSource::Temperature);
+        let json: Vec<u8, U8192> = serde_json_core::to_vec(&config).unwrap();
+        // Followed by the newline and `end`, which are collected too
+        assert!(json.len() + b"\nend".len() <= ImportBuffer::new().capacity());
+    }
+
+    #[test]
+    fn verify_missing_config() {
+        let stored = store().read_value::<ChannelConfig>("ch0");
+        assert_eq!(ConfigVerification::new(0, stored), None);
+    }
+
+    #[test]
+    fn save_summary() {
+        let mut summary = SaveSummary::default();
+        summary.record("ch0", Ok::<(), ()>(()));
+        assert!(summary.is_complete());
+        assert_eq!(&summary.summary_json().unwrap()[..], &br#"{"saved":["ch0"],"failed":[]}"#[..]);
+
+        summary.record("ipv4", Err(()));
+        assert!(!summary.is_complete());
+        assert_eq!(
+            &summary.summary_json().unwrap()[..],
+            &br#"{"saved":["ch0"],"failed":["ipv4"],"error":"unable to save all settings","code":"flash"}"#[..],
+        );
     }
 }
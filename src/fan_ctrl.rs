@@ -1,39 +1,139 @@
 use num_traits::Float;
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 use stm32f4xx_hal::{
+    gpio::{gpioa::PA15, Alternate, AF1},
     pwm::{self, PwmChannels},
-    pac::TIM8,
+    pac::{RCC, TIM2, TIM8},
+    rcc::Clocks,
 };
 
 use crate::{
     hw_rev::HWSettings,
+    channels::CHANNELS,
     command_handler::JsonBuffer,
+    command_parser::FanSource,
+    timer,
 };
 
 pub type FanPin = PwmChannels<TIM8, pwm::C4>;
+/// TIM2_CH1, the tachometer wire of the fan
+pub type FanTachPin = PA15<Alternate<AF1>>;
 
 // as stated in the schematics
 const MAX_TEC_I: f32 = 3.0;
+/// Temperatures in °C that `FanSource::Temperature` maps to 0 and 1
+const MIN_FAN_TEMP: f32 = 25.0;
+const MAX_FAN_TEMP: f32 = 75.0;
+
+/// Range accepted for the fan PWM frequency, in Hz
+const MIN_FAN_PWM_FREQ: u32 = 10;
+const MAX_FAN_PWM_FREQ: u32 = 100_000;
 
 const MAX_USER_FAN_PWM: f32 = 100.0;
 const MIN_USER_FAN_PWM: f32 = 1.0;
 
+/// Tachometer pulses per fan revolution
+const TACH_PULSES_PER_REV: u32 = 2;
+/// Interval over which tachometer pulses are counted, in ms
+const TACH_GATE_MS: u32 = 1000;
+/// Time without rotation before a driven fan is considered stalled,
+/// in ms
+const STALL_TIMEOUT_MS: u32 = 3000;
+
+/// Counts tachometer pulses in hardware, TIM2 is clocked by its
+/// channel 1 input
+pub struct FanTach {
+    tim: TIM2,
+    _pin: FanTachPin,
+}
+
+impl FanTach {
+    pub fn new(tim: TIM2, pin: FanTachPin) -> Self {
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.apb1enr.modify(|_, w| w.tim2en().set_bit());
+        // TI1 with the longest input filter against PWM crosstalk
+        tim.ccmr1_input().write(|w| unsafe { w.cc1s().bits(0b01).ic1f().bits(0b1111) });
+        // External clock mode 1, counting rising edges of TI1FP1
+        tim.smcr.write(|w| unsafe { w.sms().bits(0b111).ts().bits(0b101) });
+        tim.arr.write(|w| unsafe { w.bits(u32::MAX) });
+        tim.cr1.write(|w| w.cen().set_bit());
+        FanTach { tim, _pin: pin }
+    }
+
+    /// Pulses counted so far, wrapping
+    pub fn count(&self) -> u32 {
+        self.tim.cnt.read().bits()
+    }
+}
+
+/// RPM from the tachometer pulses counted over each gate interval
+#[derive(Default)]
+struct TachState {
+    /// Time in ms and count at the start of the gate interval
+    gate_start: Option<(u32, u32)>,
+    rpm: Option<u32>,
+    /// Since when the fan has been driven without turning
+    stopped_since: Option<u32>,
+    stalled: bool,
+}
+
+impl TachState {
+    /// `driven` if PWM is commanded
+    fn update(&mut self, now: u32, count: u32, driven: bool) {
+        let (start, start_count) = match self.gate_start {
+            Some(gate_start) => gate_start,
+            None => {
+                self.gate_start = Some((now, count));
+                return;
+            }
+        };
+        let elapsed = now.wrapping_sub(start);
+        if elapsed >= TACH_GATE_MS {
+            let pulses = u64::from(count.wrapping_sub(start_count));
+            let rpm = pulses * 60_000 / u64::from(TACH_PULSES_PER_REV * elapsed);
+            self.rpm = Some(rpm as u32);
+            self.gate_start = Some((now, count));
+            if rpm > 0 {
+                self.stopped_since = None;
+            } else if self.stopped_since.is_none() {
+                self.stopped_since = Some(now);
+            }
+        }
+        if !driven {
+            self.stopped_since = None;
+        }
+        self.stalled = self.stopped_since
+            .map_or(false, |since| now.wrapping_sub(since) >= STALL_TIMEOUT_MS);
+    }
+}
+
 
 pub struct FanCtrl {
     fan: Option<FanPin>,
+    tach: Option<FanTach>,
+    tach_state: TachState,
     fan_auto: bool,
     pwm_enabled: bool,
     k_a: f32,
     k_b: f32,
     k_c: f32,
+    source: FanSource,
     abs_max_tec_i: f32,
+    /// Current selected by `source`
+    source_tec_i: f32,
+    /// Hottest channel temperature, `None` if none has been measured
+    max_temperature: Option<f32>,
+    /// Input clock of TIM8 in Hz
+    pwm_clock: u32,
     hw_settings: HWSettings,
 }
 
 impl FanCtrl {
-    pub fn new(fan: Option<FanPin>, hw_settings: HWSettings) -> Self {
+    pub fn new(fan: Option<FanPin>, tach: Option<FanTach>, pwm_clock: u32, hw_settings: HWSettings) -> Self {
         let mut fan_ctrl = FanCtrl {
             fan,
+            tach,
+            tach_state: TachState::default(),
             // do not enable auto mode by default,
             // but allow to turn it at the user's own risk
             fan_auto: hw_settings.fan_pwm_recommended,
@@ -41,7 +141,11 @@ impl FanCtrl {
             k_a: hw_settings.fan_k_a,
             k_b: hw_settings.fan_k_b,
             k_c: hw_settings.fan_k_c,
+            source: FanSource::Max,
             abs_max_tec_i: 0f32,
+            source_tec_i: 0f32,
+            max_temperature: None,
+            pwm_clock,
             hw_settings,
         };
         if fan_ctrl.fan_auto {
@@ -50,14 +154,35 @@ impl FanCtrl {
         fan_ctrl
     }
 
-    pub fn cycle(&mut self, abs_max_tec_i: f32) {
-        self.abs_max_tec_i = abs_max_tec_i;
+    /// Follow the TEC currents or temperatures of all channels
+    pub fn cycle(&mut self, tec_i: [f32; CHANNELS], temperatures: [Option<f32>; CHANNELS]) {
+        self.abs_max_tec_i = source_current(FanSource::Max, &tec_i);
+        self.source_tec_i = source_current(self.source, &tec_i);
+        self.max_temperature = temperatures.iter()
+            .filter_map(|temperature| *temperature)
+            .fold(None, |max: Option<f32>, t| Some(max.map_or(t, |max| max.max(t))));
         if self.fan_auto && self.hw_settings.fan_available {
-            let scaled_current = self.abs_max_tec_i / MAX_TEC_I;
-            // do not limit upper bound, as it will be limited in the set_pwm()
-            let pwm = (MAX_USER_FAN_PWM * (scaled_current * (scaled_current * self.k_a + self.k_b) + self.k_c)) as u32;
+            let pwm = self.curve_pwm();
             self.set_pwm(pwm);
         }
+        if let Some(tach) = &self.tach {
+            let driven = self.pwm_enabled && self.get_pwm_raw().map_or(false, |(duty, _)| duty > 0);
+            self.tach_state.update(timer::now(), tach.count(), driven);
+        }
+    }
+
+    /// Fan power according to the curve at the input of `source`
+    fn curve_pwm(&self) -> u32 {
+        let x = match self.source {
+            FanSource::Temperature => match self.max_temperature {
+                Some(temperature) => ((temperature - MIN_FAN_TEMP) / (MAX_FAN_TEMP - MIN_FAN_TEMP)).max(0.0),
+                // Cool at full power while blind
+                None => 1.0,
+            },
+            _ => self.source_tec_i / MAX_TEC_I,
+        };
+        // do not limit upper bound, as it will be limited in the set_pwm()
+        (MAX_USER_FAN_PWM * (x * (x * self.k_a + self.k_b) + self.k_c)) as u32
     }
 
     pub fn summary(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
@@ -65,10 +190,16 @@ impl FanCtrl {
             let summary = FanSummary {
                 fan_pwm: self.get_pwm(),
                 abs_max_tec_i: self.abs_max_tec_i,
+                source: self.source,
+                source_tec_i: self.source_tec_i,
+                max_temperature: self.max_temperature,
                 auto_mode: self.fan_auto,
                 k_a: self.k_a,
                 k_b: self.k_b,
                 k_c: self.k_c,
+                pwm_freq: self.hw_settings.fan_pwm_freq_hz,
+                rpm: self.tach_state.rpm,
+                stalled: self.tach_state.stalled,
             };
             serde_json_core::to_vec(&summary)
         } else {
@@ -77,10 +208,38 @@ impl FanCtrl {
         }
     }
 
+    /// Settings to back up or restore
+    pub fn config(&self) -> FanConfig {
+        FanConfig {
+            auto_mode: self.fan_auto,
+            fan_pwm: self.get_pwm(),
+            source: self.source,
+            k_a: self.k_a,
+            k_b: self.k_b,
+            k_c: self.k_c,
+            pwm_freq: self.hw_settings.fan_pwm_freq_hz,
+        }
+    }
+
+    /// Restore settings from `config()`
+    pub fn apply_config(&mut self, config: &FanConfig) {
+        self.set_source(config.source);
+        self.set_curve(config.k_a, config.k_b, config.k_c);
+        self.set_pwm_freq(config.pwm_freq);
+        self.set_auto_mode(config.auto_mode);
+        if !config.auto_mode && self.hw_settings.fan_available {
+            self.set_pwm(config.fan_pwm);
+        }
+    }
+
     pub fn set_auto_mode(&mut self, fan_auto: bool) {
         self.fan_auto = fan_auto;
     }
 
+    pub fn set_source(&mut self, source: FanSource) {
+        self.source = source;
+    }
+
     pub fn set_curve(&mut self, k_a: f32, k_b: f32, k_c: f32) {
         self.k_a = k_a;
         self.k_b = k_b;
@@ -106,6 +265,21 @@ impl FanCtrl {
         value as f32 / (max as f32)
     }
 
+    /// Reprogram the PWM timer for a new frequency, keeping the duty
+    /// cycle. Returns the clamped frequency.
+    pub fn set_pwm_freq(&mut self, freq_hz: u32) -> u32 {
+        let freq_hz = freq_hz.max(MIN_FAN_PWM_FREQ).min(MAX_FAN_PWM_FREQ);
+        let (psc, arr) = pwm_timer_period(self.pwm_clock, freq_hz);
+        if let Some(fan) = self.fan.as_mut() {
+            let (duty, max) = (fan.get_duty(), fan.get_max_duty());
+            set_timer_period(fan, psc, arr);
+            let new_max = fan.get_max_duty();
+            fan.set_duty((u32::from(duty) * u32::from(new_max) / u32::from(max.max(1))) as u16);
+        }
+        self.hw_settings.fan_pwm_freq_hz = freq_hz;
+        freq_hz
+    }
+
     pub fn fan_pwm_recommended(&self) -> bool {
         self.hw_settings.fan_pwm_recommended
     }
@@ -114,6 +288,12 @@ impl FanCtrl {
         self.hw_settings.fan_available
     }
 
+    /// Timer duty count and maximum
+    pub fn get_pwm_raw(&self) -> Option<(u16, u16)> {
+        self.fan.as_ref()
+            .map(|fan| (fan.get_duty(), fan.get_max_duty()))
+    }
+
     fn get_pwm(&self) -> u32 {
         if let Some(fan) = &self.fan {
             let duty = fan.get_duty();
@@ -136,16 +316,190 @@ impl FanCtrl {
 }
 
 
+/// Input clock of TIM8, twice the APB2 clock when that is divided
+pub fn pwm_timer_clock(clocks: &Clocks) -> u32 {
+    clocks.pclk2().0 * if clocks.ppre2() == 1 { 1 } else { 2 }
+}
+
+/// Prescaler and auto-reload values for a PWM frequency, derived the
+/// same way as the HAL does at init
+fn pwm_timer_period(clock: u32, freq_hz: u32) -> (u32, u32) {
+    let ticks = clock / freq_hz;
+    let psc = (ticks - 1) / (1 << 16);
+    let arr = ticks / (psc + 1);
+    (psc, arr)
+}
+
+/// Reprogram the period of the fan PWM timer, which the HAL only sets
+/// at init
+fn set_timer_period(_fan: &mut FanPin, psc: u32, arr: u32) {
+    // Safety: `FanPin` was made from TIM8 and owns it, so holding it
+    // mutably guarantees exclusive access to the timer registers.
+    let tim = unsafe { &*TIM8::ptr() };
+    // Safety: the values are derived like the HAL's own at init and
+    // any value of these registers is a valid period.
+    tim.psc.write(|w| unsafe { w.bits(psc) });
+    tim.arr.write(|w| unsafe { w.bits(arr) });
+    // Reload the prescaler and restart the period
+    tim.egr.write(|w| w.ug().set_bit());
+}
+
+/// Absolute TEC current that the fan curve follows
+fn source_current(source: FanSource, tec_i: &[f32; CHANNELS]) -> f32 {
+    match source {
+        FanSource::Channel(channel) => tec_i[channel].abs(),
+        FanSource::Max => tec_i.iter().fold(0f32, |max, i| max.max(i.abs())),
+        FanSource::Sum => tec_i.iter().map(|i| i.abs()).sum(),
+        FanSource::Temperature => 0.0,
+    }
+}
+
 fn scale_number(unscaled: f32, to_min: f32, to_max: f32, from_min: f32, from_max: f32) -> f32 {
     (to_max - to_min) * (unscaled - from_min) / (from_max - from_min) + to_min
 }
 
+/// User settings of the fan controller
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FanConfig {
+    pub auto_mode: bool,
+    /// Fan power in manual mode
+    pub fan_pwm: u32,
+    pub source: FanSource,
+    pub k_a: f32,
+    pub k_b: f32,
+    pub k_c: f32,
+    /// In Hz
+    pub pwm_freq: u32,
+}
+
+impl FanConfig {
+    /// Check a config from an untrusted source before applying it
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !(MIN_FAN_PWM_FREQ..=MAX_FAN_PWM_FREQ).contains(&self.pwm_freq) {
+            return Err("fan PWM frequency out of range");
+        }
+        if self.fan_pwm > MAX_USER_FAN_PWM as u32 {
+            return Err("fan power out of range");
+        }
+        if [self.k_a, self.k_b, self.k_c].iter().any(|k| !k.is_finite()) {
+            return Err("fan curve coefficients must be finite");
+        }
+        match self.source {
+            FanSource::Channel(channel) if channel >= CHANNELS =>
+                Err("invalid fan source channel"),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct FanSummary {
     fan_pwm: u32,
     abs_max_tec_i: f32,
+    source: FanSource,
+    source_tec_i: f32,
+    max_temperature: Option<f32>,
     auto_mode: bool,
     k_a: f32,
     k_b: f32,
     k_c: f32,
+    /// In Hz
+    pwm_freq: u32,
+    /// `None` without a tachometer
+    rpm: Option<u32>,
+    /// Driven but not turning
+    stalled: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fan_ctrl() -> FanCtrl {
+        FanCtrl::new(None, None, 84_000_000, HWSettings {
+            fan_k_a: 1.0,
+            fan_k_b: 0.0,
+            fan_k_c: 0.0,
+            min_fan_pwm: 0.04,
+            max_fan_pwm: 1.0,
+            fan_pwm_freq_hz: 25_000,
+            fan_available: true,
+            fan_pwm_recommended: true,
+        })
+    }
+
+    #[test]
+    fn curve_follows_source() {
+        let mut fan_ctrl = fan_ctrl();
+        let tec_i = [1.5, -3.0];
+
+        fan_ctrl.cycle(tec_i, [None; CHANNELS]);
+        assert_eq!(fan_ctrl.abs_max_tec_i, 3.0);
+        assert_eq!(fan_ctrl.curve_pwm(), 100);
+
+        fan_ctrl.set_source(FanSource::Channel(0));
+        fan_ctrl.cycle(tec_i, [None; CHANNELS]);
+        assert_eq!(fan_ctrl.source_tec_i, 1.5);
+        assert_eq!(fan_ctrl.curve_pwm(), 25);
+
+        fan_ctrl.set_source(FanSource::Sum);
+        fan_ctrl.cycle([0.75, -0.75], [None; CHANNELS]);
+        assert_eq!(fan_ctrl.source_tec_i, 1.5);
+        assert_eq!(fan_ctrl.curve_pwm(), 25);
+        // the summary keeps showing the larger current
+        assert_eq!(fan_ctrl.abs_max_tec_i, 0.75);
+    }
+
+    #[test]
+    fn pwm_freq_clamped() {
+        let mut fan_ctrl = fan_ctrl();
+        assert_eq!(fan_ctrl.set_pwm_freq(20_000), 20_000);
+        assert_eq!(fan_ctrl.hw_settings.fan_pwm_freq_hz, 20_000);
+        assert_eq!(fan_ctrl.set_pwm_freq(1_000_000), MAX_FAN_PWM_FREQ);
+        assert_eq!(fan_ctrl.set_pwm_freq(0), MIN_FAN_PWM_FREQ);
+    }
+
+    #[test]
+    fn pwm_period_fits_timer() {
+        assert_eq!(pwm_timer_period(84_000_000, 25_000), (0, 3360));
+        // needs the prescaler
+        assert_eq!(pwm_timer_period(84_000_000, 10), (128, 65116));
+    }
+
+    #[test]
+    fn curve_follows_temperature() {
+        let mut fan_ctrl = fan_ctrl();
+        fan_ctrl.set_source(FanSource::Temperature);
+        fan_ctrl.cycle([3.0, 3.0], [Some(30.0), Some(50.0)]);
+        assert_eq!(fan_ctrl.max_temperature, Some(50.0));
+        assert_eq!(fan_ctrl.curve_pwm(), 25);
+        // below the range
+        fan_ctrl.cycle([0.0, 0.0], [Some(20.0), None]);
+        assert_eq!(fan_ctrl.curve_pwm(), 0);
+        // no temperature
+        fan_ctrl.cycle([0.0, 0.0], [None, None]);
+        assert_eq!(fan_ctrl.curve_pwm(), 100);
+    }
+
+    #[test]
+    fn tach_rpm_and_stall() {
+        let mut tach = TachState::default();
+        tach.update(0, 100, true);
+        assert_eq!(tach.rpm, None);
+        // 40 pulses in 1 s
+        tach.update(1000, 140, true);
+        assert_eq!(tach.rpm, Some(1200));
+        assert!(!tach.stalled);
+
+        // stops turning
+        tach.update(2000, 140, true);
+        assert_eq!(tach.rpm, Some(0));
+        tach.update(4000, 140, true);
+        assert!(!tach.stalled);
+        tach.update(5000, 140, true);
+        assert!(tach.stalled);
+        // not a stall when the fan is not driven
+        tach.update(5100, 140, false);
+        assert!(!tach.stalled);
+    }
 }
@@ -0,0 +1,203 @@
+use heapless::{consts::U16, Vec};
+use serde::Serialize;
+
+/// Syntax hints for `help <topic>`, kept in flash
+pub static TOPICS: &[(&str, &[&str])] = &[
+    ("report", &[
+        "report [0|1]",
+        "report mode [off|on]",
+        "report connect <off|on|snapshot>",
+        "report format <json|csv>",
+        "report interval <ms>",
+        "units",
+        "units temp <celsius|kelvin|fahrenheit>",
+        "all",
+    ]),
+    ("pwm", &[
+        "pwm",
+        "pwm <0|1> <max_i_pos|max_i_neg|max_v|i_set> <value>",
+        "pwm <0|1> pid",
+        "pwm <0|1> raw",
+        "pwm <0|1> softstart <ms>",
+        "pwm <0|1> invert <on|off>",
+        "center <0|1> <volt|vref>",
+        "dac <0|1> raw <0-262143>",
+        "dac <0|1> verify <on|off>",
+    ]),
+    ("pid", &[
+        "pid",
+        "pid <0|1> target <degC>",
+        "pid <0|1> ramp <degC> <K/s>",
+        "pid <0|1> <kp|ki|kd> <value>",
+        "pid <0|1> kd_filter <s>",
+        "pid <0|1> <output_min|output_max> <amp>",
+        "pid <0|1> <integral_min|integral_max> <amp>",
+        "pid <0|1> slew <amp/s>",
+        "pid <0|1> ff <amp>",
+        "pid <0|1> ff_target <amp/K>",
+        "pid <0|1> setpoint_source <internal|analog>",
+        "pid <0|1> setpoint_scale <degC/volt>",
+        "pid <0|1> setpoint_offset <degC>",
+        "pid <0|1> <heat|cool> <param> <value>",
+        "pid <0|1> cool off",
+        "pid <0|1> reset",
+        "pid <0|1> terms",
+        "pid <0|1> autotune <amp>",
+        "mode <0|1> pid",
+        "mode <0|1> hysteresis <low> <high> [heat]",
+        "profile <0|1>",
+        "profile <0|1> add <degC> <K/s> <hold_ms>",
+        "profile <0|1> <clear|start|stop>",
+        "profile <0|1> mode <once|loop>",
+    ]),
+    ("s-h", &[
+        "s-h",
+        "s-h <0|1> <t0|b|r0> <value>",
+        "s-h <0|1> <a|b3|c> <value>",
+        "s-h <0|1> <t_min|t_max> <degC>",
+        "s-h <0|1> fault_holdoff <n>",
+        "s-h <0|1> temp_correction <gain> <offset>",
+        "s-h <0|1> calibrate <r1> <t1> <r2> <t2> <r3> <t3>",
+        "sensor",
+        "sensor <0|1> type <thermistor|rtd>",
+        "sensor <0|1> <r0|a|b|c> <value>",
+    ]),
+    ("tec", &[
+        "tec",
+        "tec <0|1> peltier <V/K>",
+        "tec <0|1> reset",
+        "limit",
+        "limit <0|1> <temp_min|temp_max> <degC|off>",
+        "limit <0|1> runaway_window <s>",
+        "limit <0|1> runaway_slope <K/s>",
+        "clear <0|1>",
+        "defaults <0|1>",
+        "channel <0|1> <enable|disable>",
+    ]),
+    ("postfilter", &[
+        "postfilter",
+        "postfilter <0|1> off",
+        "postfilter <0|1> rate <rate>",
+        "odr <0|1> <rate>",
+        "adc <0|1> rate <rate>",
+    ]),
+    ("adc", &[
+        "adc",
+        "adc <0|1> mode <continuous|on_demand>",
+        "adc <0|1> read",
+        "adc <0|1> noisefloor",
+        "adc <0|1> interval_tolerance <ratio>",
+        "adc <0|1> refsource <external|internal|avdd>",
+        "adc <0|1> bipolar <on|off>",
+        "adc <0|1> filter <sinc5|sinc3> [decimation]",
+        "adc checksum <off|xor|crc>",
+        "adc avg <1-256>",
+        "stats <0|1>",
+        "stats <0|1> reset",
+        "adc_temp",
+        "supply",
+    ]),
+    ("fan", &[
+        "fan",
+        "fan <1-100|auto>",
+        "fan source <0|1|max|sum|current|temperature>",
+        "fan freq <hz>",
+        "fan save",
+        "fcurve <a> <b> <c>",
+        "fcurve default",
+    ]),
+    ("ipv4", &[
+        "ipv4",
+        "ipv4 <X.X.X.X/L> [Y.Y.Y.Y]",
+        "ipv4 dhcp",
+        "port [n]",
+        "mac <xx:xx:xx:xx:xx:xx>",
+        "mac default",
+        "tcp timeout <s>",
+        "tcp timeout off",
+        "telemetry udp <ip> <port> <s>",
+        "telemetry off",
+        "report udp <ip> <port>",
+        "report udp off",
+        "mqtt <ip> <port> <prefix>",
+        "mqtt off",
+        "ntp <ip>",
+        "ntp off",
+        "log to_tcp <on|off>",
+    ]),
+    ("config", &[
+        "load [0|1]",
+        "save [0|1]",
+        "save all",
+        "flash verify <0|1>",
+        "diff <0|1>",
+        "config export",
+        "config import",
+        "stage <begin|commit|abort>",
+    ]),
+    ("system", &[
+        "help [topic]",
+        "hwrev",
+        "version",
+        "uptime",
+        "netstat",
+        "watchdog [ms]",
+        "cal",
+        "cal <rsense|v_ref|r_inner|vref_sens> <value>",
+        "cal default",
+        "cal dac <0|1> [reset]",
+        "reset",
+        "reset factory",
+        "dfu",
+    ]),
+];
+
+/// `{"topics":[...]}` for `help`
+#[derive(Serialize)]
+pub struct Topics {
+    topics: Vec<&'static str, U16>,
+}
+
+impl Default for Topics {
+    fn default() -> Self {
+        Topics::new()
+    }
+}
+
+impl Topics {
+    pub fn new() -> Self {
+        let mut topics = Vec::new();
+        for (topic, _) in TOPICS {
+            let _ = topics.push(*topic);
+        }
+        Topics { topics }
+    }
+}
+
+/// `{"topic":...,"commands":[...]}` for `help <topic>`
+#[derive(Serialize)]
+pub struct Topic {
+    topic: &'static str,
+    commands: &'static [&'static str],
+}
+
+impl Topic {
+    pub fn new(topic: &'static str) -> Self {
+        Topic { topic, commands: commands(topic) }
+    }
+}
+
+/// The static name of a help topic
+pub fn topic(name: &[u8]) -> Option<&'static str> {
+    TOPICS.iter()
+        .map(|(topic, _)| *topic)
+        .find(|topic| topic.as_bytes() == name)
+}
+
+/// Syntax hints of a help topic
+pub fn commands(topic: &str) -> &'static [&'static str] {
+    TOPICS.iter()
+        .find(|(name, _)| *name == topic)
+        .map(|(_, commands)| *commands)
+        .unwrap_or(&[])
+}
@@ -1,10 +1,14 @@
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 
 use crate::{
     pins::HWRevPins,
     command_handler::JsonBuffer,
 };
 
+/// Firmware version and commit, fixed at build time
+pub static VERSION: &str = env!("CARGO_PKG_VERSION");
+pub static GIT_HASH: &str = env!("GIT_HASH");
+
 #[derive(Serialize, Copy, Clone)]
 pub struct HWRev {
     pub major: u8,
@@ -23,12 +27,33 @@ pub struct HWSettings {
     pub fan_pwm_recommended: bool,
 }
 
+/// Constants of the analog front end that conversions depend on,
+/// overridable for boards that deviate from the schematics
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HardwareParams {
+    /// TEC current sense resistor in Ω
+    pub r_sense: f64,
+    /// External ADC reference in V
+    pub v_ref: f64,
+    /// Bias resistance of the temperature sensor input in Ω
+    pub r_inner: f64,
+    /// Bias voltage of the temperature sensor input in V
+    pub vref_sens: f64,
+}
+
 #[derive(Serialize, Clone)]
 struct HWSummary<'a> {
     rev: &'a HWRev,
     settings: &'a HWSettings,
 }
 
+#[derive(Serialize, Clone)]
+struct VersionSummary<'a> {
+    version: &'static str,
+    git: &'static str,
+    hwrev: &'a HWRev,
+}
+
 impl HWRev {
     pub fn detect_hw_rev(hwrev_pins: &HWRevPins) -> Self {
         let (h0, h1, h2, h3) = (hwrev_pins.hwrev0.is_high(), hwrev_pins.hwrev1.is_high(),
@@ -74,9 +99,24 @@ impl HWRev {
         }
     }
 
+    /// Nominal values, identical on all revisions so far
+    pub fn hardware_params(&self) -> HardwareParams {
+        HardwareParams {
+            r_sense: 0.05,
+            v_ref: 3.3,
+            r_inner: 2.0 * 5100.0,
+            vref_sens: 3.3 / 2.0,
+        }
+    }
+
     pub fn summary(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
         let settings = self.settings();
         let summary = HWSummary { rev: self, settings: &settings };
         serde_json_core::to_vec(&summary)
     }
-}
\ No newline at end of file
+
+    pub fn version_summary(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let summary = VersionSummary { version: VERSION, git: GIT_HASH, hwrev: self };
+        serde_json_core::to_vec(&summary)
+    }
+}
@@ -6,6 +6,43 @@ use stm32f4xx_hal::{
     hal::digital::v2::OutputPin,
 };
 
+/// Period of the idle heartbeat in ms
+const HEARTBEAT_PERIOD: u32 = 2000;
+/// Duration of a heartbeat flash in ms
+const HEARTBEAT_ON: u32 = 100;
+/// Blink period while a PID loop drives the TEC current, in ms
+const ACTIVE_PERIOD: u32 = 500;
+/// Period of alternating red and green before a reset, in ms
+const RESET_PERIOD: u32 = 200;
+
+/// Device state shown by L1 and L3, by decreasing priority
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    /// Entering DFU mode or resetting
+    Reset,
+    /// Sensor fault of an enabled channel, temperature limit, thermal
+    /// runaway or flash error
+    Fault,
+    /// A PID loop drives the TEC current
+    Active,
+    Idle,
+}
+
+impl Status {
+    /// States of red L1 and green L3 at `now` in ms
+    pub fn pattern(&self, now: u32) -> (bool, bool) {
+        match self {
+            Status::Reset => {
+                let red = now % RESET_PERIOD < RESET_PERIOD / 2;
+                (red, !red)
+            }
+            Status::Fault => (true, false),
+            Status::Active => (false, now % ACTIVE_PERIOD < ACTIVE_PERIOD / 2),
+            Status::Idle => (false, now % HEARTBEAT_PERIOD < HEARTBEAT_ON),
+        }
+    }
+}
+
 pub struct Leds {
     /// Red LED L1
     pub r1: Led<PD9<Output<PushPull>>>,
@@ -23,6 +60,14 @@ impl Leds {
             g4: Led::new(g4.into_push_pull_output()),
         }
     }
+
+    /// Show `status` on L1 and L3, to be called on every main loop
+    /// iteration. L4 is left to the main loop.
+    pub fn show(&mut self, status: Status, now: u32) {
+        let (red, green) = status.pattern(now);
+        self.r1.set(red);
+        self.g3.set(green);
+    }
 }
 
 pub struct Led<P> {
@@ -41,4 +86,40 @@ impl<P: OutputPin> Led<P> {
     pub fn off(&mut self) {
         let _ = self.pin.set_low();
     }
+
+    pub fn set(&mut self, on: bool) {
+        if on {
+            self.on();
+        } else {
+            self.off();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fault_is_solid_red() {
+        for now in (0..4000).step_by(10) {
+            assert_eq!(Status::Fault.pattern(now), (true, false));
+        }
+    }
+
+    #[test]
+    fn active_blinks_faster_than_heartbeat() {
+        let green_edges = |status: Status| (0..HEARTBEAT_PERIOD)
+            .filter(|now| status.pattern(*now).1 && !status.pattern(now + 1).1)
+            .count();
+        assert_eq!(green_edges(Status::Idle), 1);
+        assert_eq!(green_edges(Status::Active), 4);
+        assert!(!Status::Idle.pattern(HEARTBEAT_ON).0);
+    }
+
+    #[test]
+    fn reset_alternates() {
+        assert_eq!(Status::Reset.pattern(0), (true, false));
+        assert_eq!(Status::Reset.pattern(RESET_PERIOD / 2), (false, true));
+    }
 }
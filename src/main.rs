@@ -9,6 +9,7 @@ use panic_abort as _;
 #[cfg(all(feature = "semihosting", not(test)))]
 use panic_semihosting as _;
 
+use core::fmt::Write;
 use log::{error, info, warn};
 use cortex_m::asm::wfi;
 use cortex_m_rt::entry;
@@ -29,6 +30,7 @@ mod init_log;
 use init_log::init_log;
 mod usb;
 mod leds;
+use leds::Status;
 mod pins;
 use pins::Pins;
 mod ad7172;
@@ -36,61 +38,62 @@ mod ad5680;
 mod net;
 mod server;
 use server::Server;
+mod netstat;
+mod telemetry;
+use telemetry::Telemetry;
+mod mqtt;
+use mqtt::Mqtt;
+mod sntp;
+use sntp::Sntp;
+mod tcp_log;
 mod session;
-use session::{Session, SessionInput};
+use session::{Error as SessionError, Session, SessionInput};
 mod command_parser;
-use command_parser::Ipv4Config;
+mod help;
+use command_parser::{Ipv4Config, ReportFormat, ReportOnConnect};
 mod timer;
 mod pid;
+mod profile;
+mod autotune;
 mod steinhart_hart;
+mod rtd;
+mod tec;
 mod channels;
 use channels::{CHANNELS, Channels};
 mod channel;
 mod channel_state;
 mod config;
-use config::ChannelConfig;
+use config::{ChannelConfig, read_ipv4_config};
 mod flash_store;
 mod dfu;
 mod command_handler;
-use command_handler::Handler;
+use command_handler::{Error as HandlerError, Handler, send_line, send_reports_csv};
 mod fan_ctrl;
-use fan_ctrl::FanCtrl;
+use fan_ctrl::{FanConfig, FanCtrl, pwm_timer_clock};
 mod hw_rev;
+mod watchdog;
 
 const HSE: MegaHertz = MegaHertz(8);
 #[cfg(not(feature = "semihosting"))]
-const WATCHDOG_INTERVAL: u32 = 1_000;
+const WATCHDOG_INTERVAL: u32 = watchdog::INTERVAL_MIN;
 #[cfg(feature = "semihosting")]
 const WATCHDOG_INTERVAL: u32 = 30_000;
 
 const CHANNEL_CONFIG_KEY: [&str; 2] = ["ch0", "ch1"];
+/// `vref_meas` in V found by the DAC calibration
+const DAC_CALIBRATION_KEY: [&str; 2] = ["dac0", "dac1"];
 
+/// Default TCP port, overridden by the `port` key in flash
 const TCP_PORT: u16 = 23;
 
-fn send_line(socket: &mut TcpSocket, data: &[u8]) -> bool {
-    let send_free = socket.send_capacity() - socket.send_queue();
-    if data.len() > send_free + 1 {
-        // Not enough buffer space, skip report for now,
-        // instead of sending incomplete line
-        warn!(
-            "TCP socket has only {}/{} needed {}",
-            send_free + 1, socket.send_capacity(), data.len(),
-        );
-    } else {
-        match socket.send_slice(&data) {
-            Ok(sent) if sent == data.len() => {
-                let _ = socket.send_slice(b"\n");
-                // success
-                return true
-            }
-            Ok(sent) =>
-                warn!("sent only {}/{} bytes", sent, data.len()),
-            Err(e) =>
-                error!("error sending line: {:?}", e),
+/// Send as much of a queued report as the socket buffer takes
+fn send_pending_output(socket: &mut TcpSocket, session: &mut Session) {
+    if let Some(output) = session.pending_output() {
+        match socket.send_slice(output) {
+            Ok(sent) => session.mark_output_sent(sent),
+            Err(e) => error!("error sending report: {:?}", e),
         }
     }
-    // not success
-    false
 }
 
 /// Initialization and main loop
@@ -117,11 +120,12 @@ fn main() -> ! {
     let mut wd = IndependentWatchdog::new(dp.IWDG);
     wd.start(WATCHDOG_INTERVAL.ms());
     wd.feed();
+    watchdog::set_interval(WATCHDOG_INTERVAL);
 
     timer::setup(cp.SYST, clocks);
 
-    let (pins, mut leds, mut eeprom, eth_pins, usb, fan, hwrev, hw_settings) = Pins::setup(
-        clocks, dp.TIM1, dp.TIM3, dp.TIM8,
+    let (pins, mut leds, mut eeprom, eth_pins, usb, fan, fan_tach, hwrev, hw_settings) = Pins::setup(
+        clocks, dp.TIM1, dp.TIM2, dp.TIM3, dp.TIM8,
         dp.GPIOA, dp.GPIOB, dp.GPIOC, dp.GPIOD, dp.GPIOE, dp.GPIOF, dp.GPIOG,
         dp.I2C1,
         dp.SPI2, dp.SPI4, dp.SPI5,
@@ -139,9 +143,27 @@ fn main() -> ! {
 
     let mut store = flash_store::store(dp.FLASH);
 
-    let mut channels = Channels::new(pins);
+    let mut vref_meas = [None; CHANNELS];
     for c in 0..CHANNELS {
-        match store.read_value::<ChannelConfig>(CHANNEL_CONFIG_KEY[c]) {
+        match store.read_value::<f64>(DAC_CALIBRATION_KEY[c]) {
+            Ok(stored) =>
+                vref_meas[c] = stored,
+            Err(e) =>
+                error!("unable to load DAC calibration {} from flash: {:?}", c, e),
+        }
+    }
+    let mut channels = Channels::new(pins, hwrev.hardware_params(), vref_meas);
+    match store.read_value("hw_params") {
+        Ok(Some(hardware)) =>
+            if let Err(e) = channels.set_hardware_params(hardware) {
+                error!("cannot apply hardware params: {:?}", e);
+            },
+        Ok(None) => {}
+        Err(e) =>
+            error!("cannot read hardware params: {:?}", e),
+    }
+    for c in 0..CHANNELS {
+        match ChannelConfig::read(&store, CHANNEL_CONFIG_KEY[c]) {
             Ok(Some(config)) =>
                 config.apply(&mut channels, c),
             Ok(None) =>
@@ -151,15 +173,42 @@ fn main() -> ! {
         }
     }
 
-    let mut fan_ctrl = FanCtrl::new(fan, hw_settings);
+    match store.read_value("temp_unit") {
+        Ok(Some(unit)) =>
+            channels.set_temp_unit(unit),
+        Ok(None) => {}
+        Err(e) =>
+            error!("cannot read temperature unit: {:?}", e),
+    }
+
+    let mut fan_ctrl = FanCtrl::new(fan, fan_tach, pwm_timer_clock(&clocks), hw_settings);
+    // Without a fan the stored values would mean nothing
+    if fan_ctrl.fan_available() {
+        match store.read_value::<FanConfig>("fan") {
+            Ok(Some(config)) =>
+                fan_ctrl.apply_config(&config),
+            Ok(None) => {}
+            Err(e) =>
+                error!("cannot read fan settings: {:?}", e),
+        }
+    }
+    // Saved on every change, so it is newer than the source in "fan"
+    match store.read_value("fan_source") {
+        Ok(Some(source)) =>
+            fan_ctrl.set_source(source),
+        Ok(None) => {}
+        Err(e) =>
+            error!("cannot read fan source: {:?}", e),
+    }
 
     // default net config:
     let mut ipv4_config = Ipv4Config {
         address: [192, 168, 1, 26],
         mask_len: 24,
         gateway: None,
+        dhcp: false,
     };
-    match store.read_value("ipv4") {
+    match read_ipv4_config(&store) {
         Ok(Some(config)) =>
             ipv4_config = config,
         Ok(None) => {}
@@ -167,86 +216,294 @@ fn main() -> ! {
             error!("cannot read ipv4 config: {:?}", e),
     }
 
+    let mut tcp_port = TCP_PORT;
+    match store.read_value("port") {
+        Ok(Some(port)) =>
+            tcp_port = port,
+        Ok(None) => {}
+        Err(e) =>
+            error!("cannot read port: {:?}", e),
+    }
+
+    // Idle TCP sessions are closed after this many seconds
+    let mut tcp_timeout: Option<u32> = None;
+    match store.read_value("tcp_timeout") {
+        Ok(Some(timeout)) =>
+            tcp_timeout = timeout,
+        Ok(None) => {}
+        Err(e) =>
+            error!("cannot read tcp timeout: {:?}", e),
+    }
+
+    let mut report_on_connect = ReportOnConnect::Off;
+    let mut telemetry = Telemetry::new();
+
     // EEPROM ships with a read-only EUI-48 identifier
     let mut eui48 = [0; 6];
     eeprom.read_data(0xFA, &mut eui48).unwrap();
-    let hwaddr = EthernetAddress(eui48);
+    let mut hwaddr = EthernetAddress(eui48);
     info!("EEPROM MAC address: {}", hwaddr);
+    match store.read_value("mac") {
+        Ok(Some(mac)) => {
+            hwaddr = EthernetAddress(mac);
+            info!("MAC address override: {}", hwaddr);
+        }
+        Ok(None) => {}
+        Err(e) =>
+            error!("cannot read mac address: {:?}", e),
+    }
+
+    let mut mqtt = Mqtt::new(&eui48);
+    match store.read_value("mqtt") {
+        Ok(Some(config)) =>
+            mqtt.configure(config),
+        Ok(None) => {}
+        Err(e) =>
+            error!("cannot read mqtt config: {:?}", e),
+    }
+
+    let mut sntp = Sntp::new();
+    match store.read_value("ntp") {
+        Ok(Some(server)) =>
+            sntp.configure(server),
+        Ok(None) => {}
+        Err(e) =>
+            error!("cannot read ntp server: {:?}", e),
+    }
 
     net::run(clocks, dp.ETHERNET_MAC, dp.ETHERNET_DMA, eth_pins, hwaddr, ipv4_config.clone(), |iface| {
-        Server::<Session>::run(iface, |server| {
+        Server::<Session>::run(iface, ipv4_config.clone(), |server| {
             leds.r1.off();
             let mut should_reset = false;
+            // Latched until reset
+            let mut flash_error = false;
 
             loop {
                 let mut new_ipv4_config = None;
+                let mut new_tcp_port = None;
+                let mut new_watchdog_interval = None;
                 let instant = Instant::from_millis(i64::from(timer::now()));
-                let updated_channel = channels.poll_adc(instant);
+                let updated_channel = channels.poll_adc(instant)
+                    .unwrap_or_else(|e| {
+                        warn!("ADC: {:?}", e);
+                        None
+                    });
                 if let Some(channel) = updated_channel {
-                    server.for_each(|_, session| session.set_report_pending(channel.into()));
+                    server.for_each(|_, session| {
+                        session.set_report_pending(channel.into());
+                        session.set_autotune_pending(channel.into());
+                    });
                 }
 
-                fan_ctrl.cycle(channels.current_abs_max_tec_i() as f32);
+                server.for_each(|_, session| session.poll_report_interval(timer::now()));
 
-                if channels.pid_engaged() {
-                    leds.g3.on();
-                } else {
-                    leds.g3.off();
+                if let Some((endpoint, channel)) = telemetry.poll_sample().zip(updated_channel) {
+                    match channels.report_json(channel.into()) {
+                        Ok(buf) => {
+                            server.send_telemetry(endpoint, &buf[..])
+                                .unwrap_or_else(|e| {
+                                    warn!("telemetry: {:?}", e);
+                                    netstat::count_telemetry_error();
+                                });
+                        }
+                        Err(e) =>
+                            error!("unable to serialize report: {:?}", e),
+                    }
+                }
+                if let Some(endpoint) = telemetry.poll(instant) {
+                    match channels.reports_json() {
+                        Ok(buf) => {
+                            server.send_telemetry(endpoint, &buf[..])
+                                .unwrap_or_else(|e| {
+                                    warn!("telemetry: {:?}", e);
+                                    netstat::count_telemetry_error();
+                                });
+                        }
+                        Err(e) =>
+                            error!("unable to serialize report: {:?}", e),
+                    }
+                }
+
+                {
+                    let now = timer::now();
+                    let mut socket = server.mqtt_socket();
+                    mqtt.poll(&mut socket, now);
+                    if let Some(channel) = updated_channel {
+                        if mqtt.is_connected() {
+                            match channels.report_json(channel.into()) {
+                                Ok(buf) => {
+                                    mqtt.publish_report(&mut socket, now, channel.into(), &buf[..]);
+                                }
+                                Err(e) =>
+                                    error!("unable to serialize report: {:?}", e),
+                            }
+                        }
+                    }
                 }
 
+                {
+                    let mut socket = server.sntp_socket();
+                    sntp.poll(&mut socket, timer::uptime());
+                }
+
+                fan_ctrl.cycle(channels.current_tec_i(), channels.current_temperatures());
+
+                let status = if should_reset {
+                    Status::Reset
+                } else if flash_error || channels.any_fault() {
+                    Status::Fault
+                } else if channels.pid_engaged() {
+                    Status::Active
+                } else {
+                    Status::Idle
+                };
+                leds.show(status, timer::now());
+
                 let instant = Instant::from_millis(i64::from(timer::now()));
                 cortex_m::interrupt::free(net::clear_pending);
                 server.poll(instant)
                     .unwrap_or_else(|e| {
                         warn!("poll: {:?}", e);
+                        netstat::count_poll_error();
                     });
+                if let Some(timeout) = tcp_timeout {
+                    server.close_idle(instant, timeout * 1000);
+                }
 
                 if ! should_reset {
+                    let dhcp_lease = server.dhcp_lease();
                     // TCP protocol handling
                     server.for_each(|mut socket, session| {
                         if ! socket.is_active() {
-                            let _ = socket.listen(TCP_PORT);
-                            session.reset();
+                            server::listen(&mut socket, tcp_port);
+                            session.reset(report_on_connect);
                         } else if socket.may_send() && !socket.may_recv() {
                             socket.close()
-                        } else if socket.can_send() && socket.can_recv() {
-                            match socket.recv(|buf| session.feed(buf)) {
+                        } else if socket.can_send() && session.pending_output().is_some() {
+                            // Finish the last report before responding
+                            send_pending_output(&mut socket, session);
+                        } else if socket.can_send() && session.is_all_pending().is_none() && (socket.can_recv() || session.has_pending_input()) {
+                            // Finish the commands of the last line before reading more,
+                            // and an `all` object before the next command
+                            let input = match session.next_input() {
+                                Some(input) => Ok(input),
+                                None => socket.recv(|buf| session.feed(buf)),
+                            };
+                            // Immediate and staged commands alike
+                            let mut handle_result = |socket: &mut TcpSocket, result: Result<Handler, HandlerError>| match result {
+                                Ok(Handler::NewIPV4(ip)) => new_ipv4_config = Some(ip),
+                                Ok(Handler::NewPort(port)) => new_tcp_port = Some(port),
+                                Ok(Handler::NewReportOnConnect(on_connect)) => report_on_connect = on_connect,
+                                Ok(Handler::NewTelemetry(config)) => telemetry.configure(config),
+                                Ok(Handler::NewMqtt(config)) => mqtt.configure(config),
+                                Ok(Handler::NewNtp(server)) => sntp.configure(server),
+                                Ok(Handler::NewTcpTimeout(timeout)) => tcp_timeout = timeout,
+                                Ok(Handler::NewWatchdog(interval)) => new_watchdog_interval = Some(interval),
+                                Ok(Handler::Handled) => {},
+                                Ok(Handler::CloseSocket) => socket.close(),
+                                Ok(Handler::Reset) => should_reset = true,
+                                Err(HandlerError::FlashError) => flash_error = true,
+                                Err(_) => {},
+                            };
+                            match input {
                                 // SessionInput::Nothing happens when the line reader parses a string of characters that is not
                                 // followed by a newline character. Could be due to partial commands not terminated with newline,
                                 // socket RX ring buffer wraps around, or when the command is sent as seperate TCP packets etc.
                                 // Do nothing and feed more data to the line reader in the next loop cycle.
                                 Ok(SessionInput::Nothing) => {}
                                 Ok(SessionInput::Command(command)) => {
-                                    match Handler::handle_command(command, &mut socket, &mut channels, session, &mut store, &mut ipv4_config, &mut fan_ctrl, hwrev) {
-                                        Ok(Handler::NewIPV4(ip)) => new_ipv4_config = Some(ip),                                
-                                        Ok(Handler::Handled) => {},
-                                        Ok(Handler::CloseSocket) => socket.close(),
-                                        Ok(Handler::Reset) => should_reset = true,
-                                        Err(_) => {},
+                                    let result = Handler::handle_command(command, &mut socket, &mut channels, session, &mut store, &mut ipv4_config, dhcp_lease, tcp_port, &mut fan_ctrl, hwrev);
+                                    handle_result(&mut socket, result);
+                                }
+                                Ok(SessionInput::Staged) => {
+                                    send_line(&mut socket, b"{}");
+                                }
+                                Ok(SessionInput::Commit(commands)) => {
+                                    // Apply all staged parameters before
+                                    // the next ADC sample is processed
+                                    let mut failed = None;
+                                    for (index, command) in commands.iter().cloned().enumerate() {
+                                        let result = Handler::handle_staged_command(command, &mut socket, &mut channels, session, &mut store, &mut ipv4_config, dhcp_lease, tcp_port, &mut fan_ctrl, hwrev);
+                                        match &result {
+                                            Err(e) if failed.is_none() => failed = Some((index, e.clone())),
+                                            _ => {}
+                                        }
+                                        handle_result(&mut socket, result);
+                                    }
+                                    Handler::commit_reply(&mut socket, failed);
+                                }
+                                Ok(SessionInput::Import) => {
+                                    let result = session.finish_import(|document| {
+                                        Handler::import_config(document, &mut socket, &mut channels, &mut store, &mut fan_ctrl)
+                                    });
+                                    if let Some(result) = result {
+                                        handle_result(&mut socket, result);
                                     }
                                 }
                                 Ok(SessionInput::Error(e)) => {
-                                    error!("session input: {:?}", e);
-                                    send_line(&mut socket, b"{ \"error\": \"invalid input\" }");
+                                    if e != SessionError::LineTooLong {
+                                        error!("session input: {:?}", e);
+                                    }
+                                    let _ = writeln!(socket, "{{ \"error\": \"{}\", \"code\": \"{}\" }}", e, e.code());
                                 }
                                 Err(_) =>
                                     socket.close(),
                             }
                         } else if socket.can_send() {
-                            if let Some(channel) = session.is_report_pending() {
-                                match channels.reports_json() {
+                            if let Some(part) = session.is_all_pending() {
+                                if Handler::send_all_part(part, &mut socket, &mut channels, &ipv4_config, dhcp_lease, &mut fan_ctrl, hwrev) {
+                                    session.mark_all_part_sent();
+                                }
+                            } else if let Some(part) = session.is_export_pending() {
+                                if Handler::send_config_export_part(part, &mut socket, &mut channels, &ipv4_config, &fan_ctrl) {
+                                    session.mark_export_part_sent();
+                                }
+                            } else if let Some(channel) = session.is_report_pending() {
+                                if session.report_format() == ReportFormat::Csv {
+                                    if send_reports_csv(&mut socket, &mut channels, None) {
+                                        session.mark_report_sent(channel);
+                                    }
+                                } else {
+                                    match channels.reports_json() {
+                                        Ok(buf) => {
+                                            session.queue_report(channel, &buf[..]);
+                                            send_pending_output(&mut socket, session);
+                                        }
+                                        Err(e) => {
+                                            error!("unable to serialize report: {:?}", e);
+
+                                        }
+                                    }
+                                }
+                            } else if let Some(channel) = session.is_autotune_pending() {
+                                match channels.autotune_json(channel) {
                                     Ok(buf) => {
                                         send_line(&mut socket, &buf[..]);
-                                        session.mark_report_sent(channel);
+                                        session.mark_autotune_sent(channel, !channels.autotune_running(channel));
                                     }
                                     Err(e) => {
-                                        error!("unable to serialize report: {:?}", e);
-
+                                        error!("unable to serialize autotune progress: {:?}", e);
+                                        session.mark_autotune_sent(channel, true);
                                     }
                                 }
                             }
                         }
                     });
+
+                    // Mirror log records to the sessions that asked for them
+                    let mut log_to_tcp = false;
+                    server.for_each(|_, session| log_to_tcp |= session.log_to_tcp());
+                    tcp_log::set_enabled(log_to_tcp);
+                    if let Some(line) = tcp_log::pop() {
+                        server.for_each(|mut socket, session| {
+                            // Drop rather than warn about a full buffer,
+                            // which would only log more
+                            let send_free = socket.send_capacity() - socket.send_queue();
+                            if session.log_to_tcp() && send_free > line.len() {
+                                send_line(&mut socket, line.as_bytes());
+                            }
+                        });
+                    }
                 } else {
                     // Should reset, close all TCP sockets.
                     let mut any_socket_alive = false;
@@ -270,8 +527,27 @@ fn main() -> ! {
                         ipv4_config = config;
                     });
 
+                // Listen on the new TCP port. Established sessions
+                // stay open until the client reconnects.
+                if let Some(port) = new_tcp_port {
+                    tcp_port = port;
+                    server.for_each(|mut socket, _| {
+                        if socket.is_listening() {
+                            socket.close();
+                        }
+                    });
+                }
+
                 // Update watchdog
+                if let Some(interval) = new_watchdog_interval {
+                    wd.start(interval.ms());
+                    watchdog::set_interval(interval);
+                    info!("watchdog interval: {} ms", interval);
+                }
                 wd.feed();
+                if let Some(elapsed) = watchdog::feed(timer::now()) {
+                    warn!("main loop took {} ms, close to the watchdog interval", elapsed);
+                }
 
                 leds.g4.off();
                 cortex_m::interrupt::free(|cs| {
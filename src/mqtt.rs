@@ -0,0 +1,297 @@
+use core::fmt::Write;
+use heapless::{consts::*, String, Vec};
+use log::{info, warn};
+use smoltcp::{
+    socket::TcpSocket,
+    wire::{IpAddress, IpEndpoint},
+};
+use crate::command_parser::MqttConfig;
+
+/// Seconds the broker waits for a packet before dropping us
+const KEEP_ALIVE: u16 = 60;
+/// Send PINGREQ if nothing else has been sent for this long, in ms
+const PING_INTERVAL: u32 = 30_000;
+/// Give up on a connection attempt after this long, in ms
+const CONNECT_TIMEOUT: u32 = 5_000;
+/// Reconnect delays double from min to max, in ms
+const BACKOFF_MIN: u32 = 1_000;
+const BACKOFF_MAX: u32 = 60_000;
+/// Local ports are cycled to not collide with lingering connections
+const LOCAL_PORT_FIRST: u16 = 49152;
+const LOCAL_PORT_COUNT: u16 = 1024;
+
+pub type ClientId = String<U24>;
+pub type Topic = String<U48>;
+/// Fixed header, remaining length and topic length
+pub type PublishHeader = Vec<u8, U8>;
+pub type ConnectPacket = Vec<u8, U64>;
+
+pub const PINGREQ: [u8; 2] = [0xC0, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Waiting to (re)connect at the given time
+    Disconnected { retry_at: Option<u32> },
+    /// TCP handshake in progress
+    Connecting { since: u32 },
+    /// CONNECT sent, waiting for CONNACK
+    WaitConnAck { since: u32 },
+    Connected { last_sent: u32 },
+}
+
+/// Minimal MQTT 3.1.1 client that publishes reports with QoS 0.
+///
+/// All operations are non-blocking. Reports that do not fit into the
+/// socket buffer are dropped.
+pub struct Mqtt {
+    config: Option<MqttConfig>,
+    client_id: ClientId,
+    state: State,
+    /// Delay before the next reconnect in ms
+    backoff: u32,
+    local_port: u16,
+    /// The socket is still in use for a previous configuration
+    abort: bool,
+}
+
+impl Mqtt {
+    pub fn new(hwaddr: &[u8; 6]) -> Self {
+        let mut client_id = ClientId::new();
+        let _ = write!(client_id, "thermostat-");
+        for b in hwaddr {
+            let _ = write!(client_id, "{:02x}", b);
+        }
+        Mqtt {
+            config: None,
+            client_id,
+            state: State::Disconnected { retry_at: None },
+            backoff: BACKOFF_MIN,
+            local_port: 0,
+            abort: false,
+        }
+    }
+
+    /// Connect to a new broker, or stop with `None`
+    pub fn configure(&mut self, config: Option<MqttConfig>) {
+        self.abort = self.config.is_some();
+        self.config = config;
+        self.state = State::Disconnected { retry_at: None };
+        self.backoff = BACKOFF_MIN;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected { .. })
+    }
+
+    /// Advance the connection state, to be called on every main loop
+    /// iteration
+    pub fn poll(&mut self, socket: &mut TcpSocket, now: u32) {
+        if self.abort {
+            socket.abort();
+            self.abort = false;
+        }
+        let remote = match &self.config {
+            Some(config) => {
+                let [a, b, c, d] = config.address;
+                IpEndpoint::new(IpAddress::v4(a, b, c, d), config.port)
+            }
+            None => return,
+        };
+
+        match self.state {
+            State::Disconnected { retry_at } => {
+                if retry_at.map_or(false, |retry_at| (now.wrapping_sub(retry_at) as i32) < 0) {
+                    return;
+                }
+                if socket.is_open() {
+                    // wait for the previous connection to be gone
+                    socket.abort();
+                    return;
+                }
+                self.local_port = (self.local_port + 1) % LOCAL_PORT_COUNT;
+                match socket.connect(remote, LOCAL_PORT_FIRST + self.local_port) {
+                    Ok(()) => self.state = State::Connecting { since: now },
+                    Err(e) => {
+                        warn!("mqtt connect: {:?}", e);
+                        self.retry_later(now);
+                    }
+                }
+            }
+            State::Connecting { since } => {
+                if socket.may_send() {
+                    let packet = connect_packet(&self.client_id, KEEP_ALIVE);
+                    match socket.send_slice(&packet) {
+                        Ok(sent) if sent == packet.len() =>
+                            self.state = State::WaitConnAck { since: now },
+                        _ => self.drop_connection(socket, now),
+                    }
+                } else if !socket.is_open() || now.wrapping_sub(since) >= CONNECT_TIMEOUT {
+                    self.drop_connection(socket, now);
+                }
+            }
+            State::WaitConnAck { since } => {
+                if socket.can_recv() {
+                    let mut connack = [0; 4];
+                    match socket.recv_slice(&mut connack) {
+                        Ok(4) if connack_accepted(&connack) => {
+                            info!("mqtt connected to {}", remote);
+                            self.state = State::Connected { last_sent: now };
+                            self.backoff = BACKOFF_MIN;
+                        }
+                        _ => {
+                            warn!("mqtt connection refused: {:?}", connack);
+                            self.drop_connection(socket, now);
+                        }
+                    }
+                } else if !socket.may_recv() || now.wrapping_sub(since) >= CONNECT_TIMEOUT {
+                    self.drop_connection(socket, now);
+                }
+            }
+            State::Connected { last_sent } => {
+                if !socket.may_send() || !socket.may_recv() {
+                    warn!("mqtt connection lost");
+                    self.drop_connection(socket, now);
+                    return;
+                }
+                // Discard PINGRESP
+                let _ = socket.recv(|buf| (buf.len(), ()));
+                if now.wrapping_sub(last_sent) >= PING_INTERVAL && socket.send_slice(&PINGREQ) == Ok(PINGREQ.len()) {
+                    self.state = State::Connected { last_sent: now };
+                }
+            }
+        }
+    }
+
+    /// Publish the report of a channel to `<prefix>/<channel>/report`,
+    /// returns `false` if it has been dropped.
+    pub fn publish_report(&mut self, socket: &mut TcpSocket, now: u32, channel: usize, report: &[u8]) -> bool {
+        if !self.is_connected() {
+            return false;
+        }
+        let topic = match report_topic(self.config.as_ref().unwrap(), channel) {
+            Some(topic) => topic,
+            None => return false,
+        };
+        let header = match publish_header(topic.len(), report.len()) {
+            Some(header) => header,
+            None => return false,
+        };
+        let send_free = socket.send_capacity() - socket.send_queue();
+        if header.len() + topic.len() + report.len() > send_free {
+            return false;
+        }
+        let _ = socket.send_slice(&header);
+        let _ = socket.send_slice(topic.as_bytes());
+        let _ = socket.send_slice(report);
+        self.state = State::Connected { last_sent: now };
+        true
+    }
+
+    fn drop_connection(&mut self, socket: &mut TcpSocket, now: u32) {
+        socket.abort();
+        self.retry_later(now);
+    }
+
+    fn retry_later(&mut self, now: u32) {
+        self.state = State::Disconnected { retry_at: Some(now.wrapping_add(self.backoff)) };
+        self.backoff = next_backoff(self.backoff);
+    }
+}
+
+fn next_backoff(backoff: u32) -> u32 {
+    (2 * backoff).min(BACKOFF_MAX)
+}
+
+/// `<prefix>/<channel>/report`
+pub fn report_topic(config: &MqttConfig, channel: usize) -> Option<Topic> {
+    let mut topic = Topic::new();
+    write!(topic, "{}/{}/report", config.prefix, channel).ok()?;
+    Some(topic)
+}
+
+/// Variable-length encoding of the remaining length, up to 4 bytes
+fn push_remaining_length<N: heapless::ArrayLength<u8>>(buf: &mut Vec<u8, N>, mut len: usize) -> Option<()> {
+    if len >= 1 << 28 {
+        return None;
+    }
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte).ok()?;
+        if len == 0 {
+            return Some(());
+        }
+    }
+}
+
+/// CONNECT with a clean session and no credentials
+pub fn connect_packet(client_id: &str, keep_alive: u16) -> ConnectPacket {
+    let mut packet = ConnectPacket::new();
+    let _ = packet.push(0x10);
+    let _ = push_remaining_length(&mut packet, 12 + client_id.len());
+    // Protocol name and level 4 for 3.1.1
+    let _ = packet.extend_from_slice(&[0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04]);
+    // Clean session
+    let _ = packet.push(0x02);
+    let _ = packet.extend_from_slice(&keep_alive.to_be_bytes());
+    let _ = packet.extend_from_slice(&(client_id.len() as u16).to_be_bytes());
+    let _ = packet.extend_from_slice(client_id.as_bytes());
+    packet
+}
+
+/// Everything of a QoS 0 PUBLISH that precedes the topic and payload
+pub fn publish_header(topic_len: usize, payload_len: usize) -> Option<PublishHeader> {
+    let mut header = PublishHeader::new();
+    header.push(0x30).ok()?;
+    push_remaining_length(&mut header, 2 + topic_len + payload_len)?;
+    header.extend_from_slice(&(topic_len as u16).to_be_bytes()).ok()?;
+    Some(header)
+}
+
+fn connack_accepted(connack: &[u8; 4]) -> bool {
+    connack[0] == 0x20 && connack[1] == 0x02 && connack[3] == 0x00
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connect_format() {
+        let packet = connect_packet("th", 60);
+        assert_eq!(&packet[..], &[
+            0x10, 14,
+            0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 60,
+            0x00, 0x02, b't', b'h',
+        ]);
+    }
+
+    #[test]
+    fn publish_remaining_length() {
+        let header = publish_header(5, 100).unwrap();
+        assert_eq!(&header[..], &[0x30, 107, 0x00, 0x05]);
+        // two bytes from 128 on
+        let header = publish_header(16, 500).unwrap();
+        assert_eq!(&header[..], &[0x30, 0x86, 0x04, 0x00, 0x10]);
+    }
+
+    #[test]
+    fn topic_per_channel() {
+        let mut prefix = String::new();
+        prefix.push_str("lab/thermostat").unwrap();
+        let config = MqttConfig { address: [10, 0, 0, 1], port: 1883, prefix };
+        assert_eq!(report_topic(&config, 1).unwrap().as_str(), "lab/thermostat/1/report");
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        let mut backoff = BACKOFF_MIN;
+        for _ in 0..10 {
+            backoff = next_backoff(backoff);
+        }
+        assert_eq!(backoff, BACKOFF_MAX);
+    }
+}
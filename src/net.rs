@@ -54,7 +54,12 @@ pub fn run<F>(
     eth_dev.enable_interrupt();
 
     // IP stack
-    let (ipv4_cidr, gateway) = split_ipv4_config(ipv4_config);
+    let (ipv4_cidr, gateway) = if ipv4_config.dhcp {
+        // acquired later by `Server`
+        (Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0), None)
+    } else {
+        split_ipv4_config(ipv4_config)
+    };
     let mut ip_addrs = [ipv4_cidr.into()];
     let mut neighbor_storage = [None; 16];
     let neighbor_cache = NeighborCache::new(&mut neighbor_storage[..]);
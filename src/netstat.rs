@@ -0,0 +1,93 @@
+use core::cell::RefCell;
+use cortex_m::interrupt::{free, Mutex};
+use serde::Serialize;
+
+/// Counters for the `netstat` command, updated where the events
+/// happen
+static COUNTERS: Mutex<RefCell<Counters>> = Mutex::new(RefCell::new(Counters::new()));
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Counters {
+    active_sockets: u32,
+    dropped_lines: u32,
+    poll_errors: u32,
+    telemetry_errors: u32,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            active_sockets: 0,
+            dropped_lines: 0,
+            poll_errors: 0,
+            telemetry_errors: 0,
+        }
+    }
+}
+
+fn update<F: FnOnce(&mut Counters)>(f: F) {
+    free(|cs| f(&mut COUNTERS.borrow(cs).borrow_mut()));
+}
+
+/// Number of TCP sessions currently connected
+pub fn set_active_sockets(count: u32) {
+    update(|counters| counters.active_sockets = count);
+}
+
+/// A line was not sent because the TCP TX buffer was full
+pub fn count_dropped_line() {
+    update(|counters| counters.dropped_lines += 1);
+}
+
+/// The interface poll failed
+pub fn count_poll_error() {
+    update(|counters| counters.poll_errors += 1);
+}
+
+/// A telemetry datagram could not be queued
+pub fn count_telemetry_error() {
+    update(|counters| counters.telemetry_errors += 1);
+}
+
+/// `{"active_sockets":...}` for `netstat`
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Summary {
+    active_sockets: u32,
+    dropped_lines: u32,
+    poll_errors: u32,
+    telemetry_errors: u32,
+    /// SPI errors of the ADC that have been recovered from
+    adc_errors: u32,
+}
+
+/// Snapshot of the counters, `adc_errors` are kept by `Channels`
+pub fn summary(adc_errors: u32) -> Summary {
+    let counters = free(|cs| *COUNTERS.borrow(cs).borrow());
+    Summary {
+        active_sockets: counters.active_sockets,
+        dropped_lines: counters.dropped_lines,
+        poll_errors: counters.poll_errors,
+        telemetry_errors: counters.telemetry_errors,
+        adc_errors,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let before = summary(0);
+        set_active_sockets(2);
+        count_dropped_line();
+        count_poll_error();
+        count_telemetry_error();
+        let after = summary(3);
+        assert_eq!(after.active_sockets, 2);
+        assert!(after.dropped_lines > before.dropped_lines);
+        assert!(after.poll_errors > before.poll_errors);
+        assert!(after.telemetry_errors > before.telemetry_errors);
+        assert_eq!(after.adc_errors, 3);
+    }
+}
@@ -1,17 +1,55 @@
 use serde::{Serialize, Deserialize};
 
+/// Error in K over which the heating and cooling parameters are
+/// blended
+const SCHEDULE_BAND: f64 = 0.2;
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Parameters {
     /// Gain coefficient for proportional term
     pub kp: f32,
-    /// Gain coefficient for integral term
+    /// Gain coefficient for integral term, per second
     pub ki: f32,
-    /// Gain coefficient for derivative term
+    /// Gain coefficient for derivative term, in seconds
     pub kd: f32,
+    /// Time constant of the derivative low-pass filter in seconds,
+    /// `0` to disable
+    pub kd_filter: f32,
     /// Output limit minimum
     pub output_min: f32,
     /// Output limit maximum
     pub output_max: f32,
+    /// Integral term limit minimum
+    pub integral_min: f32,
+    /// Integral term limit maximum
+    pub integral_max: f32,
+    /// Maximum output rate of change in A/s, `0` to disable
+    pub slew: f32,
+    /// Constant output offset in A, added before the output limits
+    pub ff: f32,
+    /// Output offset per °C of target in A/K, added before the
+    /// output limits
+    pub ff_target: f32,
+}
+
+impl Parameters {
+    /// Linear interpolation towards `other` by `weight` from 0 to 1
+    pub fn blend(&self, other: &Parameters, weight: f32) -> Parameters {
+        let lerp = |a: f32, b: f32| a + (b - a) * weight;
+        Parameters {
+            kp: lerp(self.kp, other.kp),
+            ki: lerp(self.ki, other.ki),
+            kd: lerp(self.kd, other.kd),
+            kd_filter: lerp(self.kd_filter, other.kd_filter),
+            output_min: lerp(self.output_min, other.output_min),
+            output_max: lerp(self.output_max, other.output_max),
+            integral_min: lerp(self.integral_min, other.integral_min),
+            integral_max: lerp(self.integral_max, other.integral_max),
+            slew: lerp(self.slew, other.slew),
+            ff: lerp(self.ff, other.ff),
+            ff_target: lerp(self.ff_target, other.ff_target),
+        }
+    }
 }
 
 impl Default for Parameters {
@@ -20,82 +58,181 @@ impl Default for Parameters {
             kp: 0.0,
             ki: 0.0,
             kd: 0.0,
+            kd_filter: 0.0,
             output_min: -2.0,
             output_max: 2.0,
+            integral_min: -2.0,
+            integral_max: 2.0,
+            slew: 0.0,
+            ff: 0.0,
+            ff_target: 0.0,
         }
     }
 }
 
+/// Contributions to the last output in A, for tuning
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Terms {
+    /// Proportional term `kp * (x - u)`
+    pub p: f64,
+    /// Accumulated integral term, within the integral limits
+    pub i: f64,
+    /// Filtered derivative term
+    pub d: f64,
+    /// Feed-forward term
+    pub ff: f64,
+}
+
 #[derive(Clone)]
 pub struct Controller {
+    /// Heating parameters, or for both directions without `cool`
     pub parameters: Parameters,
+    /// Parameters for temperatures above the target
+    pub cool: Option<Parameters>,
+    /// Share of `cool` in the last update, `None` without `cool`
+    pub cooling: Option<f64>,
     pub target : f64,
     u1 : f64,
     x1 : f64,
-    x2 : f64,
+    /// Accumulated integral term
+    i1 : f64,
+    /// Last derivative term
+    d1 : f64,
+    /// Last feed-forward term
+    f1 : f64,
     pub y1 : f64,
+    /// Terms of the last update
+    pub terms: Terms,
 }
 
 impl Controller {
     pub const fn new(parameters: Parameters) -> Controller {
         Controller {
             parameters: parameters,
+            cool: None,
+            cooling: None,
             target : 0.0,
             u1 : 0.0,
             x1 : 0.0,
-            x2 : 0.0,
+            i1 : 0.0,
+            d1 : 0.0,
+            f1 : 0.0,
             y1 : 0.0,
+            terms: Terms { p: 0.0, i: 0.0, d: 0.0, ff: 0.0 },
         }
     }
 
     // Based on https://hackmd.io/IACbwcOTSt6Adj3_F9bKuw PID implementation
-    // Input x(t), target u(t), output y(t)
-    // y0' =   y1 - ki * u0   
-    //       + x0 * (kp + ki + kd)
-    //       - x1 * (kp + 2kd)
-    //       + x2 * kd
+    // Input x(t), target u(t), output y(t), sample interval dt
+    // i0  = clip(i1 + ki * (x0 - u0) * dt, imin, imax)
+    // d0  = d1 + (kd * (x0 - x1) / dt - d1) * dt / (tau_d + dt)
+    // f0  = ff + ff_target * u0
+    // y0' =   y1 + (i0 - i1) + (d0 - d1) + (f0 - f1)
+    //       + kp * (x0 - x1)
     //       + kp * (u0 - u1)
     // y0  = clip(y0', ymin, ymax)
-    pub fn update(&mut self, input: f64) -> f64 {
-        
-        let mut output: f64 = self.y1 - self.target * f64::from(self.parameters.ki)
-                            + input * f64::from(self.parameters.kp + self.parameters.ki + self.parameters.kd)
-                            - self.x1 * f64::from(self.parameters.kp + 2.0 * self.parameters.kd)
-                            + self.x2 * f64::from(self.parameters.kd)
-                            + f64::from(self.parameters.kp) * (self.target - self.u1);
-        if output < self.parameters.output_min.into() {
-            output = self.parameters.output_min.into();
-        }
-        if output > self.parameters.output_max.into() {
-            output = self.parameters.output_max.into();
-        }
-        self.x2 = self.x1;
+    pub fn update(&mut self, input: f64, dt: f64) -> f64 {
+        let parameters = self.schedule(input);
+        let mut integral: f64 = self.i1 + f64::from(parameters.ki) * (input - self.target) * dt;
+        if integral < parameters.integral_min.into() {
+            integral = parameters.integral_min.into();
+        }
+        if integral > parameters.integral_max.into() {
+            integral = parameters.integral_max.into();
+        }
+        let derivative = if dt > 0.0 {
+            let derivative = f64::from(parameters.kd) * (input - self.x1) / dt;
+            let alpha = dt / (f64::from(parameters.kd_filter) + dt);
+            self.d1 + alpha * (derivative - self.d1)
+        } else {
+            self.d1
+        };
+        let feed_forward = f64::from(parameters.ff)
+            + f64::from(parameters.ff_target) * self.target;
+
+        let mut output: f64 = self.y1 + (integral - self.i1) + (derivative - self.d1)
+                            + (feed_forward - self.f1)
+                            + f64::from(parameters.kp) * (input - self.x1)
+                            + f64::from(parameters.kp) * (self.target - self.u1);
+        if output < parameters.output_min.into() {
+            output = parameters.output_min.into();
+        }
+        if output > parameters.output_max.into() {
+            output = parameters.output_max.into();
+        }
         self.x1 = input;
         self.u1 = self.target;
+        self.i1 = integral;
+        self.d1 = derivative;
+        self.f1 = feed_forward;
         self.y1 = output;        
+        self.terms = Terms {
+            p: f64::from(parameters.kp) * (input - self.target),
+            i: integral,
+            d: derivative,
+            ff: feed_forward,
+        };
         output
     }
 
-    pub fn summary(&self, channel: usize) -> Summary {
-        Summary {
-            channel,
-            parameters: self.parameters.clone(),
-            target: self.target,
+    /// Parameters for `input`: `parameters` below the target, `cool`
+    /// above it, blended within `SCHEDULE_BAND` to avoid chatter
+    fn schedule(&mut self, input: f64) -> Parameters {
+        match &self.cool {
+            Some(cool) => {
+                let weight = ((input - self.target) / SCHEDULE_BAND + 0.5)
+                    .max(0.0)
+                    .min(1.0);
+                self.cooling = Some(weight);
+                self.parameters.blend(cool, weight as f32)
+            }
+            None => {
+                self.cooling = None;
+                self.parameters.clone()
+            }
         }
     }
 
+    /// Clear the accumulated integral and derivative, keeping
+    /// parameters and target.
+    ///
+    /// The last input and output are kept so that the next update
+    /// continues bumplessly from the present output.
+    pub fn reset_state(&mut self) {
+        self.u1 = self.target;
+        self.i1 = 0.0;
+        self.d1 = 0.0;
+        self.terms = Terms::default();
+    }
+
+    /// Follow `output` set by another control mode at `input`, so that
+    /// switching back continues bumplessly from it.
+    pub fn track(&mut self, input: f64, output: f64) {
+        let parameters = self.schedule(input);
+        self.x1 = input;
+        self.u1 = self.target;
+        self.d1 = 0.0;
+        self.f1 = f64::from(parameters.ff)
+            + f64::from(parameters.ff_target) * self.target;
+        self.y1 = output;
+        self.terms = Terms::default();
+    }
+
+    /// Continue from `output` set manually, including the
+    /// feed-forward of the present parameters and target in it so that
+    /// the next update does not add it again.
+    pub fn preload(&mut self, output: f64) {
+        let parameters = self.schedule(self.x1);
+        self.f1 = f64::from(parameters.ff)
+            + f64::from(parameters.ff_target) * self.target;
+        self.y1 = output;
+    }
+
     pub fn update_ki(&mut self, new_ki: f32) {
         self.parameters.ki = new_ki;
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct Summary {
-    channel: usize,
-    parameters: Parameters,
-    target: f64,
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -104,8 +241,14 @@ mod test {
         kp: 0.03,
         ki: 0.002,
         kd: 0.15,
+        kd_filter: 0.0,
         output_min: -10.0,
         output_max: 10.0,
+        integral_min: -10.0,
+        integral_max: 10.0,
+        slew: 0.0,
+        ff: 0.0,
+        ff_target: 0.0,
     };
 
     #[test]
@@ -134,7 +277,7 @@ mod test {
         while !values.iter().all(|value| target.contains(value)) && total_t < CYCLE_LIMIT {
             let next_t = (t + 1) % DELAY;
             // Feed the oldest temperature
-            output = pid.update(values[next_t]);
+            output = pid.update(values[next_t], 1.0);
             // Overwrite oldest with previous temperature - output
             values[next_t] = values[t] - output - (values[t] - DEFAULT) * LOSS;
             t = next_t;
@@ -143,4 +286,192 @@ mod test {
         }
         assert_ne!(CYCLE_LIMIT, total_t);
     }
+
+    #[test]
+    fn test_reset_state() {
+        let mut pid = Controller::new(PARAMETERS.clone());
+        pid.target = 40.0;
+        for _ in 0..10 {
+            pid.update(20.0, 1.0);
+        }
+        let held = pid.y1;
+        assert!(held != 0.0);
+        pid.reset_state();
+        assert_eq!(pid.parameters, PARAMETERS);
+        assert_eq!(pid.target, 40.0);
+        assert_eq!(pid.y1, held);
+        // Continues from the held output with a fresh integral
+        let output = pid.update(20.0, 1.0);
+        assert_eq!(output, held + f64::from(PARAMETERS.ki) * (20.0 - 40.0));
+    }
+
+    #[test]
+    fn test_bumpless_engage() {
+        const TEMPERATURE: f64 = 25.1;
+        const I_SET: f64 = 0.7;
+        let mut pid = Controller::new(PARAMETERS.clone());
+        pid.target = 25.0;
+        // Running while disengaged
+        for _ in 0..10 {
+            pid.update(TEMPERATURE, 1.0);
+        }
+        // Feed-forward set while disengaged
+        pid.parameters.ff = 0.3;
+        pid.parameters.ff_target = 0.01;
+        // Pre-load with the manual output on engage
+        pid.preload(I_SET);
+        let output = pid.update(TEMPERATURE, 1.0);
+        assert!((output - I_SET).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_track() {
+        const TEMPERATURE: f64 = 27.0;
+        const OUTPUT: f64 = 2.0;
+        let mut pid = Controller::new(Parameters {
+            ff_target: 0.01,
+            ..PARAMETERS
+        });
+        pid.target = 25.0;
+        for _ in 0..10 {
+            pid.update(20.0, 1.0);
+        }
+        // Another mode drives the output at a different temperature
+        pid.track(TEMPERATURE, OUTPUT);
+        let output = pid.update(TEMPERATURE, 1.0);
+        let integral = f64::from(PARAMETERS.ki) * (TEMPERATURE - 25.0);
+        assert!((output - (OUTPUT + integral)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_kd_filter() {
+        fn output_variance(kd_filter: f32) -> f64 {
+            let mut pid = Controller::new(Parameters {
+                kd: 1.0,
+                kd_filter,
+                ..Parameters::default()
+            });
+            pid.target = 25.0;
+            // Deterministic noise of +-0.01 around a constant input
+            let mut seed: u32 = 1;
+            let mut noise = || {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                f64::from(seed >> 16) / f64::from(0xffff) * 0.02 - 0.01
+            };
+            for _ in 0..100 {
+                pid.update(25.0 + noise(), 0.1);
+            }
+            let outputs: Vec<f64> = (0..1000)
+                .map(|_| pid.update(25.0 + noise(), 0.1))
+                .collect();
+            let mean = outputs.iter().sum::<f64>() / outputs.len() as f64;
+            outputs.iter().map(|y| (y - mean).powi(2)).sum::<f64>() / outputs.len() as f64
+        }
+        assert!(output_variance(1.0) < 0.1 * output_variance(0.0));
+    }
+
+    #[test]
+    fn test_integral_limits() {
+        let mut pid = Controller::new(Parameters {
+            kp: 0.0,
+            ki: 1.0,
+            kd: 0.0,
+            kd_filter: 0.0,
+            output_min: -10.0,
+            output_max: 10.0,
+            integral_min: -0.5,
+            integral_max: 0.5,
+            slew: 0.0,
+            ff: 0.0,
+            ff_target: 0.0,
+        });
+        pid.target = 0.0;
+        for _ in 0..10 {
+            assert!(pid.update(1.0, 1.0) <= 0.5);
+        }
+        assert_eq!(pid.update(1.0, 1.0), 0.5);
+        // Unwinds immediately once the error changes sign
+        assert_eq!(pid.update(-1.0, 1.0), -0.5);
+    }
+
+    #[test]
+    fn test_terms() {
+        let mut pid = Controller::new(Parameters {
+            kp: 0.5,
+            ki: 1.0,
+            kd: 2.0,
+            ff: 0.25,
+            integral_max: 0.5,
+            ..Parameters::default()
+        });
+        pid.target = 20.0;
+        pid.x1 = 20.0;
+        pid.update(21.0, 1.0);
+        assert_eq!(pid.terms, Terms { p: 0.5, i: 0.5, d: 2.0, ff: 0.25 });
+        pid.reset_state();
+        assert_eq!(pid.terms, Terms::default());
+    }
+
+    #[test]
+    fn test_feed_forward() {
+        let mut pid = Controller::new(Parameters {
+            ff: 0.5,
+            ff_target: -0.01,
+            ..PARAMETERS
+        });
+        pid.target = 20.0;
+        pid.x1 = 20.0;
+        pid.u1 = 20.0;
+        // No error, the offset appears in the first output
+        let output = pid.update(20.0, 1.0);
+        assert!((output - 0.3).abs() < 1e-6);
+        // and is not accumulated
+        assert_eq!(pid.update(20.0, 1.0), output);
+        // Limited with the rest of the output
+        pid.parameters.ff = 20.0;
+        assert_eq!(pid.update(20.0, 1.0), 10.0);
+    }
+
+    #[test]
+    fn test_gain_schedule() {
+        let heat = Parameters { kp: 0.0, ki: 1.0, kd: 0.0, ..Parameters::default() };
+        let cool = Parameters { ki: 1.5, ..heat.clone() };
+        let mut pid = Controller::new(heat);
+        pid.cool = Some(cool);
+        pid.target = 25.0;
+        assert_eq!(pid.update(24.0, 1.0), -1.0);
+        assert_eq!(pid.cooling, Some(0.0));
+        pid.reset_state();
+        // From the held output of -1.0
+        assert_eq!(pid.update(26.0, 1.0), 0.5);
+        assert_eq!(pid.cooling, Some(1.0));
+        // Blended around zero error
+        pid.update(25.05, 1.0);
+        let cooling = pid.cooling.unwrap();
+        assert!(cooling > 0.5 && cooling < 1.0);
+        pid.cool = None;
+        pid.update(26.0, 1.0);
+        assert_eq!(pid.cooling, None);
+    }
+
+    #[test]
+    fn test_integral_scales_with_dt() {
+        let parameters = Parameters {
+            kp: 0.0,
+            ki: 1.0,
+            kd: 0.0,
+            ..Parameters::default()
+        };
+        let mut fast = Controller::new(parameters.clone());
+        let mut slow = Controller::new(parameters);
+        for _ in 0..10 {
+            fast.update(0.1, 0.1);
+        }
+        for _ in 0..5 {
+            slow.update(0.1, 0.2);
+        }
+        // Same time span, same integral
+        assert!((fast.y1 - 0.1).abs() < 1e-9);
+        assert!((slow.y1 - 0.1).abs() < 1e-9);
+    }
 }
@@ -1,7 +1,7 @@
 use stm32f4xx_hal::{
     adc::Adc,
     gpio::{
-        AF5, Alternate, AlternateOD, Analog, Floating, Input,
+        AF1, AF5, Alternate, AlternateOD, Analog, Floating, Input,
         gpioa::*,
         gpiob::*,
         gpioc::*,
@@ -23,7 +23,7 @@ use stm32f4xx_hal::{
         I2C1,
         OTG_FS_GLOBAL, OTG_FS_DEVICE, OTG_FS_PWRCLK,
         SPI2, SPI4, SPI5,
-        TIM1, TIM3, TIM8
+        TIM1, TIM2, TIM3, TIM8
     },
     timer::Timer,
     time::U32Ext,
@@ -32,8 +32,9 @@ use eeprom24x::{self, Eeprom24x};
 use stm32_eth::EthPins;
 use crate::{
     channel::{Channel0, Channel1},
+    command_parser::PwmPin,
     leds::Leds,
-    fan_ctrl::FanPin,
+    fan_ctrl::{FanPin, FanTach, FanTachPin},
     hw_rev::{HWRev, HWSettings},
 };
 
@@ -92,6 +93,8 @@ pub type AdcNss = PB12<Output<PushPull>>;
 type Dac0Spi = Spi<SPI4, (PE2<Alternate<AF5>>, NoMiso, PE6<Alternate<AF5>>), TransferModeNormal>;
 type Dac1Spi = Spi<SPI5, (PF7<Alternate<AF5>>, NoMiso, PF9<Alternate<AF5>>), TransferModeNormal>;
 pub type PinsAdc = Adc<ADC1>;
+/// Spare ADC1 input (IN9) for an external setpoint voltage
+pub type SetpointPin = PB1<Analog>;
 
 pub struct ChannelPinSet<C: ChannelPins> {
     pub dac_spi: C::DacSpi,
@@ -114,6 +117,7 @@ pub struct Pins {
     pub adc_spi: AdcSpi,
     pub adc_nss: AdcNss,
     pub pins_adc: PinsAdc,
+    pub setpoint_pin: SetpointPin,
     pub pwm: PwmPins,
     pub channel0: ChannelPinSet<Channel0>,
     pub channel1: ChannelPinSet<Channel1>,
@@ -123,13 +127,13 @@ impl Pins {
     /// Setup GPIO pins and configure MCU peripherals
     pub fn setup(
         clocks: Clocks,
-        tim1: TIM1, tim3: TIM3, tim8: TIM8,
+        tim1: TIM1, tim2: TIM2, tim3: TIM3, tim8: TIM8,
         gpioa: GPIOA, gpiob: GPIOB, gpioc: GPIOC, gpiod: GPIOD, gpioe: GPIOE, gpiof: GPIOF, gpiog: GPIOG,
         i2c1: I2C1,
         spi2: SPI2, spi4: SPI4, spi5: SPI5,
         adc1: ADC1,
         otg_fs_global: OTG_FS_GLOBAL, otg_fs_device: OTG_FS_DEVICE, otg_fs_pwrclk: OTG_FS_PWRCLK,
-    ) -> (Self, Leds, Eeprom, EthernetPins, USB, Option<FanPin>, HWRev, HWSettings) {
+    ) -> (Self, Leds, Eeprom, EthernetPins, USB, Option<FanPin>, Option<FanTach>, HWRev, HWSettings) {
         let gpioa = gpioa.split();
         let gpiob = gpiob.split();
         let gpioc = gpioc.split();
@@ -142,6 +146,7 @@ impl Pins {
         let adc_nss = gpiob.pb12.into_push_pull_output();
 
         let pins_adc = Adc::adc1(adc1, true, Default::default());
+        let setpoint_pin = gpiob.pb1.into_analog();
 
         let pwm = PwmPins::setup(
             clocks, tim1, tim3,
@@ -193,6 +198,7 @@ impl Pins {
         let pins = Pins {
             adc_spi, adc_nss,
             pins_adc,
+            setpoint_pin,
             pwm,
             channel0,
             channel1,
@@ -231,8 +237,12 @@ impl Pins {
         let fan = if hw_settings.fan_available {
              Some(Timer::new(tim8, &clocks).pwm(gpioc.pc9.into_alternate(), hw_settings.fan_pwm_freq_hz.hz()))
         } else { None };
+        let fan_tach = if hw_settings.fan_available {
+            let tach_pin: FanTachPin = gpioa.pa15.into_alternate();
+            Some(FanTach::new(tim2, tach_pin))
+        } else { None };
 
-        (pins, leds, eeprom, eth_pins, usb, fan, hwrev, hw_settings)
+        (pins, leds, eeprom, eth_pins, usb, fan, fan_tach, hwrev, hw_settings)
     }
 
     /// Configure the GPIO pins for SPI operation, and initialize SPI
@@ -303,6 +313,37 @@ pub struct PwmPins {
 }
 
 impl PwmPins {
+    /// Limit PWM of a channel, `i_set` is a DAC instead
+    pub fn get(&self, channel: usize, pin: PwmPin) -> &dyn hal::PwmPin<Duty=u16> {
+        match (channel, pin) {
+            (_, PwmPin::ISet) =>
+                panic!("i_set is no pwm pin"),
+            (0, PwmPin::MaxIPos) => &self.max_i_pos0,
+            (0, PwmPin::MaxINeg) => &self.max_i_neg0,
+            (0, PwmPin::MaxV) => &self.max_v0,
+            (1, PwmPin::MaxIPos) => &self.max_i_pos1,
+            (1, PwmPin::MaxINeg) => &self.max_i_neg1,
+            (1, PwmPin::MaxV) => &self.max_v1,
+            _ =>
+                unreachable!(),
+        }
+    }
+
+    pub fn get_mut(&mut self, channel: usize, pin: PwmPin) -> &mut dyn hal::PwmPin<Duty=u16> {
+        match (channel, pin) {
+            (_, PwmPin::ISet) =>
+                panic!("i_set is no pwm pin"),
+            (0, PwmPin::MaxIPos) => &mut self.max_i_pos0,
+            (0, PwmPin::MaxINeg) => &mut self.max_i_neg0,
+            (0, PwmPin::MaxV) => &mut self.max_v0,
+            (1, PwmPin::MaxIPos) => &mut self.max_i_pos1,
+            (1, PwmPin::MaxINeg) => &mut self.max_i_neg1,
+            (1, PwmPin::MaxV) => &mut self.max_v1,
+            _ =>
+                unreachable!(),
+        }
+    }
+
     fn setup<M1, M2, M3, M4, M5, M6>(
         clocks: Clocks,
         tim1: TIM1,
@@ -0,0 +1,145 @@
+use heapless::{consts::U8, Vec};
+use serde::Serialize;
+
+/// Segments that fit into a profile
+pub type Segments = Vec<Segment, U8>;
+
+/// Ramp to `target` in °C at `rate` in K/s, then hold it for
+/// `hold_ms`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Segment {
+    pub target: f64,
+    pub rate: f64,
+    pub hold_ms: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ProfileMode {
+    /// Stay at the last target
+    Once,
+    /// Start over with the first segment
+    Loop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Ramp,
+    /// Since when in ms
+    Hold(u32),
+}
+
+/// Sequence of setpoint ramps and holds
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub segments: Segments,
+    pub mode: ProfileMode,
+    /// Index and phase of the current segment
+    running: Option<(usize, Phase)>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            segments: Vec::new(),
+            mode: ProfileMode::Once,
+            running: None,
+        }
+    }
+}
+
+impl Profile {
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Returns the first segment to ramp to
+    pub fn start(&mut self) -> Option<&Segment> {
+        self.running = self.segments.first().map(|_| (0, Phase::Ramp));
+        self.segments.first()
+    }
+
+    pub fn stop(&mut self) {
+        self.running = None;
+    }
+
+    /// Advance at `now` in ms, `ramp_done` once the setpoint has
+    /// reached the target of the current segment. Returns the next
+    /// segment to ramp to.
+    pub fn poll(&mut self, now: u32, ramp_done: bool) -> Option<&Segment> {
+        let (index, phase) = self.running?;
+        let segment = self.segments.get(index)?;
+        match phase {
+            Phase::Ramp if ramp_done => {
+                self.running = Some((index, Phase::Hold(now)));
+                None
+            }
+            Phase::Hold(since) if now.wrapping_sub(since) >= segment.hold_ms => {
+                let next = match (index + 1 < self.segments.len(), self.mode) {
+                    (true, _) => Some(index + 1),
+                    (false, ProfileMode::Loop) => Some(0),
+                    (false, ProfileMode::Once) => None,
+                };
+                self.running = next.map(|next| (next, Phase::Ramp));
+                next.and_then(move |next| self.segments.get(next))
+            }
+            _ => None,
+        }
+    }
+
+    /// Current segment and its remaining time in ms, `target` is the
+    /// setpoint in °C on the way to the segment target
+    pub fn progress(&self, now: u32, target: f64) -> Option<(usize, u32)> {
+        let (index, phase) = self.running?;
+        let segment = self.segments.get(index)?;
+        let remaining = match phase {
+            Phase::Ramp if segment.rate > 0.0 => {
+                let ramp_ms = (segment.target - target).abs() / segment.rate * 1000.0;
+                ramp_ms as u32 + segment.hold_ms
+            }
+            Phase::Ramp => segment.hold_ms,
+            Phase::Hold(since) =>
+                segment.hold_ms.saturating_sub(now.wrapping_sub(since)),
+        };
+        Some((index, remaining))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn profile() -> Profile {
+        let mut profile = Profile::default();
+        for (target, hold_ms) in [(30.0, 1000), (40.0, 500)].iter() {
+            let _ = profile.segments.push(Segment { target: *target, rate: 1.0, hold_ms: *hold_ms });
+        }
+        profile
+    }
+
+    #[test]
+    fn segments_in_order() {
+        let mut profile = profile();
+        assert_eq!(profile.start().map(|segment| segment.target), Some(30.0));
+        assert_eq!(profile.poll(0, false), None);
+        assert_eq!(profile.poll(100, true), None);
+        assert_eq!(profile.progress(600, 30.0), Some((0, 500)));
+        assert_eq!(profile.poll(1099, true), None);
+        assert_eq!(profile.poll(1100, true).map(|segment| segment.target), Some(40.0));
+        assert_eq!(profile.progress(1100, 35.0), Some((1, 5500)));
+        assert_eq!(profile.poll(2000, true), None);
+        assert_eq!(profile.poll(2500, true), None);
+        assert!(!profile.is_running());
+    }
+
+    #[test]
+    fn loop_mode() {
+        let mut profile = profile();
+        profile.mode = ProfileMode::Loop;
+        profile.start();
+        profile.poll(0, true);
+        profile.poll(1000, true);
+        profile.poll(1000, true);
+        assert_eq!(profile.poll(1500, true).map(|segment| segment.target), Some(30.0));
+        assert!(profile.is_running());
+    }
+}
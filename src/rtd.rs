@@ -0,0 +1,105 @@
+use num_traits::float::Float;
+use uom::si::{
+    f64::{
+        ElectricalResistance,
+        ThermodynamicTemperature,
+    },
+    electrical_resistance::ohm,
+    ratio::ratio,
+    thermodynamic_temperature::degree_celsius,
+};
+use serde::{Deserialize, Serialize};
+
+/// Newton iterations below 0 °C
+const MAX_ITERATIONS: usize = 10;
+/// Convergence of the Newton iteration in K
+const TOLERANCE: f64 = 1e-6;
+
+/// Callendar–Van Dusen equation parameters of a platinum RTD
+///
+/// `R(T) = R0·(1 + A·T + B·T² + C·(T - 100)·T³)` with `T` in °C, where
+/// `C` only applies below 0 °C
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Parameters {
+    /// Resistance at 0 °C
+    pub r0: ElectricalResistance,
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl Parameters {
+    /// Resistance ratio `R/R0` at `t` in °C
+    fn get_ratio(&self, t: f64) -> f64 {
+        let mut r = 1.0 + self.a * t + self.b * t * t;
+        if t < 0.0 {
+            r += self.c * (t - 100.0) * t * t * t;
+        }
+        r
+    }
+
+    /// Perform the resistance to temperature conversion.
+    pub fn get_temperature(&self, r: ElectricalResistance) -> ThermodynamicTemperature {
+        let r = (r / self.r0).get::<ratio>();
+        // Exact above 0 °C, where C is not used
+        let mut t = if self.b == 0.0 {
+            (r - 1.0) / self.a
+        } else {
+            (-self.a + (self.a * self.a - 4.0 * self.b * (1.0 - r)).sqrt()) / (2.0 * self.b)
+        };
+        if t < 0.0 && self.c != 0.0 {
+            for _ in 0..MAX_ITERATIONS {
+                let slope = self.a + 2.0 * self.b * t
+                    + self.c * (4.0 * t * t * t - 300.0 * t * t);
+                let dt = (self.get_ratio(t) - r) / slope;
+                t -= dt;
+                if dt.abs() <= TOLERANCE {
+                    break;
+                }
+            }
+        }
+        ThermodynamicTemperature::new::<degree_celsius>(t)
+    }
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        // IEC 60751 PT100
+        Parameters {
+            r0: ElectricalResistance::new::<ohm>(100.0),
+            a: 3.9083e-3,
+            b: -5.775e-7,
+            c: -4.183e-12,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temperature(params: &Parameters, r: f64) -> f64 {
+        params.get_temperature(ElectricalResistance::new::<ohm>(r))
+            .get::<degree_celsius>()
+    }
+
+    #[test]
+    fn pt100() {
+        let params = Parameters::default();
+        assert!(temperature(&params, 100.0).abs() < 1e-9);
+        // IEC 60751 table values
+        assert!((temperature(&params, 138.5055) - 100.0).abs() < 1e-3);
+        assert!((temperature(&params, 60.2558) - -100.0).abs() < 1e-3);
+        assert!((temperature(&params, 18.5201) - -200.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn pt1000() {
+        let params = Parameters {
+            r0: ElectricalResistance::new::<ohm>(1000.0),
+            ..Parameters::default()
+        };
+        assert!((temperature(&params, 1385.055) - 100.0).abs() < 1e-3);
+        assert!((temperature(&params, 602.558) - -100.0).abs() < 1e-3);
+    }
+}
@@ -1,91 +1,292 @@
 use core::mem::MaybeUninit;
+use log::{info, warn};
 use smoltcp::{
+    dhcp::Dhcpv4Client,
     iface::EthernetInterface,
-    socket::{SocketSet, SocketHandle, TcpSocket, TcpSocketBuffer, SocketRef},
-    time::Instant,
-    wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr},
+    socket::{SocketSet, SocketHandle, TcpSocket, TcpSocketBuffer, SocketRef, RawPacketMetadata, RawSocketBuffer, UdpPacketMetadata, UdpSocket, UdpSocketBuffer},
+    time::{Duration, Instant},
+    wire::{IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr},
 };
 use crate::command_parser::Ipv4Config;
 use crate::net::split_ipv4_config;
+use crate::netstat;
+use crate::sntp;
+use crate::telemetry;
 
 pub struct SocketState<S> {
     handle: SocketHandle,
     state: S,
+    /// When data was last received or acknowledged, in ms
+    last_activity: u32,
 }
 
 /// Number of server sockets and therefore concurrent client
-/// sessions. Many data structures in `Server::run()` correspond to
-/// this const.
+/// sessions. Each one takes `TCP_RX_BUFFER_SIZE + TCP_TX_BUFFER_SIZE`
+/// of stack in `Server::run()` plus a `Session`. Up to
+/// `32 - OTHER_SOCKETS` sockets are supported, limited by `Default` of
+/// the socket storage array.
 const SOCKET_COUNT: usize = 4;
+/// Sockets for the DHCP client, telemetry, MQTT and SNTP. ICMP echo
+/// requests are answered by `EthernetInterface` itself, an `IcmpSocket`
+/// would only receive copies of them.
+const OTHER_SOCKETS: usize = 4;
 
 const TCP_RX_BUFFER_SIZE: usize = 2048;
 const TCP_TX_BUFFER_SIZE: usize = 2048;
 
+/// Longest idle timeout of `tcp timeout` in s
+pub const IDLE_TIMEOUT_MAX: u32 = 24 * 60 * 60;
+/// Probe clients that have sent nothing for this long, in ms
+const KEEP_ALIVE_INTERVAL: u64 = 10_000;
+/// Abort connections whose client stops acknowledging data or
+/// keep-alive probes for this long, in ms
+const ACK_TIMEOUT: u64 = 30_000;
+
+/// The MQTT client only receives small acknowledgements
+const MQTT_RX_BUFFER_SIZE: usize = 64;
+/// Room for the reports of both channels
+const MQTT_TX_BUFFER_SIZE: usize = 2048;
+
+const UDP_RX_BUFFER_SIZE: usize = 64;
+const UDP_TX_BUFFER_SIZE: usize = 2048;
+/// Number of datagrams queued for sending
+const UDP_TX_PACKETS: usize = 2;
+
+/// One request or reply at a time
+const SNTP_BUFFER_SIZE: usize = 2 * sntp::PACKET_LEN;
+
+const DHCP_RX_BUFFER_SIZE: usize = 900;
+const DHCP_TX_BUFFER_SIZE: usize = 600;
+/// Fall back to the static address if no lease has been acquired
+/// within this time
+const DHCP_TIMEOUT_MS: u64 = 10_000;
+
+/// Listen for a new client on a server socket, detecting clients that
+/// have gone away without closing the connection
+pub fn listen(socket: &mut TcpSocket, port: u16) {
+    let _ = socket.listen(port);
+    // Both are cleared by `listen()`
+    socket.set_keep_alive(Some(Duration::from_millis(KEEP_ALIVE_INTERVAL)));
+    socket.set_timeout(Some(Duration::from_millis(ACK_TIMEOUT)));
+}
+
 /// Contains a number of server sockets that get all sent the same
 /// data (through `fmt::Write`).
 pub struct Server<'a, 'b, S> {
     net: EthernetInterface<'a, &'a mut stm32_eth::Eth<'static, 'static>>,
     sockets: SocketSet<'b>,
-    states: [SocketState<S>; SOCKET_COUNT],
+    states: &'b mut [SocketState<S>; SOCKET_COUNT],
+    telemetry_handle: SocketHandle,
+    mqtt_handle: SocketHandle,
+    sntp_handle: SocketHandle,
+    ipv4_config: Ipv4Config,
+    dhcp: Dhcpv4Client,
+    /// When DHCP was (re)started, `None` if it is to be started on
+    /// the next poll
+    dhcp_started: Option<Instant>,
+    dhcp_lease: Option<(Ipv4Cidr, Option<Ipv4Address>)>,
+    dhcp_fallback: bool,
 }
 
 impl<'a, 'b, S: Default> Server<'a, 'b, S> {
     /// Run a server with stack-allocated sockets
-    pub fn run<F>(net: EthernetInterface<'a, &'a mut stm32_eth::Eth<'static, 'static>>, f: F)
+    pub fn run<F>(net: EthernetInterface<'a, &'a mut stm32_eth::Eth<'static, 'static>>, ipv4_config: Ipv4Config, f: F)
     where
         F: FnOnce(&mut Server<'a, '_, S>),
     {
-        let mut sockets_storage: [_; SOCKET_COUNT] = Default::default();
+        let mut sockets_storage: [_; SOCKET_COUNT + OTHER_SOCKETS] = Default::default();
         let mut sockets = SocketSet::new(&mut sockets_storage[..]);
-        let mut states: [SocketState<S>; SOCKET_COUNT] = unsafe { MaybeUninit::uninit().assume_init() };
-
-        macro_rules! create_socket {
-            ($set:ident, $rx_storage:ident, $tx_storage:ident, $target:expr) => {
-                let mut $rx_storage = [0; TCP_RX_BUFFER_SIZE];
-                let mut $tx_storage = [0; TCP_TX_BUFFER_SIZE];
-                let tcp_rx_buffer = TcpSocketBuffer::new(&mut $rx_storage[..]);
-                let tcp_tx_buffer = TcpSocketBuffer::new(&mut $tx_storage[..]);
-                let tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
-                $target = $set.add(tcp_socket);
+        // An array of `MaybeUninit` does not require initialization
+        let mut states: [MaybeUninit<SocketState<S>>; SOCKET_COUNT] = unsafe { MaybeUninit::uninit().assume_init() };
+
+        let mut tcp_rx_storage = [[0; TCP_RX_BUFFER_SIZE]; SOCKET_COUNT];
+        let mut tcp_tx_storage = [[0; TCP_TX_BUFFER_SIZE]; SOCKET_COUNT];
+        for ((state, rx_storage), tx_storage) in states.iter_mut()
+            .zip(tcp_rx_storage.iter_mut())
+            .zip(tcp_tx_storage.iter_mut())
+        {
+            let tcp_rx_buffer = TcpSocketBuffer::new(&mut rx_storage[..]);
+            let tcp_tx_buffer = TcpSocketBuffer::new(&mut tx_storage[..]);
+            let tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
+            let handle = sockets.add(tcp_socket);
+            // Written in place
+            unsafe {
+                state.as_mut_ptr().write(SocketState {
+                    handle,
+                    state: S::default(),
+                    last_activity: 0,
+                });
             }
         }
-        create_socket!(sockets, tcp_rx_storage0, tcp_tx_storage0, states[0].handle);
-        create_socket!(sockets, tcp_rx_storage1, tcp_tx_storage1, states[1].handle);
-        create_socket!(sockets, tcp_rx_storage2, tcp_tx_storage2, states[2].handle);
-        create_socket!(sockets, tcp_rx_storage3, tcp_tx_storage3, states[3].handle);
+        // Safe because every element has been written above. Borrowed
+        // instead of copied as the sessions are large.
+        let states = unsafe {
+            &mut *(states.as_mut_ptr() as *mut [SocketState<S>; SOCKET_COUNT])
+        };
 
-        for state in &mut states {
-            state.state = S::default();
-        }
+        let mut udp_rx_metadata = [UdpPacketMetadata::EMPTY; 1];
+        let mut udp_rx_storage = [0; UDP_RX_BUFFER_SIZE];
+        let mut udp_tx_metadata = [UdpPacketMetadata::EMPTY; UDP_TX_PACKETS];
+        let mut udp_tx_storage = [0; UDP_TX_BUFFER_SIZE];
+        let udp_rx_buffer = UdpSocketBuffer::new(&mut udp_rx_metadata[..], &mut udp_rx_storage[..]);
+        let udp_tx_buffer = UdpSocketBuffer::new(&mut udp_tx_metadata[..], &mut udp_tx_storage[..]);
+        let mut udp_socket = UdpSocket::new(udp_rx_buffer, udp_tx_buffer);
+        udp_socket.bind(telemetry::LOCAL_PORT).unwrap();
+        let telemetry_handle = sockets.add(udp_socket);
+
+        let mut mqtt_rx_storage = [0; MQTT_RX_BUFFER_SIZE];
+        let mut mqtt_tx_storage = [0; MQTT_TX_BUFFER_SIZE];
+        let mqtt_rx_buffer = TcpSocketBuffer::new(&mut mqtt_rx_storage[..]);
+        let mqtt_tx_buffer = TcpSocketBuffer::new(&mut mqtt_tx_storage[..]);
+        let mqtt_handle = sockets.add(TcpSocket::new(mqtt_rx_buffer, mqtt_tx_buffer));
+
+        let mut sntp_rx_metadata = [UdpPacketMetadata::EMPTY; 1];
+        let mut sntp_rx_storage = [0; SNTP_BUFFER_SIZE];
+        let mut sntp_tx_metadata = [UdpPacketMetadata::EMPTY; 1];
+        let mut sntp_tx_storage = [0; SNTP_BUFFER_SIZE];
+        let sntp_rx_buffer = UdpSocketBuffer::new(&mut sntp_rx_metadata[..], &mut sntp_rx_storage[..]);
+        let sntp_tx_buffer = UdpSocketBuffer::new(&mut sntp_tx_metadata[..], &mut sntp_tx_storage[..]);
+        let mut sntp_socket = UdpSocket::new(sntp_rx_buffer, sntp_tx_buffer);
+        sntp_socket.bind(sntp::LOCAL_PORT).unwrap();
+        let sntp_handle = sockets.add(sntp_socket);
+
+        let mut dhcp_rx_metadata = [RawPacketMetadata::EMPTY; 1];
+        let mut dhcp_rx_storage = [0; DHCP_RX_BUFFER_SIZE];
+        let mut dhcp_tx_metadata = [RawPacketMetadata::EMPTY; 1];
+        let mut dhcp_tx_storage = [0; DHCP_TX_BUFFER_SIZE];
+        let dhcp_rx_buffer = RawSocketBuffer::new(&mut dhcp_rx_metadata[..], &mut dhcp_rx_storage[..]);
+        let dhcp_tx_buffer = RawSocketBuffer::new(&mut dhcp_tx_metadata[..], &mut dhcp_tx_storage[..]);
+        let dhcp = Dhcpv4Client::new(&mut sockets, dhcp_rx_buffer, dhcp_tx_buffer, Instant::from_millis(0));
 
         let mut server = Server {
             states,
+            telemetry_handle,
+            mqtt_handle,
+            sntp_handle,
             sockets,
             net,
+            ipv4_config,
+            dhcp,
+            dhcp_started: None,
+            dhcp_lease: None,
+            dhcp_fallback: false,
         };
         f(&mut server);
     }
 
     /// Poll the interface and the sockets
     pub fn poll(&mut self, now: Instant) -> Result<(), smoltcp::Error> {
+        let mut send_queues = [0; SOCKET_COUNT];
+        for (state, send_queue) in self.states.iter().zip(send_queues.iter_mut()) {
+            *send_queue = self.sockets.get::<TcpSocket>(state.handle).send_queue();
+        }
         // Poll smoltcp EthernetInterface,
         // pass only unexpected smoltcp errors to the caller
-        match self.net.poll(&mut self.sockets, now) {
+        let result = match self.net.poll(&mut self.sockets, now) {
             Ok(_) => Ok(()),
             Err(smoltcp::Error::Malformed) => Ok(()),
             Err(smoltcp::Error::Unrecognized) => Ok(()),
             Err(e) => Err(e),
+        };
+        if self.ipv4_config.dhcp {
+            self.poll_dhcp(now);
+        }
+        // Received data or a shrinking send queue show that the client
+        // is still there
+        let millis = now.total_millis() as u32;
+        for (state, send_queue) in self.states.iter_mut().zip(send_queues.iter()) {
+            let socket = self.sockets.get::<TcpSocket>(state.handle);
+            if !socket.is_active() || socket.recv_queue() > 0 || socket.send_queue() < *send_queue {
+                state.last_activity = millis;
+            }
         }
+        let sockets = &mut self.sockets;
+        let active = self.states.iter()
+            .filter(|state| sockets.get::<TcpSocket>(state.handle).is_active())
+            .count();
+        netstat::set_active_sockets(active as u32);
+        result
+    }
+
+    fn poll_dhcp(&mut self, now: Instant) {
+        let started = match self.dhcp_started {
+            Some(started) => started,
+            None => {
+                self.dhcp.reset(now);
+                self.dhcp_started = Some(now);
+                now
+            }
+        };
+
+        match self.dhcp.poll(&mut self.net, &mut self.sockets, now) {
+            Ok(Some(config)) => {
+                if let Some(address) = config.address {
+                    info!("DHCP lease: {}", address);
+                    self.set_ipv4_address(address);
+                    self.set_gateway(config.router);
+                    self.dhcp_lease = Some((address, config.router));
+                }
+            }
+            Ok(None) => {}
+            Err(e) =>
+                warn!("dhcp: {:?}", e),
+        }
+
+        if self.dhcp_lease.is_none() && !self.dhcp_fallback && (now - started).total_millis() >= DHCP_TIMEOUT_MS {
+            warn!("no DHCP lease, falling back to static address");
+            let (address, gateway) = split_ipv4_config(self.ipv4_config.clone());
+            self.set_ipv4_address(address);
+            self.set_gateway(gateway);
+            self.dhcp_fallback = true;
+        }
+    }
+
+    /// Address and gateway acquired by DHCP
+    pub fn dhcp_lease(&self) -> Option<(Ipv4Cidr, Option<Ipv4Address>)> {
+        self.dhcp_lease
     }
 
     /// Iterate over all sockets managed by this server
     pub fn for_each<F: FnMut(SocketRef<TcpSocket>, &mut S)>(&mut self, mut callback: F) {
-        for state in &mut self.states {
+        for state in self.states.iter_mut() {
             let socket = self.sockets.get::<TcpSocket>(state.handle);
             callback(socket, &mut state.state);
         }
     }
 
+    /// Abort the connections of clients that have neither sent nor
+    /// acknowledged data for `timeout` ms, so that their sockets
+    /// listen again
+    pub fn close_idle(&mut self, now: Instant, timeout: u32) {
+        let now = now.total_millis() as u32;
+        for state in self.states.iter() {
+            let mut socket = self.sockets.get::<TcpSocket>(state.handle);
+            if socket.is_active() && now.wrapping_sub(state.last_activity) >= timeout {
+                info!("closing TCP session idle for {} ms", now.wrapping_sub(state.last_activity));
+                socket.abort();
+            }
+        }
+    }
+
+    /// Queue a report datagram, fails without blocking if the buffer
+    /// is full
+    pub fn send_telemetry(&mut self, endpoint: IpEndpoint, report: &[u8]) -> Result<(), smoltcp::Error> {
+        let mut socket = self.sockets.get::<UdpSocket>(self.telemetry_handle);
+        let buf = socket.send(telemetry::packet_len(report), endpoint)?;
+        telemetry::write_packet(report, buf);
+        Ok(())
+    }
+
+    /// The client socket for MQTT, outside of the server sockets
+    pub fn mqtt_socket(&mut self) -> SocketRef<TcpSocket> {
+        self.sockets.get::<TcpSocket>(self.mqtt_handle)
+    }
+
+    /// The client socket for SNTP
+    pub fn sntp_socket(&mut self) -> SocketRef<UdpSocket> {
+        self.sockets.get::<UdpSocket>(self.sntp_handle)
+    }
+
     fn set_ipv4_address(&mut self, ipv4_address: Ipv4Cidr) {
         self.net.update_ip_addrs(|addrs| {
             for addr in addrs.iter_mut() {
@@ -117,8 +318,17 @@ impl<'a, 'b, S: Default> Server<'a, 'b, S> {
     }
 
     pub fn set_ipv4_config(&mut self, config: Ipv4Config) {
-        let (address, gateway) = split_ipv4_config(config);
-        self.set_ipv4_address(address);
-        self.set_gateway(gateway);
+        self.dhcp_started = None;
+        self.dhcp_lease = None;
+        self.dhcp_fallback = false;
+        if config.dhcp {
+            self.set_ipv4_address(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0));
+            self.set_gateway(None);
+        } else {
+            let (address, gateway) = split_ipv4_config(config.clone());
+            self.set_ipv4_address(address);
+            self.set_gateway(gateway);
+        }
+        self.ipv4_config = config;
     }
 }
@@ -1,11 +1,117 @@
-use super::command_parser::{Command, Error as ParserError};
+use core::{fmt, sync::atomic::{AtomicBool, Ordering}};
+use heapless::{consts::{U16, U2048, U4096}, Vec};
+use super::command_parser::{Command, Error as ParserError, ReportFormat, ReportOnConnect, ShowCommand, StageCommand};
 use super::channels::CHANNELS;
 
-const MAX_LINE_LEN: usize = 64;
+/// Commands buffered between `stage begin` and `stage commit`
+pub type StagedCommands = Vec<Command, U16>;
+
+/// Room for a few `;`-separated commands
+const MAX_LINE_LEN: usize = 256;
+
+/// Document collected by `config import`, large enough for a
+/// `config export` with every number at its longest
+pub type ImportBuffer = Vec<u8, U4096>;
+/// Line that ends a `config import` document
+const IMPORT_END: &[u8] = b"end";
+
+/// Document of the one `config import` in progress, shared by all
+/// sessions to keep it off the stack. Only accessed from the main
+/// loop by the session that has set `IMPORT_OWNED`.
+static mut IMPORT_BUFFER: ImportBuffer = Vec(heapless::i::Vec::new());
+static IMPORT_OWNED: AtomicBool = AtomicBool::new(false);
+
+/// A JSON report, like `command_handler::JsonBuffer`
+pub type ReportBuffer = Vec<u8, U2048>;
+
+/// Keys of the `all` JSON object, in the order their values are sent
+pub const ALL_PARTS: [&str; 10] = [
+    "report", "pid", "pwm", "s-h", "postfilter", "adc", "tec", "fan", "ipv4", "hwrev",
+];
+
+/// Bytes to send before the value of `ALL_PARTS[part]`
+pub fn all_part_prefix(part: usize) -> Vec<u8, U16> {
+    let mut prefix = Vec::new();
+    let _ = prefix.extend_from_slice(if part == 0 { b"{\"" } else { b",\"" });
+    let _ = prefix.extend_from_slice(ALL_PARTS[part].as_bytes());
+    let _ = prefix.extend_from_slice(b"\":");
+    prefix
+}
+
+/// Bytes to send after the value of `ALL_PARTS[part]`
+pub fn all_part_suffix(part: usize) -> &'static [u8] {
+    if part == ALL_PARTS.len() - 1 {
+        b"}\n"
+    } else {
+        b""
+    }
+}
+
+/// Bytes preceding each part of the `config export` document: the
+/// config of each channel, then the network and fan settings
+pub const CONFIG_EXPORT_PREFIXES: [&[u8]; 4] = [
+    b"{\"channels\":[", b",", b"],\"ipv4\":", b",\"fan\":",
+];
+
+/// Telnet "Interpret As Command"
+const TELNET_IAC: u8 = 0xFF;
+/// Telnet subnegotiation begin and end
+const TELNET_SB: u8 = 0xFA;
+const TELNET_SE: u8 = 0xF0;
+/// Telnet WILL, WONT, DO and DONT are followed by an option byte
+const TELNET_WILL: u8 = 0xFB;
+const TELNET_DONT: u8 = 0xFE;
+
+/// Position in a Telnet command sequence, which stock telnet clients
+/// send to negotiate options
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Telnet {
+    Data,
+    /// After IAC
+    Command,
+    /// After IAC WILL, WONT, DO or DONT
+    Option,
+    /// After IAC SB, until IAC SE
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+impl Telnet {
+    /// Returns `true` if `c` is data rather than part of a command
+    fn feed(&mut self, c: u8) -> bool {
+        let (next, data) = match (*self, c) {
+            (Telnet::Data, TELNET_IAC) => (Telnet::Command, false),
+            (Telnet::Data, _) => (Telnet::Data, true),
+            (Telnet::Command, TELNET_SB) => (Telnet::Subnegotiation, false),
+            (Telnet::Command, TELNET_WILL..=TELNET_DONT) => (Telnet::Option, false),
+            // Other commands, or an escaped 0xFF which is no valid input
+            (Telnet::Command, _) => (Telnet::Data, false),
+            (Telnet::Option, _) => (Telnet::Data, false),
+            (Telnet::Subnegotiation, TELNET_IAC) => (Telnet::SubnegotiationIac, false),
+            (Telnet::Subnegotiation, _) => (Telnet::Subnegotiation, false),
+            (Telnet::SubnegotiationIac, TELNET_SE) => (Telnet::Data, false),
+            (Telnet::SubnegotiationIac, _) => (Telnet::Subnegotiation, false),
+        };
+        *self = next;
+        data
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LineStatus {
+    Incomplete,
+    Complete,
+    /// The line exceeds `MAX_LINE_LEN`, the rest of it is discarded
+    TooLong,
+}
 
 struct LineReader {
     buf: [u8; MAX_LINE_LEN],
     pos: usize,
+    /// Length of the last completed line at the start of `buf`
+    line_len: usize,
+    /// Discarding input up to the end of an overlong line
+    overflow: bool,
 }
 
 impl LineReader {
@@ -13,48 +119,171 @@ impl LineReader {
         LineReader {
             buf: [0; MAX_LINE_LEN],
             pos: 0,
+            line_len: 0,
+            overflow: false,
         }
     }
 
-    pub fn feed(&mut self, c: u8) -> Option<&[u8]> {
+    pub fn feed(&mut self, c: u8) -> LineStatus {
         if c == 13 || c == 10 {
             // Enter
-            if self.pos > 0 {
-                let len = self.pos;
+            if self.overflow {
+                self.overflow = false;
+                self.pos = 0;
+                LineStatus::Incomplete
+            } else if self.pos > 0 {
+                self.line_len = self.pos;
                 self.pos = 0;
-                Some(&self.buf[..len])
+                LineStatus::Complete
             } else {
-                None
+                LineStatus::Incomplete
             }
+        } else if self.overflow {
+            LineStatus::Incomplete
+        } else if c == 8 || c == 0x7F {
+            // Backspace, Delete
+            self.pos = self.pos.saturating_sub(1);
+            LineStatus::Incomplete
+        } else if c < 0x20 {
+            // Ignore other control characters
+            LineStatus::Incomplete
         } else if self.pos < self.buf.len() {
             // Add input
             self.buf[self.pos] = c;
             self.pos += 1;
-            None
+            LineStatus::Incomplete
         } else {
-            // Buffer is full, ignore
-            None
+            // Buffer is full
+            self.overflow = true;
+            LineStatus::TooLong
         }
     }
+
+    /// The last completed line, valid until more input is fed
+    pub fn line(&self) -> &[u8] {
+        &self.buf[..self.line_len]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    Parser(ParserError),
+    /// Too many commands staged
+    StageFull,
+    /// `stage commit` without `stage begin`
+    NotStaging,
+    /// `stage begin` while already staging
+    AlreadyStaging,
+    /// `config import` document does not fit `ImportBuffer`
+    ImportTooLong,
+    /// `config import` while another session is importing
+    ImportBusy,
+    /// Input line longer than `MAX_LINE_LEN`
+    LineTooLong,
+}
+
+impl Error {
+    /// Stable identifier for the `code` field of error replies
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Parser(e) => e.code(),
+            Error::StageFull => "stage_full",
+            Error::NotStaging => "not_staging",
+            Error::AlreadyStaging => "already_staging",
+            Error::ImportTooLong => "import_too_long",
+            Error::ImportBusy => "import_busy",
+            Error::LineTooLong => "line_too_long",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Error::Parser(e) => e.fmt(fmt),
+            Error::StageFull => "too many commands staged".fmt(fmt),
+            Error::NotStaging => "not staging".fmt(fmt),
+            Error::AlreadyStaging => "already staging, commit or abort first".fmt(fmt),
+            Error::ImportTooLong => "config import too long".fmt(fmt),
+            Error::ImportBusy => "another session is importing".fmt(fmt),
+            Error::LineTooLong => "line too long".fmt(fmt),
+        }
+    }
+}
+
+impl From<ParserError> for Error {
+    fn from(e: ParserError) -> Self {
+        Error::Parser(e)
+    }
 }
 
 pub enum SessionInput {
     Nothing,
     Command(Command),
-    Error(ParserError),
+    /// Command has been buffered for `stage commit`
+    Staged,
+    /// Apply all these commands at once
+    Commit(StagedCommands),
+    /// Complete `config import` document, passed on by
+    /// `Session::finish_import()`
+    Import,
+    Error(Error),
 }
 
 impl From<Result<Command, ParserError>> for SessionInput {
     fn from(input: Result<Command, ParserError>) -> Self {
         input.map(SessionInput::Command)
-            .unwrap_or_else(SessionInput::Error)
+            .unwrap_or_else(|e| SessionInput::Error(e.into()))
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ImportState {
+    Idle,
+    /// Collecting into `IMPORT_BUFFER` instead of parsing commands
+    Collecting {
+        /// Start of the current line
+        line_start: usize,
+    },
+    /// Document waits for `finish_import()`
+    Complete,
+}
+
+/// Report that did not fit the socket buffer at once
+struct PendingReport {
+    channel: usize,
+    line: ReportBuffer,
+    /// Bytes of `line` and its newline that have been sent
+    sent: usize,
+}
+
 pub struct Session {
+    telnet: Telnet,
     reader: LineReader,
     reporting: bool,
     report_pending: [bool; CHANNELS],
+    /// Send one report regardless of `reporting`
+    report_snapshot: bool,
+    report_format: ReportFormat,
+    /// Report every interval in ms instead of on new samples if not 0
+    report_interval: u32,
+    /// Time of the next report with `report_interval` in ms
+    next_report: Option<u32>,
+    /// Report in transmission, to be finished before anything else
+    report_out: Option<PendingReport>,
+    log_to_tcp: bool,
+    /// Channels whose autotune progress is streamed to this session
+    autotune: [bool; CHANNELS],
+    autotune_pending: [bool; CHANNELS],
+    /// Next part of a requested `all` object
+    all_part: Option<usize>,
+    /// Next part of a requested `config export` document
+    export_part: Option<usize>,
+    staged: Option<StagedCommands>,
+    /// Owns `IMPORT_BUFFER` unless `Idle`
+    import: ImportState,
+    /// Start of the next `;`-separated command in the last line
+    next_segment: Option<usize>,
 }
 
 impl Default for Session {
@@ -66,30 +295,94 @@ impl Default for Session {
 impl Session {
     pub fn new() -> Self {
         Session {
+            telnet: Telnet::Data,
             reader: LineReader::new(),
             reporting: false,
             report_pending: [false; CHANNELS],
+            report_snapshot: false,
+            report_format: ReportFormat::Json,
+            report_interval: 0,
+            next_report: None,
+            report_out: None,
+            log_to_tcp: false,
+            autotune: [false; CHANNELS],
+            autotune_pending: [false; CHANNELS],
+            all_part: None,
+            export_part: None,
+            staged: None,
+            import: ImportState::Idle,
+            next_segment: None,
         }
     }
 
-    pub fn reset(&mut self) {
+    /// Clear all state for a new connection, reporting according to
+    /// `on_connect`
+    pub fn reset(&mut self, on_connect: ReportOnConnect) {
+        self.telnet = Telnet::Data;
         self.reader = LineReader::new();
-        self.reporting = false;
+        self.reporting = on_connect == ReportOnConnect::On;
         self.report_pending = [false; CHANNELS];
+        self.report_snapshot = on_connect == ReportOnConnect::Snapshot;
+        self.report_format = ReportFormat::Json;
+        self.report_interval = 0;
+        self.next_report = None;
+        self.report_out = None;
+        self.log_to_tcp = false;
+        self.autotune = [false; CHANNELS];
+        self.autotune_pending = [false; CHANNELS];
+        self.all_part = None;
+        self.export_part = None;
+        self.staged = None;
+        self.release_import();
+        self.next_segment = None;
     }
 
     pub fn reporting(&self) -> bool {
         self.reporting
     }
 
+    pub fn report_format(&self) -> ReportFormat {
+        self.report_format
+    }
+
+    pub fn log_to_tcp(&self) -> bool {
+        self.log_to_tcp
+    }
+
     pub fn set_report_pending(&mut self, channel: usize) {
-        if self.reporting {
+        if self.reporting && self.report_interval == 0 {
             self.report_pending[channel] = true;
         }
     }
 
+    /// With a `report_interval`, mark a report of the latest samples
+    /// pending once it has elapsed
+    pub fn poll_report_interval(&mut self, now: u32) {
+        if !self.reporting || self.report_interval == 0 {
+            self.next_report = None;
+            return;
+        }
+        let due = self.next_report
+            .map_or(true, |next| now.wrapping_sub(next) as i32 >= 0);
+        if due {
+            // both channels are sent in one report
+            self.report_pending[0] = true;
+            // Keep the schedule, unless it has fallen behind
+            let next = self.next_report
+                .map(|next| next.wrapping_add(self.report_interval))
+                .filter(|next| next.wrapping_sub(now) as i32 > 0)
+                .unwrap_or_else(|| now.wrapping_add(self.report_interval));
+            self.next_report = Some(next);
+        }
+    }
+
     pub fn is_report_pending(&self) -> Option<usize> {
-        if ! self.reporting {
+        if self.report_out.is_some() {
+            // Still sending the last one
+            None
+        } else if self.report_snapshot {
+            Some(0)
+        } else if ! self.reporting {
             None
         } else {
             self.report_pending.iter()
@@ -104,27 +397,507 @@ impl Session {
 
     pub fn mark_report_sent(&mut self, channel: usize) {
         self.report_pending[channel] = false;
+        self.report_snapshot = false;
+    }
+
+    /// Retain a report line to be sent with `pending_output`. The
+    /// report counts as sent once all of it is.
+    pub fn queue_report(&mut self, channel: usize, line: &[u8]) {
+        let mut buf = ReportBuffer::new();
+        // `JsonBuffer` has the same capacity
+        let _ = buf.extend_from_slice(line);
+        self.report_out = Some(PendingReport { channel, line: buf, sent: 0 });
+    }
+
+    /// Rest of the queued report
+    pub fn pending_output(&self) -> Option<&[u8]> {
+        let report = self.report_out.as_ref()?;
+        if report.sent < report.line.len() {
+            Some(&report.line[report.sent..])
+        } else {
+            Some(b"\n")
+        }
+    }
+
+    pub fn mark_output_sent(&mut self, len: usize) {
+        let done = match self.report_out.as_mut() {
+            Some(report) => {
+                report.sent += len;
+                report.sent > report.line.len()
+            }
+            None => false,
+        };
+        if done {
+            let report = self.report_out.take().unwrap();
+            self.mark_report_sent(report.channel);
+        }
+    }
+
+    pub fn set_autotune_pending(&mut self, channel: usize) {
+        if self.autotune[channel] {
+            self.autotune_pending[channel] = true;
+        }
+    }
+
+    pub fn is_autotune_pending(&self) -> Option<usize> {
+        self.autotune_pending.iter()
+            .position(|pending| *pending)
+    }
+
+    /// Stop streaming once `finished`
+    pub fn mark_autotune_sent(&mut self, channel: usize, finished: bool) {
+        self.autotune_pending[channel] = false;
+        if finished {
+            self.autotune[channel] = false;
+        }
+    }
+
+    pub fn is_all_pending(&self) -> Option<usize> {
+        self.all_part
+    }
+
+    pub fn mark_all_part_sent(&mut self) {
+        self.all_part = self.all_part
+            .map(|part| part + 1)
+            .filter(|part| *part < ALL_PARTS.len());
+    }
+
+    pub fn is_export_pending(&self) -> Option<usize> {
+        self.export_part
+    }
+
+    pub fn mark_export_part_sent(&mut self) {
+        self.export_part = self.export_part
+            .map(|part| part + 1)
+            .filter(|part| *part < CONFIG_EXPORT_PREFIXES.len());
     }
 
     pub fn feed(&mut self, buf: &[u8]) -> (usize, SessionInput) {
         let mut buf_bytes = 0;
         for (i, b) in buf.iter().enumerate() {
             buf_bytes = i + 1;
-            let line = self.reader.feed(*b);
-            match line {
-                Some(line) => {
-                    let command = Command::parse(&line);
-                    match command {
-                        Ok(Command::Reporting(reporting)) => {
-                            self.reporting = reporting;
+            if !self.telnet.feed(*b) {
+                continue;
+            }
+            if let ImportState::Collecting { .. } = self.import {
+                if let Some(input) = self.feed_import(*b) {
+                    return (buf_bytes, input);
+                }
+            } else {
+                match self.reader.feed(*b) {
+                    LineStatus::Incomplete => {}
+                    LineStatus::Complete => {
+                        self.next_segment = Some(0);
+                        if let Some(input) = self.next_input() {
+                            return (buf_bytes, input);
                         }
-                        _ => {}
                     }
-                    return (buf_bytes, command.into());
+                    LineStatus::TooLong =>
+                        return (buf_bytes, SessionInput::Error(Error::LineTooLong)),
                 }
-                None => {}
             }
         }
         (buf_bytes, SessionInput::Nothing)
     }
+
+    /// Collect `config import` input until a line `IMPORT_END`
+    fn feed_import(&mut self, c: u8) -> Option<SessionInput> {
+        let line_start = match self.import {
+            ImportState::Collecting { line_start } => line_start,
+            _ => return None,
+        };
+        // Safe because this session owns the buffer
+        let import = unsafe { &mut IMPORT_BUFFER };
+        if c == b'\r' || c == b'\n' {
+            if &import[line_start..] == IMPORT_END {
+                while import.len() > line_start {
+                    import.pop();
+                }
+                self.import = ImportState::Complete;
+                return Some(SessionInput::Import);
+            }
+            if import.len() > line_start {
+                // Separate the lines as whitespace
+                if import.push(b'\n').is_err() {
+                    self.release_import();
+                    return Some(SessionInput::Error(Error::ImportTooLong));
+                }
+                self.import = ImportState::Collecting { line_start: import.len() };
+            }
+        } else if import.push(c).is_err() {
+            self.release_import();
+            return Some(SessionInput::Error(Error::ImportTooLong));
+        }
+        None
+    }
+
+    /// Pass the document after `SessionInput::Import` to `f` and let
+    /// the next session import
+    pub fn finish_import<R, F: FnOnce(&[u8]) -> R>(&mut self, f: F) -> Option<R> {
+        if self.import != ImportState::Complete {
+            return None;
+        }
+        // Safe because this session owns the buffer
+        let result = f(unsafe { &IMPORT_BUFFER[..] });
+        self.release_import();
+        Some(result)
+    }
+
+    fn release_import(&mut self) {
+        if self.import != ImportState::Idle {
+            self.import = ImportState::Idle;
+            IMPORT_OWNED.store(false, Ordering::Release);
+        }
+    }
+
+    /// Are there commands left in the last line?
+    pub fn has_pending_input(&self) -> bool {
+        self.next_segment.is_some()
+    }
+
+    /// Parse the next `;`-separated command of the last line
+    pub fn next_input(&mut self) -> Option<SessionInput> {
+        loop {
+            let start = self.next_segment?;
+            let rest = &self.reader.line()[start..];
+            let (segment, next_segment) = match rest.iter().position(|c| *c == b';') {
+                Some(len) => (&rest[..len], Some(start + len + 1)),
+                None => (rest, None),
+            };
+            let segment_start = segment.iter()
+                .position(|c| *c != b' ')
+                .unwrap_or(segment.len());
+            let segment = &segment[segment_start..];
+            let command = if segment.is_empty() {
+                None
+            } else {
+                Some(Command::parse(segment))
+            };
+            self.next_segment = next_segment;
+
+            if let Some(command) = command {
+                return Some(self.handle(command));
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Result<Command, ParserError>) -> SessionInput {
+        match command {
+            Ok(Command::Reporting(reporting)) => {
+                self.reporting = reporting;
+            }
+            Ok(Command::ReportFormat(format)) => {
+                self.report_format = format;
+            }
+            Ok(Command::ReportInterval(interval)) => {
+                self.report_interval = interval;
+                self.next_report = None;
+            }
+            Ok(Command::LogToTcp(enabled)) => {
+                self.log_to_tcp = enabled;
+            }
+            Ok(Command::PidAutotune { channel, .. }) => {
+                self.autotune[channel] = true;
+            }
+            Ok(Command::Show(ShowCommand::All)) => {
+                self.all_part = Some(0);
+            }
+            Ok(Command::ConfigExport) => {
+                self.export_part = Some(0);
+            }
+            Ok(Command::ConfigImport) => {
+                if self.import == ImportState::Idle && IMPORT_OWNED.swap(true, Ordering::Acquire) {
+                    return self.abort_line(Error::ImportBusy);
+                }
+                // The document follows on the next lines
+                unsafe { IMPORT_BUFFER = ImportBuffer::new() };
+                self.import = ImportState::Collecting { line_start: 0 };
+                self.next_segment = None;
+                return SessionInput::Nothing;
+            }
+            Ok(Command::Stage(StageCommand::Begin)) => {
+                if self.staged.is_some() {
+                    return self.abort_line(Error::AlreadyStaging);
+                }
+                self.staged = Some(StagedCommands::new());
+            }
+            Ok(Command::Stage(StageCommand::Abort)) => {
+                self.staged = None;
+            }
+            Ok(Command::Stage(StageCommand::Commit)) => {
+                return match self.staged.take() {
+                    Some(staged) => SessionInput::Commit(staged),
+                    None => self.abort_line(Error::NotStaging),
+                };
+            }
+            Ok(command) if command.is_stageable() && self.staged.is_some() => {
+                let staged = self.staged.as_mut().unwrap();
+                return match staged.push(command) {
+                    Ok(()) => SessionInput::Staged,
+                    Err(_) => self.abort_line(Error::StageFull),
+                };
+            }
+            _ => {}
+        }
+        command.into()
+    }
+
+    /// Skip the rest of the line after a failure of staging, as its
+    /// commands would not be applied as intended. Parser errors only
+    /// affect their own command.
+    fn abort_line(&mut self, error: Error) -> SessionInput {
+        self.next_segment = None;
+        SessionInput::Error(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command_parser::PidParameter;
+
+    fn feed_line(session: &mut Session, line: &[u8]) -> SessionInput {
+        let (_, input) = session.feed(line);
+        input
+    }
+
+    #[test]
+    fn staged_commands_apply_on_commit() {
+        let mut session = Session::new();
+        assert!(matches!(feed_line(&mut session, b"stage begin\n"), SessionInput::Command(_)));
+        assert!(matches!(feed_line(&mut session, b"pid 0 kp 1\n"), SessionInput::Staged));
+        assert!(matches!(feed_line(&mut session, b"pid 0 ki 2\n"), SessionInput::Staged));
+        // not a parameter, passes through
+        assert!(matches!(feed_line(&mut session, b"pid\n"), SessionInput::Command(_)));
+
+        match feed_line(&mut session, b"stage commit\n") {
+            SessionInput::Commit(commands) => {
+                assert_eq!(&commands[..], &[
+                    Command::Pid { channel: 0, parameter: PidParameter::KP, value: 1.0 },
+                    Command::Pid { channel: 0, parameter: PidParameter::KI, value: 2.0 },
+                ]);
+            }
+            _ => panic!("expected commit"),
+        }
+        // staging ended with commit
+        assert!(matches!(feed_line(&mut session, b"pid 0 kd 3\n"), SessionInput::Command(_)));
+    }
+
+    #[test]
+    fn staged_commands_discarded_on_abort() {
+        let mut session = Session::new();
+        feed_line(&mut session, b"stage begin\n");
+        assert!(matches!(feed_line(&mut session, b"pid 0 kp 1\n"), SessionInput::Staged));
+        feed_line(&mut session, b"stage abort\n");
+        assert!(matches!(feed_line(&mut session, b"stage commit\n"), SessionInput::Error(Error::NotStaging)));
+    }
+
+    #[test]
+    fn nested_stage_begin() {
+        let mut session = Session::new();
+        feed_line(&mut session, b"stage begin\n");
+        assert!(matches!(feed_line(&mut session, b"pid 0 kp 1\n"), SessionInput::Staged));
+        assert!(matches!(feed_line(&mut session, b"stage begin\n"), SessionInput::Error(Error::AlreadyStaging)));
+        // the staged command is kept
+        match feed_line(&mut session, b"stage commit\n") {
+            SessionInput::Commit(commands) => assert_eq!(commands.len(), 1),
+            _ => panic!("expected commit"),
+        }
+    }
+
+    #[test]
+    fn empty_commit() {
+        let mut session = Session::new();
+        feed_line(&mut session, b"stage begin\n");
+        assert!(matches!(feed_line(&mut session, b"stage commit\n"), SessionInput::Commit(commands) if commands.is_empty()));
+    }
+
+    #[test]
+    fn line_too_long() {
+        let mut session = Session::new();
+        let line = [b'a'; MAX_LINE_LEN + 1];
+        assert!(matches!(feed_line(&mut session, &line), SessionInput::Error(Error::LineTooLong)));
+        // the rest of the line is discarded
+        assert!(matches!(feed_line(&mut session, b" pid\n"), SessionInput::Nothing));
+        assert!(matches!(feed_line(&mut session, b"pid\n"), SessionInput::Command(Command::Show(ShowCommand::Pid))));
+    }
+
+    #[test]
+    fn backspace() {
+        let mut reader = LineReader::new();
+        let statuses: Vec<LineStatus, U16> = b"abc\x08\x08x\n".iter()
+            .map(|c| reader.feed(*c))
+            .collect();
+        assert_eq!(statuses.last(), Some(&LineStatus::Complete));
+        assert_eq!(reader.line(), b"ax");
+    }
+
+    #[test]
+    fn control_characters() {
+        let mut session = Session::new();
+        assert!(matches!(feed_line(&mut session, b"\x08\x7Fp\x07id\t\n"), SessionInput::Command(Command::Show(ShowCommand::Pid))));
+    }
+
+    #[test]
+    fn telnet_negotiation() {
+        let mut session = Session::new();
+        // IAC DO SUPPRESS-GO-AHEAD, IAC WILL TERMINAL-TYPE,
+        // IAC SB TERMINAL-TYPE 0 IAC SE
+        let input = b"\xff\xfd\x03\xff\xfb\x18\xff\xfa\x18\x00\xff\xf0pid\n";
+        assert!(matches!(feed_line(&mut session, input), SessionInput::Command(Command::Show(ShowCommand::Pid))));
+        // split across reads
+        assert!(matches!(feed_line(&mut session, b"p\xff"), SessionInput::Nothing));
+        assert!(matches!(feed_line(&mut session, b"\xfb"), SessionInput::Nothing));
+        assert!(matches!(feed_line(&mut session, b"\x01id\n"), SessionInput::Command(Command::Show(ShowCommand::Pid))));
+    }
+
+    #[test]
+    fn semicolon_separated_commands() {
+        let mut session = Session::new();
+        match feed_line(&mut session, b"pid 0 kp 1; pid 0 foo;;pid 0 kd 3\n") {
+            SessionInput::Command(command) =>
+                assert_eq!(command, Command::Pid { channel: 0, parameter: PidParameter::KP, value: 1.0 }),
+            _ => panic!("expected command"),
+        }
+        assert!(session.has_pending_input());
+        // a parser error does not skip the following commands
+        assert!(matches!(session.next_input(), Some(SessionInput::Error(Error::Parser(_)))));
+        match session.next_input() {
+            Some(SessionInput::Command(command)) =>
+                assert_eq!(command, Command::Pid { channel: 0, parameter: PidParameter::KD, value: 3.0 }),
+            _ => panic!("expected command"),
+        }
+        assert!(!session.has_pending_input());
+        assert!(session.next_input().is_none());
+    }
+
+    #[test]
+    fn staging_failure_skips_rest_of_line() {
+        let mut session = Session::new();
+        assert!(matches!(feed_line(&mut session, b"stage commit; pid 0 kp 1\n"),
+                         SessionInput::Error(Error::NotStaging)));
+        assert!(!session.has_pending_input());
+    }
+
+    #[test]
+    fn report_interval_replaces_sample_reports() {
+        let mut session = Session::new();
+        feed_line(&mut session, b"report mode on\n");
+        feed_line(&mut session, b"report interval 100\n");
+        session.set_report_pending(1);
+        assert_eq!(session.is_report_pending(), None);
+
+        session.poll_report_interval(1000);
+        assert_eq!(session.is_report_pending(), Some(0));
+        session.mark_report_sent(0);
+        session.poll_report_interval(1099);
+        assert_eq!(session.is_report_pending(), None);
+        session.poll_report_interval(1100);
+        assert_eq!(session.is_report_pending(), Some(0));
+        session.mark_report_sent(0);
+
+        // back to reports on new samples
+        feed_line(&mut session, b"report interval 0\n");
+        session.poll_report_interval(2000);
+        assert_eq!(session.is_report_pending(), None);
+        session.set_report_pending(1);
+        assert_eq!(session.is_report_pending(), Some(1));
+    }
+
+    #[test]
+    fn report_sent_in_parts() {
+        let mut session = Session::new();
+        feed_line(&mut session, b"report mode on\n");
+        session.set_report_pending(1);
+        assert_eq!(session.is_report_pending(), Some(1));
+
+        session.queue_report(1, b"{\"channel\":1}");
+        assert_eq!(session.is_report_pending(), None);
+        assert_eq!(session.pending_output(), Some(&b"{\"channel\":1}"[..]));
+        session.mark_output_sent(4);
+        assert_eq!(session.pending_output(), Some(&b"annel\":1}"[..]));
+        session.mark_output_sent(9);
+        assert_eq!(session.pending_output(), Some(&b"\n"[..]));
+        session.mark_output_sent(0);
+        assert_eq!(session.pending_output(), Some(&b"\n"[..]));
+        session.mark_output_sent(1);
+        assert_eq!(session.pending_output(), None);
+        assert_eq!(session.is_report_pending(), None);
+    }
+
+    #[test]
+    fn reset_clears_pending_reports() {
+        let mut session = Session::new();
+        feed_line(&mut session, b"report mode on\n");
+        session.set_report_pending(1);
+        assert_eq!(session.is_report_pending(), Some(1));
+
+        // Client reconnects
+        session.reset(ReportOnConnect::Off);
+        assert!(!session.reporting());
+        assert_eq!(session.is_report_pending(), None);
+        feed_line(&mut session, b"report mode on\n");
+        assert_eq!(session.is_report_pending(), None);
+
+        session.reset(ReportOnConnect::On);
+        assert!(session.reporting());
+        assert_eq!(session.is_report_pending(), None);
+        session.set_report_pending(0);
+        assert_eq!(session.is_report_pending(), Some(0));
+
+        session.reset(ReportOnConnect::Snapshot);
+        assert!(!session.reporting());
+        assert_eq!(session.is_report_pending(), Some(0));
+        session.mark_report_sent(0);
+        assert_eq!(session.is_report_pending(), None);
+        session.set_report_pending(0);
+        assert_eq!(session.is_report_pending(), None);
+    }
+
+    #[test]
+    fn all_parts_form_one_object() {
+        let mut session = Session::new();
+        assert!(matches!(feed_line(&mut session, b"all\n"), SessionInput::Command(_)));
+        let mut json = std::string::String::new();
+        while let Some(part) = session.is_all_pending() {
+            json.push_str(core::str::from_utf8(&all_part_prefix(part)).unwrap());
+            json.push_str("{}");
+            json.push_str(core::str::from_utf8(all_part_suffix(part)).unwrap());
+            session.mark_all_part_sent();
+        }
+        assert_eq!(json, "{\"report\":{},\"pid\":{},\"pwm\":{},\"s-h\":{},\"postfilter\":{},\
+                          \"adc\":{},\"tec\":{},\"fan\":{},\"ipv4\":{},\"hwrev\":{}}\n");
+    }
+
+    #[test]
+    fn config_import_until_end() {
+        let mut session = Session::new();
+        assert!(matches!(feed_line(&mut session, b"config import\n"), SessionInput::Nothing));
+        assert!(matches!(feed_line(&mut session, b"{\"channels\":\r\n"), SessionInput::Nothing));
+        assert!(matches!(feed_line(&mut session, b"[]}\n"), SessionInput::Nothing));
+        // one import at a time
+        let mut other = Session::new();
+        assert!(matches!(feed_line(&mut other, b"config import\n"),
+                         SessionInput::Error(Error::ImportBusy)));
+        assert!(matches!(feed_line(&mut session, b"end\n"), SessionInput::Import));
+        assert_eq!(session.finish_import(|document| document.to_vec()),
+                   Some(b"{\"channels\":\n[]}\n".to_vec()));
+        // back to commands
+        assert!(matches!(feed_line(&mut session, b"pid\n"), SessionInput::Command(_)));
+        assert!(matches!(feed_line(&mut other, b"config import\n"), SessionInput::Nothing));
+        other.reset(ReportOnConnect::Off);
+    }
+
+    #[test]
+    fn config_export_forms_one_object() {
+        let mut session = Session::new();
+        assert!(matches!(feed_line(&mut session, b"config export\n"), SessionInput::Command(_)));
+        let mut json = std::string::String::new();
+        while let Some(part) = session.is_export_pending() {
+            json.push_str(core::str::from_utf8(CONFIG_EXPORT_PREFIXES[part]).unwrap());
+            json.push_str("{}");
+            session.mark_export_part_sent();
+        }
+        assert_eq!(json, "{\"channels\":[{},{}],\"ipv4\":{},\"fan\":{}");
+    }
 }
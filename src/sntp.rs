@@ -0,0 +1,226 @@
+use log::{info, warn};
+use smoltcp::{
+    socket::UdpSocket,
+    wire::{IpAddress, IpEndpoint},
+};
+use crate::timer;
+
+/// Local port that requests are sent from
+pub const LOCAL_PORT: u16 = 50123;
+const SERVER_PORT: u16 = 123;
+/// Size of an SNTP packet without extensions
+pub const PACKET_LEN: usize = 48;
+/// Seconds from the NTP era 0 epoch 1900-01-01 to 1970-01-01
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+/// Resynchronize after this long to bound the drift of the
+/// crystal, in ms
+const SYNC_INTERVAL: u64 = 15 * 60 * 1000;
+/// Give up on a request after this long, in ms
+const TIMEOUT: u64 = 5_000;
+/// Delay before the next request after a failure, in ms
+const RETRY_INTERVAL: u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Next request due at the given uptime
+    Idle { next: u64 },
+    /// Request sent at the given uptime, which is also its transmit
+    /// timestamp
+    Waiting { sent: u64 },
+}
+
+/// Simple Network Time Protocol client (RFC 4330).
+///
+/// Sets the Unix time at boot in `timer` from the replies of one
+/// server. Without a server or reply, timestamps stay relative to
+/// boot.
+pub struct Sntp {
+    server: Option<[u8; 4]>,
+    state: State,
+}
+
+impl Default for Sntp {
+    fn default() -> Self {
+        Sntp::new()
+    }
+}
+
+impl Sntp {
+    pub fn new() -> Self {
+        Sntp {
+            server: None,
+            state: State::Idle { next: 0 },
+        }
+    }
+
+    /// Synchronize with a new server right away, or stop with `None`
+    pub fn configure(&mut self, server: Option<[u8; 4]>) {
+        if server.is_none() {
+            timer::set_boot_epoch(None);
+        }
+        self.server = server;
+        self.state = State::Idle { next: 0 };
+    }
+
+    /// Send requests and process replies, to be called on every main
+    /// loop iteration with the uptime in ms
+    pub fn poll(&mut self, socket: &mut UdpSocket, now: u64) {
+        let server = match self.server {
+            Some([a, b, c, d]) => IpEndpoint::new(IpAddress::v4(a, b, c, d), SERVER_PORT),
+            None => {
+                // Discard late replies
+                while socket.recv().is_ok() {}
+                return;
+            }
+        };
+
+        while let Ok((buf, endpoint)) = socket.recv() {
+            if let State::Waiting { sent } = self.state {
+                let server_time = Some(buf)
+                    .filter(|_| endpoint == server)
+                    .and_then(|buf| parse_reply(buf, sent));
+                if let Some(server_time) = server_time {
+                    timer::set_boot_epoch(Some(boot_epoch(server_time, sent, now)));
+                    info!("sntp: synchronized with {}", server);
+                    self.state = State::Idle { next: now + SYNC_INTERVAL };
+                }
+            }
+        }
+
+        match self.state {
+            State::Idle { next } if now >= next => {
+                match socket.send_slice(&request(now), server) {
+                    Ok(()) => self.state = State::Waiting { sent: now },
+                    Err(e) => {
+                        warn!("sntp: {:?}", e);
+                        self.state = State::Idle { next: now + RETRY_INTERVAL };
+                    }
+                }
+            }
+            State::Waiting { sent } if now - sent >= TIMEOUT => {
+                warn!("sntp: no reply from {}", server);
+                self.state = State::Idle { next: now + RETRY_INTERVAL };
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Client request with `cookie` as the transmit timestamp, which the
+/// server returns as the originate timestamp
+pub fn request(cookie: u64) -> [u8; PACKET_LEN] {
+    let mut packet = [0; PACKET_LEN];
+    // LI 0, version 4, mode 3 (client)
+    packet[0] = 0x23;
+    packet[40..48].copy_from_slice(&cookie.to_be_bytes());
+    packet
+}
+
+/// Unix time in ms of the transmit timestamp of a valid server reply
+/// to the request with `cookie`
+pub fn parse_reply(buf: &[u8], cookie: u64) -> Option<u64> {
+    if buf.len() < PACKET_LEN {
+        return None;
+    }
+    let leap = buf[0] >> 6;
+    let mode = buf[0] & 0x7;
+    let stratum = buf[1];
+    // Unsynchronized server, or a kiss-o'-death
+    if mode != 4 || leap == 3 || stratum == 0 {
+        return None;
+    }
+    let mut originate = [0; 8];
+    originate.copy_from_slice(&buf[24..32]);
+    if u64::from_be_bytes(originate) != cookie {
+        return None;
+    }
+    let seconds = u32::from_be_bytes([buf[40], buf[41], buf[42], buf[43]]);
+    let fraction = u32::from_be_bytes([buf[44], buf[45], buf[46], buf[47]]);
+    if seconds == 0 {
+        return None;
+    }
+    // From 2036 on, seconds count from the start of era 1
+    let seconds = if seconds & 0x8000_0000 == 0 {
+        u64::from(seconds) + (1 << 32)
+    } else {
+        u64::from(seconds)
+    };
+    let millis = (u64::from(fraction) * 1000) >> 32;
+    // Era 0 times before 1970 cannot be Unix time
+    Some(seconds.checked_sub(NTP_UNIX_OFFSET)? * 1000 + millis)
+}
+
+/// Unix time in ms at boot, assuming a symmetric network delay
+pub fn boot_epoch(server_time: u64, sent: u64, received: u64) -> u64 {
+    server_time + (received - sent) / 2 - received
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 2021-01-01T00:00:00.5Z
+    const SERVER_TIME: u64 = 1_609_459_200_500;
+
+    fn reply(cookie: u64) -> [u8; PACKET_LEN] {
+        let mut packet = [0; PACKET_LEN];
+        // LI 0, version 4, mode 4 (server), stratum 2
+        packet[0] = 0x24;
+        packet[1] = 2;
+        packet[24..32].copy_from_slice(&cookie.to_be_bytes());
+        let seconds = (SERVER_TIME / 1000 + NTP_UNIX_OFFSET) as u32;
+        packet[40..44].copy_from_slice(&seconds.to_be_bytes());
+        packet[44..48].copy_from_slice(&0x8000_0000u32.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn request_format() {
+        let packet = request(1234);
+        assert_eq!(packet[0], 0x23);
+        assert_eq!(&packet[40..48], &1234u64.to_be_bytes());
+    }
+
+    #[test]
+    fn reply_time() {
+        assert_eq!(parse_reply(&reply(1234), 1234), Some(SERVER_TIME));
+    }
+
+    #[test]
+    fn reply_to_other_request() {
+        assert_eq!(parse_reply(&reply(1234), 5678), None);
+        assert_eq!(parse_reply(&reply(1234)[..40], 1234), None);
+    }
+
+    #[test]
+    fn kiss_of_death() {
+        let mut packet = reply(1234);
+        packet[1] = 0;
+        assert_eq!(parse_reply(&packet, 1234), None);
+    }
+
+    #[test]
+    fn era_1() {
+        let mut packet = reply(1234);
+        packet[40..48].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+        // 2036-02-07T06:28:17Z
+        assert_eq!(parse_reply(&packet, 1234), Some(2_085_978_497_000));
+    }
+
+    #[test]
+    fn before_unix_epoch() {
+        let mut packet = reply(1234);
+        // 1968-01-20T03:14:08Z
+        packet[40..44].copy_from_slice(&0x8000_0000u32.to_be_bytes());
+        assert_eq!(parse_reply(&packet, 1234), None);
+        let seconds = (NTP_UNIX_OFFSET - 1) as u32;
+        packet[40..44].copy_from_slice(&seconds.to_be_bytes());
+        assert_eq!(parse_reply(&packet, 1234), None);
+    }
+
+    #[test]
+    fn symmetric_delay() {
+        // Sent 10 s after boot, reply after 40 ms
+        assert_eq!(boot_epoch(SERVER_TIME, 10_000, 10_040), SERVER_TIME + 20 - 10_040);
+    }
+}
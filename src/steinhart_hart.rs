@@ -10,31 +10,198 @@ use uom::si::{
 };
 use serde::{Deserialize, Serialize};
 
+/// Resistance to temperature conversion
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Model {
+    /// `1/T = 1/T0 + ln(R/R0)/B`
+    Beta,
+    /// `1/T = A + B·ln(R) + C·ln(R)³`
+    Abc,
+}
+
 /// Steinhart-Hart equation parameters
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Parameters {
+    /// Active model
+    pub model: Model,
     /// Base temperature
     pub t0: ThermodynamicTemperature,
     /// Base resistance
     pub r0: ElectricalResistance,
     /// Beta
     pub b: f64,
+    /// Steinhart-Hart coefficient A
+    pub a: f64,
+    /// Steinhart-Hart coefficient B
+    pub b3: f64,
+    /// Steinhart-Hart coefficient C
+    pub c: f64,
+    /// Lowest plausible temperature, readings below are invalid
+    pub t_min: ThermodynamicTemperature,
+    /// Highest plausible temperature, readings above are invalid
+    pub t_max: ThermodynamicTemperature,
+    /// Consecutive open-sensor readings tolerated before the sensor is
+    /// considered faulty
+    pub fault_holdoff: u32,
+    /// Gain of the linear correction applied to converted temperatures
+    pub correction_gain: f64,
+    /// Offset of the linear correction in K
+    pub correction_offset: f64,
 }
 
 impl Parameters {
     /// Perform the voltage to temperature conversion.
     pub fn get_temperature(&self, r: ElectricalResistance) -> ThermodynamicTemperature {
-        let inv_temp = 1.0 / self.t0.get::<kelvin>() + (r / self.r0).get::<ratio>().ln() / self.b;
+        let inv_temp = match self.model {
+            Model::Beta =>
+                1.0 / self.t0.get::<kelvin>() + (r / self.r0).get::<ratio>().ln() / self.b,
+            Model::Abc => {
+                let ln_r = r.get::<ohm>().ln();
+                self.a + self.b3 * ln_r + self.c * ln_r * ln_r * ln_r
+            }
+        };
         ThermodynamicTemperature::new::<kelvin>(1.0 / inv_temp)
     }
+
+    /// Fit the A/B/C coefficients through three measured points of
+    /// resistance and temperature, and select the A/B/C model.
+    ///
+    /// Returns `None` when the points do not determine the coefficients,
+    /// leaving the parameters unchanged.
+    pub fn calibrate(&mut self, points: &[(ElectricalResistance, ThermodynamicTemperature); 3]) -> Option<()> {
+        // Rows of [1, ln(R), ln(R)³ | 1/T]
+        let mut m = [[0.0; 4]; 3];
+        for (row, (r, t)) in m.iter_mut().zip(points.iter()) {
+            let r = r.get::<ohm>();
+            let t = t.get::<kelvin>();
+            if r <= 0.0 || t <= 0.0 {
+                return None;
+            }
+            let ln_r = r.ln();
+            *row = [1.0, ln_r, ln_r * ln_r * ln_r, 1.0 / t];
+            if row.iter().any(|x| !x.is_finite()) {
+                return None;
+            }
+        }
+        let [a, b3, c] = solve3(m)?;
+        self.a = a;
+        self.b3 = b3;
+        self.c = c;
+        self.model = Model::Abc;
+        Some(())
+    }
+
+    /// Apply the linear correction for a known difference between the
+    /// sensor and the sample temperature
+    pub fn correct(&self, temperature: ThermodynamicTemperature) -> ThermodynamicTemperature {
+        ThermodynamicTemperature::new::<degree_celsius>(
+            self.correction_gain * temperature.get::<degree_celsius>() + self.correction_offset
+        )
+    }
+
+    /// Is the converted temperature within the plausible range?
+    pub fn is_valid(&self, temperature: ThermodynamicTemperature) -> bool {
+        temperature >= self.t_min && temperature <= self.t_max
+    }
+}
+
+/// Solve a 3×3 linear system given as augmented matrix by Gaussian
+/// elimination with partial pivoting
+fn solve3(mut m: [[f64; 4]; 3]) -> Option<[f64; 3]> {
+    let scale = m.iter()
+        .flat_map(|row| row[..3].iter())
+        .fold(0.0, |scale: f64, x| scale.max(x.abs()));
+    for col in 0..3 {
+        let pivot = (col..3)
+            .fold(col, |pivot, row| if m[row][col].abs() > m[pivot][col].abs() { row } else { pivot });
+        if m[pivot][col].abs() <= 1e-12 * scale {
+            // Singular
+            return None;
+        }
+        m.swap(col, pivot);
+        for row in col + 1..3 {
+            let factor = m[row][col] / m[col][col];
+            for k in col..4 {
+                m[row][k] -= factor * m[col][k];
+            }
+        }
+    }
+    let mut x = [0.0; 3];
+    for row in (0..3).rev() {
+        let sum = (row + 1..3).fold(m[row][3], |sum, k| sum - m[row][k] * x[k]);
+        x[row] = sum / m[row][row];
+    }
+    Some(x)
 }
 
 impl Default for Parameters {
     fn default() -> Self {
         Parameters {
+            model: Model::Beta,
             t0: ThermodynamicTemperature::new::<degree_celsius>(25.0),
             r0: ElectricalResistance::new::<ohm>(10_000.0),
             b: 3800.0,
+            // typical 10 kΩ NTC
+            a: 1.125e-3,
+            b3: 2.347e-4,
+            c: 8.566e-8,
+            t_min: ThermodynamicTemperature::new::<degree_celsius>(-50.0),
+            t_max: ThermodynamicTemperature::new::<degree_celsius>(150.0),
+            fault_holdoff: 0,
+            correction_gain: 1.0,
+            correction_offset: 0.0,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn beta_model() {
+        let params = Parameters::default();
+        let t = params.get_temperature(params.r0);
+        assert!((t.get::<degree_celsius>() - 25.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn abc_model() {
+        let params = Parameters {
+            model: Model::Abc,
+            ..Parameters::default()
+        };
+        let t = params.get_temperature(ElectricalResistance::new::<ohm>(10_000.0));
+        assert!((t.get::<degree_celsius>() - 25.0).abs() < 0.1);
+        let t = params.get_temperature(ElectricalResistance::new::<ohm>(3_603.0));
+        assert!((t.get::<degree_celsius>() - 50.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn calibrate_from_three_points() {
+        let reference = Parameters {
+            model: Model::Abc,
+            ..Parameters::default()
+        };
+        let point = |r: f64| {
+            let r = ElectricalResistance::new::<ohm>(r);
+            (r, reference.get_temperature(r))
+        };
+        let mut params = Parameters::default();
+        params.calibrate(&[point(32_000.0), point(10_000.0), point(3_600.0)]).unwrap();
+        assert_eq!(params.model, Model::Abc);
+        assert!((params.a - reference.a).abs() < 1e-9);
+        assert!((params.b3 - reference.b3).abs() < 1e-10);
+        assert!((params.c - reference.c).abs() < 1e-11);
+    }
+
+    #[test]
+    fn calibrate_rejects_singular_points() {
+        let mut params = Parameters::default();
+        let t = |t| ThermodynamicTemperature::new::<degree_celsius>(t);
+        let r = |r| ElectricalResistance::new::<ohm>(r);
+        assert_eq!(params.calibrate(&[(r(10_000.0), t(25.0)), (r(10_000.0), t(25.0)), (r(3_600.0), t(50.0))]), None);
+        assert_eq!(params.calibrate(&[(r(0.0), t(25.0)), (r(10_000.0), t(25.0)), (r(3_600.0), t(50.0))]), None);
+        assert_eq!(params, Parameters::default());
+    }
+}
@@ -0,0 +1,169 @@
+use core::{cell::RefCell, fmt::{self, Write}};
+use cortex_m::interrupt::{free, Mutex};
+use heapless::{consts::*, String, Vec};
+use log::{Level, Record};
+use crate::timer;
+
+/// One `{"log":...}` line, without the trailing newline
+pub type LogLine = String<U192>;
+/// Message text is truncated to fit into a `LogLine`
+const MAX_MESSAGE_LEN: usize = 128;
+/// Lines that may be sent in a burst
+const RATE_BURST: u32 = 8;
+/// Sustained rate of one line per interval in ms
+const RATE_INTERVAL: u32 = 250;
+
+/// Log lines waiting for the main loop to send them to TCP clients
+static QUEUE: Mutex<RefCell<Option<LogQueue>>> = Mutex::new(RefCell::new(None));
+
+/// Start or stop queueing log records for TCP clients
+pub fn set_enabled(enabled: bool) {
+    free(|cs| {
+        let mut queue = QUEUE.borrow(cs).borrow_mut();
+        match (enabled, queue.is_some()) {
+            (true, false) => *queue = Some(LogQueue::new(timer::now())),
+            (false, true) => *queue = None,
+            _ => {}
+        }
+    });
+}
+
+/// Called by the logger for every record
+pub fn log(record: &Record) {
+    free(|cs| {
+        if let Some(queue) = QUEUE.borrow(cs).borrow_mut().as_mut() {
+            queue.push(timer::now(), record.level(), record.args());
+        }
+    });
+}
+
+/// Take the oldest queued line
+pub fn pop() -> Option<LogLine> {
+    free(|cs| {
+        QUEUE.borrow(cs).borrow_mut().as_mut()
+            .and_then(|queue| queue.pop())
+    })
+}
+
+/// Rate-limited queue of formatted log lines
+pub struct LogQueue {
+    lines: Vec<LogLine, U8>,
+    /// Records that were dropped since the last queued line
+    dropped: u32,
+    tokens: u32,
+    /// Time of the last token refill in ms
+    refilled: u32,
+}
+
+impl LogQueue {
+    pub fn new(now: u32) -> Self {
+        LogQueue {
+            lines: Vec::new(),
+            dropped: 0,
+            tokens: RATE_BURST,
+            refilled: now,
+        }
+    }
+
+    pub fn push(&mut self, now: u32, level: Level, args: &fmt::Arguments) {
+        let refill = now.wrapping_sub(self.refilled) / RATE_INTERVAL;
+        if refill > 0 {
+            self.tokens = self.tokens.saturating_add(refill).min(RATE_BURST);
+            self.refilled = self.refilled.wrapping_add(refill * RATE_INTERVAL);
+        }
+
+        if self.tokens == 0 || self.lines.len() == self.lines.capacity() {
+            self.dropped += 1;
+            return;
+        }
+        let line = format_line(level, args, self.dropped);
+        let _ = self.lines.push(line);
+        self.tokens -= 1;
+        self.dropped = 0;
+    }
+
+    pub fn pop(&mut self) -> Option<LogLine> {
+        if self.lines.is_empty() {
+            return None;
+        }
+        self.lines.rotate_left(1);
+        self.lines.pop()
+    }
+}
+
+/// `{"log":{"level":"WARN","message":"...","dropped":0}}`
+pub fn format_line(level: Level, args: &fmt::Arguments, dropped: u32) -> LogLine {
+    let mut line = LogLine::new();
+    let _ = write!(line, "{{\"log\":{{\"level\":\"{}\",\"message\":\"", level);
+    let _ = write!(JsonEscape { output: &mut line, remaining: MAX_MESSAGE_LEN }, "{}", args);
+    let _ = write!(line, "\",\"dropped\":{}}}}}", dropped);
+    line
+}
+
+/// Escapes a JSON string, truncating after `remaining` bytes
+struct JsonEscape<'a> {
+    output: &'a mut LogLine,
+    remaining: usize,
+}
+
+impl<'a> Write for JsonEscape<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut buf = [0; 4];
+            let escaped = match c {
+                '"' => "\\\"",
+                '\\' => "\\\\",
+                '\n' => "\\n",
+                '\r' => "\\r",
+                '\t' => "\\t",
+                c if c < ' ' => "?",
+                c => c.encode_utf8(&mut buf),
+            };
+            if escaped.len() > self.remaining {
+                return Err(fmt::Error);
+            }
+            self.output.push_str(escaped)
+                .map_err(|_| fmt::Error)?;
+            self.remaining -= escaped.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn line_format() {
+        let line = format_line(Level::Warn, &format_args!("read_reg {:02X}: \"retry\"", 4), 2);
+        assert_eq!(line.as_str(), "{\"log\":{\"level\":\"WARN\",\"message\":\"read_reg 04: \\\"retry\\\"\",\"dropped\":2}}");
+    }
+
+    #[test]
+    fn long_message_is_truncated() {
+        let message = [b'x'; 300];
+        let message = core::str::from_utf8(&message).unwrap();
+        let line = format_line(Level::Error, &format_args!("{}", message), 0);
+        assert!(line.ends_with("\",\"dropped\":0}}"));
+        assert_eq!(line.matches('x').count(), MAX_MESSAGE_LEN);
+    }
+
+    #[test]
+    fn rate_limited() {
+        let mut queue = LogQueue::new(0);
+        for _ in 0..RATE_BURST + 3 {
+            queue.push(0, Level::Info, &format_args!("burst"));
+        }
+        assert_eq!(queue.lines.len() as u32, RATE_BURST);
+        while queue.pop().is_some() {}
+
+        // one token after 250 ms, reporting the dropped records
+        queue.push(100, Level::Info, &format_args!("early"));
+        queue.push(250, Level::Info, &format_args!("late"));
+        let line = queue.pop().unwrap();
+        assert!(line.contains("\"late\""));
+        assert!(line.ends_with("\"dropped\":4}}"));
+        assert_eq!(queue.pop(), None);
+    }
+}
@@ -0,0 +1,62 @@
+use serde::Serialize;
+use uom::si::{
+    f64::{
+        ElectricCurrent,
+        ThermodynamicTemperature,
+        Time,
+    },
+    electric_current::ampere,
+    thermodynamic_temperature::kelvin,
+    time::second,
+};
+
+/// Heat moved by the TEC, estimated from its Peltier coefficient
+/// `Π = S * T` and integrated over time
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ThermalEnergy {
+    /// Seebeck coefficient of the TEC module in V/K
+    pub peltier: f64,
+    /// Heat moved since the last reset in J
+    pub energy: f64,
+}
+
+impl ThermalEnergy {
+    /// Account the heat moved by `current` at `temperature` during `dt`
+    pub fn update(&mut self, current: ElectricCurrent, temperature: ThermodynamicTemperature, dt: Time) {
+        let power = self.peltier * temperature.get::<kelvin>() * current.get::<ampere>();
+        self.energy += power * dt.get::<second>();
+    }
+
+    pub fn reset(&mut self) {
+        self.energy = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uom::si::time::millisecond;
+
+    #[test]
+    fn integrate_energy() {
+        let mut tec = ThermalEnergy {
+            peltier: 0.05,
+            ..ThermalEnergy::default()
+        };
+        let dt = Time::new::<millisecond>(100.0);
+        // 10 s at 1 A, 300 K: 0.05 V/K * 300 K * 1 A * 10 s = 150 J
+        for _ in 0..100 {
+            tec.update(ElectricCurrent::new::<ampere>(1.0), ThermodynamicTemperature::new::<kelvin>(300.0), dt);
+        }
+        assert!((tec.energy - 150.0).abs() < 1e-6);
+        // 5 s at -2 A, 250 K: -125 J
+        for _ in 0..50 {
+            tec.update(ElectricCurrent::new::<ampere>(-2.0), ThermodynamicTemperature::new::<kelvin>(250.0), dt);
+        }
+        assert!((tec.energy - 25.0).abs() < 1e-6);
+
+        tec.reset();
+        assert_eq!(tec.energy, 0.0);
+        assert_eq!(tec.peltier, 0.05);
+    }
+}
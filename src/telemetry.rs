@@ -0,0 +1,162 @@
+use smoltcp::{
+    time::{Duration, Instant},
+    wire::{IpAddress, IpEndpoint},
+};
+use crate::command_parser::UdpTelemetry;
+
+/// Local port that telemetry is sent from, apart from the command
+/// server and the ports of MQTT connections
+pub const LOCAL_PORT: u16 = 50200;
+/// Shortest interval in s between periodic reports
+pub const INTERVAL_MIN: f64 = 0.001;
+
+/// Periodic reports to a UDP endpoint, independent of TCP sessions
+pub struct Telemetry {
+    endpoint: Option<IpEndpoint>,
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Telemetry::new()
+    }
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Telemetry {
+            endpoint: None,
+            interval: Duration::from_millis(0),
+            next: None,
+        }
+    }
+
+    /// Start sending to a new endpoint, or stop with `None`
+    pub fn configure(&mut self, config: Option<UdpTelemetry>) {
+        self.endpoint = config.as_ref().map(|config| {
+            let [a, b, c, d] = config.address;
+            IpEndpoint::new(IpAddress::v4(a, b, c, d), config.port)
+        });
+        self.interval = config.map_or(Duration::from_millis(0), |config| {
+            Duration::from_millis((config.interval * 1000.0) as u64)
+        });
+        self.next = None;
+    }
+
+    /// Returns the endpoint if a report is due at `now`, and schedules
+    /// the next one
+    pub fn poll(&mut self, now: Instant) -> Option<IpEndpoint> {
+        let endpoint = self.endpoint?;
+        if self.per_sample() {
+            return None;
+        }
+        match self.next {
+            Some(next) if now < next => None,
+            next => {
+                // Keep the schedule, unless it has fallen behind
+                let next = next
+                    .map(|next| next + self.interval)
+                    .filter(|next| *next > now)
+                    .unwrap_or(now + self.interval);
+                self.next = Some(next);
+                Some(endpoint)
+            }
+        }
+    }
+
+    /// Returns the endpoint if the report of a new sample is to be sent
+    pub fn poll_sample(&self) -> Option<IpEndpoint> {
+        self.endpoint.filter(|_| self.per_sample())
+    }
+
+    /// Interval 0 sends the report of every new sample
+    fn per_sample(&self) -> bool {
+        self.interval.total_millis() == 0
+    }
+}
+
+/// Whether reports can be sent every `interval` s, 0 for every sample
+pub fn is_valid_interval(interval: f64) -> bool {
+    interval == 0.0 || (interval >= INTERVAL_MIN && interval.is_finite())
+}
+
+/// Size of the datagram carrying `report`
+pub fn packet_len(report: &[u8]) -> usize {
+    report.len() + 1
+}
+
+/// One report per datagram, newline-terminated like on TCP
+pub fn write_packet(report: &[u8], buf: &mut [u8]) {
+    buf[..report.len()].copy_from_slice(report);
+    buf[report.len()] = b'\n';
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn telemetry(interval: f64) -> Telemetry {
+        let mut telemetry = Telemetry::new();
+        telemetry.configure(Some(UdpTelemetry {
+            address: [192, 168, 1, 10],
+            port: 5000,
+            interval,
+        }));
+        telemetry
+    }
+
+    #[test]
+    fn disabled_sends_nothing() {
+        let mut telemetry = telemetry(1.0);
+        telemetry.configure(None);
+        assert_eq!(telemetry.poll(Instant::from_millis(0)), None);
+        assert_eq!(telemetry.poll(Instant::from_millis(10_000)), None);
+    }
+
+    #[test]
+    fn interval_range() {
+        assert!(is_valid_interval(0.0));
+        assert!(is_valid_interval(0.001));
+        assert!(!is_valid_interval(0.0005));
+        assert!(!is_valid_interval(-1.0));
+        assert!(!is_valid_interval(f64::NAN));
+        assert!(!is_valid_interval(f64::INFINITY));
+    }
+
+    #[test]
+    fn periodic_schedule() {
+        let mut telemetry = telemetry(0.5);
+        let endpoint = IpEndpoint::new(IpAddress::v4(192, 168, 1, 10), 5000);
+        // first report right away
+        assert_eq!(telemetry.poll(Instant::from_millis(1000)), Some(endpoint));
+        assert_eq!(telemetry.poll(Instant::from_millis(1499)), None);
+        // late poll does not shift the schedule
+        assert_eq!(telemetry.poll(Instant::from_millis(1510)), Some(endpoint));
+        assert_eq!(telemetry.poll(Instant::from_millis(1999)), None);
+        assert_eq!(telemetry.poll(Instant::from_millis(2000)), Some(endpoint));
+        // after a stall, resume without a burst
+        assert_eq!(telemetry.poll(Instant::from_millis(5000)), Some(endpoint));
+        assert_eq!(telemetry.poll(Instant::from_millis(5100)), None);
+        assert_eq!(telemetry.poll(Instant::from_millis(5500)), Some(endpoint));
+    }
+
+    #[test]
+    fn per_sample() {
+        let mut per_sample = telemetry(0.0);
+        let endpoint = IpEndpoint::new(IpAddress::v4(192, 168, 1, 10), 5000);
+        assert_eq!(per_sample.poll(Instant::from_millis(1000)), None);
+        assert_eq!(per_sample.poll_sample(), Some(endpoint));
+        // not with an interval
+        assert_eq!(telemetry(0.5).poll_sample(), None);
+    }
+
+    #[test]
+    fn packet_format() {
+        let report = b"[{\"channel\":0}]";
+        let mut buf = [0; 32];
+        let len = packet_len(report);
+        write_packet(report, &mut buf[..len]);
+        assert_eq!(&buf[..len], b"[{\"channel\":0}]\n");
+    }
+}
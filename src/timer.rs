@@ -13,8 +13,10 @@ use stm32f4xx_hal::{
 const TIMER_RATE: u32 = 500;
 /// Interval duration in milliseconds
 const TIMER_DELTA: u32 = 1000 / TIMER_RATE;
-/// Elapsed time in milliseconds
-static TIMER_MS: Mutex<RefCell<u32>> = Mutex::new(RefCell::new(0));
+/// Elapsed time in milliseconds, wide enough to never wrap
+static TIMER_MS: Mutex<RefCell<u64>> = Mutex::new(RefCell::new(0));
+/// Unix time in milliseconds at boot, known after SNTP synchronization
+static BOOT_EPOCH_MS: Mutex<RefCell<Option<u64>>> = Mutex::new(RefCell::new(None));
 
 /// Setup SysTick exception
 pub fn setup(syst: SYST, clocks: Clocks) {
@@ -29,12 +31,17 @@ pub fn setup(syst: SYST, clocks: Clocks) {
 fn SysTick() {
     cortex_m::interrupt::free(|cs| {
         *TIMER_MS.borrow(cs)
-            .borrow_mut() += TIMER_DELTA;
+            .borrow_mut() += u64::from(TIMER_DELTA);
     });
 }
 
-/// Obtain current time in milliseconds
+/// Obtain current time in milliseconds, wraps after ~49 days
 pub fn now() -> u32 {
+    uptime() as u32
+}
+
+/// Milliseconds since boot
+pub fn uptime() -> u64 {
     cortex_m::interrupt::free(|cs| {
         *TIMER_MS.borrow(cs)
             .borrow()
@@ -42,6 +49,23 @@ pub fn now() -> u32 {
     })
 }
 
+/// Set the Unix time at boot, `None` for relative time only
+pub fn set_boot_epoch(epoch_ms: Option<u64>) {
+    cortex_m::interrupt::free(|cs| {
+        *BOOT_EPOCH_MS.borrow(cs)
+            .borrow_mut() = epoch_ms;
+    });
+}
+
+/// Unix time in milliseconds, `None` until synchronized
+pub fn epoch() -> Option<u64> {
+    let boot_epoch = cortex_m::interrupt::free(|cs| {
+        *BOOT_EPOCH_MS.borrow(cs)
+            .borrow()
+    });
+    boot_epoch.map(|boot_epoch| boot_epoch + uptime())
+}
+
 /// block for at least `amount` milliseconds
 pub fn sleep(amount: u32) {
     let start = now();
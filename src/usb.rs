@@ -78,6 +78,7 @@ impl Log for Logger {
         if self.enabled(record.metadata()) {
             let mut output = SerialOutput;
             let _ = writeln!(&mut output, "{} - {}", record.level(), record.args());
+            crate::tcp_log::log(record);
         }
     }
 
@@ -0,0 +1,117 @@
+use core::cell::RefCell;
+use cortex_m::interrupt::{free, Mutex};
+use serde::Serialize;
+
+/// LSI clock of the independent watchdog in Hz
+const LSI_HZ: u32 = 32_000;
+/// Shortest timeout in ms, with a margin above the up to 500 ms of a
+/// 16 KiB flash sector erase during which the main loop is blocked
+pub const INTERVAL_MIN: u32 = 2_000;
+/// Longest timeout in ms, the 12-bit reload value with the largest
+/// prescaler of 256
+pub const INTERVAL_MAX: u32 = 4096 * 256 * 1000 / LSI_HZ;
+/// Main loop iterations longer than this share of the interval in
+/// percent are logged
+const NEAR_MISS_PERCENT: u32 = 50;
+
+/// Main loop timing against the watchdog interval, for the
+/// `watchdog` command
+static STATS: Mutex<RefCell<Stats>> = Mutex::new(RefCell::new(Stats::new()));
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Stats {
+    interval: u32,
+    last_feed: Option<u32>,
+    /// Longest time between two feeds since the interval was set
+    max_loop: u32,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Stats {
+            interval: 0,
+            last_feed: None,
+            max_loop: 0,
+        }
+    }
+
+    /// Returns the time since the last feed if it is a new maximum
+    /// that came close to the deadline
+    fn feed(&mut self, now: u32) -> Option<u32> {
+        let elapsed = now.wrapping_sub(self.last_feed.replace(now)?);
+        if elapsed <= self.max_loop {
+            return None;
+        }
+        self.max_loop = elapsed;
+        let near_miss = 100 * u64::from(elapsed) >= u64::from(NEAR_MISS_PERCENT * self.interval);
+        if near_miss {
+            Some(elapsed)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the IWDG can be programmed to time out after `interval` ms
+pub fn is_valid_interval(interval: u32) -> bool {
+    (INTERVAL_MIN..=INTERVAL_MAX).contains(&interval)
+}
+
+/// The watchdog has been (re)started with `interval` ms
+pub fn set_interval(interval: u32) {
+    free(|cs| {
+        let mut stats = STATS.borrow(cs).borrow_mut();
+        stats.interval = interval;
+        stats.max_loop = 0;
+    });
+}
+
+/// The watchdog has been fed at `now` ms, returns the time since the
+/// last feed if it is a new maximum above half the interval
+pub fn feed(now: u32) -> Option<u32> {
+    free(|cs| STATS.borrow(cs).borrow_mut().feed(now))
+}
+
+/// `{"interval_ms":...}` for `watchdog`
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Summary {
+    interval_ms: u32,
+    /// Longest main loop iteration since the interval was set
+    max_loop_ms: u32,
+}
+
+pub fn summary() -> Summary {
+    let stats = free(|cs| *STATS.borrow(cs).borrow());
+    Summary {
+        interval_ms: stats.interval,
+        max_loop_ms: stats.max_loop,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interval_range() {
+        assert_eq!(INTERVAL_MAX, 32_768);
+        assert!(!is_valid_interval(0));
+        // Shorter than a flash operation may take
+        assert!(!is_valid_interval(1_000));
+        assert!(is_valid_interval(2_000));
+        assert!(!is_valid_interval(40_000));
+    }
+
+    #[test]
+    fn near_miss() {
+        let mut stats = Stats { interval: 1_000, ..Stats::new() };
+        assert_eq!(stats.feed(100), None);
+        // Not close to the deadline
+        assert_eq!(stats.feed(200), None);
+        assert_eq!(stats.max_loop, 100);
+        assert_eq!(stats.feed(900), Some(700));
+        // Only new maxima
+        assert_eq!(stats.feed(1_500), None);
+        assert_eq!(stats.max_loop, 700);
+    }
+}